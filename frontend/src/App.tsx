@@ -3,6 +3,7 @@ import { Routes, Route } from 'react-router-dom';
 import { Layout } from './components/Layout';
 import { Home } from './components/Home';
 import { ManageInstallations } from './components/ManageInstallations';
+import { CrateDownloadsList } from './components/CrateDownloads';
 import { ToastContainer } from 'react-toastify';
 import 'react-toastify/dist/ReactToastify.css';
 
@@ -13,6 +14,7 @@ function App() {
                 <Route path="/" element={<Layout />}>
                     <Route index element={<Home />} />
                     <Route path="/manage-installations" element={<ManageInstallations />} />
+                    <Route path="/crate-downloads" element={<CrateDownloadsList />} />
                     <Route path="*" element={<Home />} />
                 </Route>
             </Routes>
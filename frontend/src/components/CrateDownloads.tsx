@@ -0,0 +1,67 @@
+import { FC, useState, useEffect, useCallback } from "react";
+import { Table } from "react-bootstrap";
+import { toast } from "react-toastify";
+
+type VersionDownloads = {
+  version: string;
+  yanked: boolean;
+  downloads: number;
+};
+
+type CrateDownloads = {
+  name: string;
+  downloads: number;
+  versions: VersionDownloads[];
+};
+
+export const CrateDownloadsList: FC = () => {
+  const [crates, setCrates] = useState<CrateDownloads[]>([]);
+
+  const getCrates = useCallback(async () => {
+    const response = await fetch(`api/crates`);
+    if (!response.ok) {
+      throw Error(response.statusText);
+    }
+    setCrates(await response.json());
+  }, []);
+
+  useEffect(() => {
+    toast.promise(getCrates(), {
+      error: "error while getting the crates",
+    });
+  }, []);
+
+  if (crates.length === 0) {
+    return <div className="ms-5">No crate was published yet.</div>;
+  }
+
+  return (
+    <div className="mx-5">
+      <Table striped size="sm">
+        <thead>
+          <tr>
+            <th>Crate</th>
+            <th>Downloads</th>
+            <th>Versions</th>
+          </tr>
+        </thead>
+        <tbody>
+          {crates.map((item) => (
+            <tr key={item.name}>
+              <td>{item.name}</td>
+              <td>{item.downloads}</td>
+              <td>
+                {item.versions
+                  .map(
+                    (version) =>
+                      `${version.version}${version.yanked ? " (yanked)" : ""}: ${version.downloads}`
+                  )
+                  .join(", ")}
+              </td>
+            </tr>
+          ))}
+        </tbody>
+      </Table>
+    </div>
+  );
+};
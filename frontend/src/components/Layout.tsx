@@ -1,8 +1,17 @@
-import { FC } from 'react';
+import { FC, useState, useEffect } from 'react';
 import { Link, Outlet } from 'react-router-dom';
-import { Navbar, Nav, Container } from 'react-bootstrap';
+import { Navbar, Nav, Container, Alert } from 'react-bootstrap';
 
 export const Layout: FC = () => {
+    const [banner, setBanner] = useState("");
+
+    useEffect(() => {
+        fetch(`api/banner`)
+            .then((response) => response.ok ? response.json() : { message: "" })
+            .then((body) => setBanner(body.message))
+            .catch(() => setBanner(""));
+    }, []);
+
     return <div>
         <Navbar bg="light" expand="lg">
             <Container>
@@ -12,10 +21,12 @@ export const Layout: FC = () => {
                     <Nav className="me-auto">
                         <Nav.Link as={Link} to="/">Home</Nav.Link>
                         <Nav.Link as={Link} to="/manage-installations">Manage Rust installations</Nav.Link>
+                        <Nav.Link as={Link} to="/crate-downloads">Crates</Nav.Link>
                     </Nav>
                 </Navbar.Collapse>
             </Container>
         </Navbar>
+        {banner && <Alert variant="warning" className="rounded-0" style={{ whiteSpace: "pre-line" }}>{banner}</Alert>}
         <Outlet />
     </div>
 
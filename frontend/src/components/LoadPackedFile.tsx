@@ -1,21 +1,85 @@
-import { FC } from "react";
-import { Button } from "react-bootstrap";
+import { FC, useState } from "react";
+import { Button, ProgressBar } from "react-bootstrap";
 import { FileUploader } from "react-drag-drop-files";
 import Highlight from "react-highlight";
 import { toast } from "react-toastify";
 
+interface LoadProgress {
+  state: "idle" | "receiving" | "unpacking" | "done" | "failed";
+  bytesReceived: number;
+  totalBytes: number | null;
+  filesExtracted: number;
+  error: string | null;
+}
+
+// Follow the progress events of the load, newline delimited JSON, until it ends.
+const followProgress = async (
+  onProgress: (progress: LoadProgress) => void
+) => {
+  const response = await fetch("api/load-pack-file/progress");
+  const reader = response.body?.getReader();
+  if (!reader) {
+    return;
+  }
+  const decoder = new TextDecoder();
+  let buffered = "";
+  for (;;) {
+    const { done, value } = await reader.read();
+    if (done) {
+      return;
+    }
+    buffered += decoder.decode(value, { stream: true });
+    const lines = buffered.split("\n");
+    buffered = lines.pop() ?? "";
+    for (const line of lines.filter((line) => line.length > 0)) {
+      const progress: LoadProgress = JSON.parse(line);
+      // The end of a previous load may come first.
+      if (progress.state !== "done" && progress.state !== "failed") {
+        onProgress(progress);
+      }
+    }
+  }
+};
+
 export const LoadPackedFile: FC = () => {
+  const [progress, setProgress] = useState<LoadProgress | null>(null);
+
   const handleChange = (file: any) => {
-    toast.promise(
-      fetch("api/load-pack-file", {
-        method: "put",
-        body: file,
-      }),
-      {
-        error: "upload failed",
-        pending: "uploading",
-        success: "uploaded successfully.",
-      }
+    followProgress(setProgress).catch(() => {});
+    toast
+      .promise(
+        fetch("api/load-pack-file", {
+          method: "put",
+          body: file,
+        }),
+        {
+          error: "upload failed",
+          pending: "uploading",
+          success: "uploaded successfully.",
+        }
+      )
+      .finally(() => setProgress(null));
+  };
+
+  const progressBar = () => {
+    if (!progress) {
+      return null;
+    }
+    if (progress.state === "unpacking") {
+      return (
+        <ProgressBar
+          animated
+          now={100}
+          label={`unpacking: ${progress.filesExtracted} files extracted`}
+        />
+      );
+    }
+    const total = progress.totalBytes || progress.bytesReceived || 1;
+    return (
+      <ProgressBar
+        now={(progress.bytesReceived / total) * 100}
+        label={`uploading: ${Math.round(progress.bytesReceived / 1048576)} MB`}
+      />
     );
   };
 
@@ -29,12 +93,13 @@ export const LoadPackedFile: FC = () => {
       <div className="pb-3">
         Run crates-registry pack --help for more information
       </div>
-      <FileUploader handleChange={handleChange} name="file" types={["tar"]}>
+      <FileUploader handleChange={handleChange} name="file" types={["tar", "zst"]}>
         <div className="ml-3 px-4 py-2 border border-info rounded d-flex flex-column justify-content-center">
           <Button className="d-block">Select file...</Button>
           <span>or drag and drop file here</span>
         </div>
       </FileUploader>
+      <div className="pt-3">{progressBar()}</div>
     </div>
   );
 };
@@ -1,6 +1,7 @@
 import { FC } from "react";
 import { RustVersionsList } from "./RustVersionsList";
 import { LoadPackedFile } from "./LoadPackedFile";
+import { PendingList } from "./PendingList";
 
 export const ManageInstallations: FC = () => {
   return (
@@ -15,6 +16,10 @@ export const ManageInstallations: FC = () => {
           <h6 className='mt-3'>Load new packed_file: </h6>
           <LoadPackedFile />
         </div>
+        <div className="d-flex flex-column justify-content-start align-items-start">
+          <h6 className='mt-3'>Pending review:</h6>
+          <PendingList />
+        </div>
       </div>
     </div>
   );
@@ -0,0 +1,116 @@
+import { FC, useState, useEffect, useCallback } from "react";
+import { Button, Form, ListGroup } from "react-bootstrap";
+import { toast } from "react-toastify";
+
+type Pending = {
+  id: string;
+  kind: "publish" | "pack";
+  description: string;
+  size: number;
+  publisher: string | null;
+  stagedAt: number;
+};
+
+export const PendingList: FC = () => {
+  const [pending, setPending] = useState<Pending[]>([]);
+  // The --admin-token of the registry, kept for the session.
+  const [adminToken, setAdminToken] = useState(
+    () => sessionStorage.getItem("adminToken") ?? ""
+  );
+
+  const getPending = useCallback(async () => {
+    const response = await fetch(`api/pending`, {
+      headers: { Authorization: adminToken },
+    });
+    if (!response.ok) {
+      throw Error(response.statusText);
+    }
+    setPending(await response.json());
+  }, [adminToken]);
+
+  useEffect(() => {
+    sessionStorage.setItem("adminToken", adminToken);
+    if (adminToken) {
+      toast.promise(getPending(), {
+        error: "error while getting the pending list",
+      });
+    }
+  }, [adminToken]);
+
+  const review = async (id: string, action: "accept" | "reject") => {
+    const response = await fetch(`api/pending/${id}/${action}`, {
+      method: "post",
+      headers: { Authorization: adminToken },
+    });
+    if (!response.ok) {
+      const body = await response.json().catch(() => null);
+      throw Error(body?.errors?.[0]?.detail ?? response.statusText);
+    }
+    await getPending();
+  };
+
+  const tokenInput = (
+    <Form.Control
+      type="password"
+      placeholder="Admin token"
+      className="mb-3 w-25"
+      defaultValue={adminToken}
+      onBlur={(event) => setAdminToken(event.target.value)}
+    />
+  );
+
+  if (pending.length === 0) {
+    return (
+      <div className="ms-5">
+        {tokenInput}
+        Nothing is waiting for review.
+      </div>
+    );
+  }
+
+  return (
+    <div className="ms-5">
+      {tokenInput}
+      <ListGroup>
+        {pending.map((item) => (
+          <ListGroup.Item
+            key={item.id}
+            className="d-flex justify-content-between align-items-center"
+          >
+            <span className="me-3">
+              {item.description}
+              {item.publisher && ` (publisher ${item.publisher})`}
+            </span>
+            <span>
+              <Button
+                size="sm"
+                variant="success"
+                className="me-2"
+                onClick={() =>
+                  toast.promise(review(item.id, "accept"), {
+                    error: `failed to accept ${item.description}`,
+                    success: `${item.description} accepted`,
+                  })
+                }
+              >
+                Accept
+              </Button>
+              <Button
+                size="sm"
+                variant="danger"
+                onClick={() =>
+                  toast.promise(review(item.id, "reject"), {
+                    error: `failed to reject ${item.description}`,
+                    success: `${item.description} rejected`,
+                  })
+                }
+              >
+                Reject
+              </Button>
+            </span>
+          </ListGroup.Item>
+        ))}
+      </ListGroup>
+    </div>
+  );
+};
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use glob::Pattern;
+use serde::Deserialize;
+
+/// The content of an `acl.toml` file, e.g.
+///
+/// ```toml
+/// [crates]
+/// "team-a-*" = ["3f2a9c1b0d4e"]
+/// "shared-lib" = ["3f2a9c1b0d4e", "8d1e07c2b5a9"]
+/// ```
+///
+/// Each crate name pattern maps to the identities of the publishing tokens
+/// allowed to publish the matching crates, as shown in the commit messages.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    crates: BTreeMap<String, Vec<String>>,
+}
+
+/// Who may publish which crates. Crates matching none of the patterns may be
+/// published by anyone.
+#[derive(Debug)]
+pub(crate) struct Acl {
+    rules: Vec<(Pattern, Vec<String>)>,
+}
+
+impl Acl {
+    /// Read the access control list at `path`.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("invalid access control list {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: AclFile = toml::from_str(content)?;
+        let rules = file
+            .crates
+            .into_iter()
+            .map(|(pattern, publishers)| {
+                let pattern = Pattern::new(&pattern)
+                    .with_context(|| format!("invalid crate name pattern {}", pattern))?;
+                Ok((pattern, publishers))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Whether `publisher` may publish the crate `name`: either no pattern
+    /// matches the crate, or the publisher is allowed by one that does.
+    pub(crate) fn allows(&self, name: &str, publisher: Option<&str>) -> bool {
+        let mut matching = self
+            .rules
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(name))
+            .peekable();
+        if matching.peek().is_none() {
+            return true;
+        }
+        let Some(publisher) = publisher else {
+            return false;
+        };
+        matching.any(|(_, publishers)| publishers.iter().any(|allowed| allowed == publisher))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        let acl = Acl::parse(
+            r#"
+            [crates]
+            "team-a-*" = ["aaaa"]
+            "team-?-shared" = ["bbbb"]
+            "#,
+        )
+        .unwrap();
+        assert!(acl.allows("team-a-core", Some("aaaa")));
+        assert!(!acl.allows("team-a-core", Some("bbbb")));
+        assert!(!acl.allows("team-a-core", None));
+        // Either matching pattern allows the publish.
+        assert!(acl.allows("team-a-shared", Some("aaaa")));
+        assert!(acl.allows("team-a-shared", Some("bbbb")));
+        assert!(!acl.allows("team-b-shared", Some("aaaa")));
+        // Crates matching no pattern are not restricted.
+        assert!(acl.allows("other", None));
+    }
+
+    #[test]
+    fn invalid_acl() {
+        assert!(Acl::parse(
+            r#"[crates]
+"team-[" = ["aaaa"]"#
+        )
+        .is_err());
+        assert!(Acl::parse(
+            r#"[crate]
+"team-a-*" = ["aaaa"]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use warp::http::HeaderValue;
+
+/// The header carrying the banner in the responses of the registry.
+pub(crate) const BANNER_HEADER: &str = "X-Registry-Banner";
+
+/// The maximum size of a request setting the banner.
+pub(crate) const MAX_BANNER_SIZE: u64 = 16 * 1024;
+
+/// The body of the banner endpoints.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct BannerMessage {
+    pub(crate) message: String,
+}
+
+/// A message from the operators to the users of the registry, e.g. to
+/// announce a maintenance window. It's stored in `<root>/banner.txt` and
+/// empty by default.
+#[derive(Debug)]
+pub(crate) struct Banner {
+    path: PathBuf,
+    message: RwLock<String>,
+}
+
+impl Banner {
+    /// Load the banner of the registry at `root`.
+    pub(crate) fn load(root: &Path) -> Result<Self> {
+        let path = root.join("banner.txt");
+        let message = match std::fs::read_to_string(&path) {
+            Ok(message) => message.trim().to_string(),
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path,
+            message: RwLock::new(message),
+        })
+    }
+
+    /// The current message, empty when there's none.
+    pub(crate) fn message(&self) -> String {
+        self.message.read().unwrap().clone()
+    }
+
+    /// Replace the message, an empty one removes the banner.
+    pub(crate) fn set(&self, message: &str) -> Result<()> {
+        let message = message.trim();
+        let mut current = self.message.write().unwrap();
+        if message.is_empty() {
+            match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    return Err(err)
+                        .with_context(|| format!("failed to remove {}", self.path.display()))
+                }
+                _ => (),
+            }
+        } else {
+            std::fs::write(&self.path, message)
+                .with_context(|| format!("failed to write {}", self.path.display()))?;
+        }
+        *current = message.to_string();
+        Ok(())
+    }
+
+    /// The value of the [`BANNER_HEADER`], if there's a message. Header
+    /// values are a single line, the control characters are replaced.
+    pub(crate) fn header_value(&self) -> Option<HeaderValue> {
+        let message = self.message.read().unwrap();
+        if message.is_empty() {
+            return None;
+        }
+        let line = message
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect::<String>();
+        HeaderValue::from_bytes(line.as_bytes()).ok()
+    }
+}
@@ -1,6 +1,18 @@
-use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use tracing_subscriber::filter::{LevelFilter, Targets};
+
+use crate::download::HashAlgorithm;
+use crate::download::RetryPolicy;
+use crate::publish::Kind;
+use crate::serve::ACCESS_LOG_TARGET;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -11,18 +23,262 @@ pub struct Cli {
     /// Increase verbosity (can be supplied multiple times).
     #[arg(short, long, global = true, default_value_t = 1)]
     pub verbosity: usize,
+    /// Print errors to the stderr as a JSON object {"error":"...","context":[...]}, the error
+    /// followed by its causes, for scripts to parse.
+    #[arg(long, global = true)]
+    pub json_errors: bool,
 }
 
+/// The JSON object printed for `error` with `--json-errors`.
+pub fn error_json(error: &anyhow::Error) -> serde_json::Value {
+    serde_json::json!({
+        "error": error.to_string(),
+        "context": error.chain().skip(1).map(ToString::to_string).collect::<Vec<_>>(),
+    })
+}
+
+impl Cli {
+    /// The log levels to apply: the verbosity's one, and info for the access
+    /// log when it is enabled.
+    pub fn log_filter(&self) -> Targets {
+        let level = match self.verbosity {
+            0 => LevelFilter::WARN,
+            1 => LevelFilter::INFO,
+            2 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        };
+        let filter = Targets::new().with_default(level);
+        match &self.command {
+            Commands::Serve(serve_args) if serve_args.access_log => {
+                filter.with_target(ACCESS_LOG_TARGET, LevelFilter::INFO.max(level))
+            }
+            _ => filter,
+        }
+    }
+}
+
+// The command line is parsed once, the size of the serve arguments doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Pack Rust installations to serve later.
     Pack(PackArgs),
     /// Print all available platforms installations to the stdout.
-    PlatformsList,
+    PlatformsList(PlatformsListArgs),
     /// Unpack Rust installation before serving into root registry.
     Unpack(UnpackArgs),
     /// Serve offline crates registry.
     Serve(ServeArgs),
+    /// Print the HEAD of the crates index and check that all its entries are valid.
+    IndexStatus(IndexStatusArgs),
+    /// Squash the history of the crates index into a single commit of its current content to
+    /// shrink it, e.g. before packing the registry for a transfer. The registry must not be
+    /// served meanwhile, and the clones of the index have to be made again.
+    CompactIndex(CompactIndexArgs),
+    /// Import the crates of another index (by default crates.io) into the index of the registry,
+    /// without downloading the crate files.
+    BootstrapIndex(BootstrapIndexArgs),
+    /// Download the crates a project depends on to add them to the registry later.
+    Vendor(VendorArgs),
+    /// List the crate versions of the index of the registry.
+    List(ListArgs),
+    /// Print the Rust channels mirrored in the registry, with the dates of their releases and
+    /// their number of files, as recorded in the channel history files.
+    ListChannels(ListChannelsArgs),
+    /// Check that the unpacked Rust installations of the registry are complete for the
+    /// requested platforms.
+    VerifyMirror(VerifyMirrorArgs),
+    /// Take down a crate version for legal reasons: it's yanked and its downloads are answered
+    /// with a 451 (Unavailable For Legal Reasons) carrying the reason.
+    Takedown(TakedownArgs),
+    /// Find the crate files missing from the index of the registry, and the versions of the index
+    /// missing their crate file.
+    Repair(RepairArgs),
+    /// Print the files added, removed and changed between two packs, e.g. to check what an
+    /// update brings before transferring it.
+    DiffPacks(DiffPacksArgs),
+    /// Check that a running registry serves its index and, with --deep, that crates can be
+    /// published to it, downloaded and yanked.
+    Doctor(DoctorArgs),
+}
+
+#[derive(Args)]
+pub struct PlatformsListArgs {
+    /// Print the platforms as JSON: {"unix":[...],"windows":[...]}.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct VerifyMirrorArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub(crate) root_registry: PathBuf,
+    /// The platforms the mirror should install rust on, seperated by comma.
+    /// The aliases `linux`, `windows` and `macos` of `pack --platforms` can be used as well.
+    #[arg(long, value_delimiter = ',', required = true)]
+    pub(crate) platforms: Vec<String>,
+    /// The rust versions expected in the mirror, as given to `pack --rust-versions`.
+    /// By default the latest stable and nightly releases.
+    #[arg(short, long, value_delimiter = ',')]
+    pub(crate) rust_versions: Vec<String>,
+    /// Without --rust-versions, the latest channels expected in the mirror, as given to
+    /// `pack --channels`.
+    #[arg(long, value_delimiter = ',', default_value = "stable,nightly", value_parser = ["stable", "beta", "nightly"], conflicts_with = "rust_versions")]
+    pub(crate) channels: Vec<String>,
+    /// Also check the sha256 hashes of the files of the channels, and of the crate files against
+    /// the checksums of the index.
+    #[arg(long)]
+    pub(crate) check_hashes: bool,
+    /// Number of files hashed in parallel with --check-hashes, or `auto` for the number of CPUs.
+    /// Hashing more files at once than the disk can feed only slows it down.
+    #[arg(long, default_value_t = 4, value_parser = parse_threads)]
+    pub(crate) threads: usize,
+}
+
+#[derive(Args)]
+pub struct TakedownArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub(crate) root_registry: PathBuf,
+    /// The name of the crate.
+    #[arg(long = "crate")]
+    pub(crate) crate_name: String,
+    /// The version of the crate to take down.
+    #[arg(long)]
+    pub(crate) crate_version: String,
+    /// The reason given to the clients attempting to download the version.
+    #[arg(long)]
+    pub(crate) reason: String,
+    /// Keep the version downloadable until this HTTP date (e.g. "Fri, 01 Jan 2100 00:00:00 GMT"),
+    /// announcing the takedown with the Deprecation and Sunset headers. Without it the crate
+    /// file is deleted right away.
+    #[arg(long)]
+    pub(crate) sunset: Option<String>,
+    #[command(flatten)]
+    pub(crate) storage: StorageArgs,
+}
+
+/// Where the `.crate` files of a registry are stored.
+#[derive(Args, Clone)]
+pub struct StorageArgs {
+    /// Store the .crate files in this S3 bucket instead of <root>/crates. The credentials are
+    /// read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, and downloads are redirected to
+    /// presigned URLs of the bucket.
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    pub s3_bucket: Option<String>,
+    /// The endpoint of the S3 service, or of an S3 compatible one, with --s3-bucket.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "https://s3.amazonaws.com")]
+    pub s3_endpoint: String,
+    /// The region of the bucket of --s3-bucket.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "us-east-1")]
+    pub s3_region: String,
+}
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// The URL of the running registry.
+    #[arg(long, default_value = "http://127.0.0.1:5000")]
+    pub(crate) registry_url: String,
+    /// Publish a synthetic crate, `crates-registry-doctor` in a new pre-release version, download
+    /// it back and compare its checksum, then yank it. The yanked version stays in the registry
+    /// unless an --admin-token is given to delete it.
+    #[arg(long)]
+    pub(crate) deep: bool,
+    /// The token sent to the registry, to yank the synthetic crate and for registries
+    /// restricting who may publish.
+    #[arg(long)]
+    pub(crate) token: Option<String>,
+    /// The --admin-token of the registry, to delete the synthetic crate after the deep checks.
+    #[arg(long, value_name = "TOKEN")]
+    pub(crate) admin_token: Option<String>,
+}
+
+#[derive(Args)]
+pub struct DiffPacksArgs {
+    /// The pack to compare from.
+    #[arg(long)]
+    pub old: PathBuf,
+    /// The pack to compare to.
+    #[arg(long)]
+    pub new: PathBuf,
+}
+
+#[derive(Args)]
+pub struct RepairArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub(crate) root_registry: PathBuf,
+    /// Add the crate files missing from the index to it, from the Cargo.toml they contain,
+    /// instead of only reporting them.
+    #[arg(long)]
+    pub(crate) reindex: bool,
+    /// Delete the crate files missing from the index, those that couldn't be reindexed with
+    /// --reindex.
+    #[arg(long)]
+    pub(crate) delete_orphans: bool,
+}
+
+#[derive(Args)]
+pub struct IndexStatusArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub root_registry: PathBuf,
+}
+
+#[derive(Args)]
+pub struct CompactIndexArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub root_registry: PathBuf,
+}
+
+/// The output format of the `list` command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// One crate version per line.
+    Text,
+    /// An array of objects.
+    Json,
+    /// Comma-separated values with a header line.
+    Csv,
+}
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub root_registry: PathBuf,
+    /// The output format.
+    #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+    pub format: ListFormat,
+}
+
+#[derive(Args)]
+pub struct ListChannelsArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub root_registry: PathBuf,
+    /// Also read the channel history files of this directory, relative to the registry root,
+    /// as written by `pack --channel-history-dir`.
+    #[arg(long)]
+    pub channel_history_dir: Option<PathBuf>,
+    /// Print the channels as JSON: [{"channel":...,"releases":[{"date":...,"files":...}]}].
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct BootstrapIndexArgs {
+    /// The root directory of the registry.
+    #[arg(long)]
+    pub root_registry: PathBuf,
+    /// The index to import, a git URL or a local directory.
+    #[arg(long, default_value = "https://github.com/rust-lang/crates.io-index")]
+    pub source: String,
 }
 
 #[derive(Args)]
@@ -33,35 +289,273 @@ pub struct UnpackArgs {
     /// Extract the compressed file here (Be carefull this will override some files).
     #[arg(short, long)]
     pub root_registry: PathBuf,
+    #[command(flatten)]
+    pub extract_options: ExtractOptions,
+    /// Unpack into a copy of the registry and switch to it at once, for running servers never to
+    /// see a half-unpacked registry. The root registry must then be a symbolic link to the
+    /// registry directory; send SIGHUP to the servers afterwards for them to reopen the index.
+    #[arg(long)]
+    pub atomic: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ExtractOptions {
+    /// Number of threads writing the extracted files in parallel, or `auto` for the number of
+    /// CPUs. With a single thread the pack is extracted serially.
+    #[arg(long, default_value_t = 1, value_parser = parse_threads)]
+    pub extract_threads: usize,
+    /// The maximum number of files the extraction threads keep open at once,
+    /// to stay below a low open files limit (`ulimit -n`). By default each
+    /// thread may have a file open.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_parallel_extract_open_files: Option<u64>,
+    /// Ignore the permissions of the pack: write the files with mode 0644 (0755 when executable)
+    /// and the directories with mode 0755, for everything unpacked to be readable by the server.
+    #[arg(long)]
+    pub normalize_permissions: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            extract_threads: 1,
+            max_parallel_extract_open_files: None,
+            normalize_permissions: false,
+        }
+    }
 }
 
 #[derive(Args)]
 pub struct PackArgs {
     /// Path to the dst compressed file.
-    #[arg(short, long)]
-    pub(crate) pack_file: PathBuf,
+    #[arg(short, long, required_unless_present = "output_template")]
+    pub(crate) pack_file: Option<PathBuf>,
+    /// Name the pack file after its contents instead of --pack-file, e.g.
+    /// "rust-{channel}-{version}-{date}.tar" for rust-stable-1.67.1-2023-02-09.tar.
+    /// `{channel}` is the packed channel, `{version}` its rust version and `{date}` its
+    /// release date; the values of several channels are joined with `+`.
+    #[arg(long, conflicts_with = "pack_file", value_parser = parse_output_template)]
+    pub(crate) output_template: Option<String>,
     /// The rust versions for collecting all installation files seperated by comma.
     /// Valid versions could be "1.67.1", "1.54", and "nightly-2014-12-18".
-    /// In emptry case, Crates-Registry will pack the latest versions of the stable release and the nightly release.
-    #[arg(short, long, value_delimiter=',')]
+    /// In emptry case, Crates-Registry will pack the latest versions of the --channels.
+    #[arg(short, long, value_delimiter = ',')]
     pub(crate) rust_versions: Vec<String>,
+    /// The latest channels to pack without --rust-versions, seperated by comma.
+    #[arg(long, value_delimiter = ',', default_value = "stable,nightly", value_parser = ["stable", "beta", "nightly"], conflicts_with = "rust_versions")]
+    pub(crate) channels: Vec<String>,
     /// The platforms for collecting seperated by comma.
     /// You can run `crates-registry platfroms-list` to show all available platfroms.
     /// Valid platforms could be x86_64-unknown-linux-gnu or x86_64-pc-windows-msvc.
-    #[arg(long, value_delimiter=',')]
+    /// The aliases `linux` (x86_64 and aarch64 gnu and musl, i686 gnu), `windows`
+    /// (x86_64 msvc and gnu, i686 msvc), `macos` (x86_64 and aarch64) and `all`
+    /// can be used as well.
+    #[arg(long, value_delimiter = ',')]
     pub(crate) platforms: Vec<String>,
-    /// Number of downloads that can be ran in parallel.
-    #[arg(short, long, default_value_t = 16)]
+    /// Number of downloads that can be ran in parallel, or `auto` for the number of CPUs.
+    #[arg(short, long, default_value_t = 16, value_parser = parse_threads)]
     pub(crate) threads: usize,
     /// Where to download rustup files from.
     #[arg(short, long, default_value = "https://static.rust-lang.org")]
     pub(crate) source: String,
-    /// Number of download retries before giving up.
+    /// Number of download retries before giving up, 0 to retry until
+    /// --max-retry-duration elapsed.
     #[arg(long, default_value_t = 5)]
     pub(crate) retries: usize,
+    /// With --retries 0, the number of seconds after which a failing download is given up.
+    #[arg(long, default_value_t = 3600)]
+    pub(crate) max_retry_duration: u64,
+    /// Fail when a requested platform has no files in a channel instead of
+    /// producing an incomplete mirror.
+    #[arg(long)]
+    pub(crate) strict: bool,
+    /// Stop at the first failed download instead of trying all the files.
+    /// Files missing from the source are not considered failures.
+    #[arg(long, conflicts_with = "keep_going")]
+    pub(crate) fail_fast: bool,
+    /// Succeed even when some downloads failed. The failed files are logged
+    /// and left out of the channel history.
+    #[arg(long)]
+    pub(crate) keep_going: bool,
+    /// Fail without writing the pack file when the mirror is incomplete: when the download of
+    /// a channel or of the rustup-init files failed, for CI to catch incomplete mirrors.
+    #[arg(long, conflicts_with = "keep_going")]
+    pub(crate) fail_on_partial: bool,
+    /// Only check the source: download and parse the release and channel files,
+    /// and report how many files the pack would contain and their total size.
+    #[arg(long)]
+    pub(crate) verify_only: bool,
+    /// Only pack the standard libraries (the `rust-std` packages) of the platforms, to add
+    /// targets with `rustup target add` to toolchains already installed in the registry.
+    /// The versions of these toolchains must be pinned with --rust-versions, the channel files
+    /// of the latest releases aren't replaced.
+    #[arg(long, requires = "rust_versions")]
+    pub(crate) targets_only: bool,
+    /// Also mirror the rustup-init files under rustup/archive/<version>, where
+    /// `rustup self update` looks for them (true or false).
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub(crate) mirror_rustup_archive: bool,
+    /// Write the channel history files (mirror-*-history.toml) to this directory,
+    /// relative to the registry root, instead of the registry root itself.
+    #[arg(long)]
+    pub(crate) channel_history_dir: Option<PathBuf>,
+    /// Pack the channel `new` when the rust version `old` is requested, given as `old=new` and
+    /// seperated by comma. Without an alias, versions like "1.67" are resolved to their latest
+    /// patch release found at the source.
+    #[arg(long, value_delimiter = ',', value_parser = parse_channel_alias)]
+    pub(crate) channel_alias: Vec<(String, String)>,
+    /// Also mirror the `.asc` GPG signature of each file of the channels, for rustup to
+    /// verify the toolchains offline.
+    #[arg(long)]
+    pub(crate) mirror_signatures: bool,
+    /// Verify the `.asc` GPG signature of the channel manifests against the public key in this
+    /// file, the Rust signing key of https://static.rust-lang.org/rust-key.gpg.ascii, and fail
+    /// the sync of a channel whose manifest isn't signed by it. Needs `gpg`.
+    #[arg(long, value_name = "KEY_FILE")]
+    pub(crate) verify_manifest_signature: Option<PathBuf>,
+    /// Download the installations into this directory, and keep it after packing, instead of
+    /// a temporary directory. Running the pack again with the same directory resumes it.
+    #[arg(long)]
+    pub(crate) keep_workdir: Option<PathBuf>,
+    /// With --keep-workdir, only download the files a previous run didn't complete: the files
+    /// it recorded in the channel history are kept without verifying their hash again, as long
+    /// as they have the size it recorded.
+    #[arg(long, requires = "keep_workdir")]
+    pub(crate) only_missing: bool,
+    /// Add a CHECKSUMS.<algorithm> file at the top of the pack, listing the hash of every packed
+    /// file for `sha256sum -c` to verify the unpacked files, and print the hash of the pack file.
+    #[arg(long, value_enum, value_name = "ALGORITHM")]
+    pub(crate) checksum_algorithm: Option<HashAlgorithm>,
+}
+
+/// Parse a number of threads: a positive number, or `auto` for the
+/// available parallelism of the machine.
+fn parse_threads(threads: &str) -> Result<usize, String> {
+    if threads == "auto" {
+        return Ok(std::thread::available_parallelism().map_or(1, |threads| threads.get()));
+    }
+    match threads.parse::<usize>() {
+        Ok(threads) if threads > 0 => Ok(threads),
+        _ => Err(format!("expected a positive number or auto, got {threads}")),
+    }
+}
+
+/// Parse a `--channel-alias` of the form `old=new`.
+fn parse_channel_alias(alias: &str) -> Result<(String, String), String> {
+    match alias.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_string(), new.to_string()))
+        }
+        _ => Err(format!("expected old=new, got {alias}")),
+    }
+}
+
+/// The placeholders of `--output-template`.
+pub(crate) const OUTPUT_TEMPLATE_PLACEHOLDERS: [&str; 3] = ["channel", "version", "date"];
+
+/// Parse an `--output-template`: only known placeholders, and no `..` that
+/// would place the pack file outside of the directory of the template.
+fn parse_output_template(template: &str) -> Result<String, String> {
+    let mut rest = template;
+    while let Some(start) = rest.find(&['{', '}'][..]) {
+        if rest[start..].starts_with('}') {
+            return Err(format!("unmatched `}}` in {template}"));
+        }
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("unmatched `{{` in {template}"));
+        };
+        let placeholder = &rest[start + 1..start + end];
+        if !OUTPUT_TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder {{{placeholder}}}, expected one of {{channel}}, {{version}} \
+                 or {{date}}"
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    let path = Path::new(template);
+    if path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(format!("{template} must not contain `..`"));
+    }
+    if path.file_name().is_none() || template.ends_with(&['/', '\\'][..]) {
+        return Err(format!("{template} must end with a file name"));
+    }
+    Ok(template.to_string())
+}
+
+/// The features whose optional dependencies are vendored too, see
+/// `--prefetch-deps`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PrefetchDeps {
+    /// All the features.
+    AllFeatures,
+    /// These features, besides the default ones.
+    Features(Vec<String>),
+}
+
+/// Parse a `--prefetch-deps`: `all-features` or features seperated by comma.
+fn parse_prefetch_deps(features: &str) -> Result<PrefetchDeps, String> {
+    if features == "all-features" {
+        return Ok(PrefetchDeps::AllFeatures);
+    }
+    let features = features
+        .split(',')
+        .map(str::trim)
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if features.iter().any(String::is_empty) {
+        return Err("expected all-features or features seperated by comma".to_string());
+    }
+    Ok(PrefetchDeps::Features(features))
 }
 
 #[derive(Args)]
+pub struct VendorArgs {
+    /// Path to the Cargo.toml of the project to vendor.
+    #[arg(long, default_value = "Cargo.toml")]
+    pub(crate) manifest_path: PathBuf,
+    /// Vendor these crate versions and their dependencies, resolved against `--index`,
+    /// instead of the dependencies of a project. Seperated by comma, e.g.
+    /// "serde@1.0.160,log@0.4.17".
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) crates: Vec<String>,
+    /// The sparse index the crates of `--crates` are resolved against.
+    #[arg(long, default_value = "https://index.crates.io")]
+    pub(crate) index: String,
+    /// The directory to download the crates to. The crates are stored in its `crates` folder
+    /// with the layout of the root registry.
+    #[arg(short, long)]
+    pub(crate) output: PathBuf,
+    /// The dependency kinds to leave out seperated by comma, e.g. "dev,build".
+    /// Dependencies only reachable through these kinds are not vendored.
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) exclude_kinds: Vec<Kind>,
+    /// Also vendor the optional dependencies behind features: `all-features` for those of all
+    /// the features of every crate with `--crates` (of the workspace members only for a
+    /// project), or the features of the project (or of the `--crates`) to enable seperated by
+    /// comma, e.g. "json,tls". This trades size for offline builds that don't break when a
+    /// feature is enabled later.
+    #[arg(long, value_name = "FEATURES", value_parser = parse_prefetch_deps)]
+    pub(crate) prefetch_deps: Option<PrefetchDeps>,
+    /// Number of downloads that can be ran in parallel, or `auto` for the number of CPUs.
+    #[arg(short, long, default_value_t = 16, value_parser = parse_threads)]
+    pub(crate) threads: usize,
+    /// Where to download the crates from.
+    #[arg(short, long, default_value = "https://static.crates.io/crates")]
+    pub(crate) source: String,
+    /// Number of download retries before giving up, 0 to retry until
+    /// --max-retry-duration elapsed.
+    #[arg(long, default_value_t = 5)]
+    pub(crate) retries: usize,
+    /// With --retries 0, the number of seconds after which a failing download is given up.
+    #[arg(long, default_value_t = 3600)]
+    pub(crate) max_retry_duration: u64,
+}
+
+#[derive(Args, Clone)]
 pub struct ServeArgs {
     /// The root directory of the registry. if the path does not exists Crates-Registry will create it's
     #[arg(long)]
@@ -72,4 +566,287 @@ pub struct ServeArgs {
     /// The address of the server. By default the address is the local address: 127.0.0.1:5000
     #[arg(short, long, value_parser = SocketAddr::from_str, default_value_t = SocketAddr::from(([127, 0, 0, 1], 5000)))]
     pub server_addr: SocketAddr,
+    /// The path prefix the registry is served under, e.g. "/registry" when
+    /// hosted at http://host/registry/. By default the registry is served at the root.
+    #[arg(long)]
+    pub base_path: Option<String>,
+    /// The URL the index is hosted at when it's not the registry itself, e.g. a CDN in front of
+    /// it: the discovery document then points clients to `<url>/git/index` and
+    /// `sparse+<url>/index/`. The download and publish APIs stay on the registry.
+    #[arg(long)]
+    pub index_url: Option<String>,
+    /// Serve only the index (git, sparse and the discovery document), with the crates
+    /// downloaded from and published to the registry at this URL, e.g. for the index and the
+    /// crate files to be hosted apart. The download, publish and frontend routes are disabled.
+    #[arg(
+        long,
+        value_name = "REGISTRY_URL",
+        conflicts_with_all = ["staging", "upstream", "serve_docs", "direct_download"]
+    )]
+    pub index_only: Option<String>,
+    #[command(flatten)]
+    pub storage: StorageArgs,
+    /// Serve the documentation of this mirrored toolchain (e.g. "stable" or "1.68.0") under
+    /// /docs/, extracted from its rust-docs package on start. A read-only registry only serves
+    /// the documentation extracted beforehand.
+    #[arg(long)]
+    pub serve_docs: Option<String>,
+    /// The platform of the rust-docs package served with --serve-docs.
+    #[arg(long, default_value = "x86_64-unknown-linux-gnu")]
+    pub docs_platform: String,
+    /// The message of the index commit made for each publish. The placeholders
+    /// {name}, {version} and {publisher} (an identifier of the publishing token)
+    /// are replaced accordingly.
+    #[arg(long, default_value = "Add {name} in version {version}")]
+    pub commit_message_template: String,
+    /// Allow publishing a crate version that already exists, replacing it.
+    /// By default such a publish is rejected.
+    #[arg(long)]
+    pub allow_overwrite: bool,
+    /// Don't wait for the files of a publish to reach the disk before acknowledging it.
+    /// Faster, but a power loss right after a publish may lose it.
+    #[arg(long)]
+    pub no_fsync: bool,
+    /// Fetch the crates missing from the registry from this upstream registry on download,
+    /// e.g. https://static.crates.io. The fetched crates are stored and added to the index,
+    /// refused for a read-only registry.
+    #[arg(long)]
+    pub upstream: Option<String>,
+    /// The sparse index of the upstream registry, used to verify the fetched crates.
+    #[arg(long, default_value = "https://index.crates.io")]
+    pub upstream_index: String,
+    /// The size in bytes of the buffer streaming the git index to each client.
+    #[arg(long, default_value_t = 64 * 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    pub git_buffer_size: u64,
+    /// Kill a `git http-backend` process serving the index when it neither reads its input nor
+    /// writes its output for this number of seconds, so that stuck processes don't pile up.
+    #[arg(long, default_value_t = 120, value_parser = clap::value_parser!(u64).range(1..))]
+    pub git_backend_timeout: u64,
+    /// Refuse to start when the installed git is missing or too old to serve the index,
+    /// instead of only warning about it.
+    #[arg(long)]
+    pub strict_git_version: bool,
+    /// Add the crate files dropped in <root>/crates by other tools to the index, from the
+    /// Cargo.toml they contain. The files added together are indexed in a single commit.
+    #[arg(long)]
+    pub watch_crates: bool,
+    /// The `Server` header sent with all the responses, empty to send none.
+    #[arg(long, default_value = concat!("crates-registry/", env!("CARGO_PKG_VERSION")))]
+    pub server_header: String,
+    /// Serve the frontend files from this directory, e.g. to customize the branding.
+    /// The files missing from it are served from the embedded frontend.
+    #[arg(long)]
+    pub frontend_dir: Option<PathBuf>,
+    /// The maximum number of connections waiting to be accepted.
+    #[arg(long, default_value_t = 1024)]
+    pub listen_backlog: u32,
+    /// Disable Nagle's algorithm on the connections (true or false).
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+    /// Send TCP keepalive probes after a connection is idle for this number of seconds.
+    /// By default keepalive is disabled.
+    #[arg(long)]
+    pub tcp_keepalive: Option<u64>,
+    /// Close the connections of the clients not sending the headers of a request within this
+    /// number of seconds, so that slow clients can't hold connections forever.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub header_timeout: u64,
+    /// The maximum size in bytes of the headers of a request, larger ones are rejected.
+    /// At least 8192, about 400KiB by default.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(8192..))]
+    pub max_header_size: Option<u64>,
+    /// Hold the published crates and the loaded pack files in a staging area
+    /// until they are accepted in the frontend.
+    #[arg(long)]
+    pub staging: bool,
+    /// The credential of the admin API, i.e. the review of the staging area and the banner,
+    /// sent by the admins as the `Authorization` header. Without it the
+    /// admin API is disabled.
+    #[arg(long, value_name = "TOKEN")]
+    pub admin_token: Option<String>,
+    /// The maximum total size in bytes of the crate files of the registry.
+    /// Publishes exceeding it are rejected.
+    #[arg(long)]
+    pub max_total_size: Option<u64>,
+    /// The maximum number of crate files (one per crate version) of the registry.
+    /// Publishes exceeding it are rejected.
+    #[arg(long)]
+    pub max_crates: Option<u64>,
+    /// The maximum number of publishes handled at once. The publishes received beyond it are
+    /// answered with a 503 and a `Retry-After` header instead of piling up.
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrent_publishes: u64,
+    /// Also look up the channel history files in this directory, relative to the
+    /// registry root, as written by `pack --channel-history-dir`.
+    #[arg(long)]
+    pub channel_history_dir: Option<PathBuf>,
+    /// Log a line per request (method, path, status, bytes and duration) to the
+    /// `access_log` target, whatever the verbosity.
+    #[arg(long)]
+    pub access_log: bool,
+    /// An `acl.toml` file restricting who may publish which crates. It maps
+    /// crate name patterns, like `team-a-*`, to the identities of the tokens
+    /// allowed to publish them. It is read again on each publish.
+    #[arg(long)]
+    pub acl: Option<PathBuf>,
+    /// The URL of the terms the publishers must accept before their first
+    /// publish, with a POST to /api/accept-terms authorized by their token.
+    /// Until then their publishes are refused with a link to the terms. The
+    /// acceptances are recorded in <root>/accepted-terms.json.
+    #[arg(long, value_name = "URL")]
+    pub publish_terms: Option<String>,
+    /// Write the config.json of the index on a single line instead of pretty
+    /// printing it. An existing config.json is only rewritten when its URLs
+    /// change, not for its formatting alone.
+    #[arg(long)]
+    pub compact_config: bool,
+    /// Serve a read-only registry: the config.json of the index has a null
+    /// `api`, so that cargo doesn't attempt to publish, and the publish and
+    /// yank APIs are disabled.
+    #[arg(long)]
+    pub no_api: bool,
+    /// Serve the crate files from the download API directly instead of
+    /// redirecting to `/crates`, for clients or proxies not following redirects.
+    #[arg(long)]
+    pub direct_download: bool,
+    /// A command run after each successful publish, with the name and version of the crate
+    /// and the path of its crate file as arguments. It runs in the background, failures are
+    /// only logged.
+    #[arg(long)]
+    pub on_publish: Option<PathBuf>,
+    /// Reject the publish of a crate whose name differs from an existing crate only by `-` and
+    /// `_`, like `foo_bar` when `foo-bar` exists. Cargo considers them distinct crates, which
+    /// is confusing for the users.
+    #[arg(long)]
+    pub strict_naming: bool,
+}
+
+impl PackArgs {
+    /// How the failed downloads are retried.
+    pub(crate) fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            max_duration: Duration::from_secs(self.max_retry_duration),
+        }
+    }
+}
+
+impl VendorArgs {
+    /// How the failed downloads are retried.
+    pub(crate) fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            max_duration: Duration::from_secs(self.max_retry_duration),
+        }
+    }
+}
+
+impl ServeArgs {
+    /// The normalized base path: empty or starting with a slash and
+    /// without a trailing one (e.g. "/registry").
+    pub fn base_path(&self) -> String {
+        self.base_path
+            .as_deref()
+            .unwrap_or_default()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| format!("/{segment}"))
+            .collect()
+    }
+
+    /// The URL clients reach the registry at.
+    pub fn server_url(&self) -> String {
+        format!("http://{}{}", self.server_addr, self.base_path())
+    }
+
+    /// The URL of the registry serving the downloads and publishes, as written
+    /// in the config.json of the index: the `--index-only` one, if any, or the
+    /// registry itself.
+    pub fn registry_url(&self) -> String {
+        match &self.index_only {
+            Some(registry_url) => registry_url.trim_end_matches('/').to_string(),
+            None => self.server_url(),
+        }
+    }
+
+    /// The URL clients reach the index at: the `--index-url`, if any, or the
+    /// registry itself.
+    pub fn index_url(&self) -> String {
+        match &self.index_url {
+            Some(index_url) => index_url.trim_end_matches('/').to_string(),
+            None => self.server_url(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_parsing() {
+        assert!(parse_threads("auto").unwrap() > 0);
+        assert_eq!(parse_threads("4"), Ok(4));
+        assert!(parse_threads("0").is_err());
+        assert!(parse_threads("many").is_err());
+
+        let cli = Cli::try_parse_from([
+            "crates-registry",
+            "pack",
+            "-p",
+            "pack.tar",
+            "--threads",
+            "auto",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Pack(pack_args) => assert!(pack_args.threads > 0),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn output_template_parsing() {
+        assert!(parse_output_template("packs/rust-{channel}-{version}-{date}.tar").is_ok());
+        assert!(parse_output_template("rust-{platform}.tar").is_err());
+        assert!(parse_output_template("rust-{date.tar").is_err());
+        assert!(parse_output_template("rust-date}.tar").is_err());
+        assert!(parse_output_template("../rust-{date}.tar").is_err());
+        assert!(parse_output_template("packs/{date}/").is_err());
+
+        let parse =
+            |args: &[&str]| Cli::try_parse_from(["crates-registry", "pack"].iter().chain(args));
+        assert!(parse(&["--output-template", "rust-{date}.tar"]).is_ok());
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-p", "pack.tar", "--output-template", "rust-{date}.tar"]).is_err());
+    }
+
+    #[test]
+    fn targets_only_requires_rust_versions() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["crates-registry", "pack", "-p", "pack.tar"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert!(parse(&["--targets-only"]).is_err());
+        assert!(parse(&["--targets-only", "--rust-versions", "1.68.0"]).is_ok());
+    }
+
+    #[test]
+    fn prefetch_deps_parsing() {
+        assert_eq!(
+            parse_prefetch_deps("all-features"),
+            Ok(PrefetchDeps::AllFeatures)
+        );
+        assert_eq!(
+            parse_prefetch_deps("json, tls"),
+            Ok(PrefetchDeps::Features(vec![
+                "json".to_string(),
+                "tls".to_string()
+            ]))
+        );
+        assert!(parse_prefetch_deps("json,").is_err());
+    }
 }
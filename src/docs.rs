@@ -0,0 +1,100 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+use tokio::process::Command;
+use tracing::info;
+
+use crate::rustup::channel_file;
+use crate::rustup::mirror_path;
+use crate::rustup::Channel;
+
+/// The directory of the HTML documentation inside the rust-docs package.
+pub(crate) const DOCS_HTML_DIR: &str = "rust-docs/share/doc/rust/html";
+
+/// Extract the HTML documentation of the mirrored `channel` (e.g. "stable"
+/// or "1.68.0") from its rust-docs package for `platform`, unless it was
+/// already, and return its directory. The documentation of each package is
+/// extracted once under `<root>/docs`, a `read_only` registry only serves
+/// the documentation extracted beforehand.
+pub(crate) async fn extract_docs(
+    root: &Path,
+    channel: &str,
+    platform: &str,
+    read_only: bool,
+) -> Result<PathBuf> {
+    let channel_path = root.join(channel_file(channel));
+    let content = std::fs::read_to_string(&channel_path)
+        .with_context(|| format!("the channel {channel} isn't mirrored"))?;
+    let channel_data: Channel = toml::from_str(&content)
+        .with_context(|| format!("invalid channel file {}", channel_path.display()))?;
+    let urls = channel_data
+        .pkg
+        .get("rust-docs")
+        .and_then(|pkg| pkg.target.get(platform))
+        .filter(|target| target.available)
+        .and_then(|target| target.target_urls.as_ref())
+        .ok_or_else(|| anyhow!("the channel {channel} has no rust-docs for {platform}"))?;
+    let package = root.join(mirror_path(&urls.xz_url));
+    ensure!(
+        package.is_file(),
+        "the rust-docs package {} isn't mirrored",
+        package.display()
+    );
+
+    // The package is named after the version and the platform, e.g.
+    // rust-docs-1.68.0-x86_64-unknown-linux-gnu.tar.xz, as is its top directory.
+    let name = package
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".tar.xz"))
+        .ok_or_else(|| anyhow!("unexpected rust-docs package {}", package.display()))?;
+    let docs_dir = root.join("docs").join(name);
+    if docs_dir.is_dir() {
+        return Ok(docs_dir);
+    }
+    ensure!(
+        !read_only,
+        "the documentation of {} isn't extracted to {} and the registry is read-only",
+        name,
+        docs_dir.display()
+    );
+
+    info!("Extracting the documentation of {}", name);
+    // Extract next to the final directory, for the documentation to appear
+    // at once, complete.
+    std::fs::create_dir_all(root.join("docs"))?;
+    let temp_dir = tempfile::Builder::new()
+        .prefix(".extract")
+        .tempdir_in(root.join("docs"))
+        .context("failed to create a directory to extract the documentation")?;
+    let status = Command::new("tar")
+        .arg("--extract")
+        .arg("--xz")
+        .arg("--file")
+        .arg(&package)
+        .arg("--directory")
+        .arg(temp_dir.path())
+        .status()
+        .await
+        .context("failed to run tar")?;
+    ensure!(
+        status.success(),
+        "failed to extract {}: tar exited with {}",
+        package.display(),
+        status
+    );
+    let html_dir = temp_dir.path().join(name).join(DOCS_HTML_DIR);
+    ensure!(
+        html_dir.is_dir(),
+        "{} has no {} directory",
+        package.display(),
+        DOCS_HTML_DIR
+    );
+    std::fs::rename(&html_dir, &docs_dir)
+        .with_context(|| format!("failed to move the documentation to {}", docs_dir.display()))?;
+    Ok(docs_dir)
+}
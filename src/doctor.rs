@@ -0,0 +1,277 @@
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+use reqwest::header::AUTHORIZATION;
+use reqwest::Client;
+use reqwest::Response;
+use serde::Deserialize;
+use sha2::Digest as _;
+use sha2::Sha256;
+use tracing::info;
+
+use crate::cli::DoctorArgs;
+
+/// The name of the crate published by the deep checks.
+const DOCTOR_CRATE: &str = "crates-registry-doctor";
+
+/// The part of the `config.json` of the index the checks need.
+#[derive(Debug, Deserialize)]
+struct Config {
+    dl: String,
+    api: Option<String>,
+}
+
+/// Fail with the body of `response`, the registry's JSON errors, unless it
+/// was successful.
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    bail!("the registry answered {}: {}", status, body)
+}
+
+/// The gzipped tarball of a crate `name` in version `vers` with a manifest
+/// and an empty library.
+fn synthetic_crate(name: &str, vers: &str) -> Result<Vec<u8>> {
+    let manifest = format!("[package]\nname = \"{name}\"\nversion = \"{vers}\"\n");
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    for (path, content) in [("Cargo.toml", manifest.as_str()), ("src/lib.rs", "")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(
+            &mut header,
+            format!("{name}-{vers}/{path}"),
+            content.as_bytes(),
+        )?;
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+/// The body of the publish request of the crate file `data`, in the format
+/// cargo sends.
+fn publish_body(name: &str, vers: &str, data: &[u8]) -> Vec<u8> {
+    let metadata = serde_json::json!({
+        "name": name,
+        "vers": vers,
+        "deps": [],
+        "features": {},
+        "authors": [],
+        "description": "Checks of the registry by crates-registry doctor",
+        "keywords": [],
+        "categories": [],
+        "badges": {},
+    })
+    .to_string();
+    let mut body = Vec::new();
+    body.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    body.extend_from_slice(metadata.as_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    body
+}
+
+/// The URL of the download of the crate `name` in version `vers`, from the
+/// `dl` template of the config.
+fn download_url(dl: &str, name: &str, vers: &str) -> String {
+    if dl.contains("{crate}") || dl.contains("{version}") {
+        dl.replace("{crate}", name).replace("{version}", vers)
+    } else {
+        format!("{}/{name}/{vers}/download", dl.trim_end_matches('/'))
+    }
+}
+
+/// Check the registry running at `--registry-url`: that its discovery
+/// document and index config are served and, with `--deep`, that a crate can
+/// be published, downloaded back, yanked and, with `--admin-token`, deleted.
+/// Returns the checks that passed.
+pub async fn doctor(doctor_args: &DoctorArgs) -> Result<Vec<String>> {
+    let url = doctor_args.registry_url.trim_end_matches('/');
+    let client = Client::new();
+    let mut passed = Vec::new();
+
+    let discovery_url = format!("{url}/.well-known/cargo");
+    check_status(client.get(&discovery_url).send().await?)
+        .await
+        .with_context(|| format!("failed to get the discovery document {discovery_url}"))?;
+    passed.push(format!(
+        "the discovery document is served at {discovery_url}"
+    ));
+
+    let config_url = format!("{url}/index/config.json");
+    let config = check_status(client.get(&config_url).send().await?)
+        .await
+        .with_context(|| format!("failed to get the index config {config_url}"))?
+        .bytes()
+        .await?;
+    let config: Config = serde_json::from_slice(&config)
+        .with_context(|| format!("invalid index config {config_url}"))?;
+    passed.push(format!("the index config is served at {config_url}"));
+
+    if !doctor_args.deep {
+        return Ok(passed);
+    }
+
+    let api = config
+        .api
+        .ok_or_else(|| anyhow!("the registry is read-only, a crate can't be published"))?;
+    let api = api.trim_end_matches('/');
+    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let vers = format!("0.0.0-doctor.{}", since_epoch.as_nanos());
+    let data = synthetic_crate(DOCTOR_CRATE, &vers)?;
+    info!("Publishing {} in version {}", DOCTOR_CRATE, vers);
+
+    let mut publish = client
+        .put(format!("{api}/api/v1/crates/new"))
+        .body(publish_body(DOCTOR_CRATE, &vers, &data));
+    if let Some(token) = &doctor_args.token {
+        publish = publish.header(AUTHORIZATION, token);
+    }
+    check_status(publish.send().await?)
+        .await
+        .context("failed to publish the synthetic crate")?;
+    passed.push(format!("published {DOCTOR_CRATE} in version {vers}"));
+
+    let downloaded = check_status(
+        client
+            .get(download_url(&config.dl, DOCTOR_CRATE, &vers))
+            .send()
+            .await?,
+    )
+    .await
+    .context("failed to download the synthetic crate")?
+    .bytes()
+    .await?;
+    ensure!(
+        Sha256::digest(&downloaded) == Sha256::digest(&data),
+        "the downloaded crate differs from the published one"
+    );
+    passed.push("downloaded the crate back with the published checksum".to_string());
+
+    let mut yank = client.delete(format!("{api}/api/v1/crates/{DOCTOR_CRATE}/{vers}/yank"));
+    if let Some(token) = &doctor_args.token {
+        yank = yank.header(AUTHORIZATION, token);
+    }
+    check_status(yank.send().await?)
+        .await
+        .context("failed to yank the synthetic crate")?;
+    passed.push(format!("yanked {DOCTOR_CRATE} in version {vers}"));
+
+    // Don't leave the synthetic crate behind when allowed to delete it.
+    if let Some(admin_token) = &doctor_args.admin_token {
+        let delete = client
+            .delete(format!("{api}/api/admin/crates/{DOCTOR_CRATE}/{vers}"))
+            .header(AUTHORIZATION, admin_token);
+        check_status(delete.send().await?)
+            .await
+            .context("failed to delete the synthetic crate")?;
+        passed.push(format!("deleted {DOCTOR_CRATE} in version {vers}"));
+    }
+
+    Ok(passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser as _;
+    use tempfile::tempdir;
+    use tokio::net::TcpListener;
+
+    use crate::cli::Cli;
+    use crate::cli::Commands;
+    use crate::index::Index;
+    use crate::publish::crate_path;
+    use crate::serve::spawn_serve;
+    use crate::serve::tests::serve_args;
+
+    #[test]
+    fn download_urls() {
+        assert_eq!(
+            download_url(
+                "http://host/api/v1/crates/{crate}/{version}/download",
+                "a",
+                "1.0.0"
+            ),
+            "http://host/api/v1/crates/a/1.0.0/download"
+        );
+        assert_eq!(
+            download_url("http://host/crates/", "a", "1.0.0"),
+            "http://host/crates/a/1.0.0/download"
+        );
+    }
+
+    /// Check the deep checks against a registry served in the background.
+    #[tokio::test]
+    async fn deep_doctor() {
+        let root = tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The index config points to the actual port.
+        let handle = spawn_serve(
+            serve_args(
+                root.path(),
+                &[
+                    "--server-addr",
+                    &addr.to_string(),
+                    "--admin-token",
+                    "admin-secret",
+                ],
+            ),
+            listener,
+        )
+        .await
+        .unwrap();
+
+        let registry_url = format!("http://{addr}");
+        let doctor_args = |deep: &[&str]| {
+            let args = ["crates-registry", "doctor", "--registry-url", &registry_url]
+                .into_iter()
+                .chain(deep.iter().copied());
+            match Cli::try_parse_from(args).unwrap().command {
+                Commands::Doctor(doctor_args) => doctor_args,
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(doctor(&doctor_args(&[])).await.unwrap().len(), 2);
+        let passed = doctor(&doctor_args(&["--deep", "--token", "doctor-token"]))
+            .await
+            .unwrap();
+        assert_eq!(passed.len(), 5, "{passed:?}");
+
+        let vers = passed[2]
+            .strip_prefix(&format!("published {DOCTOR_CRATE} in version "))
+            .unwrap();
+        let index = Index::open(root.path().join("index")).unwrap();
+        assert!(index.entry(DOCTOR_CRATE, vers).unwrap().unwrap().yanked);
+
+        // With the admin token nothing is left behind.
+        let passed = doctor(&doctor_args(&[
+            "--deep",
+            "--token",
+            "doctor-token",
+            "--admin-token",
+            "admin-secret",
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(passed.len(), 6, "{passed:?}");
+        let vers = passed[2]
+            .strip_prefix(&format!("published {DOCTOR_CRATE} in version "))
+            .unwrap();
+        assert!(index.entry(DOCTOR_CRATE, vers).unwrap().is_none());
+        let crate_dir = root.path().join("crates").join(crate_path(DOCTOR_CRATE));
+        assert_eq!(std::fs::read_dir(crate_dir).unwrap().count(), 1);
+        handle.shutdown().await.unwrap();
+    }
+}
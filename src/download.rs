@@ -4,9 +4,11 @@ use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{fs, io};
 use thiserror::Error;
 use tokio::io::AsyncReadExt;
+use tracing::warn;
 
 #[derive(Error, Debug)]
 pub enum DownloadError {
@@ -16,12 +18,122 @@ pub enum DownloadError {
     Download(#[from] reqwest::Error),
     #[error("Mismatched hash - expected '{expected}', got '{actual}'")]
     MismatchedHash { expected: String, actual: String },
+    #[error("Invalid {algorithm} file at {url}")]
+    InvalidHashFile {
+        algorithm: HashAlgorithm,
+        url: String,
+    },
     #[error("HTTP not found. Status: {status}, URL: {url}, data: {data}")]
     NotFound {
         status: u16,
         url: String,
         data: String,
     },
+    #[error("HTTP error. Status: {status}, URL: {url}, data: {data}")]
+    Status {
+        status: u16,
+        url: String,
+        data: String,
+    },
+}
+
+/// How failed downloads are retried. Files missing from the source are
+/// never retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of retries, 0 to retry until `max_duration` elapsed.
+    pub retries: usize,
+    /// How long to retry for with unlimited retries.
+    pub max_duration: Duration,
+}
+
+impl RetryPolicy {
+    /// Whether to give up after `attempts` failed attempts since `start`.
+    fn exhausted(&self, attempts: usize, start: Instant) -> bool {
+        if self.retries == 0 {
+            start.elapsed() >= self.max_duration
+        } else {
+            attempts > self.retries
+        }
+    }
+
+    /// How long to wait before retrying after `attempts` failed attempts.
+    /// Unlimited retries back off not to hammer the source, up to 10
+    /// seconds between attempts.
+    fn delay(&self, attempts: usize) -> Duration {
+        if self.retries == 0 {
+            Duration::from_millis(100 << attempts.min(7)).min(Duration::from_secs(10))
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// The algorithm of the hashes files are verified with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// The name of the algorithm, which is also the extension of the hash
+    /// files published next to the downloads.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// The length of a hash in hexadecimal digits.
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 64,
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An ongoing hash computation with one of the [`HashAlgorithm`]s.
+pub enum Hasher {
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Finish the computation, returning the hash in lowercase hexadecimal.
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 /// Download a URL and return it as a string.
@@ -122,6 +234,7 @@ async fn one_download(
     client: &Client,
     url: &str,
     path: &Path,
+    algorithm: HashAlgorithm,
     hash: Option<&str>,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
@@ -131,7 +244,7 @@ async fn one_download(
         .send()
         .await?;
     let part_path = append_to_path(path, ".part");
-    let mut sha256 = Sha256::new();
+    let mut hasher = algorithm.hasher();
     {
         let mut f = create_file_create_dir(&part_path)?;
         let status = http_res.status();
@@ -151,20 +264,20 @@ async fn one_download(
 
         while let Some(chunk) = http_res.chunk().await? {
             if hash.is_some() {
-                sha256.update(&chunk);
+                hasher.update(&chunk);
             }
             f.write_all(&chunk)?;
         }
     }
 
-    let f_hash = format!("{:x}", sha256.finalize());
+    let f_hash = hasher.finalize_hex();
 
     if let Some(h) = hash {
         if f_hash == h {
             move_if_exists(&part_path, path)?;
             Ok(())
         } else {
-            let badsha_path = append_to_path(path, ".badsha256");
+            let badsha_path = append_to_path(path, &format!(".bad{algorithm}"));
             fs::write(badsha_path, &f_hash)?;
             Err(DownloadError::MismatchedHash {
                 expected: h.to_string(),
@@ -177,22 +290,48 @@ async fn one_download(
     }
 }
 
-/// Download file, verifying its hash, and retrying if needed
+/// Download file, verifying its SHA-256 hash, and retrying if needed
 pub async fn download(
     client: &Client,
     url: &str,
     path: &Path,
     hash: Option<&str>,
-    retries: usize,
+    retries: RetryPolicy,
+    force_download: bool,
+    user_agent: &HeaderValue,
+) -> Result<(), DownloadError> {
+    download_with_algorithm(
+        client,
+        url,
+        path,
+        HashAlgorithm::Sha256,
+        hash,
+        retries,
+        force_download,
+        user_agent,
+    )
+    .await
+}
+
+/// Download file, verifying its hash computed with `algorithm`, and
+/// retrying if needed
+#[allow(clippy::too_many_arguments)]
+pub async fn download_with_algorithm(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    algorithm: HashAlgorithm,
+    hash: Option<&str>,
+    retries: RetryPolicy,
     force_download: bool,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
     if path.exists() && !force_download {
         if let Some(h) = hash {
-            // Verify SHA-256 hash on the filesystem.
+            // Verify the hash on the filesystem.
             let mut file = tokio::fs::File::open(path).await?;
             let mut buf = [0u8; 4096];
-            let mut sha256 = Sha256::new();
+            let mut hasher = algorithm.hasher();
 
             loop {
                 let n = file.read(&mut buf).await?;
@@ -200,10 +339,10 @@ pub async fn download(
                     break;
                 }
 
-                sha256.update(&buf[..n]);
+                hasher.update(&buf[..n]);
             }
 
-            let f_hash = format!("{:x}", sha256.finalize());
+            let f_hash = hasher.finalize_hex();
             if h == f_hash {
                 // Calculated hash matches specified hash.
                 return Ok(());
@@ -213,15 +352,40 @@ pub async fn download(
         }
     }
 
-    let mut res = Ok(());
-    for _ in 0..=retries {
-        res = match one_download(client, url, path, hash, user_agent).await {
-            Ok(_) => break,
-            Err(e) => Err(e),
+    with_retries(url, retries, || {
+        one_download(client, url, path, algorithm, hash, user_agent)
+    })
+    .await
+}
+
+/// Run `attempt` until it succeeds, retrying according to `retries`.
+async fn with_retries<T, F, Fut>(
+    url: &str,
+    retries: RetryPolicy,
+    mut attempt: F,
+) -> Result<T, DownloadError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, DownloadError>>,
+{
+    let start = Instant::now();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            // Retrying won't make a missing file appear.
+            Err(e @ DownloadError::NotFound { .. }) => return Err(e),
+            Err(e) if retries.exhausted(attempts, start) => return Err(e),
+            Err(e) => {
+                warn!(
+                    "Attempt {} to download {} failed, retrying: {}",
+                    attempts, url, e
+                );
+                tokio::time::sleep(retries.delay(attempts)).await;
+            }
         }
     }
-
-    res
 }
 
 /// Download file and associated .sha256 file, verifying the hash, and retrying if needed
@@ -229,27 +393,313 @@ pub async fn download_with_sha256_file(
     client: &Client,
     url: &str,
     path: &Path,
-    retries: usize,
+    retries: RetryPolicy,
     force_download: bool,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
-    let sha256_url = format!("{url}.sha256");
-    let sha256_data = download_string(&sha256_url, user_agent).await?;
+    download_with_hash_file(
+        client,
+        url,
+        path,
+        HashAlgorithm::Sha256,
+        retries,
+        force_download,
+        user_agent,
+    )
+    .await
+}
 
-    let sha256_hash = &sha256_data[..64];
-    download(
+/// Download file and the associated hash file of `algorithm`, e.g. .sha256,
+/// verifying the hash, and retrying if needed
+pub async fn download_with_hash_file(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    algorithm: HashAlgorithm,
+    retries: RetryPolicy,
+    force_download: bool,
+    user_agent: &HeaderValue,
+) -> Result<(), DownloadError> {
+    let hash_url = format!("{url}.{algorithm}");
+    let hash_data = with_retries(&hash_url, retries, || {
+        fetch_hash_file(client, &hash_url, user_agent)
+    })
+    .await?;
+
+    let hash = hash_data
+        .get(..algorithm.hex_len())
+        .filter(|hash| hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or(DownloadError::InvalidHashFile {
+            algorithm,
+            url: hash_url,
+        })?;
+    download_with_algorithm(
         client,
         url,
         path,
-        Some(sha256_hash),
+        algorithm,
+        Some(hash),
         retries,
         force_download,
         user_agent,
     )
     .await?;
 
-    let sha256_path = append_to_path(path, ".sha256");
-    write_file_create_dir(&sha256_path, &sha256_data)?;
+    let hash_path = append_to_path(path, &format!(".{algorithm}"));
+    write_file_create_dir(&hash_path, &hash_data)?;
 
     Ok(())
 }
+
+/// Fetch the content of a hash file. Like for the downloads, only 403 and
+/// 404 mean that the file is missing, the other failures can be retried.
+async fn fetch_hash_file(
+    client: &Client,
+    hash_url: &str,
+    user_agent: &HeaderValue,
+) -> Result<String, DownloadError> {
+    let response = client
+        .get(hash_url)
+        .header(USER_AGENT, user_agent)
+        .send()
+        .await?;
+    let status = response.status();
+    let data = response.text().await?;
+    if status == 403 || status == 404 {
+        return Err(DownloadError::NotFound {
+            status: status.as_u16(),
+            url: hash_url.to_string(),
+            data,
+        });
+    }
+    if !status.is_success() {
+        return Err(DownloadError::Status {
+            status: status.as_u16(),
+            url: hash_url.to_string(),
+            data,
+        });
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    /// Give up at the first failure.
+    const NO_RETRIES: RetryPolicy = RetryPolicy {
+        retries: 0,
+        max_duration: Duration::ZERO,
+    };
+
+    #[test]
+    fn sha256_hasher() {
+        let mut hasher = HashAlgorithm::default().hasher();
+        hasher.update(b"rustup-");
+        hasher.update(b"init");
+        assert_eq!(
+            hasher.finalize_hex(),
+            format!("{:x}", Sha256::digest(b"rustup-init"))
+        );
+        assert_eq!(HashAlgorithm::Sha256.hex_len(), 64);
+    }
+
+    /// Check the download of a file verified with its .sha256 file, through
+    /// the generalized interface.
+    #[tokio::test]
+    async fn download_with_sha256_hash_file() {
+        let hash = format!("{:x}  rustup-init", Sha256::digest(b"rustup-init"));
+        let routes = warp::path!("good" / "rustup-init")
+            .map(|| "rustup-init")
+            .or(warp::path!("good" / "rustup-init.sha256").map(move || hash.clone()))
+            .or(warp::path!("bad" / "rustup-init").map(|| "corrupted"))
+            .or(warp::path!("bad" / "rustup-init.sha256")
+                .map(|| format!("{:x}", Sha256::digest(b"rustup-init"))))
+            .or(warp::path!("invalid" / "rustup-init.sha256").map(|| "not a hash"));
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let dir = tempdir().unwrap();
+        let client = Client::new();
+        let user_agent = HeaderValue::from_static("test");
+        let download = |kind: &str| {
+            let url = format!("http://{addr}/{kind}/rustup-init");
+            let path = dir.path().join(kind).join("rustup-init");
+            let client = client.clone();
+            let user_agent = user_agent.clone();
+            async move {
+                download_with_hash_file(
+                    &client,
+                    &url,
+                    &path,
+                    HashAlgorithm::Sha256,
+                    NO_RETRIES,
+                    false,
+                    &user_agent,
+                )
+                .await
+            }
+        };
+
+        download("good").await.unwrap();
+        let path = dir.path().join("good/rustup-init");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "rustup-init");
+        assert!(append_to_path(&path, ".sha256").exists());
+
+        assert!(matches!(
+            download("bad").await,
+            Err(DownloadError::MismatchedHash { .. })
+        ));
+        assert!(dir.path().join("bad/rustup-init.badsha256").exists());
+
+        assert!(matches!(
+            download("invalid").await,
+            Err(DownloadError::InvalidHashFile {
+                algorithm: HashAlgorithm::Sha256,
+                ..
+            })
+        ));
+    }
+
+    /// Check that a server error on the hash file is retried, and isn't
+    /// reported as a missing file.
+    #[tokio::test]
+    async fn hash_file_server_errors() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_for_route = requests.clone();
+        let hash = format!("{:x}", Sha256::digest(b"rustup-init"));
+        let routes = warp::path!("flaky" / "rustup-init")
+            .map(|| "rustup-init")
+            .or(warp::path!("flaky" / "rustup-init.sha256").map(move || {
+                // The first request fails.
+                if requests_for_route.fetch_add(1, Ordering::SeqCst) < 1 {
+                    warp::reply::with_status(String::new(), StatusCode::SERVICE_UNAVAILABLE)
+                } else {
+                    warp::reply::with_status(hash.clone(), StatusCode::OK)
+                }
+            }))
+            .or(warp::path!("broken" / "rustup-init.sha256")
+                .map(|| warp::reply::with_status("error", StatusCode::INTERNAL_SERVER_ERROR)));
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let dir = tempdir().unwrap();
+        let client = Client::new();
+        let user_agent = HeaderValue::from_static("test");
+        let one_retry = RetryPolicy {
+            retries: 1,
+            max_duration: Duration::ZERO,
+        };
+
+        let path = dir.path().join("flaky/rustup-init");
+        download_with_hash_file(
+            &client,
+            &format!("http://{addr}/flaky/rustup-init"),
+            &path,
+            HashAlgorithm::Sha256,
+            one_retry,
+            false,
+            &user_agent,
+        )
+        .await
+        .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "rustup-init");
+
+        let result = download_with_hash_file(
+            &client,
+            &format!("http://{addr}/broken/rustup-init"),
+            &dir.path().join("broken/rustup-init"),
+            HashAlgorithm::Sha256,
+            NO_RETRIES,
+            false,
+            &user_agent,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::Status { status: 500, .. })
+        ));
+    }
+
+    /// Check that `--retries 0` retries until the download succeeds, but
+    /// not when the file is missing.
+    #[tokio::test]
+    async fn unlimited_retries() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_for_route = requests.clone();
+        let routes = warp::path!("flaky" / "rustup-init")
+            .map(move || {
+                // The first two downloads are truncated.
+                if requests_for_route.fetch_add(1, Ordering::SeqCst) < 2 {
+                    "rustup"
+                } else {
+                    "rustup-init"
+                }
+            })
+            .or(warp::path!("missing" / "rustup-init")
+                .map(|| warp::reply::with_status("not found", StatusCode::NOT_FOUND)));
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let dir = tempdir().unwrap();
+        let client = Client::new();
+        let user_agent = HeaderValue::from_static("test");
+        let hash = format!("{:x}", Sha256::digest(b"rustup-init"));
+        let unlimited = RetryPolicy {
+            retries: 0,
+            max_duration: Duration::from_secs(60),
+        };
+
+        let path = dir.path().join("flaky/rustup-init");
+        download(
+            &client,
+            &format!("http://{addr}/flaky/rustup-init"),
+            &path,
+            Some(&hash),
+            unlimited,
+            false,
+            &user_agent,
+        )
+        .await
+        .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "rustup-init");
+
+        // Without retries the first truncated download fails.
+        requests.store(0, Ordering::SeqCst);
+        let path = dir.path().join("once/rustup-init");
+        let result = download(
+            &client,
+            &format!("http://{addr}/flaky/rustup-init"),
+            &path,
+            Some(&hash),
+            NO_RETRIES,
+            false,
+            &user_agent,
+        )
+        .await;
+        assert!(matches!(result, Err(DownloadError::MismatchedHash { .. })));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let result = download(
+            &client,
+            &format!("http://{addr}/missing/rustup-init"),
+            &dir.path().join("missing/rustup-init"),
+            Some(&hash),
+            unlimited,
+            false,
+            &user_agent,
+        )
+        .await;
+        assert!(matches!(result, Err(DownloadError::NotFound { .. })));
+    }
+}
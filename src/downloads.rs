@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use serde::Serialize;
+use tracing::error;
+
+use crate::download::append_to_path;
+use crate::index::CrateRecord;
+
+/// How often the download counts are written to the disk. The downloads of
+/// the last interval are lost when the registry crashes.
+pub(crate) const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of downloads of each version, by crate name.
+pub(crate) type Counts = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// A crate in the response of `/api/crates`.
+#[derive(Debug, Serialize)]
+pub(crate) struct CrateDownloads {
+    pub(crate) name: String,
+    /// The downloads of all the versions.
+    pub(crate) downloads: u64,
+    pub(crate) versions: Vec<VersionDownloads>,
+}
+
+/// A version of a crate in the response of `/api/crates`.
+#[derive(Debug, Serialize)]
+pub(crate) struct VersionDownloads {
+    pub(crate) version: String,
+    pub(crate) yanked: bool,
+    pub(crate) downloads: u64,
+}
+
+/// The download counts of the crate versions of the registry, stored in
+/// `<root>/downloads.json`. They are counted in memory, not to delay the
+/// downloads, and written to the disk periodically.
+#[derive(Debug)]
+pub(crate) struct DownloadCounts {
+    path: PathBuf,
+    counts: Mutex<Counts>,
+    /// Whether there are downloads that weren't written yet.
+    dirty: AtomicBool,
+}
+
+impl DownloadCounts {
+    /// Load the download counts of the registry at `root`.
+    pub(crate) fn load(root: &Path) -> Result<Self> {
+        let path = root.join("downloads.json");
+        let counts = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("invalid download counts in {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Counts::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path,
+            counts: Mutex::new(counts),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Count a download of the crate `name` in version `vers`.
+    pub(crate) fn record(&self, name: &str, vers: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts
+            .entry(name.to_string())
+            .or_default()
+            .entry(vers.to_string())
+            .or_default() += 1;
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// The crates of `records`, a version per record, with their download
+    /// counts, the most downloaded crates first.
+    pub(crate) fn crates(&self, records: Vec<CrateRecord>) -> Vec<CrateDownloads> {
+        let counts = self.counts.lock().unwrap();
+        let mut crates = Vec::<CrateDownloads>::new();
+        for record in records {
+            let downloads = counts
+                .get(&record.name)
+                .and_then(|versions| versions.get(&record.version))
+                .copied()
+                .unwrap_or_default();
+            let version = VersionDownloads {
+                version: record.version,
+                yanked: record.yanked,
+                downloads,
+            };
+            match crates.last_mut() {
+                Some(last) if last.name == record.name => {
+                    last.downloads += downloads;
+                    last.versions.push(version);
+                }
+                _ => crates.push(CrateDownloads {
+                    name: record.name,
+                    downloads,
+                    versions: vec![version],
+                }),
+            }
+        }
+        crates.sort_by(|a, b| b.downloads.cmp(&a.downloads).then(a.name.cmp(&b.name)));
+        crates
+    }
+
+    /// Write the counts to the disk, if they changed since the last time.
+    pub(crate) async fn flush(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec(&*self.counts.lock().unwrap()).unwrap();
+        // Write aside first for a crash to never leave a truncated file.
+        let part_path = append_to_path(&self.path, ".part");
+        let result = async {
+            tokio::fs::write(&part_path, content)
+                .await
+                .with_context(|| format!("failed to write {}", part_path.display()))?;
+            tokio::fs::rename(&part_path, &self.path)
+                .await
+                .with_context(|| format!("failed to move {}", self.path.display()))
+        }
+        .await;
+        if result.is_err() {
+            // Try again the next time.
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    /// Write the counts to the disk every [`FLUSH_INTERVAL`], forever.
+    pub(crate) async fn flush_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.flush().await {
+                error!("Failed to save the download counts: {:#}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn counts_persisted() {
+        let root = tempdir().unwrap();
+        let counts = DownloadCounts::load(root.path()).unwrap();
+        counts.record("my-lib", "0.1.0");
+        counts.record("my-lib", "0.1.0");
+        counts.record("my-lib", "0.2.0");
+        counts.record("other", "1.0.0");
+        counts.flush().await.unwrap();
+        counts.record("my-lib", "0.2.0");
+        counts.flush().await.unwrap();
+        assert!(!root.path().join("downloads.json.part").exists());
+
+        let counts = DownloadCounts::load(root.path()).unwrap();
+        let record = |name: &str, version: &str| CrateRecord {
+            name: name.to_string(),
+            version: version.to_string(),
+            yanked: false,
+            cksum: String::new(),
+            dep_count: 0,
+        };
+        let crates = counts.crates(vec![
+            record("another", "0.1.0"),
+            record("my-lib", "0.1.0"),
+            record("my-lib", "0.2.0"),
+            record("my-lib", "0.3.0"),
+            record("other", "1.0.0"),
+        ]);
+        let downloads = crates
+            .iter()
+            .map(|c| {
+                let versions = c.versions.iter().map(|v| v.downloads).collect::<Vec<_>>();
+                (c.name.as_str(), c.downloads, versions)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            downloads,
+            vec![
+                ("my-lib", 4, vec![2, 2, 0]),
+                ("other", 1, vec![1]),
+                ("another", 0, vec![0]),
+            ]
+        );
+    }
+}
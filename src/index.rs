@@ -1,19 +1,19 @@
+use anyhow::bail;
 use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
-use bytes::BytesMut;
+use bytes::Bytes;
 use futures::Stream;
 use futures::StreamExt;
 use itertools::process_results;
-use itertools::Itertools;
 use serde_json::from_str;
 use serde_json::to_string;
 use smolset::SmolSet;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::create_dir_all;
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::ops::Deref;
@@ -21,26 +21,35 @@ use std::ops::DerefMut;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Stdio;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
 use tokio::process::ChildStdout;
 use tokio::process::Command;
+use tracing::info;
 use tracing::warn;
 use warp::hyper::body::Sender;
 use warp::hyper::Body;
 
-use git2::{Config as GitConfig, Repository, Signature};
+use git2::build::CheckoutBuilder;
+use git2::{Config as GitConfig, ObjectType, Repository, Signature, TreeWalkMode, TreeWalkResult};
 
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::from_reader;
-use serde_json::to_writer_pretty;
+use serde_json::to_vec;
+use serde_json::to_vec_pretty;
 use tokio::sync::Mutex;
+use tokio::sync::MutexGuard;
 use warp::http;
 use warp::path::Tail;
 
+use crate::cli::ListFormat;
+use crate::publish::crate_path;
+
 #[derive(Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
 pub struct Dep {
     /// Name of the dependency. If the dependency is renamed from the
@@ -91,15 +100,59 @@ pub struct Entry {
     /// Set of features defined for the package. Each feature maps to an
     /// array of features or dependencies it enables.
     pub features: BTreeMap<String, Vec<String>>,
+    /// The features using the `dep:` or weak `?` syntax, which older
+    /// versions of Cargo can't parse. See `split_features`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features2: Option<BTreeMap<String, Vec<String>>>,
     /// Boolean of whether or not this version has been yanked.
     pub yanked: bool,
     /// The `links` string value from the package's manifest, or null if
     /// not specified. This field is optional and defaults to null.
     pub links: Option<String>,
+    /// The version of the format of the entry: 2 with `features2`, absent
+    /// otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u32>,
+}
+
+/// The features of a package, each mapped to the features and dependencies
+/// it enables.
+pub(crate) type Features = BTreeMap<String, Vec<String>>;
+
+/// Split the features of a package as crates.io does in the index: the
+/// features with a dependency feature of the form `dep:foo` or a weak
+/// dependency feature of the form `foo?/bar` go to `features2`, the others
+/// stay in `features`. Cargo merges both, but only versions that understand
+/// the syntax read `features2`, and the entry declares the format version 2.
+pub(crate) fn split_features(features: Features) -> (Features, Option<Features>) {
+    let (features2, features): (Features, Features) =
+        features.into_iter().partition(|(_, values)| {
+            values
+                .iter()
+                .any(|value| value.starts_with("dep:") || value.contains("?/"))
+        });
+    (features, (!features2.is_empty()).then_some(features2))
 }
 
 pub(crate) struct Entries(SmolSet<[Entry; 10]>);
 
+impl Entries {
+    /// Remove the entry of the given version, if any.
+    pub(crate) fn remove_version(&mut self, vers: &str) {
+        // Note that `SmolSet::drain` and `SmolSet::retain` are unusable,
+        // rebuild the set instead.
+        self.0 = std::mem::replace(&mut self.0, SmolSet::new())
+            .into_iter()
+            .filter(|entry| entry.vers != vers)
+            .collect();
+    }
+
+    /// Take the entry of the given version out of the set, if any.
+    pub(crate) fn into_version(self, vers: &str) -> Option<Entry> {
+        self.0.into_iter().find(|entry| entry.vers == vers)
+    }
+}
+
 impl Deref for Entries {
     type Target = SmolSet<[Entry; 10]>;
 
@@ -114,35 +167,188 @@ impl DerefMut for Entries {
     }
 }
 
+/// Why the content of a crate file is not a valid set of entries.
+#[derive(Debug, Error)]
+pub enum EntriesError {
+    #[error("invalid entry: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Two different entries describe the same version.
+    #[error("conflicting entries for {name} in version {vers}")]
+    DuplicateVersion { name: String, vers: String },
+}
+
 impl TryFrom<String> for Entries {
-    type Error = serde_json::Error;
+    type Error = EntriesError;
 
     fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
-        Ok(Self(
-            value
-                .lines()
-                .map(|entry| from_str::<Entry>(entry))
-                .collect::<Result<SmolSet<[Entry; 10]>, Self::Error>>()?,
-        ))
+        let mut entries = SmolSet::new();
+        for line in value.lines() {
+            let entry = from_str::<Entry>(line)?;
+            // Identical lines are harmless, they are merged by the set.
+            if !entries.contains(&entry)
+                && entries
+                    .iter()
+                    .any(|existing: &Entry| existing.vers == entry.vers)
+            {
+                return Err(EntriesError::DuplicateVersion {
+                    name: entry.name,
+                    vers: entry.vers,
+                });
+            }
+            entries.insert(entry);
+        }
+        Ok(Self(entries))
+    }
+}
+
+impl From<Entry> for Entries {
+    fn from(entry: Entry) -> Self {
+        Self(std::iter::once(entry).collect())
     }
 }
 
+/// Serialize the entries one per line, sorted by version and each line
+/// terminated by a newline, so that rewriting a file only changes the lines
+/// of the modified versions. Invalid versions come last.
 impl TryInto<String> for Entries {
     type Error = serde_json::Error;
 
     fn try_into(self) -> std::result::Result<String, Self::Error> {
-        Ok(process_results(
-            self.0.into_iter().map(|entry| to_string(&entry)),
-            |mut ser_entries| ser_entries.join("\n"),
-        )?)
+        let mut entries = self.0.into_iter().collect::<Vec<_>>();
+        entries.sort_by_cached_key(|entry| {
+            semver::Version::parse(&entry.vers).map_err(|_| entry.vers.clone())
+        });
+        process_results(entries.iter().map(to_string), |ser_entries| {
+            ser_entries.map(|line| line + "\n").collect()
+        })
     }
 }
 
 /// An object representing a config.json file inside the index.
-#[derive(Debug, Default, Deserialize, Serialize)]
-struct Config {
-    dl: String,
-    api: Option<String>,
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct Config {
+    pub(crate) dl: String,
+    pub(crate) api: Option<String>,
+}
+
+/// Write `config` to the config.json file at `path`, pretty printed unless
+/// `compact`.
+fn write_config(path: &Path, config: &Config, compact: bool) -> Result<()> {
+    let mut content = if compact {
+        to_vec(config)?
+    } else {
+        to_vec_pretty(config)?
+    };
+    content.push(b'\n');
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+/// The error number of the writes to a read-only filesystem, `EROFS`.
+const READ_ONLY_FILESYSTEM: i32 = 30;
+
+/// Whether `path`, or its closest existing parent, can't be written to:
+/// either its permissions deny writing (even to root), or a file can't be
+/// created in it, e.g. on a read-only mount.
+pub(crate) fn read_only_dir(path: &Path) -> bool {
+    let Some(dir) = path.ancestors().find(|dir| dir.exists()) else {
+        return false;
+    };
+    match std::fs::metadata(dir) {
+        Ok(metadata) if metadata.permissions().readonly() => true,
+        Ok(_) => match tempfile::tempfile_in(dir) {
+            Ok(_) => false,
+            Err(err) => {
+                err.kind() == ErrorKind::PermissionDenied
+                    || err.raw_os_error() == Some(READ_ONLY_FILESYSTEM)
+            }
+        },
+        Err(_) => false,
+    }
+}
+
+/// A line of a crate file in the index that isn't a valid entry.
+#[derive(Debug)]
+pub struct InvalidLine {
+    /// The path of the crate file, relative to the index root.
+    pub path: PathBuf,
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// Why the line could not be parsed.
+    pub error: String,
+}
+
+/// The state of an index, as reported by the `index-status` command.
+#[derive(Debug)]
+pub struct IndexStatus {
+    /// The hash of the HEAD commit.
+    pub head: String,
+    /// The summary of the HEAD commit message.
+    pub message: String,
+    /// Number of crate files tracked in the index.
+    pub crate_files: usize,
+    /// All the lines that could not be parsed as an index entry.
+    pub invalid_lines: Vec<InvalidLine>,
+}
+
+/// The outcome of the `compact-index` command.
+#[derive(Debug)]
+pub struct CompactReport {
+    /// The number of commits squashed into a single one.
+    pub commits: usize,
+    /// The size in bytes of the git directory before the compaction.
+    pub size_before: u64,
+    /// The size in bytes of the git directory after the compaction.
+    pub size_after: u64,
+}
+
+/// An advisory lock on an index, shared by the servers and exclusive while
+/// its history is rewritten, released when dropped.
+pub(crate) struct IndexLock {
+    _file: File,
+}
+
+impl IndexLock {
+    /// Take the lock of the index at `root`, failing at once when a
+    /// conflicting lock is held.
+    fn acquire(root: &Path, exclusive: bool) -> Result<Self> {
+        let path = root.join(".git").join("crates-registry.lock");
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open the lock file {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use rustix::fs::{flock, FlockOperation};
+
+            let operation = if exclusive {
+                FlockOperation::NonBlockingLockExclusive
+            } else {
+                FlockOperation::NonBlockingLockShared
+            };
+            match flock(&file, operation) {
+                Ok(()) => {}
+                Err(err) if err == rustix::io::Errno::WOULDBLOCK && exclusive => {
+                    bail!(
+                        "the index at {} is being served, stop the server first",
+                        root.display()
+                    )
+                }
+                Err(err) if err == rustix::io::Errno::WOULDBLOCK => {
+                    bail!("the index at {} is being compacted", root.display())
+                }
+                Err(err) => {
+                    return Err(std::io::Error::from(err))
+                        .with_context(|| format!("failed to lock {}", path.display()))
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = exclusive;
+        Ok(Self { _file: file })
+    }
 }
 
 /// A struct representing a crate index.
@@ -151,15 +357,39 @@ pub struct Index {
     root: PathBuf,
     /// The git repository inside the index.
     repository: Mutex<Repository>,
+    /// Whether the index can't be written to, e.g. mounted read-only.
+    read_only: bool,
+    /// The shared lock held while the index is served, see `lock_for_serving`.
+    serve_lock: std::sync::Mutex<Option<IndexLock>>,
+    /// Held from reading a crate file until its change is committed, see
+    /// `lock_writes`.
+    writes: Mutex<()>,
 }
 
 impl Index {
-    // Create new index if there is already an index in the root the method just open it
-    pub async fn new<P>(root: P, addr: &SocketAddr) -> Result<Self>
+    // Create new index if there is already an index in the root the method just open it.
+    // `server_url` is the URL clients reach the registry at (e.g. http://127.0.0.1:5000/registry).
+    pub async fn new<P>(root: P, server_url: &str) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        Self::new_with_config_format(root, server_url, false, true).await
+    }
+
+    /// Like [`Index::new`], but with `compact` the config.json is written on
+    /// a single line rather than pretty printed, and without `api` its `api`
+    /// is null so that cargo doesn't attempt to publish to the registry.
+    pub async fn new_with_config_format<P>(
+        root: P,
+        server_url: &str,
+        compact: bool,
+        api: bool,
+    ) -> Result<Self>
     where
         P: Into<PathBuf>,
     {
         let root: PathBuf = root.into();
+        let read_only = read_only_dir(&root);
         {
             let mut config = GitConfig::open_default()?;
             if let Err(err) = config.set_str("safe.directory", &format!("{}", root.display())) {
@@ -172,6 +402,14 @@ impl Index {
 
         let repository = match Repository::open(&root) {
             Ok(r) => r,
+            Err(e) if read_only => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "failed to open git repository {}, it can't be initialized read-only",
+                        root.display()
+                    )
+                })
+            }
             Err(e) => {
                 warn!(
                     "Can't open the git repository at {} try to init [{:?}]",
@@ -186,17 +424,164 @@ impl Index {
             }
         };
 
+        if read_only {
+            warn!(
+                "The index at {} is read-only, it's served as it is",
+                root.display()
+            );
+        }
         let mut index = Index {
             root,
             repository: Mutex::new(repository),
+            read_only,
+            serve_lock: std::sync::Mutex::new(None),
+            writes: Mutex::new(()),
         };
         index.ensure_has_commit().await?;
-        index.ensure_config(addr).await?;
-        index.update_server_info()?;
+        index.ensure_config(server_url, compact, api).await?;
+        match index.update_server_info() {
+            Err(err) if read_only => {
+                warn!(
+                    "Can't update the server info of the read-only index: {:#}",
+                    err
+                )
+            }
+            result => result?,
+        }
 
         Ok(index)
     }
 
+    /// Open an existing index without touching its content.
+    pub fn open<P>(root: P) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let root: PathBuf = root.into();
+        let repository = Repository::open(&root)
+            .with_context(|| format!("failed to open git repository {}", root.display()))?;
+        Ok(Index {
+            read_only: read_only_dir(&root),
+            root,
+            repository: Mutex::new(repository),
+            serve_lock: std::sync::Mutex::new(None),
+            writes: Mutex::new(()),
+        })
+    }
+
+    /// Lock the crate files for a change, from reading a crate file until
+    /// the change is committed, so that two concurrent changes of a crate
+    /// (e.g. a publish and a yank) don't lose one another, nor commit the
+    /// half-written file of the other.
+    pub(crate) async fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.writes.lock().await
+    }
+
+    /// Hold the shared lock of the index for as long as it's served, for
+    /// `compact` to refuse to rewrite its history meanwhile. A read-only
+    /// index is never compacted, it isn't locked.
+    pub(crate) fn lock_for_serving(&self) -> Result<()> {
+        if !self.read_only {
+            *self.serve_lock.lock().unwrap() = Some(IndexLock::acquire(&self.root, false)?);
+        }
+        Ok(())
+    }
+
+    /// Whether the index can't be written to, in which case it's served
+    /// as it is, without publishing nor yanking.
+    pub(crate) fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Inspect the committed content of the index: the HEAD commit and
+    /// whether every crate file in it is valid.
+    pub async fn status(&self) -> Result<IndexStatus> {
+        let repository = self.repository.lock().await;
+        let head = repository
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("failed to find the HEAD commit")?;
+        let tree = head.tree().context("failed to find the HEAD tree")?;
+
+        let mut status = IndexStatus {
+            head: head.id().to_string(),
+            message: head.summary().unwrap_or_default().to_string(),
+            crate_files: 0,
+            invalid_lines: Vec::new(),
+        };
+
+        let mut blobs = Vec::new();
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                blobs.push((
+                    PathBuf::from(dir).join(entry.name().unwrap_or_default()),
+                    entry.id(),
+                ));
+            }
+            TreeWalkResult::Ok
+        })
+        .context("failed to walk the HEAD tree")?;
+
+        for (path, id) in blobs {
+            if path == Path::new("config.json") {
+                continue;
+            }
+            status.crate_files += 1;
+            let blob = repository
+                .find_blob(id)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let content = String::from_utf8_lossy(blob.content());
+            let mut entries = HashMap::new();
+            for (line_number, line) in content.lines().enumerate() {
+                let error = match from_str::<Entry>(line) {
+                    Ok(entry) => match entries.get(&entry.vers) {
+                        Some(existing) if *existing != entry => EntriesError::DuplicateVersion {
+                            name: entry.name,
+                            vers: entry.vers,
+                        },
+                        _ => {
+                            entries.insert(entry.vers.clone(), entry);
+                            continue;
+                        }
+                    },
+                    Err(err) => EntriesError::from(err),
+                };
+                status.invalid_lines.push(InvalidLine {
+                    path: path.clone(),
+                    line: line_number + 1,
+                    error: error.to_string(),
+                });
+            }
+        }
+        Ok(status)
+    }
+
+    /// Read the file at `path`, relative to the index root, as of the HEAD
+    /// commit. Serving the index from the commits only makes a change
+    /// visible once it is committed, the same way git clients see it.
+    /// Returns `None` when there is no such file.
+    pub async fn committed_file(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let repository = self.repository.lock().await;
+        let tree = repository
+            .head()
+            .and_then(|head| head.peel_to_tree())
+            .context("failed to find the HEAD tree")?;
+        let entry = match tree.get_path(path) {
+            Ok(entry) => entry,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to look up {}", path.display()))
+            }
+        };
+        if entry.kind() != Some(ObjectType::Blob) {
+            return Ok(None);
+        }
+        let blob = repository
+            .find_blob(entry.id())
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(Some(blob.content().to_vec()))
+    }
+
     pub async fn add_and_commit(
         &self,
         files: impl IntoIterator<Item = impl AsRef<Path>>,
@@ -222,13 +607,20 @@ impl Index {
             } else {
                 file
             };
-            index
-                .add_path(relative_path)
-                .context("failed to add file to git index")?;
-            index
-                .write()
-                .context("failed to write git repository index")?;
+            // The files deleted from the index are deleted from git too.
+            if self.root.join(relative_path).exists() {
+                index
+                    .add_path(relative_path)
+                    .context("failed to add file to git index")?;
+            } else {
+                index
+                    .remove_path(relative_path)
+                    .context("failed to remove file from git index")?;
+            }
         }
+        index
+            .write()
+            .context("failed to write git repository index")?;
 
         let tree_id = index
             .write_tree()
@@ -292,6 +684,11 @@ impl Index {
             .context("unable to check git repository empty status")?;
 
         if empty {
+            ensure!(
+                !self.read_only,
+                "the read-only index at {} has no commit",
+                self.root.display()
+            );
             self.add_and_commit(
                 std::iter::empty::<PathBuf>(),
                 "Create new repository for cargo registry",
@@ -303,54 +700,392 @@ impl Index {
     }
 
     /// Ensure that a valid `config.json` exists and that it is up-to-date.
-    async fn ensure_config(&mut self, addr: &SocketAddr) -> Result<()> {
+    /// The committed file is the reference, uncommitted changes of it are
+    /// discarded, e.g. an edit left behind when moving the registry.
+    async fn ensure_config(&mut self, server_url: &str, compact: bool, api: bool) -> Result<()> {
         let path = self.root.join("config.json");
-        let result = OpenOptions::new().read(true).write(true).open(&path);
-        match result {
-            Ok(file) => {
-                let mut config =
-                    from_reader::<_, Config>(&file).context("failed to parse config.json")?;
-                let dl = format!(
-                    "http://{}/api/v1/crates/{{crate}}/{{version}}/download",
-                    addr
+        let config = Config {
+            dl: format!(
+                "{}/api/v1/crates/{{crate}}/{{version}}/download",
+                server_url
+            ),
+            api: api.then(|| server_url.to_string()),
+        };
+        // A read-only index keeps its config.json, outdated or not.
+        if self.read_only {
+            let content = self.committed_file(Path::new("config.json")).await?;
+            let current =
+                content.and_then(|content| serde_json::from_slice::<Config>(&content).ok());
+            if current.as_ref() != Some(&config) {
+                warn!(
+                    "The config.json of the read-only index isn't up to date with {}",
+                    server_url
                 );
-                let api = format!("http://{}", addr);
-                if config.dl != dl || config.api.as_ref() != Some(&api) {
-                    config.dl = dl;
-                    config.api = Some(api);
-
-                    let file = OpenOptions::new()
-                        .write(true)
-                        .truncate(true)
-                        .open(&path)
-                        .context("failed to reopen config.json")?;
-                    to_writer_pretty(&file, &config).context("failed to update config.json")?;
+            }
+            return Ok(());
+        }
+        if self.discard_changes(Path::new("config.json")).await? {
+            warn!("Discarded the uncommitted changes of config.json");
+        }
+        match self.committed_file(Path::new("config.json")).await? {
+            Some(content) => {
+                // Compare the parsed content rather than the text, so that
+                // the file is only rewritten (and committed) when the URLs
+                // change, whatever its formatting.
+                let current = match serde_json::from_slice::<Config>(&content) {
+                    Ok(current) => Some(current),
+                    Err(err) => {
+                        warn!("Replacing the invalid config.json: {}", err);
+                        None
+                    }
+                };
+                if current.as_ref() != Some(&config) {
+                    if let Some(current) = current {
+                        info!("Moving the registry from {} to {}", current.dl, config.dl);
+                    }
+                    write_config(&path, &config, compact)
+                        .context("failed to update config.json")?;
 
                     self.add_and_commit(vec!["config.json"], "Update config.json")
                         .await
                         .context("failed to stage and commit config.json")?;
                 }
             }
-            Err(err) if err.kind() == ErrorKind::NotFound => {
-                let file = File::create(&path).context("failed to create config.json")?;
-                let config = Config {
-                    dl: format!(
-                        "http://{}/api/v1/crates/{{crate}}/{{version}}/download",
-                        addr
-                    ),
-                    api: Some(format!("http://{}", addr)),
-                };
-                to_writer_pretty(&file, &config).context("failed to write config.json")?;
+            None => {
+                write_config(&path, &config, compact).context("failed to write config.json")?;
 
                 self.add_and_commit(vec!["config.json"], "Add initial config.json")
                     .await
                     .context("failed to stage and commit config.json")?;
             }
-            Err(err) => return Err(err).context("failed to open/create config.json"),
         }
         Ok(())
     }
 
+    /// Restore the file at `path`, relative to the root, in the git index and
+    /// in the working tree to its committed content, when it has one.
+    /// Returns whether there were changes to discard.
+    async fn discard_changes(&self, path: &Path) -> Result<bool> {
+        let repository = self.repository.lock().await;
+        let status = match repository.status_file(path) {
+            Ok(status) => status,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(false),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to get the status of {}", path.display()))
+            }
+        };
+        if status.is_empty() || status.is_wt_new() {
+            return Ok(false);
+        }
+        repository
+            .checkout_head(Some(CheckoutBuilder::new().force().path(path)))
+            .with_context(|| format!("failed to restore {}", path.display()))?;
+        Ok(true)
+    }
+
+    /// Import the crate files of another index (e.g. a clone of the crates.io
+    /// index) located at `source` and commit them.
+    /// The lines are copied as they are, for the fields this registry doesn't
+    /// know of (e.g. `rust_version`) to be kept.
+    /// Versions that already exist in this index are left untouched.
+    /// Returns the number of imported crate files.
+    pub async fn import(&self, source: &Path) -> Result<usize> {
+        let _writes = self.lock_writes().await;
+        let mut crate_files = Vec::new();
+        collect_crate_files(source, source, &mut crate_files)?;
+
+        let mut imported = Vec::new();
+        for relative_path in crate_files {
+            let source_path = source.join(&relative_path);
+            let source_content = std::fs::read_to_string(&source_path)
+                .with_context(|| format!("failed to read crate file {}", source_path.display()))?;
+            Entries::try_from(source_content.clone())
+                .with_context(|| format!("invalid crate file {}", source_path.display()))?;
+
+            let path = self.root.join(&relative_path);
+            let content = if path.exists() {
+                let mut content = std::fs::read_to_string(&path)?;
+                let entries: Entries = content
+                    .clone()
+                    .try_into()
+                    .with_context(|| format!("invalid crate file {}", path.display()))?;
+                let mut versions: HashSet<String> =
+                    entries.iter().map(|entry| entry.vers.clone()).collect();
+                let before = content.len();
+                for line in source_content.lines() {
+                    let entry = from_str::<Entry>(line)?;
+                    if versions.insert(entry.vers) {
+                        if !content.is_empty() && !content.ends_with('\n') {
+                            content.push('\n');
+                        }
+                        content.push_str(line);
+                        content.push('\n');
+                    }
+                }
+                if content.len() == before {
+                    continue;
+                }
+                content
+            } else {
+                source_content
+            };
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)
+                    .with_context(|| format!("failed to create directory {}", parent.display()))?;
+            }
+            std::fs::write(&path, content)
+                .with_context(|| format!("failed to write crate file {}", path.display()))?;
+            imported.push(relative_path);
+        }
+
+        if !imported.is_empty() {
+            self.add_and_commit(
+                &imported,
+                &format!("Import {} crates from {}", imported.len(), source.display()),
+            )
+            .await?;
+        }
+        Ok(imported.len())
+    }
+
+    /// Add `entry` to the index and commit it with `message`, unless its
+    /// version is already there. Returns whether the entry was added.
+    pub(crate) async fn add_entry(&self, entry: Entry, message: &str) -> Result<bool> {
+        let _writes = self.lock_writes().await;
+        let Some(crate_meta_path) = self.write_entry(entry)? else {
+            return Ok(false);
+        };
+        self.add_and_commit(vec![&crate_meta_path], message).await?;
+        Ok(true)
+    }
+
+    /// Add `entries` to the index and commit them at once with `message`,
+    /// except the versions already there. Returns the number of entries
+    /// added.
+    pub(crate) async fn add_entries(
+        &self,
+        entries: impl IntoIterator<Item = Entry>,
+        message: &str,
+    ) -> Result<usize> {
+        let _writes = self.lock_writes().await;
+        let mut paths = Vec::new();
+        for entry in entries {
+            paths.extend(self.write_entry(entry)?);
+        }
+        if !paths.is_empty() {
+            self.add_and_commit(&paths, message).await?;
+        }
+        Ok(paths.len())
+    }
+
+    /// Write `entry` to its crate file in the index, unless its version is
+    /// already there. Returns the path of the crate file written.
+    /// The caller holds `lock_writes` until the file is committed.
+    fn write_entry(&self, entry: Entry) -> Result<Option<PathBuf>> {
+        let crate_meta_dir = self.root.join(crate_path(&entry.name));
+        let crate_meta_path = crate_meta_dir.join(&entry.name);
+        let content = if crate_meta_path.exists() {
+            let mut entries: Entries = std::fs::read_to_string(&crate_meta_path)?
+                .try_into()
+                .with_context(|| format!("invalid crate file {}", crate_meta_path.display()))?;
+            if entries.iter().any(|existing| existing.vers == entry.vers) {
+                return Ok(None);
+            }
+            entries.insert(entry);
+            TryInto::<String>::try_into(entries)?
+        } else {
+            create_dir_all(&crate_meta_dir).with_context(|| {
+                format!("failed to create directory {}", crate_meta_dir.display())
+            })?;
+            TryInto::<String>::try_into(Entries::from(entry))?
+        };
+        std::fs::write(&crate_meta_path, content)
+            .with_context(|| format!("failed to write crate file {}", crate_meta_path.display()))?;
+        Ok(Some(crate_meta_path))
+    }
+
+    /// Set whether the crate `name` in version `vers` is yanked and commit
+    /// the change, if any. Returns `None` when the index has no such
+    /// version, and otherwise whether the entry changed.
+    pub(crate) async fn set_yanked(
+        &self,
+        name: &str,
+        vers: &str,
+        yanked: bool,
+    ) -> Result<Option<bool>> {
+        let _writes = self.lock_writes().await;
+        let crate_meta_path = self.root.join(crate_path(name)).join(name);
+        let content = match std::fs::read_to_string(&crate_meta_path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to read crate file {}", crate_meta_path.display())
+                })
+            }
+        };
+        let mut entries: Entries = content
+            .try_into()
+            .with_context(|| format!("invalid crate file {}", crate_meta_path.display()))?;
+        match entries.iter().find(|entry| entry.vers == vers) {
+            None => return Ok(None),
+            Some(entry) if entry.yanked == yanked => return Ok(Some(false)),
+            Some(_) => (),
+        }
+        // The entries are hashed with the flag, rebuild the set to change it.
+        entries.0 = std::mem::replace(&mut entries.0, SmolSet::new())
+            .into_iter()
+            .map(|mut entry| {
+                if entry.vers == vers {
+                    entry.yanked = yanked;
+                }
+                entry
+            })
+            .collect();
+        std::fs::write(&crate_meta_path, TryInto::<String>::try_into(entries)?)
+            .with_context(|| format!("failed to write crate file {}", crate_meta_path.display()))?;
+        let action = if yanked { "Yank" } else { "Unyank" };
+        self.add_and_commit(
+            vec![&crate_meta_path],
+            &format!("{} {} in version {}", action, name, vers),
+        )
+        .await?;
+        Ok(Some(true))
+    }
+
+    /// Remove the crate `name` in version `vers` from the index and commit
+    /// the change. Returns the removed entry, `None` when the index has no
+    /// such version.
+    pub(crate) async fn remove_version(&self, name: &str, vers: &str) -> Result<Option<Entry>> {
+        let _writes = self.lock_writes().await;
+        let crate_meta_path = self.root.join(crate_path(name)).join(name);
+        let Some(entry) = self.entry(name, vers)? else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(&crate_meta_path)
+            .with_context(|| format!("failed to read crate file {}", crate_meta_path.display()))?;
+        let mut entries: Entries = content
+            .try_into()
+            .with_context(|| format!("invalid crate file {}", crate_meta_path.display()))?;
+        entries.remove_version(vers);
+        // A crate without version has no file in the index.
+        if entries.is_empty() {
+            std::fs::remove_file(&crate_meta_path).with_context(|| {
+                format!("failed to remove crate file {}", crate_meta_path.display())
+            })?;
+        } else {
+            std::fs::write(&crate_meta_path, TryInto::<String>::try_into(entries)?).with_context(
+                || format!("failed to write crate file {}", crate_meta_path.display()),
+            )?;
+        }
+        self.add_and_commit(
+            vec![&crate_meta_path],
+            &format!("Delete {} in version {}", name, vers),
+        )
+        .await?;
+        Ok(Some(entry))
+    }
+
+    /// Look up the entry of a crate in the given version, if any.
+    pub(crate) fn entry(&self, name: &str, vers: &str) -> Result<Option<Entry>> {
+        let crate_meta_path = self.root.join(crate_path(name)).join(name);
+        let content = match std::fs::read_to_string(&crate_meta_path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to read crate file {}", crate_meta_path.display())
+                })
+            }
+        };
+        let entries: Entries = content
+            .try_into()
+            .with_context(|| format!("invalid crate file {}", crate_meta_path.display()))?;
+        Ok(entries.into_version(vers))
+    }
+
+    /// Read the `config.json` of the index.
+    pub(crate) fn config(&self) -> Result<Config> {
+        let path = self.root.join("config.json");
+        let file =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        from_reader(file).context("failed to parse config.json")
+    }
+
+    /// Open the git repository at the root again, e.g. after the registry
+    /// directory was replaced by `unpack --atomic`.
+    pub(crate) async fn reopen(&self) -> Result<()> {
+        let repository = Repository::open(&self.root)
+            .with_context(|| format!("failed to open git repository {}", self.root.display()))?;
+        // The root may now be another directory, lock it instead.
+        {
+            let mut serve_lock = self.serve_lock.lock().unwrap();
+            if serve_lock.is_some() {
+                *serve_lock = Some(IndexLock::acquire(&self.root, false)?);
+            }
+        }
+        *self.repository.lock().await = repository;
+        Ok(())
+    }
+
+    /// Squash the history of the index into a single commit of its current
+    /// tree and drop the objects of the former commits, so that the
+    /// repository shrinks. The clones of the index have to be made again.
+    /// Refused while the index is served.
+    pub async fn compact(&self) -> Result<CompactReport> {
+        let _lock = IndexLock::acquire(&self.root, true)?;
+        let mut repository = self.repository.lock().await;
+        let git_dir = repository.path().to_path_buf();
+        let size_before = dir_size(&git_dir)?;
+        let commits = {
+            let head = repository
+                .head()
+                .and_then(|head| head.peel_to_commit())
+                .context("failed to find the HEAD commit")?;
+            let mut revwalk = repository.revwalk()?;
+            revwalk.push(head.id())?;
+            let commits = revwalk.count();
+
+            let signature = Signature::now("CrateRegistry", "crates@registry")?;
+            let tree = head.tree().context("failed to find the HEAD tree")?;
+            let message = format!("Compact the history of the index ({commits} commits)");
+            let commit = repository
+                .commit(None, &signature, &signature, &message, &tree, &[])
+                .context("failed to create git commit")?;
+            repository
+                .head()
+                .and_then(|mut head| head.set_target(commit, &message).map(|_| ()))
+                .context("failed to update HEAD")?;
+            commits
+        };
+
+        // The former commits are still referenced by the reflog.
+        for args in [
+            &["reflog", "expire", "--expire=now", "--all"][..],
+            &["gc", "--prune=now", "--quiet"][..],
+        ] {
+            let status = Command::new("git")
+                .current_dir(&self.root)
+                .args(args)
+                .status()
+                .await
+                .with_context(|| format!("failed to run git {}", args[0]))?;
+            ensure!(status.success(), "git {} failed", args[0]);
+        }
+        // Don't keep the objects of the former commits open.
+        *repository = Repository::open(&self.root)
+            .with_context(|| format!("failed to open git repository {}", self.root.display()))?;
+        drop(repository);
+        self.update_server_info()?;
+
+        Ok(CompactReport {
+            commits,
+            size_before,
+            size_after: dir_size(&git_dir)?,
+        })
+    }
+
     /// Retrieve the path to the index' root directory.
     #[inline]
     pub fn root(&self) -> &Path {
@@ -358,15 +1093,237 @@ impl Index {
     }
 }
 
+/// The total size in bytes of the files below `dir`.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory {}", dir.display()))?
+    {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Collect the paths, relative to `root`, of all crate files under `dir`.
+/// Hidden files and directories (like `.git`) and the `config.json` are skipped.
+fn collect_crate_files(root: &Path, dir: &Path, crate_files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory {}", dir.display()))?
+    {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            collect_crate_files(root, &path, crate_files)?;
+        } else {
+            let relative_path = path.strip_prefix(root)?.to_path_buf();
+            if relative_path != Path::new("config.json") {
+                crate_files.push(relative_path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Import the crates of the index at `source` (a local directory or a git
+/// URL) into the index of the registry at `root_registry`, without the
+/// `.crate` files. The crates can be resolved but not downloaded until
+/// they are added to the registry.
+pub async fn bootstrap_index(root_registry: &Path, source: &str) -> Result<usize> {
+    let index_folder = root_registry.join("index");
+    let index = if index_folder.exists() {
+        Index::open(&index_folder)?
+    } else {
+        // The server rewrites the config with its own address on start.
+        Index::new(&index_folder, "http://127.0.0.1:5000").await?
+    };
+
+    let source_path = Path::new(source);
+    if source_path.is_dir() {
+        return index.import(source_path).await;
+    }
+
+    let clone_dir = tempfile::tempdir()?;
+    let status = Command::new("git")
+        .args(["clone", "--depth", "1", source])
+        .arg(clone_dir.path())
+        .status()
+        .await
+        .context("failed to run git clone")?;
+    ensure!(status.success(), "failed to clone the index at {}", source);
+    index.import(clone_dir.path()).await
+}
+
+/// Compact the history of the crates index of the registry at `root_registry`.
+pub async fn compact_index(root_registry: &Path) -> Result<CompactReport> {
+    Index::open(root_registry.join("index"))?.compact().await
+}
+
+/// Report the status of the crates index of the registry at `root_registry`.
+pub async fn index_status(root_registry: &Path) -> Result<IndexStatus> {
+    Index::open(root_registry.join("index"))?.status().await
+}
+
+/// A crate version of the index, as printed by the `list` command.
+#[derive(Debug, Serialize)]
+pub struct CrateRecord {
+    pub name: String,
+    pub version: String,
+    pub yanked: bool,
+    pub cksum: String,
+    /// Number of direct dependencies.
+    pub dep_count: usize,
+}
+
+/// List the crate versions of the index of the registry at `root_registry`,
+/// sorted by name and version.
+pub fn list_crates(root_registry: &Path) -> Result<Vec<CrateRecord>> {
+    let index_folder = root_registry.join("index");
+    let mut crate_files = Vec::new();
+    collect_crate_files(&index_folder, &index_folder, &mut crate_files)?;
+
+    let mut records = Vec::new();
+    for relative_path in crate_files {
+        let path = index_folder.join(relative_path);
+        let entries: Entries = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read crate file {}", path.display()))?
+            .try_into()
+            .with_context(|| format!("invalid crate file {}", path.display()))?;
+        records.extend(entries.0.into_iter().map(|entry| CrateRecord {
+            dep_count: entry.deps.len(),
+            name: entry.name,
+            version: entry.vers,
+            yanked: entry.yanked,
+            cksum: entry.cksum,
+        }));
+    }
+    records.sort_by_cached_key(|record| {
+        (
+            record.name.clone(),
+            semver::Version::parse(&record.version).map_err(|_| record.version.clone()),
+        )
+    });
+    Ok(records)
+}
+
+/// Format the output of the `list` command.
+pub fn format_crates(records: &[CrateRecord], format: ListFormat) -> Result<String> {
+    Ok(match format {
+        ListFormat::Text => records
+            .iter()
+            .map(|record| {
+                format!(
+                    "{} {}{}\n",
+                    record.name,
+                    record.version,
+                    if record.yanked { " (yanked)" } else { "" }
+                )
+            })
+            .collect(),
+        ListFormat::Json => to_string(records)? + "\n",
+        // None of the fields can contain a comma or a quote, no need to escape them.
+        ListFormat::Csv => std::iter::once("name,version,yanked,cksum,dep_count\n".to_string())
+            .chain(records.iter().map(|record| {
+                format!(
+                    "{},{},{},{},{}\n",
+                    record.name, record.version, record.yanked, record.cksum, record.dep_count
+                )
+            }))
+            .collect(),
+    })
+}
+
+/// The oldest git whose `http-backend` honors the protocol version asked
+/// for by the clients, through `GIT_PROTOCOL`.
+pub(crate) const MIN_GIT_VERSION: (u64, u64, u64) = (2, 18, 0);
+
+/// Parse the version of a `git --version` output, e.g. `git version 2.39.2`,
+/// `git version 2.39.2.windows.1` or `git version 2.37.1 (Apple Git-137.1)`.
+fn parse_git_version(output: &str) -> Option<(u64, u64, u64)> {
+    let version = output
+        .trim()
+        .strip_prefix("git version ")?
+        .split_whitespace()
+        .next()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    // The patch component of a release candidate is e.g. `0-rc1`.
+    let patch = parts
+        .next()
+        .and_then(|patch| patch.split('-').next()?.parse().ok())
+        .unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Describe why the git of the `git --version` output `output` can't be
+/// relied on to serve the index.
+fn git_version_warning_of(output: &str) -> Option<String> {
+    let Some(version) = parse_git_version(output) else {
+        return Some(format!(
+            "unrecognized git version {:?}, git {}.{}.{} or newer is needed to serve the index",
+            output.trim(),
+            MIN_GIT_VERSION.0,
+            MIN_GIT_VERSION.1,
+            MIN_GIT_VERSION.2
+        ));
+    };
+    if version >= MIN_GIT_VERSION {
+        return None;
+    }
+    Some(format!(
+        "git {}.{}.{} is too old to serve the index, git {}.{}.{} or newer is needed for \
+         git http-backend to serve the protocol version cargo asks for",
+        version.0, version.1, version.2, MIN_GIT_VERSION.0, MIN_GIT_VERSION.1, MIN_GIT_VERSION.2
+    ))
+}
+
+/// Check that the installed git can serve the index with [`handle_git`] and
+/// update it with `git update-server-info`, describing why it can't.
+pub(crate) async fn git_version_warning() -> Option<String> {
+    let output = match Command::new("git").arg("--version").output().await {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            return Some(format!(
+                "git --version failed ({}), the index can't be served",
+                output.status
+            ))
+        }
+        Err(err) => {
+            return Some(format!(
+                "failed to run git ({err}), the index can't be served"
+            ))
+        }
+    };
+    git_version_warning_of(&String::from_utf8_lossy(&output.stdout))
+}
+
 /// Handle a request from a git client.
+/// The git CGI output is streamed to the client in chunks of at most
+/// `buffer_size` bytes. A request body compressed by the client, as
+/// announced by `content_encoding`, is decompressed by git itself, and the
+/// protocol version requested by the client in `git_protocol` is honored.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_git<S, B>(
     mirror_path: PathBuf,
     path_tail: Tail,
     method: http::Method,
     content_type: Option<String>,
+    content_encoding: Option<String>,
+    git_protocol: Option<String>,
     remote: Option<SocketAddr>,
-    mut body: S,
+    body: S,
     query: String,
+    buffer_size: usize,
+    timeout: Duration,
 ) -> Result<http::Response<Body>>
 where
     S: Stream<Item = Result<B, warp::Error>> + Send + Unpin + 'static,
@@ -393,41 +1350,83 @@ where
     if let Some(content_type) = content_type {
         cmd.env("CONTENT_TYPE", content_type);
     }
+    if let Some(content_encoding) = content_encoding {
+        cmd.env("HTTP_CONTENT_ENCODING", content_encoding);
+    }
+    // Without it, clients asking for protocol v2 silently fall back to v0.
+    if let Some(git_protocol) = git_protocol {
+        cmd.env("GIT_PROTOCOL", git_protocol);
+    }
     cmd.env("GIT_HTTP_EXPORT_ALL", "true");
+    run_git_backend(cmd, body, buffer_size, timeout).await
+}
+
+/// Run the git CGI command `cmd` with the request `body` as input and make
+/// a response out of its output. The command is killed when it neither
+/// reads its input nor writes its output for `timeout`, e.g. when stuck.
+async fn run_git_backend<S, B>(
+    mut cmd: Command,
+    mut body: S,
+    buffer_size: usize,
+    timeout: Duration,
+) -> Result<http::Response<Body>>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Unpin + 'static,
+    B: bytes::Buf + Sized,
+{
     cmd.stderr(Stdio::inherit());
     cmd.stdout(Stdio::piped());
     cmd.stdin(Stdio::piped());
+    // Never leave a backend behind when the request fails, the response
+    // holds the process while its output is streamed.
+    cmd.kill_on_drop(true);
 
-    let p = cmd.spawn()?;
+    let mut p = cmd.spawn()?;
 
     // Handle sending git client body to http-backend, if any
-    let mut git_input = p.stdin.expect("Process should always have stdin");
-    while let Some(Ok(mut buf)) = body.next().await {
-        git_input.write_all_buf(&mut buf).await?;
+    let mut git_input = p.stdin.take().expect("Process should always have stdin");
+    while let Some(buf) = body.next().await {
+        tokio::time::timeout(timeout, git_input.write_all_buf(&mut buf?))
+            .await
+            .context("git http-backend stopped reading its input")??;
     }
-
-    // Collect headers from git CGI output
-    let mut git_output = BufReader::new(p.stdout.expect("Process should always have stdout"));
-    let mut headers = HashMap::new();
+    // Without a CONTENT_LENGTH, git reads the body until the end of its input.
+    drop(git_input);
+
+    // Collect headers from git CGI output, in order, as a header like
+    // Cache-Control may be repeated.
+    let mut git_output =
+        BufReader::new(p.stdout.take().expect("Process should always have stdout"));
+    let mut headers = Vec::new();
     loop {
         let mut line = String::new();
-        git_output.read_line(&mut line).await?;
+        let read = tokio::time::timeout(timeout, git_output.read_line(&mut line))
+            .await
+            .context("git http-backend sent no response")??;
+        if read == 0 {
+            break;
+        }
 
         let line = line.trim_end();
         if line.is_empty() {
             break;
         }
 
-        if let Some((key, value)) = line.split_once(": ") {
-            headers.insert(key.to_string(), value.to_string());
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
         }
     }
 
-    // Add headers to response (except for Status, which is the "200 OK" line)
+    // Add headers to response (except for Status, which is the "200 OK"
+    // line). Content-Type and Content-Length, when git provides it, are
+    // forwarded as is; the body is never altered.
     let mut resp = http::Response::builder();
     for (key, val) in headers {
-        if key == "Status" {
-            resp = resp.status(&val.as_bytes()[..3]);
+        if key.eq_ignore_ascii_case("Status") {
+            let code = val.split_whitespace().next().unwrap_or_default();
+            let status = http::StatusCode::from_bytes(code.as_bytes())
+                .with_context(|| format!("invalid status from git: {val}"))?;
+            resp = resp.status(status);
         } else {
             resp = resp.header(&key, val);
         }
@@ -436,25 +1435,43 @@ where
     // Create channel, so data can be streamed without being fully loaded
     // into memory. Requires a separate future to be spawned.
     let (sender, body) = Body::channel();
-    tokio::spawn(send_git(sender, git_output));
+    tokio::spawn(async move {
+        let mut sender = sender;
+        if let Err(err) = send_git(&mut sender, git_output, buffer_size, timeout).await {
+            warn!("Failed to send the output of git http-backend: {:#}", err);
+            // Let the client know that the response is truncated.
+            sender.abort();
+            // It may be stuck, or the client gone, it has nothing left to do.
+            let _ = p.start_kill();
+        }
+        // Reap the process.
+        let _ = p.wait().await;
+    });
 
     let resp = resp.body(body)?;
     Ok(resp)
 }
 
 /// Send data from git CGI process to hyper Sender, until there is no more
-/// data left.
+/// data left. The data is read through a buffer of `buffer_size` bytes, so
+/// the memory used per connection doesn't depend on how fast git is.
 async fn send_git(
-    mut sender: Sender,
+    sender: &mut Sender,
     mut git_output: BufReader<ChildStdout>,
+    buffer_size: usize,
+    timeout: Duration,
 ) -> Result<(), anyhow::Error> {
+    let mut buffer = vec![0; buffer_size];
     loop {
-        let mut bytes_out = BytesMut::new();
-        git_output.read_buf(&mut bytes_out).await?;
-        if bytes_out.is_empty() {
+        let read = tokio::time::timeout(timeout, git_output.read(&mut buffer))
+            .await
+            .context("git http-backend stopped sending its output")??;
+        if read == 0 {
             return Ok(());
         }
-        sender.send_data(bytes_out.freeze()).await?;
+        sender
+            .send_data(Bytes::copy_from_slice(&buffer[..read]))
+            .await?;
     }
 }
 
@@ -463,7 +1480,6 @@ mod tests {
     use super::*;
 
     use std::io::Write as _;
-    use std::str::FromStr;
 
     use git2::RepositoryState;
     use git2::StatusOptions;
@@ -471,11 +1487,151 @@ mod tests {
 
     use tempfile::tempdir;
 
+    #[test]
+    fn git_versions() {
+        for (output, version) in [
+            ("git version 2.39.2\n", Some((2, 39, 2))),
+            ("git version 2.39.2.windows.1\n", Some((2, 39, 2))),
+            ("git version 2.37.1 (Apple Git-137.1)\n", Some((2, 37, 1))),
+            ("git version 2.40.0-rc1\n", Some((2, 40, 0))),
+            ("git version 2.18\n", Some((2, 18, 0))),
+            ("git version 1.8.3.1\n", Some((1, 8, 3))),
+            ("hub version 2.14.2\n", None),
+            ("git version two\n", None),
+        ] {
+            assert_eq!(parse_git_version(output), version, "{output}");
+        }
+
+        assert_eq!(git_version_warning_of("git version 2.18.0"), None);
+        assert_eq!(git_version_warning_of("git version 3.0.0"), None);
+        let warning = git_version_warning_of("git version 2.17.1").unwrap();
+        assert!(
+            warning.starts_with("git 2.17.1 is too old to serve the index, git 2.18.0 or newer"),
+            "{warning}"
+        );
+        assert!(git_version_warning_of("git version 1.8.3.1").is_some());
+        assert!(git_version_warning_of("").is_some());
+    }
+
+    /// Check that the compacted index has a single commit with the same
+    /// content, and can still be cloned.
+    #[tokio::test]
+    async fn compact_index_history() {
+        let root = tempdir().unwrap();
+        let index = Index::new(root.path(), "http://127.0.0.1:1").await.unwrap();
+        for name in ["aa", "bb", "cc"] {
+            let path = root.path().join("2").join(name);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, format!("{name}\n")).unwrap();
+            index
+                .add_and_commit([&path], &format!("Add {name}"))
+                .await
+                .unwrap();
+        }
+        let tree = |index: &Index| {
+            let repository = index.repository.try_lock().unwrap();
+            let tree = repository.head().unwrap().peel_to_tree().unwrap().id();
+            tree
+        };
+        let tree_before = tree(&index);
+
+        let report = index.compact().await.unwrap();
+        assert_eq!(report.commits, 5);
+        assert_eq!(tree(&index), tree_before);
+        {
+            let repository = index.repository.lock().await;
+            let mut revwalk = repository.revwalk().unwrap();
+            revwalk.push_head().unwrap();
+            assert_eq!(revwalk.count(), 1);
+        }
+        // The index keeps working after the compaction.
+        let path = root.path().join("2").join("dd");
+        std::fs::write(&path, "dd\n").unwrap();
+        index.add_and_commit([&path], "Add dd").await.unwrap();
+
+        let clone = tempdir().unwrap();
+        let status = Command::new("git")
+            .args(["clone", "--quiet"])
+            .arg(root.path())
+            .arg(clone.path().join("index"))
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        for file in ["config.json", "2/aa", "2/bb", "2/cc", "2/dd"] {
+            assert_eq!(
+                std::fs::read(clone.path().join("index").join(file)).unwrap(),
+                std::fs::read(root.path().join(file)).unwrap(),
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn compact_refused_while_served() {
+        let root = tempdir().unwrap();
+        let served = Index::new(root.path(), "http://127.0.0.1:1").await.unwrap();
+        served.lock_for_serving().unwrap();
+        // Another server can share the index.
+        Index::open(root.path())
+            .unwrap()
+            .lock_for_serving()
+            .unwrap();
+
+        let index = Index::open(root.path()).unwrap();
+        let err = index.compact().await.unwrap_err();
+        assert!(err.to_string().contains("is being served"), "{err}");
+        drop(served);
+        index.compact().await.unwrap();
+    }
+
+    /// Check that a stuck git backend is killed after the timeout, whether
+    /// it sent a response or not.
+    #[tokio::test]
+    async fn git_backend_timeout() {
+        let timeout = Duration::from_millis(200);
+        let no_body = || futures::stream::empty::<Result<Bytes, warp::Error>>();
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 30"]);
+        let start = std::time::Instant::now();
+        let err = run_git_backend(cmd, no_body(), 1024, timeout)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "git http-backend sent no response");
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let root = tempdir().unwrap();
+        let pid_file = root.path().join("pid");
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(r#"echo $$ > "$0"; printf 'Content-Type: text/plain\r\n\r\npartial'; exec sleep 30"#)
+            .arg(&pid_file);
+        let response = run_git_backend(cmd, no_body(), 1024, timeout)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "text/plain");
+        assert!(warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .is_err());
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let process = Path::new("/proc").join(pid.trim());
+        for _ in 0..100 {
+            if !process.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!process.exists());
+    }
+
     #[tokio::test]
     async fn empty_index_repository() {
         let root = tempdir().unwrap();
-        let addr = SocketAddr::from_str("192.168.0.1:9999").unwrap();
-        let index = Index::new(root.as_ref(), &addr).await.unwrap();
+        let index = Index::new(root.as_ref(), "http://192.168.0.1:9999")
+            .await
+            .unwrap();
         let repository = index.repository.lock().await;
         assert_eq!(repository.state(), RepositoryState::Clean);
         assert!(repository.head().is_ok());
@@ -498,8 +1654,9 @@ mod tests {
         // We always assume some valid JSON in the config.
         file.write_all(br#"{"dl":"foobar"}"#).unwrap();
 
-        let addr = SocketAddr::from_str("254.0.0.0:1").unwrap();
-        let index = Index::new(root.as_ref(), &addr).await.unwrap();
+        let index = Index::new(root.as_ref(), "http://254.0.0.0:1")
+            .await
+            .unwrap();
         let repository = index.repository.lock().await;
 
         assert_eq!(repository.state(), RepositoryState::Clean);
@@ -521,15 +1678,331 @@ mod tests {
     #[tokio::test]
     async fn recreate_index() {
         let root = tempdir().unwrap();
-        let addr = "127.0.0.1:0".parse().unwrap();
+        let server_url = "http://127.0.0.1:0";
 
         {
-            let _index = Index::new(root.path(), &addr).await.unwrap();
+            let _index = Index::new(root.path(), server_url).await.unwrap();
         }
 
         {
-            let _index = Index::new(root.path(), &addr).await.unwrap();
+            let _index = Index::new(root.path(), server_url).await.unwrap();
+        }
+    }
+
+    /// Check that uncommitted changes of config.json, staged or not, don't
+    /// prevent restarting, at the same address or at another one.
+    #[tokio::test]
+    async fn dirty_config_on_restart() {
+        let root = tempdir().unwrap();
+        let path = root.path().join("config.json");
+        let index = Index::new(root.path(), "http://127.0.0.1:1").await.unwrap();
+        let committed = std::fs::read_to_string(&path).unwrap();
+        let head = index.status().await.unwrap().head;
+        drop(index);
+
+        std::fs::write(&path, "{").unwrap();
+        let index = Index::new(root.path(), "http://127.0.0.1:1").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), committed);
+        assert_eq!(index.status().await.unwrap().head, head);
+        drop(index);
+
+        std::fs::write(&path, r#"{"dl":"http://elsewhere"}"#).unwrap();
+        {
+            let repository = Repository::open(root.path()).unwrap();
+            let mut git_index = repository.index().unwrap();
+            git_index.add_path(Path::new("config.json")).unwrap();
+            git_index.write().unwrap();
+        }
+        std::fs::write(&path, "{").unwrap();
+        let index = Index::new(root.path(), "http://127.0.0.1:2").await.unwrap();
+        assert_ne!(index.status().await.unwrap().head, head);
+        let content = index
+            .committed_file(Path::new("config.json"))
+            .await
+            .unwrap()
+            .unwrap();
+        let config = serde_json::from_slice::<Config>(&content).unwrap();
+        assert_eq!(
+            config.dl,
+            "http://127.0.0.1:2/api/v1/crates/{crate}/{version}/download"
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+
+        let repository = index.repository.lock().await;
+        let mut options = StatusOptions::new();
+        options
+            .show(StatusShow::IndexAndWorkdir)
+            .include_untracked(true)
+            .include_unmodified(false);
+        assert_eq!(repository.statuses(Some(&mut options)).unwrap().len(), 0);
+    }
+
+    /// Check that restarting with a config.json formatted differently, but
+    /// with the same content, doesn't commit it again.
+    #[tokio::test]
+    async fn no_config_commit_on_restart() {
+        let root = tempdir().unwrap();
+        let server_url = "http://127.0.0.1:0";
+        let path = root.path().join("config.json");
+
+        let index = Index::new_with_config_format(root.path(), server_url, true, true)
+            .await
+            .unwrap();
+        let compact = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(compact.lines().count(), 1);
+        let head = index.status().await.unwrap().head;
+        drop(index);
+
+        // Restarting with pretty printing keeps the compact file.
+        let index = Index::new(root.path(), server_url).await.unwrap();
+        assert_eq!(index.status().await.unwrap().head, head);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), compact);
+
+        // As does a committed config.json with other whitespace.
+        let config = format!("  {}\r\n\r\n", compact.trim().replace(",\"", ",\r\n\t\""));
+        std::fs::write(&path, &config).unwrap();
+        index
+            .add_and_commit(vec!["config.json"], "Reformat config.json")
+            .await
+            .unwrap();
+        let head = index.status().await.unwrap().head;
+        drop(index);
+        let index = Index::new_with_config_format(root.path(), server_url, true, true)
+            .await
+            .unwrap();
+        assert_eq!(index.status().await.unwrap().head, head);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), config);
+        drop(index);
+
+        // A change of URL is committed, in the requested format.
+        let index = Index::new(root.path(), "http://127.0.0.1:1").await.unwrap();
+        let status = index.status().await.unwrap();
+        assert_ne!(status.head, head);
+        assert_eq!(status.message, "Update config.json");
+        assert!(std::fs::read_to_string(&path).unwrap().lines().count() > 1);
+    }
+
+    /// Check that an index on a read-only directory is opened as it is, and
+    /// that an empty one is refused.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn read_only_index() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let root = tempdir().unwrap();
+        let path = root.path().join("config.json");
+        let index = Index::new(root.path(), "http://127.0.0.1:1").await.unwrap();
+        assert!(!index.read_only());
+        let head = index.status().await.unwrap().head;
+        let config = std::fs::read_to_string(&path).unwrap();
+        drop(index);
+
+        let set_mode = |path: &Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        set_mode(root.path(), 0o555);
+        let index = Index::new(root.path(), "http://127.0.0.1:2").await;
+        set_mode(root.path(), 0o755);
+        let index = index.unwrap();
+        assert!(index.read_only());
+        assert_eq!(index.status().await.unwrap().head, head);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), config);
+
+        let empty = tempdir().unwrap();
+        set_mode(empty.path(), 0o555);
+        let index = Index::new(empty.path(), "http://127.0.0.1:1").await;
+        set_mode(empty.path(), 0o755);
+        assert!(index.is_err());
+        assert!(!empty.path().join(".git").exists());
+    }
+
+    /// Check that the status reports malformed lines of crate files.
+    #[tokio::test]
+    async fn status_reports_invalid_lines() {
+        let root = tempdir().unwrap();
+        let index = Index::new(root.path(), "http://127.0.0.1:0").await.unwrap();
+
+        let valid = r#"{"name":"my-lib","vers":"0.1.0","deps":[],"cksum":"00","features":{},"yanked":false,"links":null}"#;
+        let crate_dir = root.path().join("my").join("-l");
+        create_dir_all(&crate_dir).unwrap();
+        std::fs::write(
+            crate_dir.join("my-lib"),
+            format!("{valid}\n{{\"name\":\"my-lib\""),
+        )
+        .unwrap();
+        index
+            .add_and_commit(vec![crate_dir.join("my-lib")], "Add my-lib")
+            .await
+            .unwrap();
+        drop(index);
+
+        let index = Index::open(root.path()).unwrap();
+        let status = index.status().await.unwrap();
+        assert_eq!(status.message, "Add my-lib");
+        assert_eq!(status.head.len(), 40);
+        assert_eq!(status.crate_files, 1);
+        assert_eq!(status.invalid_lines.len(), 1);
+        assert_eq!(status.invalid_lines[0].path, Path::new("my/-l/my-lib"));
+        assert_eq!(status.invalid_lines[0].line, 2);
+    }
+
+    /// Check that a crate file with two different entries for a version is
+    /// rejected on load and reported by the status.
+    #[tokio::test]
+    async fn conflicting_duplicate_versions() {
+        let entry = |vers: &str, cksum: &str| {
+            format!(
+                r#"{{"name":"my-lib","vers":"{vers}","deps":[],"cksum":"{cksum}","features":{{}},"yanked":false,"links":null}}"#
+            )
+        };
+        let identical = [entry("0.1.0", "00"), entry("0.1.0", "00")].join("\n");
+        assert_eq!(Entries::try_from(identical).unwrap().len(), 1);
+        let conflicting = [
+            entry("0.1.0", "00"),
+            entry("0.2.0", "00"),
+            entry("0.1.0", "11"),
+        ]
+        .join("\n");
+        assert!(matches!(
+            Entries::try_from(conflicting.clone()),
+            Err(EntriesError::DuplicateVersion { name, vers }) if name == "my-lib" && vers == "0.1.0"
+        ));
+
+        let source = tempdir().unwrap();
+        let source_dir = source.path().join("my").join("-l");
+        create_dir_all(&source_dir).unwrap();
+        std::fs::write(source_dir.join("my-lib"), &conflicting).unwrap();
+        let root = tempdir().unwrap();
+        let err = bootstrap_index(root.path(), source.path().to_str().unwrap())
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("conflicting entries for my-lib in version 0.1.0"));
+
+        let index = Index::open(root.path().join("index")).unwrap();
+        let crate_dir = root.path().join("index").join("my").join("-l");
+        create_dir_all(&crate_dir).unwrap();
+        std::fs::write(crate_dir.join("my-lib"), &conflicting).unwrap();
+        index
+            .add_and_commit(vec![crate_dir.join("my-lib")], "Add my-lib")
+            .await
+            .unwrap();
+        let status = index.status().await.unwrap();
+        assert_eq!(status.invalid_lines.len(), 1);
+        assert_eq!(status.invalid_lines[0].line, 3);
+        assert_eq!(
+            status.invalid_lines[0].error,
+            "conflicting entries for my-lib in version 0.1.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn list_crates_as_csv() {
+        let root = tempdir().unwrap();
+        let index = Index::new(root.path().join("index"), "http://127.0.0.1:0")
+            .await
+            .unwrap();
+        let crate_dir = root.path().join("index").join("my").join("-l");
+        create_dir_all(&crate_dir).unwrap();
+        std::fs::write(
+            crate_dir.join("my-lib"),
+            concat!(
+                r#"{"name":"my-lib","vers":"0.1.0","deps":[{"name":"syn","req":"^1","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal"}],"cksum":"11","features":{},"yanked":true,"links":null}"#,
+                "\n",
+                r#"{"name":"my-lib","vers":"0.10.0","deps":[],"cksum":"13","features":{},"yanked":false,"links":null}"#,
+                "\n",
+                r#"{"name":"my-lib","vers":"0.9.0","deps":[],"cksum":"12","features":{},"yanked":false,"links":null}"#,
+            ),
+        )
+        .unwrap();
+        index
+            .add_and_commit(vec![crate_dir.join("my-lib")], "Add my-lib")
+            .await
+            .unwrap();
+
+        let crates = list_crates(root.path()).unwrap();
+        assert_eq!(
+            format_crates(&crates, ListFormat::Csv).unwrap(),
+            "name,version,yanked,cksum,dep_count\nmy-lib,0.1.0,true,11,1\n\
+             my-lib,0.9.0,false,12,0\nmy-lib,0.10.0,false,13,0\n"
+        );
+        assert_eq!(
+            format_crates(&crates, ListFormat::Text).unwrap(),
+            "my-lib 0.1.0 (yanked)\nmy-lib 0.9.0\nmy-lib 0.10.0\n"
+        );
+    }
+
+    /// Check that the crates of a fixture index are imported without
+    /// overriding the versions that are already in the index.
+    #[tokio::test]
+    async fn bootstrap_from_fixture_index() {
+        let entry = |name: &str, vers: &str, cksum: &str| {
+            format!(
+                r#"{{"name":"{name}","vers":"{vers}","deps":[],"cksum":"{cksum}","features":{{}},"yanked":false,"links":null}}"#
+            )
+        };
+        let source = tempdir().unwrap();
+        for (path, content) in [
+            (
+                "my/-l/my-lib",
+                [
+                    entry("my-lib", "0.1.0", "11"),
+                    entry("my-lib", "0.2.0", "22").replace(
+                        r#""links":null"#,
+                        r#""links":null,"rust_version":"1.60","v":1,"unknown":[]"#,
+                    ),
+                ]
+                .join("\n"),
+            ),
+            ("3/s/syn", entry("syn", "1.0.0", "33")),
+            (
+                "config.json",
+                r#"{"dl":"https://crates.io/api/v1/crates"}"#.to_string(),
+            ),
+            (".github/workflows/ci.yml", "on: push".to_string()),
+        ] {
+            let path = source.path().join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
         }
+
+        let root = tempdir().unwrap();
+        let index_root = root.path().join("index");
+        let index = Index::new(&index_root, "http://127.0.0.1:0").await.unwrap();
+        let crate_dir = index_root.join("my").join("-l");
+        create_dir_all(&crate_dir).unwrap();
+        std::fs::write(crate_dir.join("my-lib"), entry("my-lib", "0.1.0", "00")).unwrap();
+        index
+            .add_and_commit(vec![crate_dir.join("my-lib")], "Add my-lib")
+            .await
+            .unwrap();
+        drop(index);
+
+        let imported = bootstrap_index(root.path(), source.path().to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(imported, 2);
+
+        let index = Index::open(&index_root).unwrap();
+        let status = index.status().await.unwrap();
+        assert_eq!(status.crate_files, 2);
+        assert!(status.invalid_lines.is_empty());
+        assert_eq!(index.entry("my-lib", "0.1.0").unwrap().unwrap().cksum, "00");
+        assert_eq!(index.entry("my-lib", "0.2.0").unwrap().unwrap().cksum, "22");
+        assert_eq!(index.entry("syn", "1.0.0").unwrap().unwrap().cksum, "33");
+        // The fields unknown to the registry are kept.
+        let content = std::fs::read_to_string(crate_dir.join("my-lib")).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.ends_with("\"rust_version\":\"1.60\",\"v\":1,\"unknown\":[]}\n"));
+        assert!(!index_root.join(".github").exists());
+
+        let config = std::fs::read_to_string(index_root.join("config.json")).unwrap();
+        assert!(config.contains("127.0.0.1:0"));
+
+        // Importing again changes nothing.
+        let imported = bootstrap_index(root.path(), source.path().to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(imported, 0);
     }
 
     /// Check that the Git repository contained in our index has no
@@ -537,8 +2010,7 @@ mod tests {
     #[tokio::test]
     async fn no_untracked_files() {
         let root = tempdir().unwrap();
-        let addr = "127.0.0.1:0".parse().unwrap();
-        let index = Index::new(root.path(), &addr).await.unwrap();
+        let index = Index::new(root.path(), "http://127.0.0.1:0").await.unwrap();
         let repository = index.repository.lock().await;
 
         // The repository should be clean.
@@ -1,16 +1,57 @@
+mod acl;
+mod banner;
 mod cli;
+mod docs;
+mod doctor;
 mod download;
+mod downloads;
 mod index;
 mod pack;
+mod proxy;
 mod publish;
+mod repair;
 mod rustup;
+#[cfg(feature = "s3")]
+mod s3;
 mod serve;
 mod serve_frontend;
+mod staging;
+mod storage;
+mod takedown;
+mod terms;
+mod vendor;
+mod watch;
 
+pub use cli::error_json;
 pub use cli::Cli;
 pub use cli::Commands;
+pub use cli::ExtractOptions;
+pub use cli::ListFormat;
+pub use cli::ServeArgs;
+pub use doctor::doctor;
+pub use index::bootstrap_index;
+pub use index::compact_index;
+pub use index::format_crates;
+pub use index::index_status;
+pub use index::list_crates;
+pub use pack::diff_packs;
 pub use pack::pack;
 pub use pack::unpack;
+pub use pack::unpack_atomic;
+pub use pack::PackChange;
+pub use repair::repair;
+pub use repair::RepairReport;
 pub use rustup::download_platform_list;
+pub use rustup::list_channels;
+pub use rustup::verify_mirror;
+pub use rustup::MirrorGap;
+pub use rustup::MirroredChannel;
+pub use rustup::MirroredRelease;
 pub use serve::serve;
-pub use serve_frontend::serve_frontend;
\ No newline at end of file
+pub use serve::spawn_serve;
+pub use serve::ServeHandle;
+pub use serve_frontend::serve_frontend;
+pub use storage::LocalStorage;
+pub use storage::Storage;
+pub use takedown::takedown;
+pub use vendor::vendor;
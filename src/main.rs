@@ -1,13 +1,17 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 use clap::Parser;
-use crates_registry::{download_platform_list, pack, serve, unpack, Cli, Commands};
+use crates_registry::{
+    bootstrap_index, compact_index, diff_packs, doctor, download_platform_list, error_json,
+    format_crates, index_status, list_channels, list_crates, pack, repair, serve, takedown, unpack,
+    unpack_atomic, vendor, verify_mirror, Cli, Commands, PackChange,
+};
 
 use itertools::Itertools;
 use tracing::subscriber::set_global_default as set_global_subscriber;
-use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::time::SystemTime;
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::Layer as _;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
@@ -17,38 +21,163 @@ async fn main() -> Result<()> {
 
     env_logger::init();
     let cli = Cli::parse();
-    let level = match cli.verbosity {
-        0 => LevelFilter::WARN,
-        1 => LevelFilter::INFO,
-        2 => LevelFilter::DEBUG,
-        _ => LevelFilter::TRACE,
-    };
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(level)
-        .with_timer(SystemTime)
-        .finish();
+    let subscriber = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_timer(SystemTime)
+            .with_filter(cli.log_filter()),
+    );
 
     set_global_subscriber(subscriber).context("failed to set tracing subscriber")?;
-    match cli.command {
+    let json_errors = cli.json_errors;
+    match run(cli.command).await {
+        Err(err) if json_errors => {
+            eprintln!("{}", error_json(&err));
+            std::process::exit(1)
+        }
+        result => result,
+    }
+}
+
+/// Run a command of the command line.
+async fn run(command: Commands) -> Result<()> {
+    match command {
         Commands::Serve(serve_args) => {
-            serve(
-                &serve_args.root_registry,
-                serve_args.binding_addr,
-                serve_args.server_addr,
-            )
-            .await?
+            let binding_addr = serve_args.binding_addr;
+            serve(serve_args, binding_addr).await?
         }
         Commands::Pack(pack_args) => pack(pack_args).await?,
-        Commands::PlatformsList => {
+        Commands::PlatformsList(platforms_list_args) => {
             let platforms =
                 download_platform_list("https://static.rust-lang.org", "nightly").await?;
-            println!(
-                "available platforms:\n - {}",
-                platforms.into_iter().join("\n - ")
+            if platforms_list_args.json {
+                println!("{}", serde_json::to_string(&platforms)?)
+            } else {
+                println!(
+                    "available platforms:\n - {}",
+                    platforms.into_iter().join("\n - ")
+                )
+            }
+        }
+        Commands::Unpack(unpack_args) if unpack_args.atomic => {
+            unpack_atomic(
+                &unpack_args.packed_file,
+                &unpack_args.root_registry,
+                &unpack_args.extract_options,
             )
+            .await?
         }
         Commands::Unpack(unpack_args) => {
-            unpack(&unpack_args.packed_file, &unpack_args.root_registry).await?
+            unpack(
+                &unpack_args.packed_file,
+                &unpack_args.root_registry,
+                &unpack_args.extract_options,
+            )
+            .await?
+        }
+        Commands::BootstrapIndex(bootstrap_args) => {
+            let imported =
+                bootstrap_index(&bootstrap_args.root_registry, &bootstrap_args.source).await?;
+            println!("imported {} crate files", imported);
+        }
+        Commands::Vendor(vendor_args) => vendor(vendor_args).await?,
+        Commands::List(list_args) => {
+            let crates = list_crates(&list_args.root_registry)?;
+            print!("{}", format_crates(&crates, list_args.format)?);
+        }
+        Commands::IndexStatus(index_status_args) => {
+            let status = index_status(&index_status_args.root_registry).await?;
+            println!("HEAD: {} {}", status.head, status.message);
+            println!("crate files: {}", status.crate_files);
+            for invalid in &status.invalid_lines {
+                println!(
+                    "invalid entry at {}:{}: {}",
+                    invalid.path.display(),
+                    invalid.line,
+                    invalid.error
+                );
+            }
+            if !status.invalid_lines.is_empty() {
+                bail!(
+                    "the index contains {} invalid entries",
+                    status.invalid_lines.len()
+                );
+            }
+        }
+        Commands::CompactIndex(compact_args) => {
+            let report = compact_index(&compact_args.root_registry).await?;
+            println!(
+                "squashed {} commits, the index shrank from {} to {} bytes",
+                report.commits, report.size_before, report.size_after
+            );
+        }
+        Commands::ListChannels(list_args) => {
+            let channels = list_channels(
+                &list_args.root_registry,
+                list_args.channel_history_dir.as_deref(),
+            )?;
+            if list_args.json {
+                println!("{}", serde_json::to_string(&channels)?);
+            } else {
+                for channel in &channels {
+                    println!("{}", channel.channel);
+                    for release in &channel.releases {
+                        println!("  {}: {} files", release.date, release.files);
+                    }
+                }
+            }
+        }
+        Commands::VerifyMirror(verify_args) => {
+            let gaps = verify_mirror(&verify_args).await?;
+            for gap in &gaps {
+                println!("{gap}");
+            }
+            if !gaps.is_empty() {
+                bail!("the mirror is incomplete: {} missing items", gaps.len());
+            }
+            println!("the mirror is complete");
+        }
+        Commands::Takedown(takedown_args) => takedown(&takedown_args).await?,
+        Commands::DiffPacks(diff_args) => {
+            let changes = diff_packs(&diff_args.old, &diff_args.new)?;
+            let mut transfer = 0;
+            for change in &changes {
+                println!("{change}");
+                match change {
+                    PackChange::Added { size, .. } | PackChange::Changed { new_size: size, .. } => {
+                        transfer += size
+                    }
+                    PackChange::Removed { .. } => (),
+                }
+            }
+            println!(
+                "{} changed files, {transfer} bytes added or changed",
+                changes.len()
+            );
+        }
+        Commands::Doctor(doctor_args) => {
+            for check in doctor(&doctor_args).await? {
+                println!("ok: {check}");
+            }
+        }
+        Commands::Repair(repair_args) => {
+            let report = repair(&repair_args).await?;
+            for (name, vers) in &report.reindexed {
+                println!("reindexed {name} {vers}");
+            }
+            for path in &report.deleted_crates {
+                println!("deleted crate file without index entry: {}", path.display());
+            }
+            for path in &report.orphaned_crates {
+                println!("crate file without index entry: {}", path.display());
+            }
+            for (name, vers) in &report.dangling_entries {
+                println!("index entry without crate file: {name} {vers}");
+            }
+            let remaining = report.orphaned_crates.len() + report.dangling_entries.len();
+            if remaining > 0 {
+                bail!("the registry has {remaining} inconsistencies left");
+            }
+            println!("the crate files and the index are consistent");
         }
     };
     Ok(())
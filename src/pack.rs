@@ -1,39 +1,238 @@
-use std::{fs::File, path::Path};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{BufRead, BufReader, Read, Write as _};
+use std::path::{Component, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use std::{fs::File, io, path::Path};
 
-use anyhow::Result;
-use tar::Archive;
+use anyhow::{anyhow, bail, Context as _, Result};
+use filetime::FileTime;
+use sha2::{Digest as _, Sha256};
+use tar::{Archive, EntryType};
 use tempfile::TempDir;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::{
-    cli::PackArgs,
-    rustup::{download_latest, download_pinned_rust_version},
+    cli::{ExtractOptions, PackArgs},
+    download::HashAlgorithm,
+    rustup::{
+        channel_file, check_mirror, download_latest, download_pinned_rust_version,
+        normalize_source, packed_channels, resolve_rust_versions, verify_source, Channel,
+    },
 };
 
-pub async fn pack(pack_args: PackArgs) -> Result<()> {
-    let root_registry = TempDir::new()?;
-    debug!("Root registry: {}", root_registry.path().display());
-    if !pack_args.rust_versions.is_empty() {
-        download_pinned_rust_version(root_registry.path(), &pack_args).await?;
+pub async fn pack(mut pack_args: PackArgs) -> Result<()> {
+    pack_args.source = normalize_source(&pack_args.source).to_owned();
+    pack_args.rust_versions = resolve_rust_versions(&pack_args).await?;
+    if pack_args.verify_only {
+        for summary in verify_source(&pack_args).await? {
+            println!(
+                "{} ({}): {} files, {} bytes",
+                summary.channel, summary.date, summary.files, summary.size
+            );
+        }
+        return Ok(());
+    }
+
+    let temp_dir;
+    let root_registry = match &pack_args.keep_workdir {
+        Some(workdir) => {
+            std::fs::create_dir_all(workdir)?;
+            workdir.as_path()
+        }
+        None => {
+            temp_dir = TempDir::new()?;
+            temp_dir.path()
+        }
+    };
+    debug!("Root registry: {}", root_registry.display());
+    let platforms = if !pack_args.rust_versions.is_empty() {
+        download_pinned_rust_version(root_registry, &pack_args).await?
     } else {
-        download_latest(root_registry.path(), &pack_args).await?;
+        download_latest(root_registry, &pack_args).await?
+    };
+
+    // Report what the mirror lacks before it's transferred. A targets-only
+    // pack completes an existing mirror, it lacks everything else.
+    if !pack_args.targets_only {
+        let gaps = check_mirror(
+            root_registry,
+            &platforms,
+            &packed_channels(&pack_args.rust_versions, &pack_args.channels),
+        )?;
+        for gap in &gaps {
+            warn!("The mirror is incomplete: {gap}");
+        }
     }
 
+    let pack_file = match (&pack_args.pack_file, &pack_args.output_template) {
+        (_, Some(template)) => {
+            let channels = packed_channels(&pack_args.rust_versions, &pack_args.channels)
+                .iter()
+                .map(|channel| template_values(root_registry, channel))
+                .collect::<Result<Vec<_>>>()?;
+            expand_output_template(template, &channels)?
+        }
+        (Some(pack_file), None) => pack_file.clone(),
+        (None, None) => bail!("either --pack-file or --output-template is required"),
+    };
     info!(
         "Collect file installations to the pack file: {}",
-        pack_args.pack_file.display()
+        pack_file.display()
     );
 
-    let tar_file = File::create(&pack_args.pack_file)?;
+    if let Some(algorithm) = pack_args.checksum_algorithm {
+        write_checksums(root_registry, algorithm)?;
+    }
+
+    let tar_file = File::create(&pack_file)?;
     // let enc = GzEncoder::new(tar_gz, Compression::none());
     let mut tar = tar::Builder::new(tar_file);
-    tar.append_dir_all(".", root_registry.path())?;
+    tar.append_dir_all(".", root_registry)?;
+    tar.into_inner()?;
 
+    if let Some(algorithm) = pack_args.checksum_algorithm {
+        let hash = file_hash(&pack_file, algorithm)?;
+        println!("{}  {}", hash, pack_file.display());
+    }
     info!("The packing finished");
     Ok(())
 }
 
-pub async fn unpack(packed_file: &Path, root_registry: &Path) -> Result<()> {
+/// The name of the file listing the hashes of the packed files.
+fn checksums_file_name(algorithm: HashAlgorithm) -> String {
+    format!("CHECKSUMS.{}", algorithm)
+}
+
+/// The hash of the file at `path`, in lowercase hexadecimal.
+fn file_hash(path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = algorithm.hasher();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(hasher.finalize_hex())
+}
+
+/// Add the paths of the regular files under `dir` to `files`, relative to
+/// `root`.
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(root, &entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path().strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Write the `CHECKSUMS.<algorithm>` file at the root of the registry to
+/// pack, listing the hash of each of its files in the format of `sha256sum`,
+/// to be checked from the root of the unpacked registry.
+fn write_checksums(root_registry: &Path, algorithm: HashAlgorithm) -> Result<()> {
+    let file_name = checksums_file_name(algorithm);
+    let mut files = Vec::new();
+    collect_files(root_registry, root_registry, &mut files)?;
+    // The file of a previous pack of the --keep-workdir is replaced.
+    files.retain(|file| file != Path::new(&file_name));
+    files.sort();
+    let mut checksums = String::new();
+    for file in &files {
+        let hash = file_hash(&root_registry.join(file), algorithm)?;
+        let path = file
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        checksums.push_str(&format!("{}  {}\n", hash, path));
+    }
+    let path = root_registry.join(&file_name);
+    std::fs::write(&path, checksums)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    info!(
+        "Listed the hashes of {} files in {}",
+        files.len(),
+        file_name
+    );
+    Ok(())
+}
+
+/// The values of the `--output-template` placeholders for a packed channel.
+#[derive(Debug)]
+struct TemplateValues {
+    channel: String,
+    /// The rust version, e.g. "1.67.1" or "1.70.0-nightly".
+    version: String,
+    date: String,
+}
+
+/// Read the values of the placeholders for `channel` from its channel file
+/// in the downloaded registry.
+fn template_values(root_registry: &Path, channel: &str) -> Result<TemplateValues> {
+    let channel_path = root_registry.join(channel_file(channel));
+    let content = std::fs::read_to_string(&channel_path)
+        .with_context(|| format!("failed to read {}", channel_path.display()))?;
+    let channel_data: Channel = toml::from_str(&content)
+        .with_context(|| format!("invalid channel file {}", channel_path.display()))?;
+    // The version of the rust package reads "1.67.1 (d5a82bbd2 2023-02-07)".
+    let version = channel_data
+        .pkg
+        .get("rust")
+        .and_then(|rust| rust.version.split_whitespace().next())
+        .ok_or_else(|| anyhow!("{} has no rust version", channel_path.display()))?;
+    Ok(TemplateValues {
+        channel: channel.to_string(),
+        version: version.to_string(),
+        date: channel_data.date,
+    })
+}
+
+/// Expand the placeholders of an `--output-template`, validated by the
+/// command line, with the values of the packed channels joined with `+`.
+fn expand_output_template(template: &str, channels: &[TemplateValues]) -> Result<PathBuf> {
+    let join = |value: fn(&TemplateValues) -> &str| {
+        channels.iter().map(value).collect::<Vec<_>>().join("+")
+    };
+    let mut pack_file = template.to_string();
+    for (placeholder, value) in [
+        ("{channel}", join(|values| &values.channel)),
+        ("{version}", join(|values| &values.version)),
+        ("{date}", join(|values| &values.date)),
+    ] {
+        // The values come from the source, they must not leave the directory
+        // of the template.
+        if value.is_empty() || value.contains(&['/', '\\'][..]) || value.contains("..") {
+            bail!("invalid value {value:?} for {placeholder} in the pack file name");
+        }
+        pack_file = pack_file.replace(placeholder, &value);
+    }
+    Ok(PathBuf::from(pack_file))
+}
+
+pub async fn unpack(
+    packed_file: &Path,
+    root_registry: &Path,
+    extract_options: &ExtractOptions,
+) -> Result<()> {
+    unpack_with_progress(packed_file, root_registry, extract_options, &|| {}).await
+}
+
+/// Unpack like `unpack`, calling `on_file` after each file is extracted.
+pub(crate) async fn unpack_with_progress(
+    packed_file: &Path,
+    root_registry: &Path,
+    extract_options: &ExtractOptions,
+    on_file: &(dyn Fn() + Sync),
+) -> Result<()> {
     info!(
         "Unpacking file installations...\n
         Packed file: {}\n
@@ -42,11 +241,1359 @@ pub async fn unpack(packed_file: &Path, root_registry: &Path) -> Result<()> {
         root_registry.display()
     );
 
-    let tar_file = File::open(packed_file)?;
-    // let enc = GzEncoder::new(tar_gz, Compression::none());
+    let tar_file = open_pack(packed_file)?;
     let mut archive = Archive::new(tar_file);
     // TODO: handle history channel files if needed
-    archive.unpack(root_registry)?;
+    std::fs::create_dir_all(root_registry)?;
+    if extract_options.extract_threads > 1 {
+        let open_files = extract_options
+            .max_parallel_extract_open_files
+            .map_or(extract_options.extract_threads, |limit| limit as usize);
+        unpack_parallel(
+            &mut archive,
+            root_registry,
+            extract_options.extract_threads,
+            open_files,
+            extract_options.normalize_permissions,
+            on_file,
+        )?;
+    } else {
+        unpack_serial(
+            &mut archive,
+            root_registry,
+            extract_options.normalize_permissions,
+            on_file,
+        )?;
+    }
     info!("The unpacking finished");
     Ok(())
 }
+
+/// Unpack `packed_file` into a copy of the registry, and then switch
+/// `root_registry`, a symbolic link to the registry directory, to the copy
+/// in one step, so that a running server never sees a partially unpacked
+/// registry. The copy is made of hard links, it only takes the space of the
+/// unpacked files. The previous directory is kept for the servers still
+/// using it; they reopen the index of the new one on `SIGHUP`.
+#[cfg(unix)]
+pub async fn unpack_atomic(
+    packed_file: &Path,
+    root_registry: &Path,
+    extract_options: &ExtractOptions,
+) -> Result<()> {
+    let parent = match root_registry.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = root_registry
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid registry path {}", root_registry.display()))?
+        .to_string_lossy();
+    let live = match std::fs::read_link(root_registry) {
+        Ok(target) => Some(parent.join(target)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(_) => bail!(
+            "{} must be a symbolic link to the registry directory to be replaced atomically",
+            root_registry.display()
+        ),
+    };
+
+    let generation = format!(
+        "{}.{}",
+        name,
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos()
+    );
+    let staged = parent.join(&generation);
+    if let Some(live) = &live {
+        hard_link_tree(live, &staged).with_context(|| {
+            format!("failed to copy {} to {}", live.display(), staged.display())
+        })?;
+    }
+    if let Err(err) = unpack(packed_file, &staged, extract_options).await {
+        let _ = std::fs::remove_dir_all(&staged);
+        return Err(err);
+    }
+
+    // Renaming a new link over the old one replaces it atomically.
+    let link = parent.join(format!("{}.swap", name));
+    match std::fs::remove_file(&link) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => (),
+    }
+    std::os::unix::fs::symlink(&generation, &link)?;
+    std::fs::rename(&link, root_registry)
+        .with_context(|| format!("failed to replace {}", root_registry.display()))?;
+    match live {
+        Some(live) => info!(
+            "{} now points to {}, {} can be removed once no server uses it",
+            root_registry.display(),
+            staged.display(),
+            live.display()
+        ),
+        None => info!(
+            "{} now points to {}",
+            root_registry.display(),
+            staged.display()
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn unpack_atomic(
+    _packed_file: &Path,
+    _root_registry: &Path,
+    _extract_options: &ExtractOptions,
+) -> Result<()> {
+    bail!("atomic unpacking is only supported on Unix")
+}
+
+/// Recreate the tree at `from` at `to`, hard linking the files.
+#[cfg(unix)]
+fn hard_link_tree(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::create_dir(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            hard_link_tree(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::hard_link(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// The magic number starting every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Open a pack file for reading the tar stream it contains, transparently
+/// decompressing zstd-compressed packs. The decompression is streamed so
+/// that packs larger than the memory can be unpacked.
+fn open_pack(packed_file: &Path) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(packed_file)?);
+    if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        debug!("Unpacking a zstd-compressed pack");
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// With `--normalize-permissions`, the mode of an extracted file given its
+/// mode in the archive: readable by everyone, and executable by everyone if
+/// executable at all.
+fn normalized_file_mode(mode: u32) -> u32 {
+    if mode & 0o111 != 0 {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+/// With `--normalize-permissions`, make `dir` and its parents below
+/// `root_registry` readable and traversable by everyone. The directories
+/// already in `normalized` are skipped, along with their parents.
+fn normalize_dir_permissions(
+    root_registry: &Path,
+    dir: &Path,
+    normalized: &mut HashSet<PathBuf>,
+) -> io::Result<()> {
+    for dir in dir
+        .ancestors()
+        .take_while(|dir| *dir != root_registry && dir.starts_with(root_registry))
+    {
+        if !normalized.insert(dir.to_path_buf()) {
+            break;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    Ok(())
+}
+
+/// Extract all the archive entries one after another.
+fn unpack_serial<R: Read>(
+    archive: &mut Archive<R>,
+    root_registry: &Path,
+    normalize_permissions: bool,
+    on_file: &(dyn Fn() + Sync),
+) -> Result<()> {
+    let mut written = 0u64;
+    let mut normalized = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let size = entry.header().size()?;
+        let destination = entry_destination(root_registry, &entry.path()?);
+        entry
+            .unpack_in(root_registry)
+            .and_then(|_| match destination {
+                Some(path) if normalize_permissions => {
+                    normalize_entry_permissions(root_registry, &entry, &path, &mut normalized)
+                }
+                _ => Ok(()),
+            })
+            .map_err(|err| unpack_error(err, root_registry, written))?;
+        written += size;
+        if matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        ) {
+            on_file();
+        }
+    }
+    Ok(())
+}
+
+/// With `--normalize-permissions`, fix the permissions of the entry
+/// extracted at `path` and of its directories.
+fn normalize_entry_permissions<R: Read>(
+    root_registry: &Path,
+    entry: &tar::Entry<'_, R>,
+    path: &Path,
+    normalized: &mut HashSet<PathBuf>,
+) -> io::Result<()> {
+    match entry.header().entry_type() {
+        EntryType::Directory => normalize_dir_permissions(root_registry, path, normalized),
+        entry_type => {
+            if matches!(entry_type, EntryType::Regular | EntryType::Continuous) {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt as _;
+                    let mode = normalized_file_mode(entry.header().mode()?);
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                }
+            }
+            match path.parent() {
+                Some(parent) => normalize_dir_permissions(root_registry, parent, normalized),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+/// The size above which the files are streamed to the disk by the reading
+/// thread, instead of being read in memory and handed to a worker.
+const STREAMED_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// A regular file read from the archive, waiting to be written by a worker.
+struct ExtractedFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    mode: u32,
+    /// The modification time, in seconds since the epoch.
+    mtime: u64,
+}
+
+/// A hard link read from the archive, created once all the files are
+/// written, so that its target exists.
+struct DeferredHardLink {
+    path: PathBuf,
+    target: PathBuf,
+}
+
+/// The state the reading thread shares with the workers of a parallel
+/// extraction.
+struct ExtractState<'a> {
+    /// The canonical registry root, every write must stay below it.
+    canonical_root: PathBuf,
+    open_files: OpenFileLimit,
+    /// The number of bytes written, for the error messages.
+    written: AtomicU64,
+    /// The first write that failed, stopping the extraction.
+    failure: Mutex<Option<io::Error>>,
+    on_file: &'a (dyn Fn() + Sync),
+}
+
+/// A counting semaphore bounding the number of files open at once.
+struct OpenFileLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// The right to have a file open, given back on drop.
+struct OpenFilePermit<'a>(&'a OpenFileLimit);
+
+impl OpenFileLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            available: Mutex::new(limit),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until a file can be opened.
+    fn acquire(&self) -> OpenFilePermit<'_> {
+        let mut available = self
+            .released
+            .wait_while(self.available.lock().unwrap(), |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+        OpenFilePermit(self)
+    }
+}
+
+impl Drop for OpenFilePermit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Extract the archive entries using a pool of `threads` workers, with at
+/// most `open_files` files open at once.
+///
+/// The archive itself can only be read sequentially, so the entries are
+/// read here and only the file writes are handed to the workers.
+/// Directories (including the parents of every file) are created here,
+/// before the file is dispatched, so the workers never race on directory
+/// creation. The hard links are created last, once the workers are done.
+fn unpack_parallel<R: Read>(
+    archive: &mut Archive<R>,
+    root_registry: &Path,
+    threads: usize,
+    open_files: usize,
+    normalize_permissions: bool,
+    on_file: &(dyn Fn() + Sync),
+) -> Result<()> {
+    let state = ExtractState {
+        canonical_root: std::fs::canonicalize(root_registry)?,
+        open_files: OpenFileLimit::new(open_files),
+        written: AtomicU64::new(0),
+        failure: Mutex::new(None),
+        on_file,
+    };
+    let (sender, receiver) = sync_channel::<ExtractedFile>(threads * 2);
+    let receiver = Mutex::new(receiver);
+
+    let mut hard_links = Vec::new();
+    let dispatched = thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let file = match receiver.lock().unwrap().recv() {
+                    Ok(file) => file,
+                    Err(_) => return,
+                };
+                // After a failure keep draining the channel so the reading
+                // side never blocks, but don't write anything else.
+                if state.failure.lock().unwrap().is_some() {
+                    continue;
+                }
+                let permit = state.open_files.acquire();
+                let result = write_extracted_file(&state.canonical_root, &file);
+                drop(permit);
+                match result {
+                    Ok(()) => {
+                        state
+                            .written
+                            .fetch_add(file.data.len() as u64, Ordering::Relaxed);
+                        on_file();
+                    }
+                    Err(err) => {
+                        state.failure.lock().unwrap().get_or_insert(err);
+                    }
+                }
+            });
+        }
+
+        let result = dispatch_entries(
+            archive,
+            root_registry,
+            &sender,
+            &state,
+            &mut hard_links,
+            normalize_permissions,
+        );
+        drop(sender);
+        result
+    });
+
+    let written = state.written.load(Ordering::Relaxed);
+    if let Some(err) = state.failure.lock().unwrap().take() {
+        return Err(unpack_error(err, root_registry, written));
+    }
+    dispatched
+        .and_then(|()| {
+            create_hard_links(
+                &state.canonical_root,
+                root_registry,
+                &hard_links,
+                normalize_permissions,
+            )
+        })
+        .map_err(|err| unpack_error(err, root_registry, written))
+}
+
+/// Create the hard links deferred by `dispatch_entries`.
+fn create_hard_links(
+    canonical_root: &Path,
+    root_registry: &Path,
+    hard_links: &[DeferredHardLink],
+    normalize_permissions: bool,
+) -> io::Result<()> {
+    let mut normalized = HashSet::new();
+    for link in hard_links {
+        if let Some(parent) = link.path.parent() {
+            create_dir_in_root(canonical_root, root_registry, parent)?;
+            if normalize_permissions {
+                normalize_dir_permissions(root_registry, parent, &mut normalized)?;
+            }
+        }
+        if let Some(parent) = link.target.parent() {
+            ensure_inside_root(canonical_root, parent)?;
+        }
+        match std::fs::remove_file(&link.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        std::fs::hard_link(&link.target, &link.path)?;
+    }
+    Ok(())
+}
+
+/// Read the archive entries, sending the small regular files to the workers,
+/// streaming the big ones to the disk, deferring the hard links and
+/// extracting everything else in place.
+fn dispatch_entries<R: Read>(
+    archive: &mut Archive<R>,
+    root_registry: &Path,
+    sender: &SyncSender<ExtractedFile>,
+    state: &ExtractState,
+    hard_links: &mut Vec<DeferredHardLink>,
+    normalize_permissions: bool,
+) -> io::Result<()> {
+    let mut normalized = HashSet::new();
+    for entry in archive.entries()? {
+        if state.failure.lock().unwrap().is_some() {
+            break;
+        }
+        let mut entry = entry?;
+        let path = match entry_destination(root_registry, &entry.path()?) {
+            Some(path) => path,
+            None => {
+                warn!(
+                    "Skipping archive entry outside of the registry: {}",
+                    entry.path()?.display()
+                );
+                continue;
+            }
+        };
+
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                create_dir_in_root(&state.canonical_root, root_registry, &path)?;
+                if normalize_permissions {
+                    normalize_dir_permissions(root_registry, &path, &mut normalized)?;
+                }
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                if let Some(parent) = path.parent() {
+                    create_dir_in_root(&state.canonical_root, root_registry, parent)?;
+                    if normalize_permissions {
+                        normalize_dir_permissions(root_registry, parent, &mut normalized)?;
+                    }
+                }
+                let mut mode = entry.header().mode()?;
+                if normalize_permissions {
+                    mode = normalized_file_mode(mode);
+                }
+                let mtime = entry.header().mtime()?;
+                // The size comes from the archive, it's only trusted to pick
+                // how to write the file.
+                let size = entry.header().size()?;
+                if size > STREAMED_FILE_SIZE {
+                    let permit = state.open_files.acquire();
+                    let mut file = create_extracted_file(&state.canonical_root, &path)?;
+                    let written = io::copy(&mut entry, &mut file)?;
+                    drop(file);
+                    set_extracted_file_metadata(&path, mode, mtime)?;
+                    drop(permit);
+                    state.written.fetch_add(written, Ordering::Relaxed);
+                    (state.on_file)();
+                    continue;
+                }
+                let mut data = Vec::with_capacity(size as usize);
+                entry.read_to_end(&mut data)?;
+                let file = ExtractedFile {
+                    path,
+                    data,
+                    mode,
+                    mtime,
+                };
+                if sender.send(file).is_err() {
+                    break;
+                }
+            }
+            EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .and_then(|target| entry_destination(root_registry, &target))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("invalid hard link target of {}", path.display()),
+                        )
+                    })?;
+                hard_links.push(DeferredHardLink { path, target });
+            }
+            _ => {
+                // `tar` refuses to extract these outside of the root itself.
+                entry.unpack_in(root_registry)?;
+                if normalize_permissions {
+                    normalize_entry_permissions(root_registry, &entry, &path, &mut normalized)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compute where an archive entry should be written, refusing entries that
+/// would escape the registry root.
+fn entry_destination(root_registry: &Path, entry_path: &Path) -> Option<PathBuf> {
+    let mut destination = root_registry.to_path_buf();
+    for component in entry_path.components() {
+        match component {
+            Component::Normal(part) => destination.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(destination)
+}
+
+/// Check that `path`, once the symbolic links are resolved, is below the
+/// canonical registry root.
+fn ensure_inside_root(canonical_root: &Path, path: &Path) -> io::Result<()> {
+    let canonical = std::fs::canonicalize(path)?;
+    if canonical.starts_with(canonical_root) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "trying to unpack outside of destination path: {} resolves to {}",
+                path.display(),
+                canonical.display()
+            ),
+        ))
+    }
+}
+
+/// Create the directory `dir` below `root_registry`, along with its missing
+/// parents. Unlike `create_dir_all`, every directory that already exists is
+/// checked to stay below the root, so that a symbolic link extracted earlier
+/// can't lead the extraction outside of it.
+fn create_dir_in_root(canonical_root: &Path, root_registry: &Path, dir: &Path) -> io::Result<()> {
+    let relative = dir.strip_prefix(root_registry).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} is outside of the registry", dir.display()),
+        )
+    })?;
+    let mut current = root_registry.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match std::fs::create_dir(&current) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                ensure_inside_root(canonical_root, &current)?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn write_extracted_file(canonical_root: &Path, file: &ExtractedFile) -> io::Result<()> {
+    create_extracted_file(canonical_root, &file.path)?.write_all(&file.data)?;
+    set_extracted_file_metadata(&file.path, file.mode, file.mtime)
+}
+
+/// Create the file of an extracted entry, refusing to create it outside of
+/// the registry root.
+fn create_extracted_file(canonical_root: &Path, path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        ensure_inside_root(canonical_root, parent)?;
+    }
+    // Replace existing files rather than writing into them, as `tar` does,
+    // not to change the files they are hard linked to.
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    // `create_new` doesn't follow a symbolic link created in the meantime.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// Give the file of an extracted entry its permissions and modification
+/// time, as `tar` does.
+fn set_extracted_file_metadata(path: &Path, mode: u32, mtime: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    // `tar` sets the invalid modification time 0 to 1.
+    let mtime = FileTime::from_unix_time(mtime.max(1) as i64, 0);
+    filetime::set_file_times(path, mtime, mtime)
+}
+
+/// Check whether an IO error (or any error it wraps) is the OS error `code`.
+fn has_os_error(err: &io::Error, code: i32) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            if io_err.raw_os_error() == Some(code) {
+                return true;
+            }
+            // `tar` wraps the underlying error as the inner error of a
+            // custom one, which `source` does not expose.
+            if let Some(inner) = io_err.get_ref() {
+                source = Some(inner);
+                continue;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Check whether an IO error (or any error it wraps) is caused by a full disk.
+pub(crate) fn is_out_of_space(err: &io::Error) -> bool {
+    // ENOSPC, `ErrorKind::StorageFull` is not stable on our MSRV.
+    const ENOSPC: i32 = 28;
+    has_os_error(err, ENOSPC)
+}
+
+/// Check whether an IO error (or any error it wraps) is caused by the open
+/// files limit of the process.
+pub(crate) fn is_too_many_open_files(err: &io::Error) -> bool {
+    // EMFILE on Unix, ERROR_TOO_MANY_OPEN_FILES on Windows.
+    #[cfg(unix)]
+    const EMFILE: i32 = 24;
+    #[cfg(not(unix))]
+    const EMFILE: i32 = 4;
+    has_os_error(err, EMFILE)
+}
+
+/// Map an error that happened during extraction into a readable one.
+pub(crate) fn unpack_error(err: io::Error, root_registry: &Path, written: u64) -> anyhow::Error {
+    if is_out_of_space(&err) {
+        anyhow!(
+            "not enough disk space to unpack into {}: the disk filled up after writing {} bytes",
+            root_registry.display(),
+            written
+        )
+    } else if is_too_many_open_files(&err) {
+        anyhow!(
+            "too many open files while unpacking into {}: lower --max-parallel-extract-open-files \
+             or raise the open files limit (ulimit -n)",
+            root_registry.display()
+        )
+    } else {
+        anyhow!(err).context(format!("failed to unpack into {}", root_registry.display()))
+    }
+}
+
+/// A file of a pack, as compared by `diff-packs`.
+#[derive(Debug, PartialEq, Eq)]
+struct PackedFile {
+    size: u64,
+    /// The SHA-256 of the content.
+    digest: Vec<u8>,
+}
+
+/// List the regular files of a pack by their path in the registry.
+fn packed_files(packed_file: &Path) -> Result<BTreeMap<PathBuf, PackedFile>> {
+    let mut archive = Archive::new(
+        open_pack(packed_file)
+            .with_context(|| format!("failed to open {}", packed_file.display()))?,
+    );
+    let mut files = BTreeMap::new();
+    for entry in archive
+        .entries()
+        .with_context(|| format!("failed to read {}", packed_file.display()))?
+    {
+        let mut entry = entry?;
+        if !matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        ) {
+            continue;
+        }
+        let Some(path) = entry_destination(Path::new(""), &entry.path()?) else {
+            continue;
+        };
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut entry, &mut hasher)?;
+        files.insert(
+            path,
+            PackedFile {
+                size,
+                digest: hasher.finalize().to_vec(),
+            },
+        );
+    }
+    Ok(files)
+}
+
+/// A difference between two packs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PackChange {
+    /// The file is only in the new pack.
+    Added { path: PathBuf, size: u64 },
+    /// The file is only in the old pack.
+    Removed { path: PathBuf, size: u64 },
+    /// The content of the file differs between the packs.
+    Changed {
+        path: PathBuf,
+        old_size: u64,
+        new_size: u64,
+    },
+}
+
+impl Display for PackChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Added { path, size } => write!(f, "added {} ({size} bytes)", path.display()),
+            Self::Removed { path, size } => {
+                write!(f, "removed {} ({size} bytes)", path.display())
+            }
+            Self::Changed {
+                path,
+                old_size,
+                new_size,
+            } => write!(
+                f,
+                "changed {} ({old_size} -> {new_size} bytes)",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Compare the files of the packs `old` and `new`, by path, size and
+/// content. The changes are sorted by path.
+pub fn diff_packs(old: &Path, new: &Path) -> Result<Vec<PackChange>> {
+    let mut old_files = packed_files(old)?;
+    let mut changes = Vec::new();
+    for (path, new_file) in packed_files(new)? {
+        match old_files.remove(&path) {
+            None => changes.push(PackChange::Added {
+                path,
+                size: new_file.size,
+            }),
+            Some(old_file) if old_file != new_file => changes.push(PackChange::Changed {
+                path,
+                old_size: old_file.size,
+                new_size: new_file.size,
+            }),
+            Some(_) => (),
+        }
+    }
+    changes.extend(
+        old_files
+            .into_iter()
+            .map(|(path, old_file)| PackChange::Removed {
+                path,
+                size: old_file.size,
+            }),
+    );
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+
+impl PackChange {
+    /// The path of the changed file in the registry.
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Changed { path, .. } => {
+                path
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tar::Builder;
+    use tar::Header;
+    use tempfile::tempdir;
+
+    #[test]
+    fn out_of_space_detection() {
+        let err = io::Error::from_raw_os_error(28);
+        assert!(is_out_of_space(&err));
+
+        // The way `tar` reports errors while unpacking an entry.
+        let wrapped = io::Error::new(err.kind(), io::Error::from_raw_os_error(28));
+        assert!(is_out_of_space(&wrapped));
+
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(!is_out_of_space(&err));
+    }
+
+    #[test]
+    fn out_of_space_error_message() {
+        let err = unpack_error(io::Error::from_raw_os_error(28), Path::new("/reg"), 1024);
+        assert_eq!(
+            err.to_string(),
+            "not enough disk space to unpack into /reg: the disk filled up after writing 1024 bytes"
+        );
+
+        let err = unpack_error(
+            io::Error::from(io::ErrorKind::PermissionDenied),
+            Path::new("/reg"),
+            0,
+        );
+        assert_eq!(err.to_string(), "failed to unpack into /reg");
+    }
+
+    /// Create a pack file containing the given files.
+    fn create_pack(packed_file: &Path, files: &[(String, Vec<u8>)]) {
+        let mut builder = Builder::new(File::create(packed_file).unwrap());
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_678_000_000 + data.len() as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, path, &data[..]).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Collect all the files under `root` with their content.
+    fn read_tree(root: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let content = std::fs::read(&path).unwrap();
+                    files.push((path.strip_prefix(root).unwrap().to_path_buf(), content));
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn unpack_files() {
+        let dir = tempdir().unwrap();
+        let packed_file = dir.path().join("pack.tar");
+        let files = [(
+            "rustup/dist/x86_64-unknown-linux-gnu/rustup-init".to_string(),
+            b"rustup-init".to_vec(),
+        )];
+        create_pack(&packed_file, &files);
+
+        let root = dir.path().join("registry");
+        unpack(&packed_file, &root, &ExtractOptions::default())
+            .await
+            .unwrap();
+        let content =
+            std::fs::read(root.join("rustup/dist/x86_64-unknown-linux-gnu/rustup-init")).unwrap();
+        assert_eq!(content, b"rustup-init");
+    }
+
+    /// Check that the serial and the parallel extraction produce the same
+    /// tree, with the same modification times.
+    #[tokio::test]
+    async fn parallel_unpack_matches_serial() {
+        let dir = tempdir().unwrap();
+        let packed_file = dir.path().join("pack.tar");
+        let mut files = (0..200)
+            .map(|i| {
+                (
+                    format!("dist/2023-0{}-01/component-{i}.tar.xz", i % 7),
+                    format!("content of file {i}").repeat(i).into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // Streamed instead of being handed to a worker.
+        files.push((
+            "dist/2023-03-09/rustc.tar.xz".to_string(),
+            vec![7; STREAMED_FILE_SIZE as usize + 1],
+        ));
+        create_pack(&packed_file, &files);
+
+        let serial = dir.path().join("serial");
+        unpack(&packed_file, &serial, &ExtractOptions::default())
+            .await
+            .unwrap();
+
+        let parallel = dir.path().join("parallel");
+        let options = ExtractOptions {
+            extract_threads: 8,
+            ..ExtractOptions::default()
+        };
+        unpack(&packed_file, &parallel, &options).await.unwrap();
+
+        let serial_tree = read_tree(&serial);
+        assert_eq!(serial_tree.len(), files.len());
+        assert_eq!(serial_tree, read_tree(&parallel));
+        for (path, _) in serial_tree {
+            let mtime = |root: &Path| std::fs::metadata(root.join(&path)).unwrap().modified();
+            assert_eq!(mtime(&serial).unwrap(), mtime(&parallel).unwrap());
+        }
+    }
+
+    /// Compare the durations of the serial and the parallel extractions of
+    /// a pack of many small files, as in a rustup mirror. Run it with
+    /// `cargo test --release -- --ignored --nocapture unpack_benchmark`.
+    #[tokio::test]
+    #[ignore]
+    async fn unpack_benchmark() {
+        let dir = tempdir().unwrap();
+        let packed_file = dir.path().join("pack.tar");
+        let files = (0..20_000)
+            .map(|i| {
+                (
+                    format!("dist/{}/file-{i}", i % 100),
+                    vec![i as u8; 16 * 1024],
+                )
+            })
+            .collect::<Vec<_>>();
+        create_pack(&packed_file, &files);
+
+        for extract_threads in [1, 4, 8, 16] {
+            let root = dir.path().join(format!("registry-{extract_threads}"));
+            let options = ExtractOptions {
+                extract_threads,
+                ..ExtractOptions::default()
+            };
+            let start = std::time::Instant::now();
+            unpack(&packed_file, &root, &options).await.unwrap();
+            println!(
+                "{extract_threads} extraction threads: {:?}",
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn open_file_limit_bounds_concurrency() {
+        let limit = OpenFileLimit::new(2);
+        let open = AtomicU64::new(0);
+        let max_open = AtomicU64::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        let _permit = limit.acquire();
+                        let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_open.fetch_max(now, Ordering::SeqCst);
+                        thread::yield_now();
+                        open.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(max_open.into_inner() <= 2);
+        assert_eq!(*limit.available.lock().unwrap(), 2);
+    }
+
+    /// Check that many threads sharing a single open file still extract
+    /// the whole pack.
+    #[tokio::test]
+    async fn parallel_unpack_with_one_open_file() {
+        let dir = tempdir().unwrap();
+        let packed_file = dir.path().join("pack.tar");
+        let files = (0..100)
+            .map(|i| (format!("dist/file-{i}"), vec![i as u8; 1024]))
+            .collect::<Vec<_>>();
+        create_pack(&packed_file, &files);
+
+        let root = dir.path().join("registry");
+        let options = ExtractOptions {
+            extract_threads: 8,
+            max_parallel_extract_open_files: Some(1),
+            ..ExtractOptions::default()
+        };
+        unpack(&packed_file, &root, &options).await.unwrap();
+        assert_eq!(read_tree(&root).len(), files.len());
+        assert_eq!(
+            std::fs::read(root.join("dist/file-42")).unwrap(),
+            vec![42u8; 1024]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn too_many_open_files_error_message() {
+        let err = io::Error::new(io::ErrorKind::Other, io::Error::from_raw_os_error(24));
+        let err = unpack_error(err, Path::new("/reg"), 0);
+        assert!(err
+            .to_string()
+            .starts_with("too many open files while unpacking into /reg"));
+    }
+
+    #[tokio::test]
+    async fn unpack_zstd_pack() {
+        let dir = tempdir().unwrap();
+        let tar_file = dir.path().join("pack.tar");
+        // Big enough for the decompression to span many buffers.
+        let files = (0..32)
+            .map(|i| (format!("dist/file-{i}"), vec![i as u8; 1024 * 1024]))
+            .collect::<Vec<_>>();
+        create_pack(&tar_file, &files);
+
+        let packed_file = dir.path().join("pack.tar.zst");
+        zstd::stream::copy_encode(
+            File::open(&tar_file).unwrap(),
+            File::create(&packed_file).unwrap(),
+            0,
+        )
+        .unwrap();
+        assert!(std::fs::metadata(&packed_file).unwrap().len() < 1024 * 1024);
+
+        for extract_threads in [1, 4] {
+            let root = dir.path().join(format!("registry-{extract_threads}"));
+            let options = ExtractOptions {
+                extract_threads,
+                ..ExtractOptions::default()
+            };
+            unpack(&packed_file, &root, &options).await.unwrap();
+            let tree = read_tree(&root);
+            assert_eq!(tree.len(), files.len());
+            assert_eq!(
+                std::fs::read(root.join("dist/file-7")).unwrap(),
+                vec![7u8; 1024 * 1024]
+            );
+        }
+    }
+
+    /// Check that the registry a symbolic link points to is only ever the
+    /// previous or the completely unpacked one, and that the previous one
+    /// is left untouched.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn atomic_unpack() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        let dir = tempdir().unwrap();
+        let previous = dir.path().join("registry.0");
+        std::fs::create_dir_all(previous.join("index")).unwrap();
+        std::fs::write(previous.join("index/config.json"), "{}").unwrap();
+        std::fs::write(previous.join("a"), "old").unwrap();
+        let root = dir.path().join("registry");
+        std::os::unix::fs::symlink("registry.0", &root).unwrap();
+
+        let packed_file = dir.path().join("pack.tar");
+        let mut files = (0..100)
+            .map(|i| (format!("dist/file-{i}"), b"new".to_vec()))
+            .collect::<Vec<_>>();
+        files.push(("a".to_string(), b"new".to_vec()));
+        create_pack(&packed_file, &files);
+
+        // Watch the registry the link points to while unpacking.
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let done = done.clone();
+            let dir = dir.path().to_path_buf();
+            let root = root.clone();
+            thread::spawn(move || {
+                let mut observed = Vec::new();
+                while !done.load(Ordering::SeqCst) {
+                    let registry = dir.join(std::fs::read_link(&root).unwrap());
+                    let files = read_tree(&registry);
+                    if !observed.contains(&files) {
+                        observed.push(files);
+                    }
+                }
+                observed
+            })
+        };
+        let options = ExtractOptions {
+            extract_threads: 4,
+            ..ExtractOptions::default()
+        };
+        let unpacked = unpack_atomic(&packed_file, &root, &options).await;
+        done.store(true, Ordering::SeqCst);
+        let observed = watcher.join().unwrap();
+        unpacked.unwrap();
+
+        let old_tree = vec![
+            (PathBuf::from("a"), b"old".to_vec()),
+            (PathBuf::from("index/config.json"), b"{}".to_vec()),
+        ];
+        let new_tree = read_tree(&root);
+        assert_eq!(new_tree.len(), 102);
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), b"new");
+        assert_eq!(
+            std::fs::read(root.join("index/config.json")).unwrap(),
+            b"{}"
+        );
+        for files in &observed {
+            assert!(*files == old_tree || *files == new_tree, "{files:?}");
+        }
+        assert_eq!(read_tree(&previous), old_tree);
+
+        // A directory can't be replaced atomically.
+        let err = unpack_atomic(&packed_file, &previous, &ExtractOptions::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("must be a symbolic link"), "{err}");
+    }
+
+    /// With `--normalize-permissions` the files and directories are readable
+    /// by everyone whatever their mode in the pack, in both extraction modes.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn normalized_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempdir().unwrap();
+        let packed_file = dir.path().join("pack.tar");
+        let mut builder = Builder::new(File::create(&packed_file).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o700);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "dist/", io::empty())
+            .unwrap();
+        for (path, mode) in [("dist/channel.toml", 0o600), ("rustup/rustup-init", 0o700)] {
+            let mut header = Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(mode);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, &b"data"[..])
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let mode = |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        for extract_threads in [1, 4] {
+            let root = dir.path().join(format!("registry-{extract_threads}"));
+            let options = ExtractOptions {
+                extract_threads,
+                normalize_permissions: true,
+                ..ExtractOptions::default()
+            };
+            unpack(&packed_file, &root, &options).await.unwrap();
+            assert_eq!(mode(root.join("dist")), 0o755);
+            assert_eq!(mode(root.join("dist/channel.toml")), 0o644);
+            assert_eq!(mode(root.join("rustup")), 0o755);
+            assert_eq!(mode(root.join("rustup/rustup-init")), 0o755);
+        }
+
+        // Without the flag, the modes of the pack are kept.
+        let root = dir.path().join("registry");
+        unpack(&packed_file, &root, &ExtractOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(mode(root.join("dist")), 0o700);
+        assert_eq!(mode(root.join("dist/channel.toml")), 0o600);
+    }
+
+    #[test]
+    fn pack_differences() {
+        let dir = tempdir().unwrap();
+        let old = dir.path().join("old.tar");
+        let new = dir.path().join("new.tar");
+        let file = |path: &str, data: &[u8]| (path.to_string(), data.to_vec());
+        create_pack(
+            &old,
+            &[
+                file("./dist/channel-rust-stable.toml", b"1.67.0"),
+                file("./dist/rustc.tar.xz", b"rustc"),
+                file("./rustup/dist/rustup-init", b"init"),
+            ],
+        );
+        create_pack(
+            &new,
+            &[
+                file("./dist/channel-rust-stable.toml", b"1.68.0"),
+                file("./dist/cargo.tar.xz", b"cargo!"),
+                file("./rustup/dist/rustup-init", b"init"),
+            ],
+        );
+
+        let changes = diff_packs(&old, &new).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                PackChange::Added {
+                    path: PathBuf::from("dist/cargo.tar.xz"),
+                    size: 6
+                },
+                PackChange::Changed {
+                    path: PathBuf::from("dist/channel-rust-stable.toml"),
+                    old_size: 6,
+                    new_size: 6
+                },
+                PackChange::Removed {
+                    path: PathBuf::from("dist/rustc.tar.xz"),
+                    size: 5
+                },
+            ]
+        );
+        assert_eq!(
+            changes[1].to_string(),
+            "changed dist/channel-rust-stable.toml (6 -> 6 bytes)"
+        );
+        assert!(diff_packs(&old, &old).unwrap().is_empty());
+    }
+
+    /// Check that a file following a symbolic link to a directory outside of
+    /// the registry is refused by both extractions.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unpack_through_symlink_is_refused() {
+        let dir = tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        let packed_file = dir.path().join("evil.tar");
+        let mut builder = Builder::new(File::create(&packed_file).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "dist", &outside).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "dist/passwd", &b"pwned\n"[..])
+            .unwrap();
+        builder.finish().unwrap();
+
+        for extract_threads in [1, 4] {
+            let root = dir.path().join(format!("registry-{extract_threads}"));
+            let options = ExtractOptions {
+                extract_threads,
+                ..ExtractOptions::default()
+            };
+            let err = unpack(&packed_file, &root, &options).await.unwrap_err();
+            assert!(
+                format!("{err:#}").contains("outside of destination path"),
+                "{err:#}"
+            );
+            assert!(!outside.join("passwd").exists());
+        }
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+    }
+
+    /// Check that the parallel extraction creates the hard links once their
+    /// targets are written.
+    #[tokio::test]
+    async fn parallel_unpack_hard_links() {
+        let dir = tempdir().unwrap();
+        let packed_file = dir.path().join("pack.tar");
+        let mut builder = Builder::new(File::create(&packed_file).unwrap());
+        for i in 0..50 {
+            let data = format!("file {i}").into_bytes();
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("dist/file-{i}"), &data[..])
+                .unwrap();
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Link);
+            header.set_size(0);
+            header.set_mode(0o644);
+            builder
+                .append_link(
+                    &mut header,
+                    format!("links/{i}/link"),
+                    format!("dist/file-{i}"),
+                )
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let root = dir.path().join("registry");
+        let options = ExtractOptions {
+            extract_threads: 8,
+            ..ExtractOptions::default()
+        };
+        unpack(&packed_file, &root, &options).await.unwrap();
+        for i in 0..50 {
+            let content = std::fs::read(root.join(format!("links/{i}/link"))).unwrap();
+            assert_eq!(content, format!("file {i}").into_bytes());
+        }
+    }
+
+    #[test]
+    fn entry_destination_stays_in_root() {
+        let root = Path::new("/reg");
+        assert_eq!(
+            entry_destination(root, Path::new("./dist/a.toml")),
+            Some(PathBuf::from("/reg/dist/a.toml"))
+        );
+        assert_eq!(entry_destination(root, Path::new("../etc/passwd")), None);
+        assert_eq!(entry_destination(root, Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn output_template_expansion() {
+        let root = tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("dist")).unwrap();
+        for (channel, date, version) in [
+            ("stable", "2023-02-09", "1.67.1 (d5a82bbd2 2023-02-07)"),
+            (
+                "nightly",
+                "2023-03-20",
+                "1.70.0-nightly (da7c50c08 2023-03-19)",
+            ),
+        ] {
+            std::fs::write(
+                root.path()
+                    .join(format!("dist/channel-rust-{channel}.toml")),
+                format!(
+                    "manifest-version = \"2\"\ndate = \"{date}\"\n\
+                     [pkg.rust]\nversion = \"{version}\"\n[pkg.rust.target]\n"
+                ),
+            )
+            .unwrap();
+        }
+
+        let stable = [template_values(root.path(), "stable").unwrap()];
+        assert_eq!(
+            expand_output_template("packs/rust-{channel}-{version}-{date}.tar", &stable).unwrap(),
+            Path::new("packs/rust-stable-1.67.1-2023-02-09.tar")
+        );
+        let both =
+            ["stable", "nightly"].map(|channel| template_values(root.path(), channel).unwrap());
+        assert_eq!(
+            expand_output_template("rust-{version}.tar", &both).unwrap(),
+            Path::new("rust-1.67.1+1.70.0-nightly.tar")
+        );
+        assert!(template_values(root.path(), "beta").is_err());
+
+        let traversal = [TemplateValues {
+            channel: "../../etc".to_string(),
+            version: "1.67.1".to_string(),
+            date: "2023-02-09".to_string(),
+        }];
+        assert!(expand_output_template("rust-{channel}.tar", &traversal).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{ensure, Context, Result};
+use itertools::Itertools;
+use reqwest::header::{HeaderValue, USER_AGENT};
+use reqwest::{Client, StatusCode};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::index::{Entries, Entry, Index};
+use crate::publish::{crate_file_name, crate_path};
+use crate::storage::{crate_key, Storage};
+
+/// The registry crates missing from the registry are fetched from.
+pub(crate) struct Upstream {
+    client: Client,
+    user_agent: HeaderValue,
+    /// The URL the `.crate` files are downloaded from, e.g. https://static.crates.io.
+    url: String,
+    /// The URL of the sparse index of the registry, e.g. https://index.crates.io.
+    index_url: String,
+    /// The locks of the crate files being fetched, by key, for concurrent
+    /// downloads of a missing crate to fetch it once.
+    in_flight: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Upstream {
+    pub(crate) fn new(url: &str, index_url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            user_agent: HeaderValue::from_str(&format!(
+                "Offline Mirror/{}",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .expect("Hardcoded user agent string should never fail."),
+            url: url.trim_end_matches('/').to_string(),
+            index_url: index_url.trim_end_matches('/').to_string(),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fetch the upstream index entry of a crate in the given version, if any.
+    async fn entry(&self, name: &str, version: &str) -> Result<Option<Entry>> {
+        let url = sparse_index_file_url(&self.index_url, name);
+        let Some(content) = fetch_sparse_index_file(&self.client, &self.user_agent, &url).await?
+        else {
+            return Ok(None);
+        };
+        let entries: Entries = content
+            .try_into()
+            .with_context(|| format!("invalid index file {}", url))?;
+        Ok(entries.into_version(version))
+    }
+
+    /// Download a `.crate` file.
+    async fn download(&self, name: &str, version: &str) -> Result<Vec<u8>> {
+        let url = format!(
+            "{}/crates/{}/{}",
+            self.url,
+            name,
+            crate_file_name(name, version)
+        );
+        let data = self
+            .client
+            .get(&url)
+            .header(USER_AGENT, &self.user_agent)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("failed to fetch {}", url))?
+            .bytes()
+            .await?;
+        Ok(data.to_vec())
+    }
+
+    /// Fetch a crate that is missing from the registry: download it, check it
+    /// against the upstream index, store it in `crates_folder` and add it to
+    /// the index. Returns the name of the crate as the upstream index has it,
+    /// its case may differ from `name`, or `None` when the upstream registry
+    /// doesn't have it. Concurrent fetches of a crate download it once.
+    pub(crate) async fn fetch(
+        &self,
+        index: &Index,
+        storage: &dyn Storage,
+        name: &str,
+        version: &str,
+    ) -> Result<Option<String>> {
+        let Some(entry) = self.entry(name, version).await? else {
+            return Ok(None);
+        };
+        // The index entry is authoritative for the name's case.
+        let name = entry.name.clone();
+        let key = crate_key(&name, version);
+        let lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let fetched = {
+            let _guard = lock.lock().await;
+            self.fetch_entry(index, storage, entry, &key).await
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&key)
+            .map_or(false, |current| Arc::ptr_eq(current, &lock))
+        {
+            in_flight.remove(&key);
+        }
+        fetched.map(|()| Some(name))
+    }
+
+    /// Download and store the crate of `entry` at `key` unless another fetch
+    /// stored it already, then add it to the index.
+    async fn fetch_entry(
+        &self,
+        index: &Index,
+        storage: &dyn Storage,
+        entry: Entry,
+        key: &str,
+    ) -> Result<()> {
+        if storage.exists(key).await? {
+            return Ok(());
+        }
+        let name = entry.name.clone();
+        let version = entry.vers.clone();
+        let data = self.download(&name, &version).await?;
+        let cksum = format!("{:x}", Sha256::digest(&data));
+        ensure!(
+            cksum == entry.cksum,
+            "checksum mismatch for {} in version {}: expected {}, got {}",
+            name,
+            version,
+            entry.cksum,
+            cksum
+        );
+
+        storage
+            .put(key, data.into())
+            .await
+            .with_context(|| format!("failed to store crate file {}", key))?;
+
+        index
+            .add_entry(
+                entry,
+                &format!("Cache {} in version {} from upstream", name, version),
+            )
+            .await?;
+        info!("Cached {} in version {} from upstream", name, version);
+        Ok(())
+    }
+}
+
+/// The URL of the file of the crate `name` in the sparse index at `index_url`.
+pub(crate) fn sparse_index_file_url(index_url: &str, name: &str) -> String {
+    // The sparse index uses lowercase paths.
+    let name = name.to_lowercase();
+    let path = crate_path(&name)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .join("/");
+    format!("{}/{}/{}", index_url.trim_end_matches('/'), path, name)
+}
+
+/// Fetch a file of a sparse index, `None` when there is no such crate.
+pub(crate) async fn fetch_sparse_index_file(
+    client: &Client,
+    user_agent: &HeaderValue,
+    url: &str,
+) -> Result<Option<String>> {
+    let response = client
+        .get(url)
+        .header(USER_AGENT, user_agent)
+        .send()
+        .await
+        .with_context(|| format!("failed to fetch {}", url))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let content = response
+        .error_for_status()
+        .with_context(|| format!("failed to fetch {}", url))?
+        .text()
+        .await?;
+    Ok(Some(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tempfile::tempdir;
+    use warp::http::StatusCode;
+    use warp::Filter as _;
+
+    use crate::cli::ServeArgs;
+    use crate::serve::routes;
+    use crate::serve::tests::serve_args;
+
+    /// Serve a fixture upstream registry with the crate `my-lib` in versions
+    /// 0.1.0 and 0.2.0, the latter with a wrong checksum, counting the
+    /// downloads of crate files.
+    fn fixture_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let entry = |vers: &str, cksum: &str| {
+            format!(
+                r#"{{"name":"my-lib","vers":"{vers}","deps":[],"cksum":"{cksum}","features":{{}},"yanked":false}}"#
+            )
+        };
+        let index_file = [
+            entry("0.1.0", &format!("{:x}", Sha256::digest(b"my-lib 0.1.0"))),
+            entry("0.2.0", "00"),
+        ]
+        .join("\n");
+        let index = warp::path!("index" / "my" / "-l" / "my-lib").map(move || index_file.clone());
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = downloads.clone();
+        let crates = warp::path!("crates" / "my-lib" / String).map(move |file: String| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match file.as_str() {
+                "my-lib-0.1.0.crate" => "my-lib 0.1.0",
+                _ => "my-lib 0.2.0",
+            }
+        });
+        let (addr, server) = warp::serve(index.or(crates)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, downloads)
+    }
+
+    fn upstream_args(root: &Path, upstream: SocketAddr) -> ServeArgs {
+        serve_args(
+            root,
+            &[
+                "--upstream",
+                &format!("http://{upstream}"),
+                "--upstream-index",
+                &format!("http://{upstream}/index/"),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn download_from_upstream() {
+        let (upstream, _) = fixture_upstream();
+        let root = tempdir().unwrap();
+        let args = upstream_args(root.path(), upstream);
+        let routes = routes(&args).await.unwrap();
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.1.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        let response = warp::test::request()
+            .path("/crates/my/-l/my-lib-0.1.0.crate")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "my-lib 0.1.0");
+        let index = Index::open(root.path().join("index")).unwrap();
+        assert!(index.entry("my-lib", "0.1.0").unwrap().is_some());
+        assert_eq!(
+            index.status().await.unwrap().message,
+            "Cache my-lib in version 0.1.0 from upstream"
+        );
+
+        // A crate not matching the upstream index is rejected.
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.2.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(String::from_utf8_lossy(response.body()).contains("checksum mismatch"));
+        assert!(!root.path().join("crates/my/-l/my-lib-0.2.0.crate").exists());
+        assert!(index.entry("my-lib", "0.2.0").unwrap().is_none());
+
+        // Versions unknown upstream are simply missing.
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.3.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert!(!root.path().join("crates/my/-l/my-lib-0.3.0.crate").exists());
+    }
+
+    #[tokio::test]
+    async fn download_from_upstream_once() {
+        let (upstream, downloads) = fixture_upstream();
+        let root = tempdir().unwrap();
+        let args = upstream_args(root.path(), upstream);
+        let routes = routes(&args).await.unwrap();
+
+        // The name's case comes from the upstream index.
+        let responses = futures::future::join_all((0..4).map(|_| {
+            warp::test::request()
+                .path("/api/v1/crates/My-Lib/0.1.0/download")
+                .reply(&routes)
+        }))
+        .await;
+        for response in responses {
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(
+                response.headers()["location"],
+                "/crates/my/-l/my-lib-0.1.0.crate"
+            );
+        }
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    }
+}
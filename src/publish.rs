@@ -1,10 +1,14 @@
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::convert::TryInto as _;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fmt::Result as FmtResult;
 use std::fs::create_dir_all;
-use std::fs::OpenOptions;
+use std::fs::File;
+use std::io::Read as _;
 use std::io::Write as _;
 use std::mem::size_of;
 use std::ops::Deref as _;
@@ -13,12 +17,13 @@ use std::path::PathBuf;
 use std::slice::from_ref as slice_from_ref;
 use std::str::from_utf8 as str_from_utf8;
 use std::sync::Arc;
+use std::sync::Mutex;
 
+use anyhow::anyhow;
 use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
 
-use serde_json::to_string;
 use sha2::Digest as _;
 use sha2::Sha256;
 
@@ -26,17 +31,26 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_json::from_slice;
 
+use thiserror::Error;
+
+use tracing::error;
 use tracing::warn;
 
 use warp::hyper::body::Bytes;
+use warp::reject::Reject;
 
+use crate::acl::Acl;
+use crate::cli::ServeArgs;
+use crate::index::split_features;
 use crate::index::Entries;
 use crate::index::Entry;
 use crate::index::Index;
+use crate::storage::crate_key;
+use crate::storage::Storage;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
-enum Kind {
+pub(crate) enum Kind {
     Dev,
     Build,
     Normal,
@@ -157,6 +171,7 @@ struct MetaData {
 impl From<(MetaData, &[u8])> for Entry {
     fn from(source: (MetaData, &[u8])) -> Self {
         let (metadata, data) = source;
+        let (features, features2) = split_features(metadata.features);
 
         Self {
             name: metadata.name,
@@ -167,18 +182,86 @@ impl From<(MetaData, &[u8])> for Entry {
                 .map(crate::index::Dep::from)
                 .collect(),
             cksum: format!("{:x}", Sha256::digest(data)),
-            features: metadata.features,
+            v: features2.as_ref().map(|_| 2),
+            features,
+            features2,
             yanked: false,
             links: metadata.links,
         }
     }
 }
 
+/// Check that a checksum is a SHA-256 hash as Cargo expects it in the
+/// index: 64 lowercase hexadecimal digits.
+fn check_cksum(cksum: &str) -> Result<(), PublishError> {
+    if cksum.len() != 64
+        || !cksum
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        return Err(PublishError::Internal(anyhow!(
+            "invalid checksum {:?}, expected 64 lowercase hexadecimal digits",
+            cksum
+        )));
+    }
+    Ok(())
+}
+
+/// Identify a publisher by its token, without revealing the token itself.
+pub fn publisher_id(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))[..12].to_string()
+}
+
+/// Build the index commit message of a publish from the configured template.
+fn commit_message(template: &str, name: &str, version: &str, publisher: Option<&str>) -> String {
+    template
+        .replace("{name}", name)
+        .replace("{version}", version)
+        .replace("{publisher}", publisher.unwrap_or("anonymous"))
+}
+
 /// Craft the file name for a crate named `name` in version `version`.
 pub fn crate_file_name(name: &str, version: &str) -> String {
     format!("{}-{}.crate", name, version)
 }
 
+/// Craft the file name of the README stored next to the crate file.
+pub fn readme_file_name(name: &str, version: &str) -> String {
+    format!("{}-{}.readme", name, version)
+}
+
+/// Get the README of a published crate: the file the manifest points to in
+/// the crate data or, failing that, the content sent by cargo.
+fn readme(metadata: &MetaData, data: &[u8]) -> Option<String> {
+    let from_crate = metadata.readme_file.as_deref().and_then(|readme_file| {
+        extract_file(data, &metadata.name, &metadata.vers, readme_file)
+            .map_err(|err| warn!("failed to extract the README from the crate: {:#}", err))
+            .ok()
+            .flatten()
+    });
+    from_crate.or_else(|| metadata.readme.clone())
+}
+
+/// Extract a file of the package from the gzipped tarball of a crate.
+pub(crate) fn extract_file(
+    data: &[u8],
+    name: &str,
+    version: &str,
+    file: &str,
+) -> Result<Option<String>> {
+    let wanted = Path::new(&format!("{}-{}", name, version)).join(file.trim_start_matches("./"));
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(data));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == wanted {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
 /// Extract and parse a `u32` value from a `Bytes` object.
 fn parse_u32(bytes: &mut Bytes) -> Result<u32> {
     ensure!(bytes.len() >= size_of::<u32>(), "not enough data for u32");
@@ -199,6 +282,20 @@ fn parse_metadata(bytes: &mut Bytes, json_length: usize) -> Result<MetaData> {
     Ok(metadata)
 }
 
+/// Whether `name` and `version` may be those of a crate version: only those
+/// map to a file inside the registry. Checked on publish, and by the routes
+/// looking crates up.
+pub(crate) fn valid_crate_version(name: &str, version: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && version.starts_with(|c: char| c.is_ascii_digit())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'))
+}
+
 /// Infer the path to a crate inside the index from its name.
 pub fn crate_path(name: &str) -> PathBuf {
     // Should have been verified already at this point.
@@ -230,6 +327,207 @@ fn read_crate(bytes: &mut Bytes, crate_length: usize) -> Result<Bytes> {
     Ok(data)
 }
 
+/// The maximum size of a publish request body. At the time of last check,
+/// crates.io employed a limit of 10 MiB.
+pub(crate) const MAX_PUBLISH_SIZE: usize = 20 * 1024 * 1024;
+
+/// The reasons a publish can fail.
+#[derive(Debug, Error)]
+pub enum PublishError {
+    /// The request body does not follow the format cargo sends.
+    #[error("invalid publish request: {0:#}")]
+    InvalidRequest(anyhow::Error),
+    /// The metadata of the crate is missing or not acceptable.
+    #[error("invalid crate metadata: {0:#}")]
+    InvalidMetadata(anyhow::Error),
+    /// The request exceeds the size we accept.
+    #[error("the publish request exceeds the limit of {limit} bytes")]
+    TooLarge { limit: usize },
+    /// A different crate was already published in this version.
+    #[error("crate {name} in version {version} already exists in the registry")]
+    VersionExists { name: String, version: String },
+    /// Storing the crate would exceed the limits of the registry.
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    /// The access control list does not allow the publisher to publish the crate.
+    #[error("{publisher} is not allowed to publish crate {name}")]
+    NotAllowed { name: String, publisher: String },
+    /// Yanking and unyanking are refused without a token.
+    #[error("yanking requires a token, anonymous yanks are refused")]
+    AnonymousYank,
+    /// The publisher didn't accept the publishing terms of the registry yet.
+    #[error(
+        "the publishing terms at {terms} must be accepted first, with a POST to \
+         /api/accept-terms authorized by the publish token"
+    )]
+    TermsNotAccepted { terms: String },
+    /// The name of the crate differs from an existing one only by `-` and `_`.
+    #[error("crate {name} conflicts with the existing crate {existing}")]
+    NameConflict { name: String, existing: String },
+    /// The registry failed to store the crate.
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl Reject for PublishError {}
+
+/// The storage used by the crates of the registry.
+#[derive(Debug, Default)]
+struct Usage {
+    /// The total size in bytes of the crate files.
+    total_size: u64,
+    /// The number of crate files.
+    crates: u64,
+}
+
+impl Usage {
+    /// Compute the usage of the crate files under `folder`.
+    fn scan(folder: &Path) -> Result<Self> {
+        let mut usage = Self::default();
+        if !folder.exists() {
+            return Ok(usage);
+        }
+        for entry in std::fs::read_dir(folder)
+            .with_context(|| format!("failed to read directory {}", folder.display()))?
+        {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                let sub = Self::scan(&entry.path())?;
+                usage.total_size += sub.total_size;
+                usage.crates += sub.crates;
+            } else if file_type.is_file() && entry.path().extension() == Some("crate".as_ref()) {
+                usage.total_size += entry.metadata()?.len();
+                usage.crates += 1;
+            }
+        }
+        Ok(usage)
+    }
+}
+
+/// The limits of `--max-total-size` and `--max-crates`, along with the
+/// current usage. The usage is computed once and then kept up to date on
+/// each publish.
+#[derive(Debug)]
+pub struct Quota {
+    max_total_size: Option<u64>,
+    max_crates: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+impl Quota {
+    /// Create the quota of the registry storing its crates in `crates_folder`.
+    pub(crate) fn new(crates_folder: &Path, serve_args: &ServeArgs) -> Result<Self> {
+        let max_total_size = serve_args.max_total_size;
+        let max_crates = serve_args.max_crates;
+        // Without limits there's no need to walk through all the crates.
+        let usage = if max_total_size.is_some() || max_crates.is_some() {
+            Usage::scan(crates_folder)?
+        } else {
+            Usage::default()
+        };
+        Ok(Self {
+            max_total_size,
+            max_crates,
+            usage: Mutex::new(usage),
+        })
+    }
+
+    /// Check that a crate file of `size` bytes fits, replacing the file of
+    /// `replaced_size` bytes if any.
+    fn check(&self, size: u64, replaced_size: Option<u64>) -> Result<(), PublishError> {
+        self.check_usage(&self.usage.lock().unwrap(), size, replaced_size)
+    }
+
+    fn check_usage(
+        &self,
+        usage: &Usage,
+        size: u64,
+        replaced_size: Option<u64>,
+    ) -> Result<(), PublishError> {
+        if let Some(max_crates) = self.max_crates {
+            if replaced_size.is_none() && usage.crates >= max_crates {
+                return Err(PublishError::QuotaExceeded(format!(
+                    "the registry already holds the maximum of {} crates",
+                    max_crates
+                )));
+            }
+        }
+        if let Some(max_total_size) = self.max_total_size {
+            let total_size = usage.total_size.saturating_sub(replaced_size.unwrap_or(0)) + size;
+            if total_size > max_total_size {
+                return Err(PublishError::QuotaExceeded(format!(
+                    "storing {} bytes would exceed the maximum total size of {} bytes",
+                    size, max_total_size
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserve the usage of a crate file of `size` bytes, replacing the file
+    /// of `replaced_size` bytes if any, when it fits. Checking and reserving
+    /// at once keeps concurrent publishes from both taking the last room.
+    fn reserve(
+        &self,
+        size: u64,
+        replaced_size: Option<u64>,
+    ) -> Result<QuotaReservation<'_>, PublishError> {
+        let mut usage = self.usage.lock().unwrap();
+        self.check_usage(&usage, size, replaced_size)?;
+        match replaced_size {
+            Some(replaced_size) => {
+                usage.total_size = usage.total_size.saturating_sub(replaced_size)
+            }
+            None => usage.crates += 1,
+        }
+        usage.total_size += size;
+        Ok(QuotaReservation {
+            quota: self,
+            size,
+            replaced_size,
+            committed: false,
+        })
+    }
+
+    /// Account for the deletion of a stored crate file of `size` bytes.
+    pub(crate) fn remove(&self, size: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.total_size = usage.total_size.saturating_sub(size);
+        usage.crates = usage.crates.saturating_sub(1);
+    }
+}
+
+/// The usage of a crate file reserved in the [`Quota`], released when
+/// dropped before being committed, e.g. when storing the file failed.
+struct QuotaReservation<'a> {
+    quota: &'a Quota,
+    size: u64,
+    replaced_size: Option<u64>,
+    committed: bool,
+}
+
+impl QuotaReservation<'_> {
+    /// Keep the reserved usage, the crate file being stored.
+    fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let mut usage = self.quota.usage.lock().unwrap();
+        usage.total_size = usage.total_size.saturating_sub(self.size);
+        match self.replaced_size {
+            Some(replaced_size) => usage.total_size += replaced_size,
+            None => usage.crates = usage.crates.saturating_sub(1),
+        }
+    }
+}
+
 /// PUT handler for the `/api/v1/crates/new` endpoint.
 // TODO: We may want to rollback earlier changes if we error out
 //       somewhere in the middle.
@@ -237,69 +535,419 @@ fn read_crate(bytes: &mut Bytes, crate_length: usize) -> Result<Bytes> {
 // design, but if we ever were to change our security model and assume
 // bad-faith actors attempting to publish and do other things, that may
 // not be so wise.
-pub async fn publish_crate(mut body: Bytes, index: Arc<Index>, crates_folder: &Path) -> Result<()> {
-    let json_length = parse_u32(&mut body)
-        .context("failed to read JSON length")?
+pub async fn publish_crate(
+    body: Bytes,
+    index: Arc<Index>,
+    storage: &dyn Storage,
+    quota: &Quota,
+    serve_args: &ServeArgs,
+    token: Option<&str>,
+) -> Result<(), PublishError> {
+    let publisher = token.map(publisher_id);
+    publish_crate_as(
+        body,
+        index,
+        storage,
+        quota,
+        serve_args,
+        publisher.as_deref(),
+    )
+    .await
+}
+
+/// The header asking to only check a publish request, not to publish it:
+/// `X-Publish-Dry-Run: true`.
+pub(crate) const DRY_RUN_HEADER: &str = "X-Publish-Dry-Run";
+
+/// The magic number starting gzip streams, like `.crate` files.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Check that a publish request body plausibly is the frame cargo sends, to
+/// tell clients sending something else, like a raw `.crate` file, what is
+/// expected rather than failing to parse it.
+fn check_publish_frame(body: &[u8]) -> Result<(), PublishError> {
+    if body.len() < size_of::<u32>() {
+        return Ok(());
+    }
+    let json_length = u32::from_le_bytes(body[..size_of::<u32>()].try_into().unwrap());
+    let rest = &body[size_of::<u32>()..];
+    let fits = json_length as usize <= rest.len();
+    if fits && (json_length == 0 || rest[0] == b'{') {
+        return Ok(());
+    }
+    // The length may start with the bytes checked below, they only tell what
+    // the body is once it isn't a frame.
+    let sent = if body.starts_with(&GZIP_MAGIC) {
+        "a gzip archive, like a .crate file".to_string()
+    } else if matches!(
+        body.iter().find(|byte| !byte.is_ascii_whitespace()),
+        Some(b'{' | b'[')
+    ) {
+        "JSON".to_string()
+    } else if !fits {
+        format!("shorter than the JSON length of {json_length} bytes it starts with")
+    } else {
+        "not JSON metadata after its first 4 bytes".to_string()
+    };
+    Err(PublishError::InvalidRequest(anyhow!(
+        "the body is {sent}, expected the format of cargo publish: the length of the JSON \
+         metadata as a 32-bit little endian integer, the metadata, then the length of the \
+         .crate file and the file"
+    )))
+}
+
+/// Read and check the metadata at the start of a publish request body,
+/// leaving the rest of the body in `body`.
+fn read_metadata(body: &mut Bytes) -> Result<MetaData, PublishError> {
+    if body.len() > MAX_PUBLISH_SIZE {
+        return Err(PublishError::TooLarge {
+            limit: MAX_PUBLISH_SIZE,
+        });
+    }
+    check_publish_frame(body)?;
+
+    let json_length = parse_u32(body)
+        .context("failed to read JSON length")
+        .map_err(PublishError::InvalidRequest)?
         .try_into()
         .unwrap();
 
-    let metadata = parse_metadata(&mut body, json_length).context("failed to read JSON body")?;
-    let crate_name = metadata.name.clone();
-    let crate_vers = metadata.vers.clone();
+    let metadata = parse_metadata(body, json_length)
+        .context("failed to read JSON body")
+        .map_err(PublishError::InvalidMetadata)?;
 
     // TODO: Strictly speaking we should have more checks in place here.
-    ensure!(!crate_name.is_empty(), "crate name cannot be empty");
-    ensure!(
-        crate_name.is_ascii(),
-        "crate name contains non-ASCII characters"
-    );
+    if metadata.name.is_empty() {
+        return Err(PublishError::InvalidMetadata(anyhow!(
+            "crate name cannot be empty"
+        )));
+    }
+    if !metadata.name.is_ascii() {
+        return Err(PublishError::InvalidMetadata(anyhow!(
+            "crate name contains non-ASCII characters"
+        )));
+    }
+    // The version is part of the paths of the crate and README files.
+    if let Err(err) = semver::Version::parse(&metadata.vers) {
+        return Err(PublishError::InvalidMetadata(anyhow!(
+            "invalid crate version {}: {}",
+            metadata.vers,
+            err
+        )));
+    }
+    if !valid_crate_version(&metadata.name, &metadata.vers) {
+        return Err(PublishError::InvalidMetadata(anyhow!(
+            "invalid crate name {}: only ASCII letters, digits, `-` and `_` are allowed",
+            metadata.name
+        )));
+    }
+    Ok(metadata)
+}
 
-    let crate_meta_dir = index.root().join(crate_path(&crate_name));
-    create_dir_all(&crate_meta_dir)
-        .with_context(|| format!("failed to create directory {}", crate_meta_dir.display()))?;
+/// Get the name and version of the crate in a publish request body.
+pub(crate) fn published_crate(body: &Bytes) -> Result<(String, String), PublishError> {
+    let metadata = read_metadata(&mut body.clone())?;
+    Ok((metadata.name, metadata.vers))
+}
 
-    let crate_length = parse_u32(&mut body)
-        .context("failed to read crate length")?
+/// Check that the `--acl` of the registry, if any, allows `publisher` to
+/// publish (or yank) the crate `name`. The list is read on each request so
+/// that it can be edited while serving.
+pub(crate) fn check_acl(
+    serve_args: &ServeArgs,
+    name: &str,
+    publisher: Option<&str>,
+) -> Result<(), PublishError> {
+    let Some(path) = &serve_args.acl else {
+        return Ok(());
+    };
+    if Acl::load(path)?.allows(name, publisher) {
+        Ok(())
+    } else {
+        Err(PublishError::NotAllowed {
+            name: name.to_string(),
+            publisher: publisher.unwrap_or("anonymous").to_string(),
+        })
+    }
+}
+
+/// The name of a crate of the index at `index_root` differing from `name`
+/// only by `-` and `_`, if any.
+fn conflicting_crate_name(index_root: &Path, name: &str) -> Result<Option<String>> {
+    let normalized = |name: &str| name.replace('_', "-");
+    // The directory of a crate depends on its first four characters at most,
+    // look into the directories of all their spellings.
+    let prefix_len = name.len().min(4);
+    let mut prefixes = vec![String::new()];
+    for c in name[..prefix_len].chars() {
+        let spellings: &[char] = if c == '-' || c == '_' {
+            &['-', '_']
+        } else {
+            &[c]
+        };
+        prefixes = prefixes
+            .iter()
+            .flat_map(|prefix| spellings.iter().map(move |c| format!("{prefix}{c}")))
+            .collect();
+    }
+    let dirs = prefixes
+        .iter()
+        .map(|prefix| index_root.join(crate_path(&format!("{prefix}{}", &name[prefix_len..]))))
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", dir.display()))
+            }
+        };
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let Some(existing) = file_name.to_str() else {
+                continue;
+            };
+            if existing != name && normalized(existing) == normalized(name) {
+                return Ok(Some(existing.to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Check, with `--strict-naming`, that a new crate `name` doesn't differ
+/// from an existing crate only by `-` and `_`. The crates published before
+/// the check was enabled can still get new versions.
+fn check_naming(serve_args: &ServeArgs, index_root: &Path, name: &str) -> Result<(), PublishError> {
+    if !serve_args.strict_naming || index_root.join(crate_path(name)).join(name).exists() {
+        return Ok(());
+    }
+    match conflicting_crate_name(index_root, name)? {
+        Some(existing) => Err(PublishError::NameConflict {
+            name: name.to_string(),
+            existing,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Write `data` to the file at `path` and, with `fsync`, wait for it to
+/// reach the disk.
+pub(crate) fn write_file(path: &Path, data: &[u8], fsync: bool) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    if fsync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Wait for the entries of the directory at `path`, e.g. newly created
+/// files, to reach the disk.
+pub(crate) fn sync_dir(path: &Path) -> std::io::Result<()> {
+    // Directories can only be synced this way on Unix.
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// A publish request checked to be acceptable, with nothing written yet.
+struct CheckedPublish {
+    crate_name: String,
+    crate_vers: String,
+    data: Bytes,
+    readme: Option<String>,
+    entry: Entry,
+    /// The key of the crate file in the storage.
+    key: String,
+    /// The size of the crate file the publish replaces, if any.
+    replaced_size: Option<u64>,
+}
+
+/// Check a publish request on behalf of `publisher` without writing
+/// anything, leaving what follows the crate file in `body`.
+async fn check_publish_request(
+    body: &mut Bytes,
+    index: &Index,
+    storage: &dyn Storage,
+    quota: &Quota,
+    serve_args: &ServeArgs,
+    publisher: Option<&str>,
+) -> Result<CheckedPublish, PublishError> {
+    let metadata = read_metadata(body)?;
+    let crate_name = metadata.name.clone();
+    let crate_vers = metadata.vers.clone();
+    check_acl(serve_args, &crate_name, publisher)?;
+    check_naming(serve_args, index.root(), &crate_name)?;
+
+    let crate_length = parse_u32(body)
+        .context("failed to read crate length")
+        .map_err(PublishError::InvalidRequest)?
         .try_into()
         .unwrap();
 
-    // TODO: We may want to sanitize `metadata.vers` somewhat.
-    let data = read_crate(&mut body, crate_length).context("failed to read crate data")?;
-    let crate_meta_path = crate_meta_dir.join(&crate_name);
+    let data = read_crate(body, crate_length)
+        .context("failed to read crate data")
+        .map_err(PublishError::InvalidRequest)?;
+    let readme = readme(&metadata, &data);
     let entry = Entry::from((metadata, data.deref()));
+    // A malformed checksum in the index would break Cargo's verification.
+    check_cksum(&entry.cksum)?;
+
+    let crate_meta_path = index.root().join(crate_path(&crate_name)).join(&crate_name);
+    if crate_meta_path.exists() && !serve_args.allow_overwrite {
+        let entries: Entries = std::fs::read_to_string(&crate_meta_path)
+            .with_context(|| format!("failed to read {}", crate_meta_path.display()))?
+            .try_into()
+            .with_context(|| format!("invalid crate file {}", crate_meta_path.display()))?;
+        // Publishing the very same version again is harmless.
+        if !entries.contains(&entry) && entries.iter().any(|existing| existing.vers == entry.vers) {
+            return Err(PublishError::VersionExists {
+                name: crate_name,
+                version: crate_vers,
+            });
+        }
+    }
+
+    let key = crate_key(&crate_name, &crate_vers);
+    let replaced_size = storage.size(&key).await?;
+    quota.check(data.len() as u64, replaced_size)?;
+    Ok(CheckedPublish {
+        crate_name,
+        crate_vers,
+        data,
+        readme,
+        entry,
+        key,
+        replaced_size,
+    })
+}
+
+/// Check a publish request, as [`publish_crate`] would, without publishing
+/// it: nothing is written, only the errors are reported.
+pub async fn check_publish(
+    mut body: Bytes,
+    index: &Index,
+    storage: &dyn Storage,
+    quota: &Quota,
+    serve_args: &ServeArgs,
+    token: Option<&str>,
+) -> Result<(), PublishError> {
+    let publisher = token.map(publisher_id);
+    check_publish_request(
+        &mut body,
+        index,
+        storage,
+        quota,
+        serve_args,
+        publisher.as_deref(),
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Publish a crate on behalf of `publisher`, as identified by [`publisher_id`].
+pub(crate) async fn publish_crate_as(
+    mut body: Bytes,
+    index: Arc<Index>,
+    storage: &dyn Storage,
+    quota: &Quota,
+    serve_args: &ServeArgs,
+    publisher: Option<&str>,
+) -> Result<(), PublishError> {
+    let CheckedPublish {
+        crate_name,
+        crate_vers,
+        data,
+        readme,
+        entry,
+        key,
+        replaced_size,
+    } = check_publish_request(&mut body, &index, storage, quota, serve_args, publisher).await?;
+    let reservation = quota.reserve(data.len() as u64, replaced_size)?;
+
+    // Held until the crate file is committed, not to race with the other
+    // changes of the crate.
+    let writes = index.lock_writes().await;
+    let crate_meta_dir = index.root().join(crate_path(&crate_name));
+    create_dir_all(&crate_meta_dir)
+        .with_context(|| format!("failed to create directory {}", crate_meta_dir.display()))?;
+    let crate_meta_path = crate_meta_dir.join(&crate_name);
+    let fsync = !serve_args.no_fsync;
 
     if crate_meta_path.exists() {
-        let mut entries: Entries = std::fs::read_to_string(&crate_meta_path)?.try_into()?;
-        if !entries.insert(entry) {
+        let mut entries: Entries = std::fs::read_to_string(&crate_meta_path)
+            .with_context(|| format!("failed to read {}", crate_meta_path.display()))?
+            .try_into()
+            .with_context(|| format!("invalid crate file {}", crate_meta_path.display()))?;
+        if entries.contains(&entry) {
             warn!("Crate already exists in the registry. Skipping...");
             return Ok(());
         }
-        std::fs::write(&crate_meta_path, TryInto::<String>::try_into(entries)?)?;
+        // Only with --allow-overwrite, as checked above.
+        if entries.iter().any(|existing| existing.vers == entry.vers) {
+            warn!(
+                "Overwriting {} in version {} in the registry",
+                crate_name, crate_vers
+            );
+            entries.remove_version(&entry.vers);
+        }
+        entries.insert(entry);
+        let content =
+            TryInto::<String>::try_into(entries).context("failed to serialize entries")?;
+        write_file(&crate_meta_path, content.as_bytes(), fsync)
+            .with_context(|| format!("failed to write {}", crate_meta_path.display()))?;
     } else {
-        std::fs::write(&crate_meta_path, to_string(&entry)?)?;
+        let content = TryInto::<String>::try_into(Entries::from(entry))
+            .context("failed to serialize entry")?;
+        write_file(&crate_meta_path, content.as_bytes(), fsync)
+            .with_context(|| format!("failed to write {}", crate_meta_path.display()))?;
     }
 
-    let crate_file_name = crate_file_name(&crate_name, &crate_vers);
-    let crate_dir = crates_folder.join(crate_path(&crate_name));
-    create_dir_all(&crate_dir)
-        .with_context(|| format!("failed to create directory {}", crate_dir.display()))?;
-
-    let crate_path = crate_dir.join(crate_file_name);
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&crate_path)
-        .with_context(|| format!("failed to create crate file {}", crate_path.display()))?;
+    storage.put(&key, data.clone()).await?;
+    reservation.commit();
 
-    file.write(&data)
-        .with_context(|| format!("failed to write to crate file {}", crate_path.display()))?;
+    // The READMEs stay in the registry directory, whatever the storage.
+    let crate_dir = serve_args
+        .root_registry
+        .join("crates")
+        .join(crate_path(&crate_name));
+    let readme_path = crate_dir.join(readme_file_name(&crate_name, &crate_vers));
+    let mut synced_dirs = vec![&crate_meta_dir];
+    match readme {
+        Some(readme) => {
+            create_dir_all(&crate_dir)
+                .with_context(|| format!("failed to create directory {}", crate_dir.display()))?;
+            write_file(&readme_path, readme.as_bytes(), fsync).with_context(|| {
+                format!("failed to write README file {}", readme_path.display())
+            })?;
+            synced_dirs.push(&crate_dir);
+        }
+        // Don't keep the README of an overwritten version.
+        None if readme_path.exists() => {
+            std::fs::remove_file(&readme_path).with_context(|| {
+                format!("failed to remove README file {}", readme_path.display())
+            })?;
+            synced_dirs.push(&crate_dir);
+        }
+        None => (),
+    }
+    if fsync {
+        for dir in synced_dirs {
+            sync_dir(dir).with_context(|| format!("failed to sync directory {}", dir.display()))?;
+        }
+    }
 
+    let message = commit_message(
+        &serve_args.commit_message_template,
+        &crate_name,
+        &crate_vers,
+        publisher,
+    );
     index
-        .add_and_commit(
-            vec![&crate_meta_path],
-            &format!("Add {} in version {}", crate_name, crate_vers),
-        )
+        .add_and_commit(vec![&crate_meta_path], &message)
         .await
         .with_context(|| {
             format!(
@@ -307,19 +955,595 @@ pub async fn publish_crate(mut body: Bytes, index: Arc<Index>, crates_folder: &P
                 crate_meta_path.display()
             )
         })?;
+    drop(writes);
 
     if !body.is_empty() {
         warn!("body has {} bytes left", body.len());
     }
+    if let Some(hook) = &serve_args.on_publish {
+        // The hook gets the URL of the crate file when it isn't stored locally.
+        let location = storage
+            .local_path(&key)
+            .map(PathBuf::into_os_string)
+            .or_else(|| storage.url(&key).map(OsString::from))
+            .unwrap_or_else(|| OsString::from(&key));
+        spawn_publish_hook(hook, &crate_name, &crate_vers, &location);
+    }
     Ok(())
 }
 
+/// Run the `--on-publish` command for a published crate in the background,
+/// logging its failures.
+fn spawn_publish_hook(hook: &Path, name: &str, vers: &str, crate_location: &OsStr) {
+    let mut command = tokio::process::Command::new(hook);
+    command.arg(name).arg(vers).arg(crate_location);
+    let description = format!("{} {} {}", hook.display(), name, vers);
+    tokio::spawn(async move {
+        match command.status().await {
+            Ok(status) if status.success() => (),
+            Ok(status) => error!("Publish hook {} failed: {}", description, status),
+            Err(err) => error!("Failed to run publish hook {}: {}", description, err),
+        }
+    });
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     use std::path::Path;
 
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use crate::serve::tests::serve_args;
+    use crate::storage::LocalStorage;
+
+    /// Craft the body of a publish request, as sent by cargo, for a crate
+    /// with the given metadata.
+    pub(crate) fn publish_body(metadata: &serde_json::Value, data: &[u8]) -> Bytes {
+        let metadata = metadata.to_string();
+        let mut body = Vec::new();
+        body.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        body.extend_from_slice(metadata.as_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        Bytes::from(body)
+    }
+
+    /// Craft the gzipped tarball of a crate containing the given files.
+    pub(crate) fn crate_data(name: &str, vers: &str, files: &[(&str, &str)]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(
+                    &mut header,
+                    format!("{name}-{vers}/{path}"),
+                    content.as_bytes(),
+                )
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// The minimal metadata cargo sends for a crate without dependencies.
+    pub(crate) fn metadata(name: &str, vers: &str) -> serde_json::Value {
+        json!({
+            "name": name,
+            "vers": vers,
+            "deps": [],
+            "features": {},
+            "authors": [],
+            "description": null,
+            "documentation": null,
+            "homepage": null,
+            "readme": null,
+            "readme_file": null,
+            "keywords": [],
+            "categories": [],
+            "license": null,
+            "license_file": null,
+            "repository": null,
+            "badges": {},
+            "links": null,
+        })
+    }
+
+    #[tokio::test]
+    async fn publish_commit_message() {
+        let root = tempdir().unwrap();
+        let args = serve_args(
+            root.path(),
+            &[
+                "--commit-message-template",
+                "Publish {name}@{version} by {publisher}",
+            ],
+        );
+        let index = Arc::new(
+            Index::new(root.path().join("index"), &args.server_url())
+                .await
+                .unwrap(),
+        );
+        let body = publish_body(&metadata("my-lib", "1.2.3"), b"crate data");
+        let crates_folder = root.path().join("crates");
+        let quota = Quota::new(&crates_folder, &args).unwrap();
+        publish_crate(
+            body,
+            index.clone(),
+            &LocalStorage::new(&crates_folder, !args.no_fsync),
+            &quota,
+            &args,
+            Some("token"),
+        )
+        .await
+        .unwrap();
+
+        let status = index.status().await.unwrap();
+        assert_eq!(
+            status.message,
+            format!("Publish my-lib@1.2.3 by {}", publisher_id("token"))
+        );
+    }
+
+    /// Check that the features using the weak and `dep:` syntaxes are
+    /// published to `features2` of the index entry, and read back merged.
+    #[tokio::test]
+    async fn weak_dependency_features() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &[]);
+        let index = Arc::new(
+            Index::new(root.path().join("index"), &args.server_url())
+                .await
+                .unwrap(),
+        );
+        let mut metadata = metadata("my-lib", "0.1.0");
+        metadata["features"] = json!({
+            "default": ["std"],
+            "std": [],
+            "derive": ["serde?/derive"],
+            "serde": ["dep:serde", "std"],
+        });
+        let body = publish_body(&metadata, b"crate data");
+        let crates_folder = root.path().join("crates");
+        let quota = Quota::new(&crates_folder, &args).unwrap();
+        let storage = LocalStorage::new(&crates_folder, !args.no_fsync);
+        publish_crate(body, index, &storage, &quota, &args, None)
+            .await
+            .unwrap();
+
+        let line = std::fs::read_to_string(root.path().join("index/my/-l/my-lib")).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["features"], json!({"default": ["std"], "std": []}));
+        assert_eq!(
+            entry["features2"],
+            json!({"derive": ["serde?/derive"], "serde": ["dep:serde", "std"]})
+        );
+        assert_eq!(entry["v"], 2);
+
+        let entries: Entries = line.try_into().unwrap();
+        let entry = entries.iter().next().unwrap();
+        assert_eq!(entry.features.len(), 2);
+        assert_eq!(entry.features2.as_ref().unwrap().len(), 2);
+        assert_eq!(entry.v, Some(2));
+
+        // Without the new syntaxes, the entry keeps the original format.
+        let (features, features2) =
+            split_features(BTreeMap::from([("std".to_string(), Vec::new())]));
+        assert_eq!(features.len(), 1);
+        assert!(features2.is_none());
+    }
+
+    /// Publish `data` as the content of the crate `name` in version `vers`.
+    async fn publish(
+        root: &Path,
+        args: &ServeArgs,
+        name: &str,
+        vers: &str,
+        data: &[u8],
+    ) -> Result<(), PublishError> {
+        let index = Arc::new(Index::new(root.join("index"), &args.server_url()).await?);
+        let body = publish_body(&metadata(name, vers), data);
+        let crates_folder = root.join("crates");
+        let quota = Quota::new(&crates_folder, args)?;
+        let storage = LocalStorage::new(&crates_folder, !args.no_fsync);
+        publish_crate(body, index, &storage, &quota, args, None).await
+    }
+
+    /// Check that the crate and its index entry are complete once a
+    /// publish returns, with and without syncing them to the disk.
+    #[tokio::test]
+    async fn publish_writes_complete_files() {
+        let data = (0..4 * 1024 * 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        for extra_args in [&[][..], &["--no-fsync"]] {
+            let root = tempdir().unwrap();
+            let args = serve_args(root.path(), extra_args);
+            assert_eq!(args.no_fsync, !extra_args.is_empty());
+            publish(root.path(), &args, "my-lib", "0.1.0", &data)
+                .await
+                .unwrap();
+
+            let crate_file = root.path().join("crates/my/-l/my-lib-0.1.0.crate");
+            assert_eq!(std::fs::read(crate_file).unwrap(), data);
+            let index_file = root.path().join("index/my/-l/my-lib");
+            let entries: Entries = std::fs::read_to_string(index_file)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            assert_eq!(
+                entries.iter().next().unwrap().cksum,
+                format!("{:x}", Sha256::digest(&data))
+            );
+        }
+    }
+
+    /// Check that the entries of an index file are sorted by version
+    /// whatever the order of the publishes.
+    #[tokio::test]
+    async fn index_file_sorted_by_version() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &[]);
+        for vers in ["0.10.0", "0.2.0", "1.0.0", "1.0.0-alpha.1", "0.9.1"] {
+            publish(root.path(), &args, "my-lib", vers, b"data")
+                .await
+                .unwrap();
+        }
+
+        let content = std::fs::read_to_string(root.path().join("index/my/-l/my-lib")).unwrap();
+        assert!(content.ends_with("}\n"));
+        let versions = content
+            .lines()
+            .map(|line| serde_json::from_str::<Entry>(line).unwrap().vers)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            versions,
+            ["0.2.0", "0.9.1", "0.10.0", "1.0.0-alpha.1", "1.0.0"]
+        );
+    }
+
+    /// Check that concurrent publishes and yanks of a crate keep all their
+    /// changes, in the crate file and in its commit.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_publish_and_yank() {
+        let root = tempdir().unwrap();
+        let args = Arc::new(serve_args(root.path(), &[]));
+        let index = Arc::new(
+            Index::new(root.path().join("index"), &args.server_url())
+                .await
+                .unwrap(),
+        );
+        let crates_folder = root.path().join("crates");
+        let quota = Arc::new(Quota::new(&crates_folder, &args).unwrap());
+        let storage = Arc::new(LocalStorage::new(&crates_folder, !args.no_fsync));
+        let publish = |vers: String| {
+            let (index, storage, quota, args) =
+                (index.clone(), storage.clone(), quota.clone(), args.clone());
+            tokio::spawn(async move {
+                let body = publish_body(&metadata("my-lib", &vers), b"data");
+                publish_crate(body, index, storage.as_ref(), &quota, &args, None).await
+            })
+        };
+        publish("0.1.0".to_string()).await.unwrap().unwrap();
+
+        let mut tasks = Vec::new();
+        for minor in 2..20 {
+            tasks.push(publish(format!("0.{minor}.0")));
+            let index = index.clone();
+            let yanked = minor % 2 == 0;
+            tasks.push(tokio::spawn(async move {
+                index.set_yanked("my-lib", "0.1.0", yanked).await?;
+                Ok(())
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        index.set_yanked("my-lib", "0.1.0", true).await.unwrap();
+
+        let path = root.path().join("index/my/-l/my-lib");
+        let content = std::fs::read_to_string(&path).unwrap();
+        let committed = index
+            .committed_file(Path::new("my/-l/my-lib"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(committed, content.as_bytes());
+        let entries: Entries = content.try_into().unwrap();
+        assert_eq!(entries.len(), 19);
+        assert!(entries
+            .iter()
+            .all(|entry| entry.yanked == (entry.vers == "0.1.0")));
+    }
+
+    /// Check that the publish hook runs with the crate name, version and
+    /// crate file path.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn publish_hook_arguments() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let root = tempdir().unwrap();
+        let hook = root.path().join("hook.sh");
+        let output = root.path().join("hook-output");
+        std::fs::write(
+            &hook,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}.tmp && mv {0}.tmp {0}\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let args = serve_args(root.path(), &["--on-publish", hook.to_str().unwrap()]);
+        publish(root.path(), &args, "my-lib", "0.1.0", b"data")
+            .await
+            .unwrap();
+
+        // The hook runs in the background.
+        for _ in 0..100 {
+            if output.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        let crate_file = root.path().join("crates/my/-l/my-lib-0.1.0.crate");
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            format!("my-lib 0.1.0 {}\n", crate_file.display())
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_with_acl() {
+        let root = tempdir().unwrap();
+        let acl = root.path().join("acl.toml");
+        std::fs::write(
+            &acl,
+            format!(
+                "[crates]\n\"team-a-*\" = [\"{}\"]\n",
+                publisher_id("team-a-token")
+            ),
+        )
+        .unwrap();
+        let args = serve_args(root.path(), &["--acl", acl.to_str().unwrap()]);
+        let index = Arc::new(
+            Index::new(root.path().join("index"), &args.server_url())
+                .await
+                .unwrap(),
+        );
+        let crates_folder = root.path().join("crates");
+        let quota = Quota::new(&crates_folder, &args).unwrap();
+        let storage = LocalStorage::new(&crates_folder, !args.no_fsync);
+        let publish = |name: &str, token: Option<&'static str>| {
+            publish_crate(
+                publish_body(&metadata(name, "0.1.0"), b"data"),
+                index.clone(),
+                &storage,
+                &quota,
+                &args,
+                token,
+            )
+        };
+
+        publish("team-a-core", Some("team-a-token")).await.unwrap();
+        let err = publish("team-a-util", Some("team-b-token"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PublishError::NotAllowed { .. }), "{err}");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is not allowed to publish crate team-a-util",
+                publisher_id("team-b-token")
+            )
+        );
+        let err = publish("team-a-util", None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "anonymous is not allowed to publish crate team-a-util"
+        );
+        assert!(!root
+            .path()
+            .join("crates/te/am/team-a-util-0.1.0.crate")
+            .exists());
+        // Crates no pattern matches can be published by anyone.
+        publish("other", None).await.unwrap();
+
+        // The list is read on each publish.
+        std::fs::write(&acl, "[crates]\n").unwrap();
+        publish("team-a-util", Some("team-b-token")).await.unwrap();
+    }
+
+    #[test]
+    fn crate_name_collisions() {
+        let index = tempdir().unwrap();
+        for name in ["foo-bar", "a_b", "ab", "my_lib-x"] {
+            let dir = index.path().join(crate_path(name));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let conflict = |name| conflicting_crate_name(index.path(), name).unwrap();
+        assert_eq!(conflict("foo_bar").as_deref(), Some("foo-bar"));
+        assert_eq!(conflict("a-b").as_deref(), Some("a_b"));
+        assert_eq!(conflict("my-lib_x").as_deref(), Some("my_lib-x"));
+        assert_eq!(conflict("my-lib-x").as_deref(), Some("my_lib-x"));
+        // A crate doesn't conflict with itself nor with other names.
+        assert_eq!(conflict("foo-bar"), None);
+        assert_eq!(conflict("foobar"), None);
+        assert_eq!(conflict("a-c"), None);
+        assert_eq!(conflict("new-crate"), None);
+    }
+
+    #[tokio::test]
+    async fn publish_with_strict_naming() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &[]);
+        publish(root.path(), &args, "foo-bar", "0.1.0", b"data")
+            .await
+            .unwrap();
+        // Without the flag, both spellings are accepted.
+        publish(root.path(), &args, "foo_bar", "0.1.0", b"data")
+            .await
+            .unwrap();
+
+        let args = serve_args(root.path(), &["--strict-naming"]);
+        // New versions of the existing crates are fine, even conflicting ones.
+        publish(root.path(), &args, "foo-bar", "0.2.0", b"data")
+            .await
+            .unwrap();
+        publish(root.path(), &args, "foo-baz", "0.1.0", b"data")
+            .await
+            .unwrap();
+        let err = publish(root.path(), &args, "foo_baz", "0.1.0", b"data")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "crate foo_baz conflicts with the existing crate foo-baz"
+        );
+        assert!(!root.path().join("index/fo/o_/foo_baz").exists());
+    }
+
+    #[tokio::test]
+    async fn republish_rejected_by_default() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &[]);
+        publish(root.path(), &args, "my-lib", "0.1.0", b"first")
+            .await
+            .unwrap();
+        // Publishing the very same crate again is harmless.
+        publish(root.path(), &args, "my-lib", "0.1.0", b"first")
+            .await
+            .unwrap();
+
+        let err = publish(root.path(), &args, "my-lib", "0.1.0", b"second")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "crate my-lib in version 0.1.0 already exists in the registry"
+        );
+        let crate_file = root.path().join("crates/my/-l/my-lib-0.1.0.crate");
+        assert_eq!(std::fs::read(crate_file).unwrap(), b"first");
+    }
+
+    #[tokio::test]
+    async fn republish_with_allow_overwrite() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &["--allow-overwrite"]);
+        publish(root.path(), &args, "my-lib", "0.1.0", b"first")
+            .await
+            .unwrap();
+        publish(root.path(), &args, "my-lib", "0.1.0", b"second")
+            .await
+            .unwrap();
+
+        let crate_file = root.path().join("crates/my/-l/my-lib-0.1.0.crate");
+        assert_eq!(std::fs::read(crate_file).unwrap(), b"second");
+        let index_file = root.path().join("index/my/-l/my-lib");
+        let entries: Entries = std::fs::read_to_string(index_file)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries.iter().next().unwrap().cksum,
+            format!("{:x}", Sha256::digest(b"second"))
+        );
+    }
+
+    #[tokio::test]
+    async fn crate_count_limit() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &["--max-crates", "2", "--allow-overwrite"]);
+        let index = Arc::new(
+            Index::new(root.path().join("index"), &args.server_url())
+                .await
+                .unwrap(),
+        );
+        let crates_folder = root.path().join("crates");
+        let quota = Quota::new(&crates_folder, &args).unwrap();
+        let storage = LocalStorage::new(&crates_folder, !args.no_fsync);
+        let publish = |name: &str, vers: &str, data: &[u8]| {
+            publish_crate(
+                publish_body(&metadata(name, vers), data),
+                index.clone(),
+                &storage,
+                &quota,
+                &args,
+                None,
+            )
+        };
+
+        publish("my-lib", "0.1.0", b"first").await.unwrap();
+        publish("my-lib", "0.2.0", b"second").await.unwrap();
+        // Overwriting a version doesn't take another crate.
+        publish("my-lib", "0.2.0", b"third").await.unwrap();
+        let err = publish("other", "0.1.0", b"data").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "quota exceeded: the registry already holds the maximum of 2 crates"
+        );
+        assert!(index.entry("other", "0.1.0").unwrap().is_none());
+        assert!(!crates_folder.join("ot/he/other-0.1.0.crate").exists());
+
+        // The usage of the stored crates is computed on start.
+        let quota = Quota::new(&crates_folder, &args).unwrap();
+        assert!(matches!(
+            quota.check(4, None),
+            Err(PublishError::QuotaExceeded(_))
+        ));
+    }
+
+    /// Check that the usage is reserved at once and released unless
+    /// committed.
+    #[test]
+    fn quota_reservations() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &["--max-crates", "1"]);
+        let quota = Quota::new(&root.path().join("crates"), &args).unwrap();
+
+        let reservation = quota.reserve(4, None).unwrap();
+        // A concurrent publish doesn't get the room taken.
+        assert!(matches!(
+            quota.reserve(4, None),
+            Err(PublishError::QuotaExceeded(_))
+        ));
+        // Storing the file failed.
+        drop(reservation);
+        quota.reserve(4, None).unwrap().commit();
+        assert!(quota.check(4, None).is_err());
+        quota.check(4, Some(4)).unwrap();
+    }
+
+    #[test]
+    fn cksum_validation() {
+        check_cksum(&format!("{:x}", Sha256::digest(b"data"))).unwrap();
+        for cksum in ["", "00", &"A".repeat(64), &"g".repeat(64), &"0".repeat(65)] {
+            let err = check_cksum(cksum).unwrap_err();
+            assert!(err.to_string().starts_with("invalid checksum"), "{cksum}");
+        }
+    }
+
+    #[test]
+    fn commit_message_template() {
+        let template = "Add {name} in version {version}";
+        assert_eq!(
+            commit_message(template, "foo", "0.1.0", None),
+            "Add foo in version 0.1.0"
+        );
+        assert_eq!(
+            commit_message("{name}@{version} by {publisher}", "foo", "0.1.0", None),
+            "foo@0.1.0 by anonymous"
+        );
+    }
+
     #[test]
     fn parse_short_length() {
         let mut body = Bytes::from([255u8, 255, 255].as_ref());
@@ -354,4 +1578,19 @@ mod tests {
         assert_eq!(&crate_path("abcd"), Path::new("ab/cd"));
         assert_eq!(&crate_path("ydasdayusiy"), Path::new("yd/as"));
     }
+
+    /// Check that cargo's frame is accepted whatever the bytes of its JSON
+    /// length, even those a JSON body or a gzip archive start with.
+    #[test]
+    fn frame_with_json_like_length() {
+        for length in [0x17b, 0x25b, 0x27b, 0x8b1f] {
+            let mut metadata = metadata("my-lib", "0.1.0");
+            let base = metadata.to_string().len() + "\"\"".len() - "null".len();
+            metadata["description"] = json!("x".repeat(length - base));
+            let mut body = publish_body(&metadata, b"data");
+            assert_eq!(&body[..4], &(length as u32).to_le_bytes());
+            check_publish_frame(&body).unwrap();
+            assert_eq!(read_metadata(&mut body).unwrap().name, "my-lib");
+        }
+    }
 }
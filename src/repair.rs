@@ -0,0 +1,503 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+use itertools::Itertools as _;
+use serde::Deserialize;
+use sha2::Digest as _;
+use sha2::Sha256;
+use tracing::info;
+use tracing::warn;
+
+use crate::cli::RepairArgs;
+use crate::index::list_crates;
+use crate::index::split_features;
+use crate::index::Dep;
+use crate::index::Entry;
+use crate::index::Index;
+use crate::publish::crate_file_name;
+use crate::publish::crate_path;
+use crate::publish::extract_file;
+use crate::publish::Kind;
+use crate::storage::LocalStorage;
+use crate::storage::Storage as _;
+use crate::takedown::Takedown;
+
+/// The subset of the normalized `Cargo.toml` cargo packages into a crate
+/// file needed to index it.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    package: Package,
+    #[serde(default)]
+    dependencies: BTreeMap<String, DepSpec>,
+    #[serde(default, rename = "dev-dependencies")]
+    dev_dependencies: BTreeMap<String, DepSpec>,
+    #[serde(default, rename = "build-dependencies")]
+    build_dependencies: BTreeMap<String, DepSpec>,
+    /// The platform specific dependencies, by target (e.g. "cfg(windows)").
+    #[serde(default)]
+    target: BTreeMap<String, Platform>,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    links: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    #[serde(default)]
+    dependencies: BTreeMap<String, DepSpec>,
+    #[serde(default, rename = "dev-dependencies")]
+    dev_dependencies: BTreeMap<String, DepSpec>,
+    #[serde(default, rename = "build-dependencies")]
+    build_dependencies: BTreeMap<String, DepSpec>,
+}
+
+/// A dependency of the manifest: either its version requirement alone, or
+/// a table.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DepSpec {
+    Version(String),
+    Detailed(DetailedDep),
+}
+
+#[derive(Debug, Deserialize)]
+struct DetailedDep {
+    version: Option<String>,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    optional: bool,
+    #[serde(rename = "default-features", alias = "default_features")]
+    default_features: Option<bool>,
+    /// The actual name of the package when the dependency is renamed.
+    package: Option<String>,
+    /// The index of the registry of the dependency, when not this one.
+    #[serde(rename = "registry-index")]
+    registry_index: Option<String>,
+}
+
+impl DepSpec {
+    /// Convert the dependency `name` of the manifest into its index form.
+    fn into_dep(self, name: String, kind: Kind, target: Option<String>) -> Dep {
+        let detailed = match self {
+            DepSpec::Version(req) => DetailedDep {
+                version: Some(req),
+                features: Vec::new(),
+                optional: false,
+                default_features: None,
+                package: None,
+                registry_index: None,
+            },
+            DepSpec::Detailed(detailed) => detailed,
+        };
+        Dep {
+            name,
+            req: detailed.version.unwrap_or_else(|| "*".to_string()),
+            features: detailed.features,
+            optional: detailed.optional,
+            default_features: detailed.default_features.unwrap_or(true),
+            target,
+            kind: Some(kind.to_string()),
+            registry: detailed.registry_index,
+            package: detailed.package,
+        }
+    }
+}
+
+impl Manifest {
+    /// Build the index entry of the crate file `data` this is the manifest of.
+    fn into_entry(self, data: &[u8]) -> Entry {
+        let mut deps = Vec::new();
+        let tables = [
+            (None, Kind::Normal, self.dependencies),
+            (None, Kind::Dev, self.dev_dependencies),
+            (None, Kind::Build, self.build_dependencies),
+        ]
+        .into_iter()
+        .chain(self.target.into_iter().flat_map(|(target, platform)| {
+            [
+                (Some(target.clone()), Kind::Normal, platform.dependencies),
+                (Some(target.clone()), Kind::Dev, platform.dev_dependencies),
+                (Some(target), Kind::Build, platform.build_dependencies),
+            ]
+        }));
+        for (target, kind, table) in tables {
+            deps.extend(
+                table
+                    .into_iter()
+                    .map(|(name, spec)| spec.into_dep(name, kind, target.clone())),
+            );
+        }
+        let (features, features2) = split_features(self.features);
+        Entry {
+            name: self.package.name,
+            vers: self.package.version,
+            deps,
+            cksum: format!("{:x}", Sha256::digest(data)),
+            v: features2.as_ref().map(|_| 2),
+            features,
+            features2,
+            yanked: false,
+            links: self.package.links,
+        }
+    }
+}
+
+/// The inconsistencies between the crate files and the index of a registry.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The crate files without an entry in the index, left as they are.
+    pub orphaned_crates: Vec<PathBuf>,
+    /// The orphaned crate files added to the index, by name and version.
+    pub reindexed: Vec<(String, String)>,
+    /// The orphaned crate files deleted with `--delete-orphans`.
+    pub deleted_crates: Vec<PathBuf>,
+    /// The versions of the index without a crate file (and not taken down),
+    /// by name and version. They can't be downloaded: their crate files must
+    /// be added to the registry, or the versions yanked.
+    pub dangling_entries: Vec<(String, String)>,
+}
+
+/// Collect the `.crate` files under `dir`.
+pub(crate) fn collect_crate_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory {}", dir.display()))?
+    {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_crate_files(&path, files)?;
+        } else if path
+            .extension()
+            .map_or(false, |extension| extension == "crate")
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Find the name and version of the crate file at `path` from its location
+/// below `crates_folder`, if it's where the registry stores crate files.
+pub(crate) fn crate_file_version(crates_folder: &Path, path: &Path) -> Option<(String, String)> {
+    let dir = path.parent()?.strip_prefix(crates_folder).ok()?;
+    let stem = path.file_stem()?.to_str()?;
+    // Both the name and the version may contain dashes: the name is the
+    // shortest prefix followed by a valid version.
+    stem.match_indices('-').find_map(|(index, _)| {
+        let (name, vers) = (&stem[..index], &stem[index + 1..]);
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        (valid_name && semver::Version::parse(vers).is_ok() && crate_path(name) == dir)
+            .then(|| (name.to_string(), vers.to_string()))
+    })
+}
+
+/// The index entry of the crate file at `path` below `crates_folder`, from
+/// its manifest.
+pub(crate) fn crate_file_entry(crates_folder: &Path, path: &Path) -> Result<Entry> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let (name, vers) = crate_file_version(crates_folder, path)
+        .ok_or_else(|| anyhow!("{} is not named after a crate version", path.display()))?;
+    let manifest = extract_file(&data, &name, &vers, "Cargo.toml")?
+        .ok_or_else(|| anyhow!("{} has no Cargo.toml", path.display()))?;
+    let manifest: Manifest = toml::from_str(&manifest)
+        .with_context(|| format!("invalid Cargo.toml in {}", path.display()))?;
+    ensure!(
+        manifest.package.name == name && manifest.package.version == vers,
+        "{} contains the crate {} in version {}",
+        path.display(),
+        manifest.package.name,
+        manifest.package.version
+    );
+    Ok(manifest.into_entry(&data))
+}
+
+/// Add the orphaned crate file at `path` to `index` from its manifest.
+async fn reindex(index: &Index, crates_folder: &Path, path: &Path) -> Result<(String, String)> {
+    let entry = crate_file_entry(crates_folder, path)?;
+    let (name, vers) = (entry.name.clone(), entry.vers.clone());
+    index
+        .add_entry(entry, &format!("Reindex {} in version {}", name, vers))
+        .await?;
+    Ok((name, vers))
+}
+
+/// Check that the crate files of the registry match the versions of its
+/// index: report the crate files without index entry, adding them to the
+/// index from their manifest with `--reindex`, and the index entries
+/// without crate file.
+pub async fn repair(repair_args: &RepairArgs) -> Result<RepairReport> {
+    let root = &repair_args.root_registry;
+    let crates_folder = root.join("crates");
+    let mut report = RepairReport::default();
+
+    let mut indexed = HashSet::new();
+    for record in list_crates(root)? {
+        let crate_dir = crates_folder.join(crate_path(&record.name));
+        let has_file = crate_dir
+            .join(crate_file_name(&record.name, &record.version))
+            .exists();
+        if !has_file && Takedown::read(&crates_folder, &record.name, &record.version)?.is_none() {
+            report
+                .dangling_entries
+                .push((record.name.clone(), record.version.clone()));
+        }
+        indexed.insert((record.name, record.version));
+    }
+
+    let mut crate_files = Vec::new();
+    if crates_folder.exists() {
+        collect_crate_files(&crates_folder, &mut crate_files)?;
+    }
+    crate_files.sort();
+    let index = Index::open(root.join("index"))?;
+    let storage = LocalStorage::new(&crates_folder, true);
+    for path in crate_files {
+        if let Some(version) = crate_file_version(&crates_folder, &path) {
+            if indexed.contains(&version) {
+                continue;
+            }
+        }
+        if repair_args.reindex {
+            match reindex(&index, &crates_folder, &path).await {
+                Ok((name, vers)) => {
+                    info!("Reindexed {} in version {}", name, vers);
+                    report.reindexed.push((name, vers));
+                    continue;
+                }
+                Err(err) => warn!("failed to reindex {}: {:#}", path.display(), err),
+            }
+        }
+        if repair_args.delete_orphans {
+            let key = path
+                .strip_prefix(&crates_folder)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .join("/");
+            storage.delete(&key).await?;
+            info!("Deleted {}", path.display());
+            report.deleted_crates.push(path);
+            continue;
+        }
+        report.orphaned_crates.push(path);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    use crate::cli::Cli;
+    use crate::cli::Commands;
+    use crate::publish::tests::crate_data;
+
+    use clap::Parser as _;
+
+    fn repair_args(root: &Path, extra_args: &[&str]) -> RepairArgs {
+        let args = ["crates-registry", "repair", "--root-registry"]
+            .into_iter()
+            .chain([root.to_str().unwrap()])
+            .chain(extra_args.iter().copied());
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Repair(repair_args) => repair_args,
+            _ => unreachable!(),
+        }
+    }
+
+    /// An index entry for the crate `name` in version `vers`.
+    fn entry(name: &str, vers: &str) -> Entry {
+        Entry {
+            name: name.to_string(),
+            vers: vers.to_string(),
+            deps: Vec::new(),
+            cksum: "0".repeat(64),
+            features: BTreeMap::new(),
+            features2: None,
+            yanked: false,
+            links: None,
+            v: None,
+        }
+    }
+
+    #[test]
+    fn crate_file_versions() {
+        let crates = Path::new("/registry/crates");
+        assert_eq!(
+            crate_file_version(crates, &crates.join("my/-l/my-lib-0.1.0-alpha.1.crate")),
+            Some(("my-lib".to_string(), "0.1.0-alpha.1".to_string()))
+        );
+        assert_eq!(
+            crate_file_version(crates, &crates.join("3/a/abc-1.0.0+build.crate")),
+            Some(("abc".to_string(), "1.0.0+build".to_string()))
+        );
+        // Not where the registry would store the crate.
+        assert_eq!(
+            crate_file_version(crates, &crates.join("ot/he/my-lib-0.1.0.crate")),
+            None
+        );
+        assert_eq!(
+            crate_file_version(crates, &crates.join("my/-l/my-lib.crate")),
+            None
+        );
+    }
+
+    /// Check that crate files without index entry are reported, and
+    /// reindexed from their manifest with `--reindex`.
+    #[tokio::test]
+    async fn orphaned_crate_files() {
+        let root = tempdir().unwrap();
+        Index::new(root.path().join("index"), "http://127.0.0.1:0")
+            .await
+            .unwrap();
+        let manifest = r#"
+            [package]
+            name = "my-lib"
+            version = "0.1.0"
+            links = "z"
+
+            [dependencies]
+            log = "0.4"
+
+            [dependencies.serde]
+            version = "^1.0"
+            features = ["derive"]
+            optional = true
+            default-features = false
+
+            [dependencies.rng]
+            version = "0.8"
+            package = "rand"
+
+            [target."cfg(windows)".build-dependencies]
+            cc = "1"
+
+            [features]
+            std = ["serde?/std"]
+        "#;
+        let data = crate_data("my-lib", "0.1.0", &[("Cargo.toml", manifest)]);
+        let path = root.path().join("crates/my/-l/my-lib-0.1.0.crate");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, &data).unwrap();
+        // Without a manifest the crate can't be reindexed.
+        let broken = root.path().join("crates/3/a/abc-0.1.0.crate");
+        std::fs::create_dir_all(broken.parent().unwrap()).unwrap();
+        std::fs::write(&broken, crate_data("abc", "0.1.0", &[])).unwrap();
+
+        let report = repair(&repair_args(root.path(), &[])).await.unwrap();
+        assert_eq!(report.orphaned_crates, vec![broken.clone(), path.clone()]);
+        assert!(report.reindexed.is_empty());
+        assert!(report.dangling_entries.is_empty());
+        assert!(list_crates(root.path()).unwrap().is_empty());
+
+        let report = repair(&repair_args(root.path(), &["--reindex"]))
+            .await
+            .unwrap();
+        assert_eq!(report.orphaned_crates, vec![broken.clone()]);
+        assert_eq!(
+            report.reindexed,
+            vec![("my-lib".to_string(), "0.1.0".to_string())]
+        );
+
+        let index = Index::open(root.path().join("index")).unwrap();
+        let entry = index.entry("my-lib", "0.1.0").unwrap().unwrap();
+        assert_eq!(entry.cksum, format!("{:x}", Sha256::digest(&data)));
+        assert_eq!(entry.links.as_deref(), Some("z"));
+        assert_eq!(
+            entry.features2.as_ref().unwrap()["std"],
+            vec!["serde?/std".to_string()]
+        );
+        assert_eq!(entry.v, Some(2));
+        let mut deps = entry
+            .deps
+            .iter()
+            .map(|dep| {
+                (
+                    dep.name.as_str(),
+                    dep.req.as_str(),
+                    dep.kind.as_deref(),
+                    dep.target.as_deref(),
+                    dep.package.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        deps.sort();
+        assert_eq!(
+            deps,
+            vec![
+                ("cc", "1", Some("build"), Some("cfg(windows)"), None),
+                ("log", "0.4", Some("normal"), None, None),
+                ("rng", "0.8", Some("normal"), None, Some("rand")),
+                ("serde", "^1.0", Some("normal"), None, None),
+            ]
+        );
+        let serde = entry.deps.iter().find(|dep| dep.name == "serde").unwrap();
+        assert!(serde.optional && !serde.default_features);
+        assert_eq!(serde.features, vec!["derive".to_string()]);
+
+        let report = repair(&repair_args(root.path(), &["--reindex"]))
+            .await
+            .unwrap();
+        assert_eq!(report.orphaned_crates, vec![broken.clone()]);
+        assert!(report.reindexed.is_empty());
+
+        let report = repair(&repair_args(root.path(), &["--delete-orphans"]))
+            .await
+            .unwrap();
+        assert!(report.orphaned_crates.is_empty());
+        assert_eq!(report.deleted_crates, vec![broken.clone()]);
+        assert!(!broken.exists());
+        assert!(path.exists());
+    }
+
+    /// Check that the index entries without crate file are reported, but
+    /// not the versions taken down.
+    #[tokio::test]
+    async fn dangling_index_entries() {
+        let root = tempdir().unwrap();
+        let index = Index::new(root.path().join("index"), "http://127.0.0.1:0")
+            .await
+            .unwrap();
+        for vers in ["0.1.0", "0.2.0", "0.3.0"] {
+            index
+                .add_entry(entry("my-lib", vers), "Add my-lib")
+                .await
+                .unwrap();
+        }
+        let crate_dir = root.path().join("crates/my/-l");
+        std::fs::create_dir_all(&crate_dir).unwrap();
+        std::fs::write(crate_dir.join("my-lib-0.1.0.crate"), b"").unwrap();
+        std::fs::write(
+            crate_dir.join("my-lib-0.3.0.takedown.json"),
+            br#"{"reason":"legal","sunset":null}"#,
+        )
+        .unwrap();
+
+        let report = repair(&repair_args(root.path(), &["--reindex"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            report.dangling_entries,
+            vec![("my-lib".to_string(), "0.2.0".to_string())]
+        );
+        assert!(report.orphaned_crates.is_empty());
+        assert!(report.reindexed.is_empty());
+    }
+}
@@ -1,18 +1,25 @@
 use crate::cli::PackArgs;
+use crate::cli::VerifyMirrorArgs;
 use crate::download::{
     append_to_path, copy_file_create_dir_with_sha256, download, download_string,
     download_with_sha256_file, move_if_exists, move_if_exists_with_sha256, write_file_create_dir,
-    DownloadError,
+    DownloadError, RetryPolicy,
 };
-use anyhow::{anyhow, Result};
+use crate::index::list_crates;
+use crate::storage::crate_key;
+use anyhow::{anyhow, bail, ensure, Result};
 use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
 use itertools::Itertools;
-use reqwest::header::HeaderValue;
+use reqwest::header::{HeaderValue, CONTENT_LENGTH, USER_AGENT};
 use reqwest::Client;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::Duration;
 use std::{fs, io};
 use thiserror::Error;
@@ -31,6 +38,30 @@ static PLATFORMS_WINDOWS: &[&str] = &[
     "x86_64-pc-windows-msvc",
 ];
 
+/// Aliases accepted by `--platforms`, with the triples they expand to.
+/// `all` expands to every available platform.
+static PLATFORM_ALIASES: &[(&str, &[&str])] = &[
+    (
+        "linux",
+        &[
+            "x86_64-unknown-linux-gnu",
+            "x86_64-unknown-linux-musl",
+            "aarch64-unknown-linux-gnu",
+            "aarch64-unknown-linux-musl",
+            "i686-unknown-linux-gnu",
+        ],
+    ),
+    (
+        "windows",
+        &[
+            "x86_64-pc-windows-msvc",
+            "x86_64-pc-windows-gnu",
+            "i686-pc-windows-msvc",
+        ],
+    ),
+    ("macos", &["x86_64-apple-darwin", "aarch64-apple-darwin"]),
+];
+
 #[derive(Error, Debug)]
 pub enum SyncError {
     #[error("IO error: {0}")]
@@ -50,12 +81,37 @@ pub enum SyncError {
 
     #[error("Failed {count} downloads")]
     FailedDownloads { count: usize },
+
+    #[error("The channel has no files for the platforms: {}", targets.join(", "))]
+    UnavailableTargets { targets: Vec<String> },
+
+    #[error("Wrong platforms: {}", platforms.iter().join(", "))]
+    InvalidPlatforms { platforms: Vec<InvalidPlatform> },
+
+    #[error("The signature of the {channel} channel manifest can't be verified: {reason}")]
+    InvalidSignature { channel: String, reason: String },
+}
+
+/// A requested platform that isn't a known target triple, nor an alias.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidPlatform {
+    pub platform: String,
+    /// The closest known triple, if any is close enough to be a typo.
+    pub suggestion: Option<String>,
+}
+
+impl Display for InvalidPlatform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.platform)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " (did you mean {suggestion}?)")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TargetUrls {
-    pub url: String,
-    pub hash: String,
     pub xz_url: String,
     pub xz_hash: String,
 }
@@ -76,8 +132,6 @@ pub struct Pkg {
 
 #[derive(Deserialize, Debug)]
 pub struct Channel {
-    #[serde(alias = "manifest-version")]
-    pub manifest_version: String,
     pub date: String,
     pub pkg: HashMap<String, Pkg>,
 }
@@ -87,7 +141,7 @@ struct Release {
     version: String,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Platforms {
     unix: Vec<String>,
     windows: Vec<String>,
@@ -115,7 +169,7 @@ impl<'a> IntoIterator for &'a Platforms {
 
     fn into_iter(self) -> Self::IntoIter {
         PlatformsIntoIterator {
-            platforms: &self,
+            platforms: self,
             index: 0,
         }
     }
@@ -146,7 +200,14 @@ impl<'a> Iterator for PlatformsIntoIterator<'a> {
     }
 }
 
+/// Strip a single trailing slash from the source URL, which would otherwise
+/// end up in the URLs as `//dist` that some servers reject.
+pub(crate) fn normalize_source(source: &str) -> &str {
+    source.strip_suffix('/').unwrap_or(source)
+}
+
 pub async fn download_platform_list(source: &str, channel: &str) -> Result<Platforms> {
+    let source = normalize_source(source);
     let channel_url = format!("{source}/dist/channel-rust-{channel}.toml");
     let user_agent =
         HeaderValue::from_str(&format!("Offline Mirror/{}", env!("CARGO_PKG_VERSION")))
@@ -182,23 +243,75 @@ pub async fn get_platforms(pack_args: &PackArgs) -> Result<Platforms> {
     Ok(if pack_args.platforms.is_empty() {
         all_platforms
     } else {
-        pack_args.platforms.iter().cloned().try_fold(
-            Platforms::default(),
-            |mut platforms, platform| {
-                if all_platforms.windows.contains(&platform) {
-                    platforms.windows.push(platform);
-                } else if all_platforms.unix.contains(&platform) {
-                    platforms.unix.push(platform);
-                } else {
-                    return Err(anyhow!("Wrong platform: {platform}"));
-                }
-                Ok(platforms)
-            },
-        )?
+        expand_platforms(&pack_args.platforms, &all_platforms)?
     })
 }
 
-/// Synchronize one rustup-init file.
+/// Select the `requested` platforms out of `all_platforms`, after expanding
+/// the aliases of [`PLATFORM_ALIASES`] and `all`.
+fn expand_platforms(requested: &[String], all_platforms: &Platforms) -> Result<Platforms> {
+    let mut expanded = Vec::new();
+    for platform in requested {
+        if platform == "all" {
+            expanded.extend(all_platforms.into_iter().cloned());
+        } else if let Some((_, triples)) =
+            PLATFORM_ALIASES.iter().find(|(alias, _)| alias == platform)
+        {
+            expanded.extend(triples.iter().map(ToString::to_string));
+        } else {
+            expanded.push(platform.clone());
+        }
+    }
+    let mut platforms = Platforms::default();
+    let mut invalid = Vec::new();
+    for platform in expanded.into_iter().unique() {
+        if all_platforms.windows.contains(&platform) {
+            platforms.windows.push(platform);
+        } else if all_platforms.unix.contains(&platform) {
+            platforms.unix.push(platform);
+        } else {
+            let suggestion = closest_platform(&platform, all_platforms);
+            invalid.push(InvalidPlatform {
+                platform,
+                suggestion,
+            });
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(SyncError::InvalidPlatforms { platforms: invalid }.into());
+    }
+    Ok(platforms)
+}
+
+/// The triple of `all_platforms` closest to `platform`, when it's close
+/// enough for `platform` to be a typo of it.
+fn closest_platform(platform: &str, all_platforms: &Platforms) -> Option<String> {
+    all_platforms
+        .into_iter()
+        .map(|candidate| (edit_distance(platform, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 3.max(platform.len() / 5))
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Synchronize one rustup-init file, for fresh installs under rustup/dist
+/// and, with `mirror_archive`, for `rustup self update` under
+/// rustup/archive/<version>.
 #[allow(clippy::too_many_arguments)]
 pub async fn sync_one_init(
     client: &Client,
@@ -207,7 +320,8 @@ pub async fn sync_one_init(
     platform: &str,
     is_exe: bool,
     rustup_version: &str,
-    retries: usize,
+    mirror_archive: bool,
+    retries: RetryPolicy,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
     let local_path = path
@@ -233,13 +347,26 @@ pub async fn sync_one_init(
         format!("{source}/rustup/dist/{platform}/rustup-init")
     };
 
-    download_with_sha256_file(client, &source_url, &local_path, retries, false, user_agent).await?;
-    copy_file_create_dir_with_sha256(&local_path, &archive_path)?;
+    if mirror_archive {
+        download_with_sha256_file(client, &source_url, &local_path, retries, false, user_agent)
+            .await?;
+        copy_file_create_dir_with_sha256(&local_path, &archive_path)?;
+    } else {
+        download_with_sha256_file(
+            client,
+            &source_url,
+            &archive_path,
+            retries,
+            false,
+            user_agent,
+        )
+        .await?;
+    }
 
     Ok(())
 }
 
-fn registry_progress_bar(size: usize) -> ProgressBar {
+pub(crate) fn registry_progress_bar(size: usize) -> ProgressBar {
     ProgressBar::new(size as u64)
         .with_style(
             ProgressStyle::with_template(
@@ -268,7 +395,8 @@ async fn create_sync_tasks(
             let rustup_version = rustup_version.to_string();
             let path = path.to_path_buf();
             let source = pack_args.source.to_string();
-            let retries = pack_args.retries;
+            let retries = pack_args.retry_policy();
+            let mirror_archive = pack_args.mirror_rustup_archive;
             let user_agent = user_agent.clone();
             let platform = platform.clone();
             let pb = pb.clone();
@@ -281,6 +409,7 @@ async fn create_sync_tasks(
                     platform.as_str(),
                     is_exe,
                     &rustup_version,
+                    mirror_archive,
                     retries,
                     &user_agent,
                 )
@@ -318,7 +447,7 @@ pub async fn sync_rustup_init(
         &release_url,
         &release_part_path,
         None,
-        pack_args.retries,
+        pack_args.retry_policy(),
         false,
         user_agent,
     )
@@ -377,54 +506,171 @@ pub async fn sync_rustup_init(
     }
 }
 
-/// Get the rustup file downloads, in pairs of URLs and sha256 hashes.
+/// Get the rustup file downloads, in pairs of URLs and sha256 hashes. With
+/// `targets_only`, only the `rust-std` packages are listed.
+///
+/// Targets without downloadable files are logged. In `strict` mode it's an
+/// error when a requested platform (or the `*` platform) has no files at all.
 pub fn rustup_download_list(
     path: &Path,
     platforms: &Platforms,
+    strict: bool,
+    targets_only: bool,
 ) -> Result<(String, Vec<(String, String)>), SyncError> {
+    let ChannelDownloads {
+        date,
+        files,
+        unavailable,
+    } = channel_download_list(path, platforms, targets_only)?;
+    if !unavailable.is_empty() {
+        error!(
+            "The channel has no files for the platforms: {}",
+            unavailable.join(", ")
+        );
+        if strict {
+            return Err(SyncError::UnavailableTargets {
+                targets: unavailable,
+            });
+        }
+    }
+
+    Ok((date, files))
+}
+
+/// The path of the file at `url` in the mirror, relative to its root: the
+/// path of the URL without its host.
+pub(crate) fn mirror_path(url: &str) -> String {
+    url.split('/').collect::<Vec<&str>>()[3..].join("/")
+}
+
+/// The downloads of a channel file for some platforms.
+struct ChannelDownloads {
+    date: String,
+    /// The files, in pairs of URLs and sha256 hashes.
+    files: Vec<(String, String)>,
+    /// The sorted platforms without any file.
+    unavailable: Vec<String>,
+}
+
+/// Read the channel file at `path` and get the downloads of the `platforms`,
+/// only those of the `rust-std` packages with `targets_only`.
+fn channel_download_list(
+    path: &Path,
+    platforms: &Platforms,
+    targets_only: bool,
+) -> Result<ChannelDownloads, SyncError> {
     let channel_str = fs::read_to_string(path).map_err(DownloadError::Io)?;
     let channel: Channel = toml::from_str(&channel_str)?;
 
-    Ok((
-        channel.date,
-        channel
-            .pkg
-            .into_iter()
-            .filter(|(pkg_name, _)| pkg_name != "rustc-dev")
-            .flat_map(|(_, pkg)| {
-                pkg.target
-                    .into_iter()
-                    .filter(
-                        |(name, _)| platforms.contains(name) || name == "*", // The * platform contains rust-src, always download
-                    )
-                    .filter_map(|(_, target)| {
-                        target.target_urls.map(|urls| {
-                            (
-                                urls.xz_url.split('/').collect::<Vec<&str>>()[3..].join("/"),
-                                urls.xz_hash,
-                            )
-                        })
-                    })
-            })
-            .collect(),
-    ))
+    let mut files = Vec::new();
+    let mut available_targets = HashSet::new();
+    let mut expected_targets = platforms.into_iter().cloned().collect::<HashSet<_>>();
+    for (pkg_name, pkg) in channel.pkg {
+        if pkg_name == "rustc-dev" || (targets_only && pkg_name != "rust-std") {
+            continue;
+        }
+        for (name, target) in pkg.target {
+            // The * platform contains rust-src, always download
+            if !platforms.contains(&name) && name != "*" {
+                continue;
+            }
+            if name == "*" {
+                expected_targets.insert(name.clone());
+            }
+            match target.target_urls {
+                Some(urls) if target.available => {
+                    files.push((mirror_path(&urls.xz_url), urls.xz_hash));
+                    available_targets.insert(name);
+                }
+                _ => warn!("{pkg_name} is not available for {name} in the channel"),
+            }
+        }
+    }
+
+    let mut unavailable = expected_targets
+        .difference(&available_targets)
+        .cloned()
+        .collect::<Vec<_>>();
+    unavailable.sort();
+    Ok(ChannelDownloads {
+        date: channel.date,
+        files,
+        unavailable,
+    })
 }
 
+/// Check with gpg that `signature` is a signature of `file` by the public key
+/// in `key_file`, returning why it isn't.
+async fn check_signature(key_file: &Path, file: &Path, signature: &Path) -> Result<(), String> {
+    // A keyring of its own, for only this key to be trusted.
+    let home = tempfile::tempdir().map_err(|e| format!("failed to create a gpg home: {e}"))?;
+    let gpg = || {
+        let mut command = tokio::process::Command::new("gpg");
+        command
+            .args([
+                "--batch",
+                "--no-autostart",
+                "--no-permission-warning",
+                "--homedir",
+            ])
+            .arg(home.path());
+        command
+    };
+    let output = gpg()
+        .arg("--import")
+        .arg(key_file)
+        .output()
+        .await
+        .map_err(|e| format!("failed to run gpg: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "failed to import the key {}: {}",
+            key_file.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let output = gpg()
+        .args(["--status-fd", "1", "--verify"])
+        .arg(signature)
+        .arg(file)
+        .output()
+        .await
+        .map_err(|e| format!("failed to run gpg: {e}"))?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let has_status = |keyword: &str| {
+        status
+            .lines()
+            .any(|line| line.starts_with(&format!("[GNUPG:] {keyword} ")))
+    };
+    if output.status.success() && has_status("GOODSIG") {
+        Ok(())
+    } else if has_status("BADSIG") {
+        Err("bad signature".to_string())
+    } else {
+        Err(format!(
+            "no valid signature by the key: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Download one file of a channel and, with `mirror_signatures`, its `.asc`
+/// GPG signature.
+#[allow(clippy::too_many_arguments)]
 pub async fn sync_one_rustup_target(
     client: &Client,
     path: &Path,
     source: &str,
     url: &str,
     hash: &str,
-    retries: usize,
+    retries: RetryPolicy,
+    mirror_signatures: bool,
     user_agent: &HeaderValue,
 ) -> Result<(), DownloadError> {
     // Chop off the source portion of the URL, to mimic the rest of the path
     //let target_url = path.join(url[source.len()..].trim_start_matches("/"));
     let target_url = format!("{source}/{url}");
-    let target_path: PathBuf = std::iter::once(path.to_owned())
-        .chain(url.split('/').map(PathBuf::from))
-        .collect();
+    let target_path = mirrored_path(path, url);
 
     download(
         client,
@@ -435,12 +681,39 @@ pub async fn sync_one_rustup_target(
         false,
         user_agent,
     )
-    .await
+    .await?;
+
+    if mirror_signatures {
+        // The signature is checked by rustup itself, there's no hash of it.
+        download(
+            client,
+            &format!("{target_url}.asc"),
+            &append_to_path(&target_path, ".asc"),
+            None,
+            retries,
+            false,
+            user_agent,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The path in the mirror at `root` of the file at `url` relative to the
+/// source.
+fn mirrored_path(root: &Path, url: &str) -> PathBuf {
+    std::iter::once(root.to_owned())
+        .chain(url.split('/').map(PathBuf::from))
+        .collect()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChannelHistoryFile {
     pub versions: HashMap<String, Vec<String>>,
+    /// The size of the mirrored files when they were recorded, for
+    /// `--only-missing` to tell whether they changed since.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sizes: BTreeMap<String, u64>,
 }
 
 pub fn get_channel_history(path: &Path, channel: &str) -> Result<ChannelHistoryFile, SyncError> {
@@ -449,25 +722,110 @@ pub fn get_channel_history(path: &Path, channel: &str) -> Result<ChannelHistoryF
     Ok(toml::from_str(&ch_data)?)
 }
 
+/// A channel mirrored in a registry, as listed by `list-channels`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct MirroredChannel {
+    pub channel: String,
+    /// The mirrored releases of the channel, the oldest first.
+    pub releases: Vec<MirroredRelease>,
+}
+
+/// A mirrored release of a channel.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct MirroredRelease {
+    pub date: String,
+    /// Number of files mirrored for the release.
+    pub files: usize,
+}
+
+/// List the channels mirrored in the registry at `root_registry` from their
+/// history files, in the registry root and in `channel_history_dir`. The
+/// files of `channel_history_dir` take precedence.
+pub fn list_channels(
+    root_registry: &Path,
+    channel_history_dir: Option<&Path>,
+) -> Result<Vec<MirroredChannel>> {
+    let mut histories = BTreeMap::new();
+    let dirs = std::iter::once(root_registry.to_path_buf())
+        .chain(channel_history_dir.map(|dir| root_registry.join(dir)));
+    for dir in dirs {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(anyhow!(err).context(format!("failed to read {}", dir.display())))
+            }
+        };
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let Some(channel) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix("mirror-")?.strip_suffix("-history.toml"))
+            else {
+                continue;
+            };
+            let history = get_channel_history(&dir, channel).map_err(|e| {
+                anyhow!(
+                    "invalid history file {}: {e}",
+                    dir.join(&file_name).display()
+                )
+            })?;
+            histories.insert(channel.to_string(), history);
+        }
+    }
+    Ok(histories
+        .into_iter()
+        .map(|(channel, history)| {
+            let mut releases = history
+                .versions
+                .into_iter()
+                .map(|(date, files)| MirroredRelease {
+                    date,
+                    files: files.len(),
+                })
+                .collect::<Vec<_>>();
+            releases.sort_by(|a, b| a.date.cmp(&b.date));
+            MirroredChannel { channel, releases }
+        })
+        .collect())
+}
+
+/// Serializes the read-modify-write of the channel history files, so that
+/// concurrent channel syncs don't lose each other's entries.
+static CHANNEL_HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Record the files of the release of `channel` at `date` in its history
+/// file in `path`, with their size in the mirror at `root`.
 pub fn add_to_channel_history(
     path: &Path,
+    root: &Path,
     channel: &str,
     date: &str,
     files: &[(String, String)],
     extra_files: &[String],
 ) -> Result<(), SyncError> {
+    // A panic while holding the lock leaves no partial state behind.
+    let _lock = CHANNEL_HISTORY_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
     let mut channel_history = match get_channel_history(path, channel) {
         Ok(c) => c,
         Err(SyncError::Io(_)) => ChannelHistoryFile {
             versions: HashMap::new(),
+            sizes: BTreeMap::new(),
         },
-        Err(e) => return Err(e.into()),
+        Err(e) => return Err(e),
     };
 
     let files = files.iter().map(|(f, _)| f.to_string());
     let extra_files = extra_files.iter().map(|ef| ef.to_string());
 
-    let files = files.chain(extra_files).collect();
+    let files: Vec<String> = files.chain(extra_files).collect();
+    for file in &files {
+        if let Ok(metadata) = fs::metadata(mirrored_path(root, file)) {
+            channel_history.sizes.insert(file.clone(), metadata.len());
+        }
+    }
 
     channel_history.versions.insert(date.to_string(), files);
 
@@ -485,6 +843,15 @@ pub fn get_rustup_version(path: &Path) -> Result<String, SyncError> {
     Ok(release_data.version)
 }
 
+/// The path of the channel file of `channel` relative to the source.
+pub(crate) fn channel_file(channel: &str) -> String {
+    if let Some(inner_channel) = channel.strip_prefix("nightly-") {
+        format!("dist/{inner_channel}/channel-rust-nightly.toml")
+    } else {
+        format!("dist/channel-rust-{channel}.toml")
+    }
+}
+
 pub async fn sync_rustup_channel(
     path: &Path,
     pack_args: &PackArgs,
@@ -494,50 +861,122 @@ pub async fn sync_rustup_channel(
 ) -> Result<(), SyncError> {
     info!("Downloading rustup channe {} ...", channel);
     // Download channel file
-    let (channel_url, channel_path, extra_files) =
-        if let Some(inner_channel) = channel.strip_prefix("nightly-") {
-            let url = format!(
-                "{}/dist/{inner_channel}/channel-rust-nightly.toml",
-                pack_args.source
-            );
-            let path_chunk = format!("dist/{inner_channel}/channel-rust-nightly.toml");
-            let path = path.join(&path_chunk);
-            // Make sure the cleanup step doesn't delete the channel toml
-            let extra_files = vec![path_chunk.clone(), format!("{path_chunk}.sha256")];
-            (url, path, extra_files)
-        } else {
-            let url = format!("{}/dist/channel-rust-{channel}.toml", pack_args.source);
-            let path = path.join(format!("dist/channel-rust-{channel}.toml"));
-            (url, path, Vec::new())
-        };
+    let path_chunk = channel_file(channel);
+    let channel_url = format!("{}/{path_chunk}", pack_args.source);
+    let channel_path = path.join(&path_chunk);
+    let extra_files = if channel.starts_with("nightly-") {
+        // Make sure the cleanup step doesn't delete the channel toml
+        vec![path_chunk.clone(), format!("{path_chunk}.sha256")]
+    } else {
+        Vec::new()
+    };
     let channel_part_path = append_to_path(&channel_path, ".part");
     let client = Client::new();
     download_with_sha256_file(
         &client,
         &channel_url,
         &channel_part_path,
-        pack_args.retries,
+        pack_args.retry_policy(),
         true,
         user_agent,
     )
     .await?;
+    let mut extra_files = extra_files;
+    if let Some(key_file) = &pack_args.verify_manifest_signature {
+        // Next to the manifest until verified, not to replace the signature of
+        // the mirrored manifest with a bad one.
+        let signature_path = append_to_path(&channel_part_path, ".asc");
+        download(
+            &client,
+            &format!("{channel_url}.asc"),
+            &signature_path,
+            None,
+            pack_args.retry_policy(),
+            true,
+            user_agent,
+        )
+        .await?;
+        if let Err(reason) = check_signature(key_file, &channel_part_path, &signature_path).await {
+            // Neither the tampered manifest nor its signature are mirrored.
+            fs::remove_file(&channel_part_path)?;
+            fs::remove_file(append_to_path(&channel_part_path, ".sha256"))?;
+            fs::remove_file(&signature_path)?;
+            return Err(SyncError::InvalidSignature {
+                channel: channel.to_string(),
+                reason,
+            });
+        }
+        info!("The signature of the {channel} channel manifest is valid");
+        if channel.starts_with("nightly-") {
+            extra_files.push(format!("{path_chunk}.asc"));
+        }
+    }
 
     // Open toml file, find all files to download
-    let (date, files) = rustup_download_list(&channel_part_path, platforms)?;
+    let (date, files) = rustup_download_list(
+        &channel_part_path,
+        platforms,
+        pack_args.strict,
+        pack_args.targets_only,
+    )?;
+    move_if_exists(
+        &append_to_path(&channel_part_path, ".asc"),
+        &append_to_path(&channel_path, ".asc"),
+    )?;
     move_if_exists_with_sha256(&channel_part_path, &channel_path)?;
 
-    let pb = registry_progress_bar(files.len());
-    pb.enable_steady_tick(Duration::from_millis(10));
+    let history_dir = match &pack_args.channel_history_dir {
+        Some(dir) => path.join(dir),
+        None => path.to_path_buf(),
+    };
+    // The files recorded in the history of this very release were already
+    // verified by an earlier run, trust them as long as they still have the
+    // recorded size.
+    let mirrored = if pack_args.only_missing {
+        let history = get_channel_history(&history_dir, channel).ok();
+        history
+            .as_ref()
+            .and_then(|history| history.versions.get(&date))
+            .into_iter()
+            .flatten()
+            .filter(|file| {
+                let recorded = history
+                    .as_ref()
+                    .and_then(|history| history.sizes.get(*file));
+                let size = fs::metadata(mirrored_path(path, file))
+                    .ok()
+                    .filter(|metadata| metadata.is_file())
+                    .map(|metadata| metadata.len());
+                recorded.is_some() && recorded == size.as_ref()
+            })
+            .cloned()
+            .collect::<HashSet<_>>()
+    } else {
+        HashSet::new()
+    };
+    let missing = files
+        .iter()
+        .filter(|(url, _)| !mirrored.contains(url))
+        .collect::<Vec<_>>();
+    if pack_args.only_missing {
+        info!(
+            "{} of the {} files of the channel {channel} are already mirrored",
+            files.len() - missing.len(),
+            files.len()
+        );
+    }
 
-    let mut errors_occurred = 0usize;
+    let pb = registry_progress_bar(missing.len());
+    pb.enable_steady_tick(Duration::from_millis(10));
 
-    let tasks = futures::stream::iter(files.iter())
+    let mut tasks = futures::stream::iter(missing)
         .map(|(url, hash)| {
             // Clone the variables that will be moved into the tokio task.
             let client = client.clone();
             let path = path.to_path_buf();
             let source = pack_args.source.to_string();
-            let retries = pack_args.retries;
+            let retries = pack_args.retry_policy();
+            let mirror_signatures = pack_args.mirror_signatures;
             let user_agent = user_agent.clone();
             let url = url.clone();
             let hash = hash.clone();
@@ -551,50 +990,73 @@ pub async fn sync_rustup_channel(
                     &url,
                     &hash,
                     retries,
+                    mirror_signatures,
                     &user_agent,
                 )
                 .await;
 
                 pb.inc(1);
 
-                out
+                (url, out)
             })
         })
-        .buffer_unordered(pack_args.threads)
-        .collect::<Vec<_>>()
-        .await;
+        .buffer_unordered(pack_args.threads);
 
-    for res in tasks {
+    let mut failed = HashSet::new();
+    while let Some(res) = tasks.next().await {
         // Unwrap the join result.
-        let res = res.unwrap();
-
-        if let Err(e) = res {
-            match e {
-                DownloadError::NotFound { .. } => {}
-                _ => {
-                    errors_occurred += 1;
-                    error!("Download failed: {e:?}");
-                }
+        let (url, res) = res.unwrap();
+
+        match res {
+            Ok(()) | Err(DownloadError::NotFound { .. }) => {}
+            Err(e) if pack_args.fail_fast => {
+                // Dropping the stream cancels the downloads that didn't start yet.
+                error!("Download failed: {e:?}");
+                return Err(e.into());
+            }
+            Err(e) => {
+                error!("Download failed: {e:?}");
+                failed.insert(url);
             }
         }
     }
+    drop(tasks);
 
-    if errors_occurred == 0 {
-        // Write channel history file
-        add_to_channel_history(path, channel, &date, &files, &extra_files)?;
+    if failed.is_empty() || pack_args.keep_going {
+        if !failed.is_empty() {
+            warn!(
+                "{} downloads failed, the channel {channel} is incomplete",
+                failed.len()
+            );
+        }
+        let files = files
+            .into_iter()
+            .filter(|(url, _)| !failed.contains(url))
+            .collect::<Vec<_>>();
+        // Record the signatures as well, so that they're kept with their files.
+        if pack_args.mirror_signatures {
+            extra_files.extend(files.iter().map(|(url, _)| format!("{url}.asc")));
+        }
+        // Write channel history file. A targets-only pack is added to an
+        // existing installation, whose history lists all its files.
+        if !pack_args.targets_only {
+            add_to_channel_history(&history_dir, path, channel, &date, &files, &extra_files)?;
+        }
         Ok(())
     } else {
         Err(SyncError::FailedDownloads {
-            count: errors_occurred,
+            count: failed.len(),
         })
     }
 }
 
+/// Download the channels of the pinned rust versions, returning the platforms
+/// they were downloaded for.
 pub async fn download_pinned_rust_version(
     root_registry: &Path,
     pack_args: &PackArgs,
-) -> Result<()> {
-    let platforms = get_platforms(&pack_args).await?;
+) -> Result<Platforms> {
+    let platforms = get_platforms(pack_args).await?;
     let user_agent =
         HeaderValue::from_str(&format!("Offline Mirror/{}", env!("CARGO_PKG_VERSION")))?;
     info!(
@@ -604,10 +1066,15 @@ pub async fn download_pinned_rust_version(
         &platforms.into_iter().join(", ")
     );
 
+    let mut failures = Vec::new();
     // Mirror rustup-init
-    if let Err(e) = sync_rustup_init(root_registry, pack_args, &user_agent, &platforms).await {
+    if pack_args.targets_only {
+        info!("Skipping rustup-init, only the targets are packed");
+    } else if let Err(e) = sync_rustup_init(root_registry, pack_args, &user_agent, &platforms).await
+    {
         error!("Downloading rustup init files failed: {e:?}");
         error!("You will need to sync again to finish this download.");
+        failures.push(format!("rustup-init: {e}"));
     }
 
     for rust_version in &pack_args.rust_versions {
@@ -615,7 +1082,7 @@ pub async fn download_pinned_rust_version(
         if let Err(e) = sync_rustup_channel(
             root_registry,
             pack_args,
-            &rust_version,
+            rust_version,
             &user_agent,
             &platforms,
         )
@@ -626,51 +1093,1665 @@ pub async fn download_pinned_rust_version(
                 return Err(anyhow!(
                     "Pinned rust version {rust_version} could not be found"
                 ));
+            } else if let SyncError::UnavailableTargets { .. } = e {
+                return Err(anyhow!(e).context(format!("Pinned rust version {rust_version}")));
             } else {
                 error!("Downloading pinned rust {rust_version} failed: {e:?}");
                 error!("You will need to sync again to finish this download.");
+                failures.push(format!("{rust_version}: {e}"));
             }
         }
     }
 
+    check_complete(pack_args, &failures)?;
+    Ok(platforms)
+}
+
+/// With `--fail-on-partial`, fail when some downloads of the mirror failed,
+/// described by `failures`, instead of packing an incomplete mirror.
+fn check_complete(pack_args: &PackArgs, failures: &[String]) -> Result<()> {
+    if pack_args.fail_on_partial && !failures.is_empty() {
+        bail!(
+            "the mirror is incomplete, not packing it: {}",
+            failures.join(", ")
+        );
+    }
     Ok(())
 }
 
-pub async fn download_latest(root_registry: &Path, pack_args: &PackArgs) -> Result<()> {
-    let platforms = get_platforms(&pack_args).await?;
+/// Download the latest releases of the `--channels`, returning the platforms
+/// they were downloaded for.
+pub async fn download_latest(root_registry: &Path, pack_args: &PackArgs) -> Result<Platforms> {
+    let platforms = get_platforms(pack_args).await?;
     let user_agent =
         HeaderValue::from_str(&format!("Offline Mirror/{}", env!("CARGO_PKG_VERSION")))?;
 
     info!(
-        "Downloading the latest rust installations of stable and nightly for [{}] platforms ({})",
+        "Downloading the latest rust installations of {} for [{}] platforms ({})",
+        pack_args.channels.join(", "),
         platforms.len(),
         &platforms.into_iter().join(", ")
     );
 
+    let mut failures = Vec::new();
     // Mirror rustup-init
-    if let Err(e) = sync_rustup_init(root_registry, pack_args, &user_agent, &platforms).await {
+    if pack_args.targets_only {
+        info!("Skipping rustup-init, only the targets are packed");
+    } else if let Err(e) = sync_rustup_init(root_registry, pack_args, &user_agent, &platforms).await
+    {
         error!("Downloading rustup init files failed: {e:?}");
         error!("You will need to sync again to finish this download.");
+        failures.push(format!("rustup-init: {e}"));
     }
 
-    info!("Download latest stable");
-    // Mirror stable
-    if let Err(e) =
-        sync_rustup_channel(root_registry, pack_args, "stable", &user_agent, &platforms).await
-    {
-        error!("Downloading stable release failed: {e:?}");
-        warn!("You will need to sync again to finish this download.");
+    for channel in &pack_args.channels {
+        info!("Download latest {channel}");
+        if let Err(e) =
+            sync_rustup_channel(root_registry, pack_args, channel, &user_agent, &platforms).await
+        {
+            if let SyncError::UnavailableTargets { .. } = e {
+                return Err(anyhow!(e).context(format!("Latest {channel} release")));
+            }
+            error!("Downloading {channel} release failed: {e:?}");
+            warn!("You will need to sync again to finish this download.");
+            failures.push(format!("{channel}: {e}"));
+        }
     }
 
-    info!("Download latest nightly");
-    // Mirror nightly
-    if let Err(e) =
-        sync_rustup_channel(root_registry, pack_args, "nightly", &user_agent, &platforms).await
-    {
-        error!("Downloading nightly release failed: {e:?}");
-        warn!("You will need to sync again to finish this download.");
+    check_complete(pack_args, &failures)?;
+    info!("Syncing Rustup repositories complete!");
+    Ok(platforms)
+}
+
+/// The major and minor components of a version without a patch component,
+/// e.g. `1.67`.
+fn partial_version(version: &str) -> Option<(u64, u64)> {
+    let (major, minor) = version.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Whether the source has the channel file of `channel`.
+async fn channel_exists(
+    client: &Client,
+    source: &str,
+    channel: &str,
+    user_agent: &HeaderValue,
+) -> Result<bool> {
+    let url = format!("{source}/{}", channel_file(channel));
+    let response = client
+        .head(&url)
+        .header(USER_AGENT, user_agent)
+        .send()
+        .await?;
+    match response.status() {
+        status if status.is_success() => Ok(true),
+        StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(false),
+        status => Err(anyhow!("{url} returned {status}")),
     }
+}
 
-    info!("Syncing Rustup repositories complete!");
-    Ok(())
+/// The highest patch release the source has, of at most that many.
+const MAX_PATCH_RELEASES: u64 = 100;
+
+/// Resolve the `--rust-versions` to the channels to pack: the
+/// `--channel-alias`es are replaced, then versions without a patch
+/// component (e.g. `1.67`) are resolved to the latest patch release found at
+/// the source (e.g. `1.67.1`).
+pub(crate) async fn resolve_rust_versions(pack_args: &PackArgs) -> Result<Vec<String>> {
+    let user_agent =
+        HeaderValue::from_str(&format!("Offline Mirror/{}", env!("CARGO_PKG_VERSION")))?;
+    let client = Client::new();
+    let mut channels = Vec::new();
+    for version in &pack_args.rust_versions {
+        if let Some((_, channel)) = pack_args
+            .channel_alias
+            .iter()
+            .find(|(alias, _)| alias == version)
+        {
+            info!("Rust version {version} is aliased to {channel}");
+            channels.push(channel.clone());
+            continue;
+        }
+        let Some((major, minor)) = partial_version(version) else {
+            channels.push(version.clone());
+            continue;
+        };
+        let mut latest = None;
+        for patch in 0..MAX_PATCH_RELEASES {
+            let channel = format!("{major}.{minor}.{patch}");
+            if !channel_exists(&client, &pack_args.source, &channel, &user_agent).await? {
+                break;
+            }
+            latest = Some(channel);
+        }
+        let channel = latest.ok_or_else(|| {
+            anyhow!(
+                "No release of rust {version} found at {} (tried {}), use --channel-alias to \
+                 name its channel",
+                pack_args.source,
+                channel_file(&format!("{major}.{minor}.0"))
+            )
+        })?;
+        info!("Rust version {version} resolved to {channel}");
+        channels.push(channel);
+    }
+    Ok(channels)
+}
+
+/// The channels packed for the `--rust-versions`: by default the latest
+/// releases of the `--channels`.
+pub(crate) fn packed_channels(rust_versions: &[String], channels: &[String]) -> Vec<String> {
+    if rust_versions.is_empty() {
+        channels.to_vec()
+    } else {
+        rust_versions.to_vec()
+    }
+}
+
+/// What a pack of a channel would download, as computed by `verify_source`.
+#[derive(Debug)]
+pub struct ChannelSummary {
+    pub channel: String,
+    pub date: String,
+    /// Number of files to download.
+    pub files: usize,
+    /// Total size of the files in bytes, as reported by the source.
+    pub size: u64,
+}
+
+/// Get the size of a file from the source without downloading it.
+async fn remote_size(client: &Client, url: &str, user_agent: &HeaderValue) -> Result<u64> {
+    let response = client
+        .head(url)
+        .header(USER_AGENT, user_agent)
+        .send()
+        .await?
+        .error_for_status()?;
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| anyhow!("{url} has no content length"))
+}
+
+/// Check that the source is reachable and its release and channel files
+/// parse, and compute what packing the requested channels would download,
+/// without downloading the files themselves.
+pub async fn verify_source(pack_args: &PackArgs) -> Result<Vec<ChannelSummary>> {
+    let platforms = get_platforms(pack_args).await?;
+    let user_agent =
+        HeaderValue::from_str(&format!("Offline Mirror/{}", env!("CARGO_PKG_VERSION")))?;
+    let client = Client::new();
+    let dir = tempfile::tempdir()?;
+
+    let release_path = dir.path().join("release-stable.toml");
+    download(
+        &client,
+        &format!("{}/rustup/release-stable.toml", pack_args.source),
+        &release_path,
+        None,
+        pack_args.retry_policy(),
+        true,
+        &user_agent,
+    )
+    .await?;
+    info!("rustup version: {}", get_rustup_version(&release_path)?);
+
+    let mut summaries = Vec::new();
+    for channel in packed_channels(&pack_args.rust_versions, &pack_args.channels) {
+        let path_chunk = channel_file(&channel);
+        let channel_path = dir.path().join(&path_chunk);
+        download_with_sha256_file(
+            &client,
+            &format!("{}/{path_chunk}", pack_args.source),
+            &channel_path,
+            pack_args.retry_policy(),
+            true,
+            &user_agent,
+        )
+        .await
+        .map_err(|e| anyhow!(e).context(format!("Channel {channel}")))?;
+        let (date, files) = rustup_download_list(
+            &channel_path,
+            &platforms,
+            pack_args.strict,
+            pack_args.targets_only,
+        )?;
+
+        let sizes = futures::stream::iter(files.iter())
+            .map(|(url, _)| {
+                let client = client.clone();
+                let user_agent = user_agent.clone();
+                let url = format!("{}/{url}", pack_args.source);
+                async move { remote_size(&client, &url, &user_agent).await }
+            })
+            .buffer_unordered(pack_args.threads)
+            .collect::<Vec<_>>()
+            .await;
+        let size = sizes.into_iter().sum::<Result<u64>>()?;
+
+        info!(
+            "Channel {channel} ({date}): {} files, {size} bytes",
+            files.len()
+        );
+        summaries.push(ChannelSummary {
+            channel,
+            date,
+            files: files.len(),
+            size,
+        });
+    }
+    Ok(summaries)
+}
+
+/// Something a mirror lacks to install rust on one of its platforms, as
+/// found by [`check_mirror`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum MirrorGap {
+    /// The rustup-init file of the platform is missing.
+    MissingRustupInit { platform: String },
+    /// The channel file is missing.
+    MissingChannel { channel: String },
+    /// The channel file lists no files for the platform.
+    UnavailablePlatform { channel: String, platform: String },
+    /// A file listed in the channel file is missing.
+    MissingFile { channel: String, path: String },
+    /// A file listed in the channel file doesn't match its hash.
+    CorruptFile { channel: String, path: String },
+    /// The crate file of a version of the index doesn't match its checksum.
+    CorruptCrate { name: String, version: String },
+}
+
+impl Display for MirrorGap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::MissingRustupInit { platform } => {
+                write!(f, "rustup-init is missing for {platform}")
+            }
+            Self::MissingChannel { channel } => {
+                write!(f, "the channel file of {channel} is missing")
+            }
+            Self::UnavailablePlatform { channel, platform } => {
+                write!(f, "the channel {channel} has no files for {platform}")
+            }
+            Self::MissingFile { channel, path } => {
+                write!(f, "{path} of the channel {channel} is missing")
+            }
+            Self::CorruptFile { channel, path } => {
+                write!(f, "{path} of the channel {channel} doesn't match its hash")
+            }
+            Self::CorruptCrate { name, version } => {
+                write!(
+                    f,
+                    "the crate file of {name} in version {version} doesn't match its checksum"
+                )
+            }
+        }
+    }
+}
+
+/// Check that the mirror at `root` has the rustup-init file of each of the
+/// `platforms` and, for each of the `channels`, the channel file and all the
+/// files it lists for them.
+pub fn check_mirror(
+    root: &Path,
+    platforms: &Platforms,
+    channels: &[String],
+) -> Result<Vec<MirrorGap>, SyncError> {
+    let mut gaps = Vec::new();
+    let rustup_inits = platforms
+        .unix
+        .iter()
+        .map(|platform| (platform, "rustup-init"))
+        .chain(
+            platforms
+                .windows
+                .iter()
+                .map(|platform| (platform, "rustup-init.exe")),
+        );
+    for (platform, file) in rustup_inits {
+        if !root.join("rustup/dist").join(platform).join(file).is_file() {
+            gaps.push(MirrorGap::MissingRustupInit {
+                platform: platform.clone(),
+            });
+        }
+    }
+
+    for channel in channels {
+        let channel_path = root.join(channel_file(channel));
+        if !channel_path.is_file() {
+            gaps.push(MirrorGap::MissingChannel {
+                channel: channel.clone(),
+            });
+            continue;
+        }
+        let ChannelDownloads {
+            files, unavailable, ..
+        } = channel_download_list(&channel_path, platforms, false)?;
+        gaps.extend(
+            unavailable
+                .into_iter()
+                .map(|platform| MirrorGap::UnavailablePlatform {
+                    channel: channel.clone(),
+                    platform,
+                }),
+        );
+        let mut missing = files
+            .into_iter()
+            .map(|(path, _)| path)
+            .filter(|path| !root.join(path).is_file())
+            .collect::<Vec<_>>();
+        missing.sort();
+        gaps.extend(missing.into_iter().map(|path| MirrorGap::MissingFile {
+            channel: channel.clone(),
+            path,
+        }));
+    }
+    Ok(gaps)
+}
+
+/// The sha256 hash of the file at `path`.
+fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hash the `files`, pairs of paths and expected sha256 hashes, `threads` at
+/// a time and return the indices of those not matching, in order.
+pub(crate) async fn hash_mismatches(
+    files: Vec<(PathBuf, String)>,
+    threads: usize,
+) -> Result<Vec<usize>> {
+    let pb = registry_progress_bar(files.len());
+    let mut mismatches = futures::stream::iter(files.into_iter().enumerate())
+        .map(|(i, (path, expected))| {
+            let pb = pb.clone();
+            async move {
+                let hash = tokio::task::spawn_blocking(move || {
+                    file_sha256(&path)
+                        .map_err(|err| anyhow!("failed to hash {}: {err}", path.display()))
+                })
+                .await??;
+                pb.inc(1);
+                Ok::<_, anyhow::Error>((hash != expected).then_some(i))
+            }
+        })
+        .buffer_unordered(threads)
+        .filter_map(|result| async move { result.transpose() })
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    mismatches.sort_unstable();
+    Ok(mismatches)
+}
+
+/// Check the hashes of the files of the `channels` present in the mirror at
+/// `root` and of the crate files of its index, `threads` files at a time.
+async fn check_hashes(
+    root: &Path,
+    platforms: &Platforms,
+    channels: &[String],
+    threads: usize,
+) -> Result<Vec<MirrorGap>> {
+    // The files to hash, with the gap each one is when it doesn't match.
+    let mut files = Vec::new();
+    let mut gaps = Vec::new();
+    for channel in channels {
+        let channel_path = root.join(channel_file(channel));
+        if !channel_path.is_file() {
+            continue;
+        }
+        let ChannelDownloads { files: listed, .. } =
+            channel_download_list(&channel_path, platforms, false)?;
+        for (path, hash) in listed {
+            if root.join(&path).is_file() {
+                files.push((root.join(&path), hash));
+                gaps.push(MirrorGap::CorruptFile {
+                    channel: channel.clone(),
+                    path,
+                });
+            }
+        }
+    }
+    if root.join("index").is_dir() {
+        for record in list_crates(root)? {
+            let path = root
+                .join("crates")
+                .join(crate_key(&record.name, &record.version));
+            if path.is_file() {
+                files.push((path, record.cksum));
+                gaps.push(MirrorGap::CorruptCrate {
+                    name: record.name,
+                    version: record.version,
+                });
+            }
+        }
+    }
+    let mismatches = hash_mismatches(files, threads).await?;
+    Ok(gaps
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| mismatches.binary_search(i).is_ok())
+        .map(|(_, gap)| gap)
+        .collect())
+}
+
+/// Check the unpacked mirror of a registry with [`check_mirror`] and, with
+/// `--check-hashes`, the hashes of its files.
+pub async fn verify_mirror(verify_args: &VerifyMirrorArgs) -> Result<Vec<MirrorGap>> {
+    let platforms = requested_platforms(&verify_args.platforms)?;
+    let channels = packed_channels(&verify_args.rust_versions, &verify_args.channels);
+    let mut gaps = check_mirror(&verify_args.root_registry, &platforms, &channels)?;
+    if verify_args.check_hashes {
+        gaps.extend(
+            check_hashes(
+                &verify_args.root_registry,
+                &platforms,
+                &channels,
+                verify_args.threads,
+            )
+            .await?,
+        );
+    }
+    Ok(gaps)
+}
+
+/// The `requested` platforms after expanding the aliases of
+/// [`PLATFORM_ALIASES`], without a list of the available platforms to check
+/// them against.
+fn requested_platforms(requested: &[String]) -> Result<Platforms> {
+    let mut platforms = Platforms::default();
+    for platform in requested {
+        ensure!(
+            platform != "all",
+            "`all` can't be checked without the platform list of the source"
+        );
+        let triples = match PLATFORM_ALIASES.iter().find(|(alias, _)| alias == platform) {
+            Some((_, triples)) => triples.iter().map(ToString::to_string).collect(),
+            None => vec![platform.clone()],
+        };
+        for triple in triples {
+            if PLATFORMS_WINDOWS.contains(&triple.as_str()) {
+                platforms.windows.push(triple);
+            } else {
+                platforms.unix.push(triple);
+            }
+        }
+    }
+    platforms.unix = platforms.unix.into_iter().unique().collect();
+    platforms.windows = platforms.windows.into_iter().unique().collect();
+    Ok(platforms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use clap::Parser as _;
+    use tempfile::tempdir;
+    use warp::Filter as _;
+
+    use crate::{Cli, Commands};
+
+    const CHANNEL: &str = r#"
+manifest-version = "2"
+date = "2023-03-09"
+
+[pkg.rustc]
+version = "1.68.0 (2c8cc3432 2023-03-06)"
+
+[pkg.rustc.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://static.rust-lang.org/dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.gz"
+hash = "aa"
+xz_url = "https://static.rust-lang.org/dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz"
+xz_hash = "bb"
+
+[pkg.rustc.target.aarch64-unknown-linux-gnu]
+available = false
+
+[pkg.rust-src]
+version = "1.68.0 (2c8cc3432 2023-03-06)"
+
+[pkg.rust-src.target."*"]
+available = true
+url = "https://static.rust-lang.org/dist/2023-03-09/rust-src-1.68.0.tar.gz"
+hash = "cc"
+xz_url = "https://static.rust-lang.org/dist/2023-03-09/rust-src-1.68.0.tar.xz"
+xz_hash = "dd"
+"#;
+
+    fn platforms(unix: &[&str]) -> Platforms {
+        Platforms {
+            unix: unix.iter().map(ToString::to_string).collect(),
+            windows: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn concurrent_channel_history_updates() {
+        let root = tempdir().unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..2 {
+                let root = root.path();
+                scope.spawn(move || {
+                    for day in 0..20 {
+                        let date = format!("2023-0{}-{:02}", thread + 1, day + 1);
+                        let files = [(format!("dist/{date}/rustc.tar.xz"), String::new())];
+                        add_to_channel_history(root, root, "stable", &date, &files, &[]).unwrap();
+                    }
+                });
+            }
+        });
+
+        let history = get_channel_history(root.path(), "stable").unwrap();
+        assert_eq!(history.versions.len(), 40);
+        assert_eq!(
+            history.versions["2023-02-20"],
+            ["dist/2023-02-20/rustc.tar.xz"]
+        );
+    }
+
+    #[test]
+    fn source_normalization() {
+        assert_eq!(
+            normalize_source("https://static.rust-lang.org/"),
+            "https://static.rust-lang.org"
+        );
+        assert_eq!(
+            normalize_source("https://static.rust-lang.org"),
+            "https://static.rust-lang.org"
+        );
+        assert_eq!(
+            normalize_source("http://mirror/rust/"),
+            "http://mirror/rust"
+        );
+    }
+
+    #[test]
+    fn platform_aliases() {
+        let all_platforms = Platforms {
+            unix: [
+                "aarch64-apple-darwin",
+                "aarch64-unknown-linux-gnu",
+                "aarch64-unknown-linux-musl",
+                "i686-unknown-linux-gnu",
+                "x86_64-apple-darwin",
+                "x86_64-unknown-linux-gnu",
+                "x86_64-unknown-linux-musl",
+                "x86_64-unknown-freebsd",
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+            windows: PLATFORMS_WINDOWS.iter().map(ToString::to_string).collect(),
+        };
+        let expand = |requested: &[&str]| {
+            let requested: Vec<String> = requested.iter().map(ToString::to_string).collect();
+            expand_platforms(&requested, &all_platforms)
+        };
+
+        let platforms = expand(&["linux", "x86_64-unknown-linux-gnu", "windows"]).unwrap();
+        assert_eq!(
+            platforms.unix,
+            [
+                "x86_64-unknown-linux-gnu",
+                "x86_64-unknown-linux-musl",
+                "aarch64-unknown-linux-gnu",
+                "aarch64-unknown-linux-musl",
+                "i686-unknown-linux-gnu",
+            ]
+        );
+        assert_eq!(
+            platforms.windows,
+            [
+                "x86_64-pc-windows-msvc",
+                "x86_64-pc-windows-gnu",
+                "i686-pc-windows-msvc",
+            ]
+        );
+
+        let platforms = expand(&["macos"]).unwrap();
+        assert_eq!(
+            platforms.unix,
+            ["x86_64-apple-darwin", "aarch64-apple-darwin"]
+        );
+        assert!(platforms.windows.is_empty());
+
+        let platforms = expand(&["all", "macos"]).unwrap();
+        assert_eq!(platforms.unix, all_platforms.unix);
+        assert_eq!(platforms.windows, all_platforms.windows);
+
+        assert!(expand(&["solaris"]).is_err());
+    }
+
+    #[test]
+    fn invalid_platforms_suggestions() {
+        let all_platforms = Platforms {
+            unix: vec![
+                "x86_64-unknown-linux-gnu".to_string(),
+                "aarch64-apple-darwin".to_string(),
+            ],
+            windows: PLATFORMS_WINDOWS.iter().map(ToString::to_string).collect(),
+        };
+        let requested = [
+            "x86_64-unknwon-linux-gnu",
+            "x86_64-unknown-linux-gnu",
+            "aarch64-apple-drawin",
+            "solaris",
+        ]
+        .map(ToString::to_string);
+        let err = expand_platforms(&requested, &all_platforms).unwrap_err();
+        let Some(SyncError::InvalidPlatforms { platforms }) = err.downcast_ref::<SyncError>()
+        else {
+            panic!("unexpected error: {err:#}");
+        };
+        let suggestion = |triple: &str| Some(triple.to_string());
+        assert_eq!(
+            platforms,
+            &[
+                InvalidPlatform {
+                    platform: "x86_64-unknwon-linux-gnu".to_string(),
+                    suggestion: suggestion("x86_64-unknown-linux-gnu"),
+                },
+                InvalidPlatform {
+                    platform: "aarch64-apple-drawin".to_string(),
+                    suggestion: suggestion("aarch64-apple-darwin"),
+                },
+                InvalidPlatform {
+                    platform: "solaris".to_string(),
+                    suggestion: None,
+                },
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "Wrong platforms: x86_64-unknwon-linux-gnu (did you mean x86_64-unknown-linux-gnu?), \
+             aarch64-apple-drawin (did you mean aarch64-apple-darwin?), solaris"
+        );
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn platforms_json() {
+        let platforms = Platforms {
+            unix: vec!["x86_64-unknown-linux-gnu".to_string()],
+            windows: vec!["x86_64-pc-windows-msvc".to_string()],
+        };
+        let json = serde_json::to_string(&platforms).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "unix": ["x86_64-unknown-linux-gnu"],
+                "windows": ["x86_64-pc-windows-msvc"],
+            })
+        );
+    }
+
+    #[test]
+    fn download_list_skips_unavailable_targets() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("channel-rust-stable.toml");
+        fs::write(&path, CHANNEL).unwrap();
+
+        let platforms = platforms(&["x86_64-unknown-linux-gnu", "aarch64-unknown-linux-gnu"]);
+        let (date, mut files) = rustup_download_list(&path, &platforms, false, false).unwrap();
+        files.sort();
+        assert_eq!(date, "2023-03-09");
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "dist/2023-03-09/rust-src-1.68.0.tar.xz".to_string(),
+                    "dd".to_string()
+                ),
+                (
+                    "dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz".to_string(),
+                    "bb".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn targets_only_download_list() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("channel-rust-stable.toml");
+        let mut channel = CHANNEL.to_string();
+        channel.push_str("\n[pkg.rust-std]\nversion = \"1.68.0 (2c8cc3432 2023-03-06)\"\n");
+        for target in ["x86_64-unknown-linux-gnu", "wasm32-unknown-unknown"] {
+            channel.push_str(&format!(
+                r#"
+[pkg.rust-std.target.{target}]
+available = true
+url = "https://static.rust-lang.org/dist/2023-03-09/rust-std-1.68.0-{target}.tar.gz"
+hash = "{target}"
+xz_url = "https://static.rust-lang.org/dist/2023-03-09/rust-std-1.68.0-{target}.tar.xz"
+xz_hash = "{target}"
+"#
+            ));
+        }
+        fs::write(&path, channel).unwrap();
+
+        let platforms = platforms(&["x86_64-unknown-linux-gnu", "wasm32-unknown-unknown"]);
+        let (_, mut files) = rustup_download_list(&path, &platforms, true, true).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "dist/2023-03-09/rust-std-1.68.0-wasm32-unknown-unknown.tar.xz".to_string(),
+                    "wasm32-unknown-unknown".to_string()
+                ),
+                (
+                    "dist/2023-03-09/rust-std-1.68.0-x86_64-unknown-linux-gnu.tar.xz".to_string(),
+                    "x86_64-unknown-linux-gnu".to_string()
+                ),
+            ]
+        );
+
+        // The full toolchain has rustc and rust-src as well.
+        let (_, files) = rustup_download_list(&path, &platforms, false, false).unwrap();
+        assert_eq!(files.len(), 4);
+    }
+
+    /// Check the gaps reported for a mirror of the stable channel lacking
+    /// the aarch64 files.
+    #[tokio::test]
+    async fn mirror_missing_a_platform() {
+        let root = tempdir().unwrap();
+        let path = root.path();
+        let write = |file: &str, content: &str| {
+            write_file_create_dir(&path.join(file), content).unwrap();
+        };
+        write("dist/channel-rust-stable.toml", CHANNEL);
+        write(
+            "rustup/dist/x86_64-unknown-linux-gnu/rustup-init",
+            "rustup-init",
+        );
+        write(
+            "dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+            "rustc",
+        );
+
+        let gaps = check_mirror(
+            path,
+            &platforms(&["x86_64-unknown-linux-gnu", "aarch64-unknown-linux-gnu"]),
+            &["stable".to_string(), "nightly".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            gaps,
+            [
+                MirrorGap::MissingRustupInit {
+                    platform: "aarch64-unknown-linux-gnu".to_string()
+                },
+                MirrorGap::UnavailablePlatform {
+                    channel: "stable".to_string(),
+                    platform: "aarch64-unknown-linux-gnu".to_string()
+                },
+                MirrorGap::MissingFile {
+                    channel: "stable".to_string(),
+                    path: "dist/2023-03-09/rust-src-1.68.0.tar.xz".to_string()
+                },
+                MirrorGap::MissingChannel {
+                    channel: "nightly".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            gaps[1].to_string(),
+            "the channel stable has no files for aarch64-unknown-linux-gnu"
+        );
+
+        write("dist/2023-03-09/rust-src-1.68.0.tar.xz", "rust-src");
+        let args = [
+            "crates-registry",
+            "verify-mirror",
+            "--root-registry",
+            path.to_str().unwrap(),
+            "--platforms",
+            "x86_64-unknown-linux-gnu",
+            "--rust-versions",
+            "stable",
+        ];
+        let verify_args = match Cli::try_parse_from(args).unwrap().command {
+            Commands::VerifyMirror(verify_args) => verify_args,
+            _ => unreachable!(),
+        };
+        assert_eq!(verify_mirror(&verify_args).await.unwrap(), []);
+
+        // The hashes of the channel file don't match the content of the files.
+        let verify_args = match Cli::try_parse_from(args.iter().chain(&["--check-hashes"]))
+            .unwrap()
+            .command
+        {
+            Commands::VerifyMirror(verify_args) => verify_args,
+            _ => unreachable!(),
+        };
+        let gaps = verify_mirror(&verify_args).await.unwrap();
+        let mut corrupt = gaps.iter().map(ToString::to_string).collect::<Vec<_>>();
+        corrupt.sort();
+        assert_eq!(
+            corrupt,
+            [
+                "dist/2023-03-09/rust-src-1.68.0.tar.xz of the channel stable doesn't match its hash",
+                "dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz of the channel stable \
+                 doesn't match its hash",
+            ]
+        );
+    }
+
+    /// Check that hashing in parallel finds the same mismatching files as
+    /// hashing one file at a time.
+    #[tokio::test]
+    async fn parallel_hash_check() {
+        let root = tempdir().unwrap();
+        let files = (0..20)
+            .map(|i| {
+                let path = root.path().join(format!("file-{i}"));
+                let content = format!("content {i}");
+                fs::write(&path, &content).unwrap();
+                // The 7th file is corrupted.
+                let hashed = if i == 7 { "corrupted" } else { &content };
+                (path, format!("{:x}", Sha256::digest(hashed)))
+            })
+            .collect::<Vec<_>>();
+
+        let serial = hash_mismatches(files.clone(), 1).await.unwrap();
+        assert_eq!(serial, [7]);
+        assert_eq!(hash_mismatches(files.clone(), 8).await.unwrap(), serial);
+
+        let mut missing = files;
+        missing.push((root.path().join("missing"), String::new()));
+        assert!(hash_mismatches(missing, 8).await.is_err());
+    }
+
+    #[test]
+    fn requested_platforms_without_source() {
+        let platforms =
+            requested_platforms(&["windows".to_string(), "x86_64-pc-windows-msvc".to_string()])
+                .unwrap();
+        assert!(platforms.unix.is_empty());
+        assert_eq!(
+            platforms.windows,
+            [
+                "x86_64-pc-windows-msvc",
+                "x86_64-pc-windows-gnu",
+                "i686-pc-windows-msvc"
+            ]
+        );
+        assert!(requested_platforms(&["all".to_string()]).is_err());
+    }
+
+    fn pack_args(extra_args: &[&str]) -> PackArgs {
+        let args = ["crates-registry", "pack", "--pack-file", "mirror.tar"]
+            .into_iter()
+            .chain(extra_args.iter().copied());
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Pack(pack_args) => pack_args,
+            _ => unreachable!(),
+        }
+    }
+
+    fn sha256(data: &str) -> String {
+        format!("{:x}", Sha256::digest(data.as_bytes()))
+    }
+
+    /// Serve a stable channel with three files, the rust-src one being
+    /// corrupted. Only the path of the file URLs matters, they are
+    /// downloaded from the source.
+    fn fixture_source() -> SocketAddr {
+        let files = [
+            (
+                "rustc",
+                "rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+                "rustc",
+            ),
+            (
+                "cargo",
+                "cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+                "cargo",
+            ),
+            ("rust-src", "rust-src-1.68.0.tar.xz", "rust-src"),
+        ];
+        let mut channel = String::from("manifest-version = \"2\"\ndate = \"2023-03-09\"\n");
+        for (pkg, file, content) in files {
+            let target = if pkg == "rust-src" {
+                "\"*\""
+            } else {
+                "x86_64-unknown-linux-gnu"
+            };
+            channel.push_str(&format!(
+                r#"
+[pkg.{pkg}]
+version = "1.68.0"
+
+[pkg.{pkg}.target.{target}]
+available = true
+url = "https://static.rust-lang.org/dist/2023-03-09/{file}.gz"
+hash = "00"
+xz_url = "https://static.rust-lang.org/dist/2023-03-09/{file}"
+xz_hash = "{}"
+"#,
+                sha256(content)
+            ));
+        }
+        let channel_sha256 = format!("{}  channel-rust-stable.toml", sha256(&channel));
+        // The platforms are listed from the nightly channel.
+        let nightly = channel.clone();
+        let nightly_sha256 = format!("{}  channel-rust-nightly.toml", sha256(&nightly));
+        let routes = warp::path!("dist" / "channel-rust-stable.toml")
+            .map(move || channel.clone())
+            .or(warp::path!("dist" / "channel-rust-nightly.toml").map(move || nightly.clone()))
+            .or(warp::path!("rustup" / "release-stable.toml").map(|| "version = \"1.25.2\"\n"))
+            .or(
+                warp::path!("rustup" / "dist" / String / String).map(|_, file: String| {
+                    if file.ends_with(".sha256") {
+                        format!("{}  rustup-init", sha256("rustup-init"))
+                    } else {
+                        "rustup-init".to_string()
+                    }
+                }),
+            )
+            .or(warp::path!("dist" / "channel-rust-stable.toml.sha256")
+                .map(move || channel_sha256.clone()))
+            .or(warp::path!("dist" / "channel-rust-nightly.toml.sha256")
+                .map(move || nightly_sha256.clone()))
+            .or(
+                warp::path!("dist" / "2023-03-09" / String).map(|file: String| {
+                    if file.starts_with("rust-src") {
+                        "corrupted".to_string()
+                    } else {
+                        file.split('-').next().unwrap().to_string()
+                    }
+                }),
+            );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn sync_fixture(root: &Path, extra_args: &[&str]) -> Result<(), SyncError> {
+        let source = format!("http://{}", fixture_source());
+        let pack_args = pack_args(
+            &[
+                &[
+                    "--source",
+                    &source,
+                    "--retries",
+                    "0",
+                    "--max-retry-duration",
+                    "0",
+                ],
+                extra_args,
+            ]
+            .concat(),
+        );
+        let user_agent = HeaderValue::from_static("test");
+        sync_rustup_channel(
+            root,
+            &pack_args,
+            "stable",
+            &user_agent,
+            &platforms(&["x86_64-unknown-linux-gnu"]),
+        )
+        .await
+    }
+
+    async fn sync_init_fixture(root: &Path, extra_args: &[&str]) {
+        let source = format!("http://{}", fixture_source());
+        let pack_args = pack_args(
+            &[
+                &[
+                    "--source",
+                    &source,
+                    "--retries",
+                    "0",
+                    "--max-retry-duration",
+                    "0",
+                ],
+                extra_args,
+            ]
+            .concat(),
+        );
+        let user_agent = HeaderValue::from_static("test");
+        sync_rustup_init(
+            root,
+            &pack_args,
+            &user_agent,
+            &platforms(&["x86_64-unknown-linux-gnu"]),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Check that rustup-init is mirrored both for fresh installs and for
+    /// `rustup self update`.
+    #[tokio::test]
+    async fn rustup_init_in_dist_and_archive() {
+        let root = tempdir().unwrap();
+        sync_init_fixture(root.path(), &[]).await;
+        for path in [
+            "rustup/dist/x86_64-unknown-linux-gnu/rustup-init",
+            "rustup/archive/1.25.2/x86_64-unknown-linux-gnu/rustup-init",
+        ] {
+            let path = root.path().join(path);
+            assert_eq!(fs::read_to_string(&path).unwrap(), "rustup-init");
+            assert!(append_to_path(&path, ".sha256").exists());
+        }
+        assert!(root.path().join("rustup/release-stable.toml").exists());
+
+        let root = tempdir().unwrap();
+        sync_init_fixture(root.path(), &["--mirror-rustup-archive", "false"]).await;
+        assert!(root
+            .path()
+            .join("rustup/dist/x86_64-unknown-linux-gnu/rustup-init")
+            .exists());
+        assert!(!root.path().join("rustup/archive").exists());
+    }
+
+    #[tokio::test]
+    async fn failed_download_fails_channel() {
+        let root = tempdir().unwrap();
+        let err = sync_fixture(root.path(), &[]).await.unwrap_err();
+        assert!(matches!(err, SyncError::FailedDownloads { count: 1 }));
+        // All the other files were still downloaded.
+        assert!(root
+            .path()
+            .join("dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz")
+            .exists());
+        assert!(root
+            .path()
+            .join("dist/2023-03-09/cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz")
+            .exists());
+        assert!(get_channel_history(root.path(), "stable").is_err());
+    }
+
+    #[tokio::test]
+    async fn fail_fast_stops_at_first_error() {
+        let root = tempdir().unwrap();
+        let err = sync_fixture(root.path(), &["--fail-fast", "--threads", "1"])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Download(DownloadError::MismatchedHash { .. })
+        ));
+        assert!(get_channel_history(root.path(), "stable").is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_going_succeeds_without_failed_files() {
+        let root = tempdir().unwrap();
+        sync_fixture(root.path(), &["--keep-going"]).await.unwrap();
+        let history = get_channel_history(root.path(), "stable").unwrap();
+        let mut files = history.versions["2023-03-09"].clone();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "dist/2023-03-09/cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+                "dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+            ]
+        );
+    }
+
+    /// Sign each of `data` with a new GPG key, returning the armored public
+    /// key and signatures.
+    fn gpg_sign(data: &[&str]) -> (String, Vec<String>) {
+        let home = tempdir().unwrap();
+        let gpg = |args: &[&str]| {
+            let output = std::process::Command::new("gpg")
+                .args([
+                    "--batch",
+                    "--passphrase",
+                    "",
+                    "--no-permission-warning",
+                    "--homedir",
+                ])
+                .arg(home.path())
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{output:?}");
+            String::from_utf8(output.stdout).unwrap()
+        };
+        gpg(&[
+            "--quick-gen-key",
+            "Test <test@example.com>",
+            "ed25519",
+            "sign",
+            "never",
+        ]);
+        let signatures = data
+            .iter()
+            .map(|data| {
+                let file = home.path().join("data");
+                fs::write(&file, data).unwrap();
+                gpg(&["--yes", "--armor", "--detach-sign", file.to_str().unwrap()]);
+                fs::read_to_string(append_to_path(&file, ".asc")).unwrap()
+            })
+            .collect();
+        let key = gpg(&["--armor", "--export"]);
+        std::process::Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(home.path())
+            .args(["--kill", "gpg-agent"])
+            .status()
+            .unwrap();
+        (key, signatures)
+    }
+
+    /// Check that a channel manifest is only mirrored, with its signature,
+    /// when signed by the `--verify-manifest-signature` key.
+    #[tokio::test]
+    async fn manifest_signature_verified() {
+        let channel = format!(
+            r#"manifest-version = "2"
+date = "2023-03-09"
+
+[pkg.rustc]
+version = "1.68.0"
+
+[pkg.rustc.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://static.rust-lang.org/dist/2023-03-09/rustc.tar.gz"
+hash = "00"
+xz_url = "https://static.rust-lang.org/dist/2023-03-09/rustc.tar.xz"
+xz_hash = "{}"
+"#,
+            sha256("rustc")
+        );
+        let (key, signatures) = gpg_sign(&[&channel, &format!("{channel}\n")]);
+        let (_, other_signatures) = gpg_sign(&[&channel]);
+        let [signature, tampered_signature] = <[String; 2]>::try_from(signatures).unwrap();
+        let other_signature = other_signatures.into_iter().next().unwrap();
+
+        let dir = tempdir().unwrap();
+        let key_file = dir.path().join("rust-key.gpg.ascii");
+        fs::write(&key_file, key).unwrap();
+        for (signature, failure) in [
+            (signature, None),
+            (other_signature, Some("no valid signature by the key")),
+            (tampered_signature, Some("bad signature")),
+        ] {
+            let served_channel = channel.clone();
+            let channel_sha256 = format!("{}  channel-rust-stable.toml", sha256(&channel));
+            let asc = signature.clone();
+            let routes = warp::path!("dist" / "channel-rust-stable.toml")
+                .map(move || served_channel.clone())
+                .or(warp::path!("dist" / "channel-rust-stable.toml.sha256")
+                    .map(move || channel_sha256.clone()))
+                .or(warp::path!("dist" / "channel-rust-stable.toml.asc").map(move || asc.clone()))
+                .or(warp::path!("dist" / "2023-03-09" / "rustc.tar.xz").map(|| "rustc"));
+            let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+
+            let root = tempdir().unwrap();
+            // The manifest mirrored by an earlier run, verified then.
+            let channel_path = root.path().join("dist/channel-rust-stable.toml");
+            fs::create_dir_all(channel_path.parent().unwrap()).unwrap();
+            fs::write(&channel_path, "previous").unwrap();
+            fs::write(append_to_path(&channel_path, ".asc"), "previous signature").unwrap();
+            let pack_args = pack_args(&[
+                "--source",
+                &format!("http://{addr}"),
+                "--verify-manifest-signature",
+                key_file.to_str().unwrap(),
+            ]);
+            let result = sync_rustup_channel(
+                root.path(),
+                &pack_args,
+                "stable",
+                &HeaderValue::from_static("test"),
+                &platforms(&["x86_64-unknown-linux-gnu"]),
+            )
+            .await;
+            if let Some(failure) = failure {
+                let err = result.unwrap_err();
+                assert!(
+                    matches!(&err, SyncError::InvalidSignature { channel, reason }
+                        if channel == "stable" && reason.starts_with(failure)),
+                    "{err}"
+                );
+                assert_eq!(fs::read_to_string(&channel_path).unwrap(), "previous");
+                assert_eq!(
+                    fs::read_to_string(append_to_path(&channel_path, ".asc")).unwrap(),
+                    "previous signature"
+                );
+                assert_eq!(fs::read_dir(root.path().join("dist")).unwrap().count(), 2);
+            } else {
+                result.unwrap();
+                assert_eq!(fs::read_to_string(&channel_path).unwrap(), channel);
+                assert_eq!(
+                    fs::read_to_string(append_to_path(&channel_path, ".asc")).unwrap(),
+                    signature
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn channels_listed_from_history() {
+        let root = tempdir().unwrap();
+        let history = |dir: &Path, channel: &str, releases: &[(&str, usize)]| {
+            let versions = releases
+                .iter()
+                .map(|(date, files)| {
+                    let files = (0..*files)
+                        .map(|i| format!("dist/{date}/file-{i}.tar.xz"))
+                        .collect();
+                    (date.to_string(), files)
+                })
+                .collect();
+            fs::create_dir_all(dir).unwrap();
+            fs::write(
+                dir.join(format!("mirror-{channel}-history.toml")),
+                toml::to_string(&ChannelHistoryFile {
+                    versions,
+                    sizes: BTreeMap::new(),
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        };
+        history(
+            root.path(),
+            "stable",
+            &[("2023-03-28", 40), ("2023-03-09", 38)],
+        );
+        history(root.path(), "1.67.1", &[("2023-02-09", 12)]);
+        history(root.path(), "nightly-2023-03-01", &[("2023-03-01", 50)]);
+        // The files of the history directory take precedence.
+        history(
+            &root.path().join("history"),
+            "1.67.1",
+            &[("2023-02-09", 30)],
+        );
+        fs::write(root.path().join("config.toml"), "").unwrap();
+
+        let summary = |channel_history_dir: Option<&Path>| {
+            list_channels(root.path(), channel_history_dir)
+                .unwrap()
+                .into_iter()
+                .map(|channel| {
+                    let releases = channel
+                        .releases
+                        .into_iter()
+                        .map(|release| (release.date, release.files))
+                        .collect::<Vec<_>>();
+                    (channel.channel, releases)
+                })
+                .collect::<Vec<_>>()
+        };
+        let release = |date: &str, files| (date.to_string(), files);
+        assert_eq!(
+            summary(None),
+            vec![
+                ("1.67.1".to_string(), vec![release("2023-02-09", 12)]),
+                (
+                    "nightly-2023-03-01".to_string(),
+                    vec![release("2023-03-01", 50)]
+                ),
+                (
+                    "stable".to_string(),
+                    vec![release("2023-03-09", 38), release("2023-03-28", 40)]
+                ),
+            ]
+        );
+        assert_eq!(
+            summary(Some(Path::new("history")))[0],
+            ("1.67.1".to_string(), vec![release("2023-02-09", 30)])
+        );
+        assert!(list_channels(&root.path().join("missing"), None)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Check that only the latest releases of the `--channels` are mirrored.
+    #[tokio::test]
+    async fn latest_channels_synced() {
+        let source = format!("http://{}", fixture_source());
+        assert_eq!(pack_args(&[]).channels, ["stable", "nightly"]);
+        let root = tempdir().unwrap();
+        let pack_args = pack_args(&[
+            "--source",
+            &source,
+            "--channels",
+            "nightly",
+            "--platforms",
+            "x86_64-unknown-linux-gnu",
+            "--keep-going",
+        ]);
+        download_latest(root.path(), &pack_args).await.unwrap();
+        assert!(get_channel_history(root.path(), "nightly").is_ok());
+        assert!(get_channel_history(root.path(), "stable").is_err());
+        assert!(!root.path().join("dist/channel-rust-stable.toml").exists());
+    }
+
+    /// Check that a pack with a failed download isn't written with
+    /// `--fail-on-partial`, and is otherwise.
+    #[tokio::test]
+    async fn fail_on_partial_skips_pack() {
+        let source = format!("http://{}", fixture_source());
+        let dir = tempdir().unwrap();
+        for (extra_args, packed) in [(&["--fail-on-partial"][..], false), (&[], true)] {
+            let pack_file = dir.path().join(format!("mirror-{packed}.tar"));
+            let mut pack_args = pack_args(
+                &[
+                    &[
+                        "--source",
+                        &source,
+                        "--rust-versions",
+                        "stable",
+                        "--platforms",
+                        "x86_64-unknown-linux-gnu",
+                        "--retries",
+                        "0",
+                        "--max-retry-duration",
+                        "0",
+                    ],
+                    extra_args,
+                ]
+                .concat(),
+            );
+            pack_args.pack_file = Some(pack_file.clone());
+            let packing = crate::pack::pack(pack_args).await;
+            if packed {
+                packing.unwrap();
+            } else {
+                let err = packing.unwrap_err().to_string();
+                assert!(
+                    err.starts_with("the mirror is incomplete, not packing it: stable: "),
+                    "{err}"
+                );
+            }
+            assert_eq!(pack_file.exists(), packed);
+        }
+    }
+
+    /// Check that `--checksum-algorithm` lists every packed file in a
+    /// CHECKSUMS file `sha256sum -c` accepts.
+    #[tokio::test]
+    async fn pack_checksums() {
+        let source = format!("http://{}", fixture_source());
+        let dir = tempdir().unwrap();
+        let pack_file = dir.path().join("mirror.tar");
+        let mut pack_args = pack_args(&[
+            "--source",
+            &source,
+            "--rust-versions",
+            "stable",
+            "--platforms",
+            "x86_64-unknown-linux-gnu",
+            "--keep-going",
+            "--checksum-algorithm",
+            "sha256",
+        ]);
+        pack_args.pack_file = Some(pack_file.clone());
+        crate::pack::pack(pack_args).await.unwrap();
+
+        let unpacked = dir.path().join("unpacked");
+        tar::Archive::new(std::fs::File::open(&pack_file).unwrap())
+            .unpack(&unpacked)
+            .unwrap();
+        let checksums = std::fs::read_to_string(unpacked.join("CHECKSUMS.sha256")).unwrap();
+        let mut listed = Vec::new();
+        for line in checksums.lines() {
+            let (hash, path) = line.split_once("  ").unwrap();
+            let data = std::fs::read(unpacked.join(path)).unwrap();
+            assert_eq!(hash, format!("{:x}", Sha256::digest(&data)), "{path}");
+            listed.push(path.to_string());
+        }
+        let mut files = Vec::new();
+        let mut dirs = vec![unpacked.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let path = path.strip_prefix(&unpacked).unwrap();
+                    files.push(path.to_str().unwrap().to_string());
+                }
+            }
+        }
+        files.retain(|file| file != "CHECKSUMS.sha256");
+        files.sort();
+        listed.sort();
+        assert!(listed.contains(&"dist/channel-rust-stable.toml".to_string()));
+        assert_eq!(listed, files);
+
+        // The standard tool accepts the file, when it's there.
+        if let Ok(status) = std::process::Command::new("sha256sum")
+            .args(["--check", "--quiet", "CHECKSUMS.sha256"])
+            .current_dir(&unpacked)
+            .status()
+        {
+            assert!(status.success());
+        }
+    }
+
+    #[tokio::test]
+    async fn mirror_signatures() {
+        let root = tempdir().unwrap();
+        sync_fixture(root.path(), &["--keep-going", "--mirror-signatures"])
+            .await
+            .unwrap();
+        let dist = root.path().join("dist/2023-03-09");
+        for file in [
+            "rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz.asc",
+            "cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz.asc",
+        ] {
+            assert!(dist.join(file).exists(), "{file}");
+        }
+        // No signature for the file that failed.
+        assert!(!dist.join("rust-src-1.68.0.tar.xz.asc").exists());
+        let history = get_channel_history(root.path(), "stable").unwrap();
+        let mut files = history.versions["2023-03-09"].clone();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "dist/2023-03-09/cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+                "dist/2023-03-09/cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz.asc",
+                "dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz",
+                "dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz.asc",
+            ]
+        );
+
+        // Without the option only the files are mirrored.
+        let root = tempdir().unwrap();
+        sync_fixture(root.path(), &["--keep-going"]).await.unwrap();
+        assert!(!root
+            .path()
+            .join("dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz.asc")
+            .exists());
+    }
+
+    /// Check that a re-run with `--only-missing` only downloads what the
+    /// previous run didn't complete.
+    #[tokio::test]
+    async fn only_missing_resumes_channel() {
+        let root = tempdir().unwrap();
+        sync_fixture(root.path(), &["--keep-going"]).await.unwrap();
+        let dist = root.path().join("dist/2023-03-09");
+        let rustc = dist.join("rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz");
+        let cargo = dist.join("cargo-1.68.0-x86_64-unknown-linux-gnu.tar.xz");
+        // An interrupted run: one file is missing, and one recorded file is
+        // changed, keeping its size, to tell whether it's checked again.
+        fs::remove_file(&cargo).unwrap();
+        fs::write(&rustc, "RUSTC").unwrap();
+
+        let workdir = root.path().to_str().unwrap();
+        let only_missing = ["--keep-going", "--keep-workdir", workdir, "--only-missing"];
+        sync_fixture(root.path(), &only_missing).await.unwrap();
+        assert_eq!(fs::read_to_string(&rustc).unwrap(), "RUSTC");
+        assert_eq!(fs::read_to_string(&cargo).unwrap(), "cargo");
+        let history = get_channel_history(root.path(), "stable").unwrap();
+        assert_eq!(history.versions["2023-03-09"].len(), 2);
+        assert_eq!(
+            history.sizes["dist/2023-03-09/rustc-1.68.0-x86_64-unknown-linux-gnu.tar.xz"],
+            5
+        );
+
+        // A recorded file whose size changed, e.g. truncated, is verified.
+        fs::write(&rustc, "rust").unwrap();
+        sync_fixture(root.path(), &only_missing).await.unwrap();
+        assert_eq!(fs::read_to_string(&rustc).unwrap(), "rustc");
+
+        // Without it, all the files are verified.
+        fs::write(&rustc, "RUSTC").unwrap();
+        sync_fixture(root.path(), &["--keep-going"]).await.unwrap();
+        assert_eq!(fs::read_to_string(&rustc).unwrap(), "rustc");
+
+        // --only-missing resumes a kept workdir.
+        assert!(Cli::try_parse_from([
+            "crates-registry",
+            "pack",
+            "--pack-file",
+            "mirror.tar",
+            "--only-missing",
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn channel_history_in_dedicated_dir() {
+        let root = tempdir().unwrap();
+        sync_fixture(
+            root.path(),
+            &["--keep-going", "--channel-history-dir", "history"],
+        )
+        .await
+        .unwrap();
+        assert!(get_channel_history(root.path(), "stable").is_err());
+        let history = get_channel_history(&root.path().join("history"), "stable").unwrap();
+        assert_eq!(history.versions["2023-03-09"].len(), 2);
+    }
+
+    #[tokio::test]
+    async fn verify_source_computes_files_and_size() {
+        let source = format!("http://{}", fixture_source());
+        let args = pack_args(&[
+            "--source",
+            &source,
+            "--rust-versions",
+            "stable",
+            "--platforms",
+            "x86_64-unknown-linux-gnu",
+            "--verify-only",
+        ]);
+        let summaries = verify_source(&args).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].channel, "stable");
+        assert_eq!(summaries[0].date, "2023-03-09");
+        assert_eq!(summaries[0].files, 3);
+        // "rustc", "cargo" and "corrupted".
+        assert_eq!(summaries[0].size, 19);
+
+        let missing_version = pack_args(&[
+            "--source",
+            &source,
+            "--rust-versions",
+            "1.0.0",
+            "--platforms",
+            "x86_64-unknown-linux-gnu",
+        ]);
+        assert!(verify_source(&missing_version).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_partial_versions() {
+        let routes = warp::path!("dist" / String).map(|file: String| {
+            let status = match file.as_str() {
+                "channel-rust-1.66.0.toml"
+                | "channel-rust-1.67.0.toml"
+                | "channel-rust-1.67.1.toml" => StatusCode::OK,
+                _ => StatusCode::NOT_FOUND,
+            };
+            warp::reply::with_status("", status)
+        });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let source = format!("http://{addr}");
+        let resolve = |versions: &str| {
+            let args = pack_args(&[
+                "--source",
+                &source,
+                "--rust-versions",
+                versions,
+                "--channel-alias",
+                "lts=1.66.0,1.65=1.65.0",
+            ]);
+            async move { resolve_rust_versions(&args).await }
+        };
+
+        assert_eq!(
+            resolve("1.67,1.66,1.67.0,stable,nightly-2023-03-09")
+                .await
+                .unwrap(),
+            ["1.67.1", "1.66.0", "1.67.0", "stable", "nightly-2023-03-09"]
+        );
+        // Aliases are used as they are.
+        assert_eq!(resolve("lts,1.65").await.unwrap(), ["1.66.0", "1.65.0"]);
+        let err = resolve("1.68").await.unwrap_err();
+        assert!(err.to_string().contains("No release of rust 1.68"), "{err}");
+
+        assert!(Cli::try_parse_from([
+            "crates-registry",
+            "pack",
+            "--pack-file",
+            "mirror.tar",
+            "--channel-alias",
+            "1.67",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn fail_fast_conflicts_with_keep_going() {
+        let args = [
+            "crates-registry",
+            "pack",
+            "--pack-file",
+            "mirror.tar",
+            "--fail-fast",
+            "--keep-going",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+        assert!(pack_args(&["--fail-on-partial"]).fail_on_partial);
+        let args = [
+            "crates-registry",
+            "pack",
+            "--pack-file",
+            "mirror.tar",
+            "--fail-on-partial",
+            "--keep-going",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn strict_download_list_rejects_unavailable_platform() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("channel-rust-stable.toml");
+        fs::write(&path, CHANNEL).unwrap();
+
+        let available = platforms(&["x86_64-unknown-linux-gnu"]);
+        assert!(rustup_download_list(&path, &available, true, false).is_ok());
+
+        let unavailable = platforms(&["x86_64-unknown-linux-gnu", "aarch64-unknown-linux-gnu"]);
+        let err = rustup_download_list(&path, &unavailable, true, false).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::UnavailableTargets { targets } if targets == ["aarch64-unknown-linux-gnu"]
+        ));
+    }
 }
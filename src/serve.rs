@@ -1,30 +1,115 @@
+use std::convert::Infallible;
+use std::future;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::io::Write as _;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Error;
 use anyhow::Result;
 
-use itertools::Itertools;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use percent_encoding::percent_decode_str;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::AsciiSet;
+use percent_encoding::CONTROLS;
 use serde::Deserialize;
 use serde::Serialize;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
+use tokio::io::AsyncReadExt as _;
 use tokio::net::TcpListener;
+use tokio::net::TcpSocket;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::Stream;
+use tokio_stream::StreamExt as _;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
 
+use warp::filters::BoxedFilter;
+use warp::http::header::CONTENT_ENCODING;
+use warp::http::header::CONTENT_LENGTH;
+use warp::http::header::CONTENT_TYPE;
+use warp::http::header::RETRY_AFTER;
+use warp::http::header::SERVER;
+use warp::http::header::VARY;
+use warp::http::HeaderMap;
+use warp::http::HeaderValue;
+use warp::http::Method;
 use warp::http::StatusCode;
 use warp::http::Uri;
+use warp::hyper::body::HttpBody;
+use warp::hyper::server::accept;
+use warp::hyper::service::make_service_fn;
+use warp::hyper::Body;
+use warp::hyper::Server;
+use warp::path::FullPath;
+use warp::path::Peek;
+use warp::path::Tail;
+use warp::reject::InvalidHeader;
+use warp::reject::InvalidQuery;
+use warp::reject::LengthRequired;
+use warp::reject::MethodNotAllowed;
+use warp::reject::MissingHeader;
+use warp::reject::PayloadTooLarge;
 use warp::reject::Reject;
+use warp::reject::UnsupportedMediaType;
 use warp::Filter;
 use warp::Rejection;
+use warp::Reply as _;
 
+use crate::acl::Acl;
+use crate::banner::Banner;
+use crate::banner::BannerMessage;
+use crate::banner::BANNER_HEADER;
+use crate::banner::MAX_BANNER_SIZE;
+use crate::cli::ServeArgs;
+use crate::docs::extract_docs;
+use crate::downloads::DownloadCounts;
+use crate::index::git_version_warning;
 use crate::index::handle_git;
+use crate::index::list_crates;
+use crate::index::read_only_dir;
 use crate::index::Index;
-use crate::publish::crate_file_name;
+use crate::proxy::Upstream;
+use crate::publish::check_acl;
+use crate::publish::check_publish;
 use crate::publish::crate_path;
 use crate::publish::publish_crate;
+use crate::publish::publisher_id;
+use crate::publish::readme_file_name;
+use crate::publish::valid_crate_version;
+use crate::publish::PublishError;
+use crate::publish::Quota;
+use crate::publish::DRY_RUN_HEADER;
+use crate::publish::MAX_PUBLISH_SIZE;
+use crate::repair::crate_file_version;
 use crate::serve_frontend;
+use crate::staging::Staging;
+use crate::storage::crate_key;
+use crate::storage::open_storage;
+use crate::storage::Storage;
+use crate::takedown::Takedown;
+use crate::terms::PublishTerms;
+use crate::watch::watch_crates;
+use crate::watch::DEBOUNCE as WATCH_DEBOUNCE;
 
 #[derive(Debug)]
 pub(crate) struct ServerError(pub(crate) anyhow::Error);
@@ -37,14 +122,76 @@ struct RegistryError {
     detail: String,
 }
 
+/// The index of the registry, as advertised in the discovery document.
+#[derive(Debug, Deserialize, Serialize)]
+struct DiscoveryIndex {
+    /// The URL of the git index.
+    git: String,
+    /// The URL of the sparse index, if served.
+    sparse: Option<String>,
+}
+
+/// The document served at `/.well-known/cargo` to let tools configure Cargo
+/// for the registry.
+#[derive(Debug, Deserialize, Serialize)]
+struct Discovery {
+    index: DiscoveryIndex,
+    /// The download URL template of the crates, as in the index config.
+    dl: String,
+    /// The base URL of the web API, as in the index config.
+    api: Option<String>,
+    /// Whether the registry requires a token for the operations changing it:
+    /// with `--acl` a publish needs one, like a yank always does.
+    auth_required: bool,
+}
+
 /// A list of errors that the registry returns in its response.
 #[derive(Debug, Default, Deserialize, Serialize)]
 struct RegistryErrors {
     errors: Vec<RegistryError>,
 }
 
-impl From<Error> for RegistryErrors {
-    fn from(error: Error) -> Self {
+/// A request for something the registry doesn't have, answered with a
+/// 404 carrying a detail for the client.
+#[derive(Debug)]
+struct MissingError(String);
+
+impl Reject for MissingError {}
+
+/// A request for a crate version taken down for legal reasons, answered
+/// with a 451 carrying the reason.
+#[derive(Debug)]
+struct UnavailableError(String);
+
+impl Reject for UnavailableError {}
+
+/// A publish received while `--max-concurrent-publishes` are being handled,
+/// answered with a 503 asking the client to retry later.
+#[derive(Debug)]
+struct PublishesSaturatedError;
+
+impl Reject for PublishesSaturatedError {}
+
+/// A request changing a registry served from a read-only filesystem,
+/// answered with a 403.
+#[derive(Debug)]
+struct ReadOnlyError;
+
+impl Reject for ReadOnlyError {}
+
+/// A request to the admin API without the `--admin-token`, answered with a
+/// 403 carrying a detail for the client.
+#[derive(Debug)]
+struct AdminError(&'static str);
+
+impl Reject for AdminError {}
+
+/// The seconds after which a publish rejected with
+/// [`PublishesSaturatedError`] should be retried.
+const PUBLISH_RETRY_AFTER: u64 = 5;
+
+impl From<&Error> for RegistryErrors {
+    fn from(error: &Error) -> Self {
         Self {
             errors: error
                 .chain()
@@ -55,6 +202,19 @@ impl From<Error> for RegistryErrors {
     }
 }
 
+impl From<&PublishError> for RegistryErrors {
+    fn from(error: &PublishError) -> Self {
+        match error {
+            PublishError::Internal(err) => Self::from(err),
+            _ => Self {
+                errors: vec![RegistryError {
+                    detail: error.to_string(),
+                }],
+            },
+        }
+    }
+}
+
 pub enum ServerBinding {
     Addr(SocketAddr),
     Listener(TcpListener),
@@ -73,14 +233,42 @@ impl From<TcpListener> for ServerBinding {
 }
 
 impl ServerBinding {
-    async fn to_listener(self) -> Result<TcpListener> {
+    /// Get the listener, binding the address with an accept queue of
+    /// `backlog` connections if needed.
+    fn into_listener(self, backlog: u32) -> Result<TcpListener> {
         Ok(match self {
-            ServerBinding::Addr(addr) => TcpListener::bind(addr).await?,
+            ServerBinding::Addr(addr) => {
+                bind(addr, backlog).with_context(|| format!("failed to listen on {}", addr))?
+            }
             ServerBinding::Listener(listener) => listener,
         })
     }
 }
 
+/// Listen on `addr` with an accept queue of `backlog` connections.
+fn bind(addr: SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Like `TcpListener::bind`, allow restarting while old connections linger.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Apply the TCP options of `serve_args` to an accepted connection.
+fn configure_stream(stream: &TcpStream, serve_args: &ServeArgs) -> std::io::Result<()> {
+    stream.set_nodelay(serve_args.tcp_nodelay)?;
+    if let Some(keepalive) = serve_args.tcp_keepalive {
+        SockRef::from(stream)
+            .set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(keepalive)))?;
+    }
+    Ok(())
+}
+
 /// Convert a result back into a response.
 fn response<T>(result: Result<T>) -> Result<impl warp::Reply, warp::Rejection>
 where
@@ -102,34 +290,581 @@ where
     // Ok(reply)
 }
 
-/// Serve a registry at the given path on the given socket address.
-pub async fn serve(root: &Path, binding: impl Into<ServerBinding>, server_addr: SocketAddr) -> Result<()> {
-    let frontend = serve_frontend(root);
+/// A JSON response with an explicit `Content-Length`, as some Cargo
+/// versions expect for the registry API.
+fn json_response<T: Serialize>(value: &T, status: StatusCode) -> warp::reply::Response {
+    let body = serde_json::to_vec(value).expect("registry responses always serialize");
+    let length = HeaderValue::from(body.len());
+    let mut response = warp::reply::Response::new(body.into());
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(CONTENT_LENGTH, length);
+    response
+}
+
+/// The HTTP status a failed publish is reported with.
+fn publish_error_status(error: &PublishError) -> StatusCode {
+    match error {
+        PublishError::InvalidRequest(_) | PublishError::InvalidMetadata(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        PublishError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        PublishError::VersionExists { .. } | PublishError::NameConflict { .. } => {
+            StatusCode::CONFLICT
+        }
+        PublishError::QuotaExceeded(_)
+        | PublishError::NotAllowed { .. }
+        | PublishError::AnonymousYank
+        | PublishError::TermsNotAccepted { .. } => StatusCode::FORBIDDEN,
+        PublishError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Convert a `ServerError`, `PublishError`, `MissingError`, `UnavailableError` or
+/// `PublishesSaturatedError` rejection into a response carrying the error chain in the
+/// registry's JSON error format. Any other rejection is left to warp.
+async fn handle_rejection(rejection: Rejection) -> Result<impl warp::Reply, Rejection> {
+    if rejection.find::<PublishesSaturatedError>().is_some() {
+        warn!("request status: too many concurrent publishes");
+        let errors = RegistryErrors {
+            errors: vec![RegistryError {
+                detail: format!(
+                    "the registry is handling too many publishes, retry in {PUBLISH_RETRY_AFTER} \
+                     seconds"
+                ),
+            }],
+        };
+        let mut response = json_response(&errors, StatusCode::SERVICE_UNAVAILABLE);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(PUBLISH_RETRY_AFTER));
+        return Ok(response);
+    }
+    let (errors, status) = if let Some(ServerError(err)) = rejection.find::<ServerError>() {
+        error!("request status: failure: {:#}", err);
+        (RegistryErrors::from(err), StatusCode::INTERNAL_SERVER_ERROR)
+    } else if let Some(err) = rejection.find::<PublishError>() {
+        error!("request status: failure: {}", err);
+        (RegistryErrors::from(err), publish_error_status(err))
+    } else if let Some(MissingError(detail)) = rejection.find::<MissingError>() {
+        error!("request status: failure: {}", detail);
+        let errors = RegistryErrors {
+            errors: vec![RegistryError {
+                detail: detail.clone(),
+            }],
+        };
+        (errors, StatusCode::NOT_FOUND)
+    } else if let Some(AdminError(detail)) = rejection.find::<AdminError>() {
+        warn!("request status: refused: {}", detail);
+        let errors = RegistryErrors {
+            errors: vec![RegistryError {
+                detail: detail.to_string(),
+            }],
+        };
+        (errors, StatusCode::FORBIDDEN)
+    } else if rejection.find::<ReadOnlyError>().is_some() {
+        warn!("request status: refused, the registry is read-only");
+        let errors = RegistryErrors {
+            errors: vec![RegistryError {
+                detail: "the registry is served from a read-only filesystem".to_string(),
+            }],
+        };
+        (errors, StatusCode::FORBIDDEN)
+    } else if let Some(UnavailableError(detail)) = rejection.find::<UnavailableError>() {
+        info!("request status: unavailable: {}", detail);
+        let errors = RegistryErrors {
+            errors: vec![RegistryError {
+                detail: detail.clone(),
+            }],
+        };
+        (errors, StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS)
+    } else if rejection.find::<PayloadTooLarge>().is_some() {
+        // Only publishing limits the body size.
+        let err = PublishError::TooLarge {
+            limit: MAX_PUBLISH_SIZE,
+        };
+        error!("request status: failure: {}", err);
+        (RegistryErrors::from(&err), publish_error_status(&err))
+    } else {
+        return Err(rejection);
+    };
+    Ok(json_response(&errors, status))
+}
+
+/// A filter matching the (possibly empty) path prefix the registry is
+/// mounted under.
+fn base_path_filter(base_path: &str) -> BoxedFilter<()> {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(ToString::to_string)
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter
+                .and(warp::path::param::<String>())
+                .and_then(move |param: String| {
+                    let matches = param == segment;
+                    async move {
+                        if matches {
+                            Ok(())
+                        } else {
+                            Err(warp::reject::not_found())
+                        }
+                    }
+                })
+                .untuple_one()
+                .boxed()
+        })
+}
+
+/// A path segment, percent-decoded: the `+` of versions with build metadata,
+/// like `1.0.0+build.1`, may be sent as `%2B`. Decoding may produce `/`, so
+/// the routes must check the crate names and versions with
+/// [`valid_crate_version`] before looking them up.
+fn decoded_param() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+    warp::path::param::<String>().and_then(|segment: String| async move {
+        percent_decode_str(&segment)
+            .decode_utf8()
+            .map(|decoded| decoded.into_owned())
+            .map_err(|_| warp::reject::not_found())
+    })
+}
+
+/// The characters percent-encoded in the segments of the crate file paths:
+/// those not allowed in a path segment, the `%` of the escapes and the `/`.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// The URI of the crate file at `key` under `/crates`, its path segments
+/// percent-encoded.
+fn crate_file_uri(base_path: &str, key: &str) -> Result<Uri> {
+    let path = key
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{}/crates/{}", base_path, path)
+        .parse()
+        .with_context(|| format!("invalid path for the crate file {}", key))
+}
+
+/// A filter rejecting the requests to the publish and yank APIs, and the
+/// reviews of staged publishes, as not found when the registry is read-only
+/// (`--no-api`).
+pub(crate) fn api_enabled(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(MissingError(
+                    "the registry is read-only".to_string(),
+                )))
+            }
+        })
+        .untuple_one()
+}
+
+/// A filter rejecting the requests to the admin API as forbidden unless their
+/// `Authorization` is the `admin_token`, all of them without one.
+pub(crate) fn admin(
+    admin_token: Option<&str>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let admin_token = admin_token.map(|token| Arc::new(token.to_string()));
+    warp::header::optional::<String>("Authorization")
+        .and_then(move |authorization: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                match (admin_token, authorization) {
+                    (Some(token), Some(authorization)) if *token == authorization => Ok(()),
+                    (Some(_), _) => Err(warp::reject::custom(AdminError(
+                        "the admin token is missing or invalid",
+                    ))),
+                    (None, _) => Err(warp::reject::custom(AdminError(
+                        "the admin API is disabled, see --admin-token",
+                    ))),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// A filter rejecting the requests changing the registry as forbidden when
+/// its root is read-only.
+pub(crate) fn writable(read_only: bool) -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::any()
+        .and_then(move || async move {
+            if read_only {
+                Err(warp::reject::custom(ReadOnlyError))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// A filter rejecting the requests to anything but the index as not found
+/// when only the index is served (`--index-only`).
+fn registry_enabled(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(MissingError(
+                    "only the index is served here".to_string(),
+                )))
+            }
+        })
+        .untuple_one()
+}
+
+/// Whether an `Accept-Encoding` header value accepts `encoding`, by name or
+/// through `*`, with a non-zero quality.
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let refused = params.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|quality| quality.parse::<f32>().ok())
+                == Some(0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+/// A filter extracting the encoding of the response the request accepts,
+/// gzip rather than deflate, `None` for none of them.
+fn response_encoding() -> impl Filter<Extract = (Option<&'static str>,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("Accept-Encoding").map(|accept_encoding: Option<String>| {
+        let accept_encoding = accept_encoding?;
+        ["gzip", "deflate"]
+            .into_iter()
+            .find(|encoding| accepts_encoding(&accept_encoding, encoding))
+    })
+}
+
+/// Compress `response` with `encoding`, either gzip or deflate.
+async fn compress_response(
+    encoding: &'static str,
+    response: warp::reply::Response,
+) -> Result<warp::reply::Response> {
+    let (mut parts, body) = response.into_parts();
+    let body = warp::hyper::body::to_bytes(body).await?;
+    let compressed = if encoding == "gzip" {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        encoder.finish()?
+    } else {
+        // The "deflate" of HTTP is the zlib format.
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        encoder.finish()?
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    Ok(warp::reply::Response::from_parts(parts, compressed.into()))
+}
+
+/// Compress the responses of the JSON API `route` with gzip or deflate, as
+/// accepted by the client. The crate files, compressed already, are served
+/// as they are. The route runs once, whatever the encoding.
+pub(crate) fn compressed<F, R>(route: F) -> BoxedFilter<(warp::reply::Response,)>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    response_encoding()
+        .and(route)
+        .and_then(|encoding: Option<&'static str>, reply: R| {
+            let response = reply.into_response();
+            async move {
+                let mut response = match encoding {
+                    Some(encoding) => compress_response(encoding, response)
+                        .await
+                        .map_err(|err| warp::reject::custom(ServerError(err)))?,
+                    None => response,
+                };
+                response
+                    .headers_mut()
+                    .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+                Ok::<_, Rejection>(response)
+            }
+        })
+        .boxed()
+}
+
+/// Serve the files below `dir`, without ever serving directories
+/// themselves so that the layout of the mirror can't be explored.
+fn static_dir(dir: PathBuf) -> BoxedFilter<(warp::fs::File,)> {
+    let root = dir.clone();
+    warp::path::peek()
+        .and_then(move |tail: Peek| {
+            let is_dir = root.join(tail.as_str()).is_dir();
+            async move {
+                if is_dir {
+                    Err(warp::reject::not_found())
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::dir(dir))
+        .boxed()
+}
+
+/// A filter rejecting the requests for the crate files of the versions
+/// taken down, kept in `crates_folder` when their sunset passed.
+fn not_taken_down(crates_folder: PathBuf) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(move |tail: Peek| {
+            let path = crates_folder.join(&*percent_decode_str(tail.as_str()).decode_utf8_lossy());
+            let version = crate_file_version(&crates_folder, &path).filter(|_| {
+                path.extension()
+                    .map_or(false, |extension| extension == "crate")
+            });
+            let takedown = version
+                .as_ref()
+                .map(|(name, version)| Takedown::read(&crates_folder, name, version))
+                .transpose();
+            async move {
+                match takedown.map_err(|err| warp::reject::custom(ServerError(err)))? {
+                    Some(Some(takedown)) if takedown.in_effect(SystemTime::now()) => {
+                        let (name, version) = version.unwrap_or_default();
+                        Err(warp::reject::custom(UnavailableError(format!(
+                            "crate {} in version {} is unavailable for legal reasons: {}",
+                            name, version, takedown.reason
+                        ))))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// A response streaming the crate file at `path`, read in chunks so that
+/// large crates aren't loaded into memory.
+async fn crate_file_response(path: &Path) -> Result<warp::reply::Response, Rejection> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(warp::reject::custom(MissingError(format!(
+                "no crate file {}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ))))
+        }
+        Err(err) => {
+            return Err(warp::reject::custom(ServerError(
+                Error::new(err).context(format!("failed to open {}", path.display())),
+            )))
+        }
+    };
+    let len = file
+        .metadata()
+        .await
+        .with_context(|| format!("failed to read the metadata of {}", path.display()))
+        .map_err(|err| warp::reject::custom(ServerError(err)))?
+        .len();
+    let chunks = futures::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0; 64 * 1024];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), Some(file)))
+            }
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+    let mut response = warp::reply::Response::new(Body::wrap_stream(chunks));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/gzip"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    Ok(response)
+}
+
+/// Serve the crate file at `key` of a storage that isn't local.
+async fn stored_crate_response(
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<warp::reply::Response, Rejection> {
+    let data = storage
+        .get(key)
+        .await
+        .map_err(|err| warp::reject::custom(ServerError(err)))?
+        .ok_or_else(|| warp::reject::custom(MissingError(format!("no crate file {key}"))))?;
+    let len = data.len();
+    let mut response = warp::reply::Response::new(Body::from(data));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/gzip"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    Ok(response)
+}
+
+/// Reopen the index on each `SIGHUP`, sent once `unpack --atomic` replaced
+/// the registry directory, for the publishes to go to the new one.
+#[cfg(unix)]
+async fn reopen_index_on_hangup(index: Arc<Index>) {
+    use tokio::signal::unix::signal;
+    use tokio::signal::unix::SignalKind;
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!(
+                "Can't listen to SIGHUP, the index won't be reopened: {}",
+                err
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match index.reopen().await {
+            Ok(()) => info!("Reopened the index at {}", index.root().display()),
+            Err(err) => error!("Failed to reopen the index: {:#}", err),
+        }
+    }
+}
+
+/// Create all the routes of the registry described by `serve_args`.
+pub(crate) async fn routes(
+    serve_args: &ServeArgs,
+) -> Result<impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone> {
+    if let Some(warning) = git_version_warning().await {
+        ensure!(!serve_args.strict_git_version, "{}", warning);
+        warn!("{}", warning);
+    }
+    let mut server_headers = HeaderMap::new();
+    if !serve_args.server_header.is_empty() {
+        let value = HeaderValue::from_str(&serve_args.server_header)
+            .with_context(|| format!("invalid --server-header {:?}", serve_args.server_header))?;
+        server_headers.insert(SERVER, value);
+    }
+    let root = serve_args.root_registry.as_path();
+    let base_path = serve_args.base_path();
     let crates_folder = Arc::new(root.join("crates"));
     let index_folder = root.join("index");
     let git_index = Arc::new(
-        Index::new(&index_folder, &server_addr)
-            .await
-            .with_context(|| {
-                format!(
-                    "failed to create/instantiate crate index at {}",
-                    index_folder.display()
-                )
-            })?,
+        Index::new_with_config_format(
+            &index_folder,
+            &serve_args.registry_url(),
+            serve_args.compact_config,
+            !serve_args.no_api,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "failed to create/instantiate crate index at {}",
+                index_folder.display()
+            )
+        })?,
+    );
+    // Refuse to compact the index while it's served.
+    git_index.lock_for_serving()?;
+    #[cfg(unix)]
+    tokio::spawn(reopen_index_on_hangup(git_index.clone()));
+    let read_only = git_index.read_only() || read_only_dir(root);
+    if read_only {
+        warn!(
+            "The registry at {} is read-only, publishing, yanking and loading are disabled",
+            root.display()
+        );
+    }
+    // Refuse to start with an access control list that every publish would
+    // then fail to read.
+    if let Some(acl) = &serve_args.acl {
+        Acl::load(acl)?;
+    }
+    let quota =
+        Arc::new(Quota::new(&crates_folder, serve_args).with_context(|| {
+            format!("failed to compute the usage of {}", crates_folder.display())
+        })?);
+    let storage = open_storage(root, &serve_args.storage, !serve_args.no_fsync)?;
+    if serve_args.watch_crates {
+        ensure!(
+            !read_only,
+            "--watch-crates can't index the crate files of a read-only registry"
+        );
+        #[cfg(feature = "s3")]
+        ensure!(
+            serve_args.storage.s3_bucket.is_none(),
+            "--watch-crates watches <root>/crates, the crate files can't be in --s3-bucket"
+        );
+        tokio::spawn(watch_crates(
+            git_index.clone(),
+            crates_folder.to_path_buf(),
+            WATCH_DEBOUNCE,
+        )?);
+    }
+    ensure!(
+        !read_only || serve_args.upstream.is_none(),
+        "--upstream can't cache the missing crates in a read-only registry"
+    );
+    let staging = serve_args.staging.then(|| {
+        Arc::new(Staging::new(
+            root,
+            git_index.clone(),
+            storage.clone(),
+            quota.clone(),
+            Arc::new(serve_args.clone()),
+        ))
+    });
+    let frontend = serve_frontend(
+        root,
+        serve_args.frontend_dir.as_deref(),
+        serve_args.channel_history_dir.as_deref(),
+        staging.clone(),
+        read_only,
+        !serve_args.no_api,
+        serve_args.admin_token.as_deref(),
     );
 
     let path_for_git = index_folder.to_path_buf();
+    let git_buffer_size = serve_args.git_buffer_size as usize;
+    let git_backend_timeout = Duration::from_secs(serve_args.git_backend_timeout);
     // Serve git client requests to /git/index
     let index = warp::path("git")
         .and(warp::path("index"))
         .and(warp::path::tail())
         .and(warp::method())
         .and(warp::header::optional::<String>("Content-Type"))
+        .and(warp::header::optional::<String>("Content-Encoding"))
+        .and(warp::header::optional::<String>("Git-Protocol"))
         .and(warp::addr::remote())
         .and(warp::body::stream())
         .and(warp::query::raw().or_else(|_| async { Ok::<(String,), Rejection>((String::new(),)) }))
         .and_then(
-            move |path_tail, method, content_type, remote, body, query| {
+            move |path_tail,
+                  method,
+                  content_type,
+                  content_encoding,
+                  git_protocol,
+                  remote,
+                  body,
+                  query| {
                 let mirror_path = path_for_git.clone();
                 async move {
                     response(
@@ -138,99 +873,2635 @@ pub async fn serve(root: &Path, binding: impl Into<ServerBinding>, server_addr:
                             path_tail,
                             method,
                             content_type,
+                            content_encoding,
+                            git_protocol,
                             remote,
                             body,
                             query,
+                            git_buffer_size,
+                            git_backend_timeout,
                         )
                         .await,
                     )
                 }
             },
         );
-    // Handle sparse index requests at /index/
-    // let sparse_index = warp::path("index").and(warp::fs::dir(index_folder.clone()));
+    // Handle sparse index requests at /index/, from the same commits the
+    // git clients fetch.
+    let sparse_index_git = git_index.clone();
+    let sparse_index = warp::get()
+        .and(warp::path("index"))
+        .and(warp::path::tail())
+        .and_then(move |tail: Tail| {
+            let index = sparse_index_git.clone();
+            async move {
+                let path = tail.as_str();
+                // Never look outside of the index, nor into its git directory.
+                if path.is_empty()
+                    || path
+                        .split('/')
+                        .any(|segment| matches!(segment, "" | "." | ".." | ".git"))
+                {
+                    return Err(warp::reject::not_found());
+                }
+                match index.committed_file(Path::new(path)).await {
+                    Ok(Some(content)) => Ok(warp::reply::with_header(
+                        content,
+                        "Content-Type",
+                        if path == "config.json" {
+                            "application/json"
+                        } else {
+                            "text/plain; charset=utf-8"
+                        },
+                    )),
+                    Ok(None) => Err(warp::reject::custom(MissingError(format!(
+                        "no index file {}",
+                        path
+                    )))),
+                    Err(err) => Err(warp::reject::custom(ServerError(err))),
+                }
+            }
+        });
 
     // Serve the contents of <root>/ at /crates. This allows for directly
     // downloading the .crate files, to which we redirect from the
-    // download handler below.
+    // download handler below, unless it serves them itself.
     let crates = warp::path("crates")
-        .and(warp::fs::dir(crates_folder.to_path_buf()))
+        .and(not_taken_down(crates_folder.to_path_buf()))
+        .and(static_dir(crates_folder.to_path_buf()))
         .with(warp::trace::request());
+    let download_base_path = base_path.clone();
+    let download_index = git_index.clone();
+    let download_crates_folder = crates_folder.clone();
+    let download_storage = storage.clone();
+    let download_counts = Arc::new(DownloadCounts::load(root)?);
+    // The downloads of a read-only registry are only counted in memory.
+    if !read_only {
+        tokio::spawn(download_counts.clone().flush_periodically());
+    }
+    let counts_for_download = download_counts.clone();
+    let upstream = serve_args
+        .upstream
+        .as_deref()
+        .map(|upstream| Arc::new(Upstream::new(upstream, &serve_args.upstream_index)));
+    let direct_download = serve_args.direct_download;
     let download = warp::get()
         .and(warp::path("api"))
         .and(warp::path("v1"))
         .and(warp::path("crates"))
-        .and(warp::path::param())
-        .and(warp::path::param())
+        .and(decoded_param())
+        .and(decoded_param())
         .and(warp::path("download"))
-        .map(move |name: String, version: String| {
-            let crate_path = crate_path(&name).join(crate_file_name(&name, &version));
-            let path = format!(
-                "/crates/{}",
-                crate_path
-                    .components()
-                    .map(|c| format!("{}", c.as_os_str().to_str().unwrap()))
-                    .join("/")
-            );
+        .and_then(move |name: String, version: String| {
+            let base_path = download_base_path.clone();
+            let index = download_index.clone();
+            let crates_folder = download_crates_folder.clone();
+            let storage = download_storage.clone();
+            let counts = counts_for_download.clone();
+            let upstream = upstream.clone();
+            async move {
+                if !valid_crate_version(&name, &version) {
+                    return Err(warp::reject::custom(MissingError(format!(
+                        "crate {} has no version {}",
+                        name, version
+                    ))));
+                }
+                // A taken down version must not be fetched again from upstream.
+                let takedown = Takedown::read(&crates_folder, &name, &version)
+                    .map_err(|err| warp::reject::custom(ServerError(err)))?;
+                let sunset = match takedown {
+                    Some(takedown) if takedown.in_effect(SystemTime::now()) => {
+                        return Err(warp::reject::custom(UnavailableError(format!(
+                            "crate {} in version {} is unavailable for legal reasons: {}",
+                            name, version, takedown.reason
+                        ))));
+                    }
+                    Some(takedown) => takedown.sunset_time(),
+                    None => None,
+                };
+                let mut name = name;
+                let mut key = crate_key(&name, &version);
+                if let Some(upstream) = upstream {
+                    let exists = storage
+                        .exists(&key)
+                        .await
+                        .map_err(|err| warp::reject::custom(ServerError(err)))?;
+                    if !exists {
+                        let fetched = upstream
+                            .fetch(&index, storage.as_ref(), &name, &version)
+                            .await
+                            .with_context(|| {
+                                format!(
+                                    "failed to fetch {} in version {} from upstream",
+                                    name, version
+                                )
+                            })
+                            .map_err(|err| warp::reject::custom(ServerError(err)))?;
+                        // Redirect to the crate as stored, under the upstream name.
+                        if let Some(upstream_name) = fetched {
+                            key = crate_key(&upstream_name, &version);
+                            name = upstream_name;
+                        }
+                    }
+                }
+                // The storage may serve the file itself, e.g. from a bucket.
+                let redirect_uri = match storage.url(&key) {
+                    Some(url) => url
+                        .parse::<Uri>()
+                        .with_context(|| format!("invalid URL of the crate file {}", key)),
+                    None => crate_file_uri(&base_path, &key),
+                };
+
+                // Cargo downloads yanked versions that are pinned in a lock file,
+                // make them visible in the logs and to the client.
+                let entry = index.entry(&name, &version).unwrap_or_else(|err| {
+                    warn!("Can't look up {} in version {}: {:#}", name, version, err);
+                    None
+                });
+                let yanked = entry.as_ref().map_or(false, |entry| entry.yanked);
+                if yanked {
+                    info!("Downloading yanked crate {} in version {}", name, version);
+                }
+
+                let mut response = if direct_download {
+                    match storage.local_path(&key) {
+                        Some(path) => crate_file_response(&path).await?,
+                        None => stored_crate_response(storage.as_ref(), &key).await?,
+                    }
+                } else {
+                    let uri = redirect_uri.map_err(|err| warp::reject::custom(ServerError(err)))?;
+                    warp::redirect(uri).into_response()
+                };
+                if yanked {
+                    response
+                        .headers_mut()
+                        .insert("X-Crate-Yanked", HeaderValue::from_static("true"));
+                }
+                // Announce the upcoming takedown of the version.
+                if let Some(sunset) = sunset {
+                    let headers = response.headers_mut();
+                    headers.insert("Deprecation", HeaderValue::from_static("true"));
+                    headers.insert(
+                        "Sunset",
+                        HeaderValue::from_str(&httpdate::fmt_http_date(sunset)).unwrap(),
+                    );
+                }
+                // Only count the versions of the index, not the failed lookups.
+                if entry.is_some() {
+                    counts.record(&name, &version);
+                }
+                Ok::<_, Rejection>(response)
+            }
+        })
+        .with(warp::trace::request());
+    // List the crates of the index with their download counts, for the frontend.
+    let crates_root = root.to_path_buf();
+    let crate_downloads = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("crates"))
+        .and(warp::path::end())
+        .and_then(move || {
+            let root = crates_root.clone();
+            let counts = download_counts.clone();
+            async move {
+                let records =
+                    list_crates(&root).map_err(|err| warp::reject::custom(ServerError(err)))?;
+                Ok::<_, Rejection>(warp::reply::json(&counts.crates(records)))
+            }
+        });
+    let crate_downloads = compressed(crate_downloads);
+    let readme_crates_folder = crates_folder.clone();
+    let readme = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("v1"))
+        .and(warp::path("crates"))
+        .and(decoded_param())
+        .and(decoded_param())
+        .and(warp::path("readme"))
+        .and(warp::path::end())
+        .and_then(move |name: String, version: String| {
+            let crates_folder = readme_crates_folder.clone();
+            async move {
+                if !valid_crate_version(&name, &version) {
+                    return Err(warp::reject::not_found());
+                }
+                let path = crates_folder
+                    .join(crate_path(&name))
+                    .join(readme_file_name(&name, &version));
+                match tokio::fs::read(&path).await {
+                    Ok(readme) => Ok(warp::reply::with_header(
+                        readme,
+                        "Content-Type",
+                        "text/plain; charset=utf-8",
+                    )),
+                    Err(err) if err.kind() == ErrorKind::NotFound => Err(warp::reject::not_found()),
+                    Err(err) => Err(warp::reject::custom(ServerError(
+                        Error::new(err).context(format!("failed to read {}", path.display())),
+                    ))),
+                }
+            }
+        })
+        .with(warp::trace::request());
+    let discovery_index = git_index.clone();
+    let discovery_git_url = format!("{}/git/index", serve_args.index_url());
+    let discovery_sparse_url = format!("sparse+{}/index/", serve_args.index_url());
+    let discovery_auth_required = serve_args.acl.is_some();
+    let discovery = warp::get()
+        .and(warp::path(".well-known"))
+        .and(warp::path("cargo"))
+        .and(warp::path::end())
+        .and_then(move || {
+            let index = discovery_index.clone();
+            let git_url = discovery_git_url.clone();
+            let sparse_url = discovery_sparse_url.clone();
+            async move {
+                let config = index
+                    .config()
+                    .map_err(|err| warp::reject::custom(ServerError(err)))?;
+                Ok::<_, Rejection>(warp::reply::json(&Discovery {
+                    index: DiscoveryIndex {
+                        git: git_url,
+                        sparse: Some(sparse_url),
+                    },
+                    dl: config.dl,
+                    api: config.api,
+                    auth_required: discovery_auth_required,
+                }))
+            }
+        });
 
-            // TODO: Ideally we shouldn't unwrap here. That's not that easily
-            //       possible, though, because then we'd need to handle errors
-            //       and we can't use the response function because it will
-            //       overwrite the HTTP status even on success.
-            path.parse::<Uri>().map(warp::redirect).unwrap()
+    let yank_index = git_index.clone();
+    let yank_args = Arc::new(serve_args.clone());
+    let yank = warp::path("api")
+        .and(warp::path("v1"))
+        .and(warp::path("crates"))
+        .and(decoded_param())
+        .and(decoded_param())
+        .and(
+            warp::delete()
+                .and(warp::path("yank"))
+                .map(|| true)
+                .or(warp::put().and(warp::path("unyank")).map(|| false))
+                .unify(),
+        )
+        .and(warp::path::end())
+        .and(api_enabled(!serve_args.no_api))
+        .and(writable(read_only))
+        .and(warp::header::optional::<String>("Authorization"))
+        .and_then(
+            move |name: String, version: String, yanked: bool, token: Option<String>| {
+                let index = yank_index.clone();
+                let serve_args = yank_args.clone();
+                async move {
+                    // Anyone could yank the crates of others otherwise.
+                    let Some(token) = token else {
+                        return Err(warp::reject::custom(PublishError::AnonymousYank));
+                    };
+                    // Only the publishers allowed by the access control list
+                    // may yank the versions of a crate.
+                    check_acl(&serve_args, &name, Some(&publisher_id(&token)))
+                        .map_err(warp::reject::custom)?;
+                    // Only valid crate names map to a path inside the index.
+                    let changed = if valid_crate_version(&name, &version) {
+                        index
+                            .set_yanked(&name, &version, yanked)
+                            .await
+                            .map_err(|err| warp::reject::custom(ServerError(err)))?
+                    } else {
+                        None
+                    };
+                    match changed {
+                        // Yanking a yanked version (or the reverse) is fine.
+                        Some(_) => Ok(warp::reply::json(&serde_json::json!({ "ok": true }))),
+                        None => Err(warp::reject::custom(MissingError(format!(
+                            "crate {} has no version {}",
+                            name, version
+                        )))),
+                    }
+                }
+            },
+        )
+        .with(warp::trace::request());
+    // Delete a crate version altogether, e.g. the versions published by
+    // `crates-registry doctor --deep`. Unlike a yank, it's not for the
+    // publishers: the lock files pinning the version break.
+    let delete_index = git_index.clone();
+    let delete_storage = storage.clone();
+    let delete_quota = quota.clone();
+    let delete_crates_folder = crates_folder.clone();
+    let delete_version = warp::delete()
+        .and(warp::path("api"))
+        .and(warp::path("admin"))
+        .and(warp::path("crates"))
+        .and(decoded_param())
+        .and(decoded_param())
+        .and(warp::path::end())
+        .and(api_enabled(!serve_args.no_api))
+        .and(writable(read_only))
+        .and(admin(serve_args.admin_token.as_deref()))
+        .and_then(move |name: String, version: String| {
+            let index = delete_index.clone();
+            let storage = delete_storage.clone();
+            let quota = delete_quota.clone();
+            let crates_folder = delete_crates_folder.clone();
+            async move {
+                let missing = || {
+                    warp::reject::custom(MissingError(format!(
+                        "crate {} has no version {}",
+                        name, version
+                    )))
+                };
+                if !valid_crate_version(&name, &version) {
+                    return Err(missing());
+                }
+                let deleted = async {
+                    let Some(entry) = index.remove_version(&name, &version).await? else {
+                        return Ok(false);
+                    };
+                    let key = crate_key(&entry.name, &entry.vers);
+                    if let Some(size) = storage.size(&key).await? {
+                        storage.delete(&key).await?;
+                        quota.remove(size);
+                    }
+                    let readme = crates_folder
+                        .join(crate_path(&entry.name))
+                        .join(readme_file_name(&entry.name, &entry.vers));
+                    match std::fs::remove_file(&readme) {
+                        Err(err) if err.kind() != ErrorKind::NotFound => {
+                            return Err(err)
+                                .with_context(|| format!("failed to remove {}", readme.display()))
+                        }
+                        _ => (),
+                    }
+                    info!("Deleted {} in version {}", name, version);
+                    Ok(true)
+                }
+                .await
+                .map_err(|err: Error| warp::reject::custom(ServerError(err)))?;
+                if deleted {
+                    Ok(warp::reply::json(&serde_json::json!({ "ok": true })))
+                } else {
+                    Err(missing())
+                }
+            }
+        })
+        .with(warp::trace::request());
+    let publish_terms = serve_args
+        .publish_terms
+        .as_deref()
+        .map(|url| PublishTerms::load(root, url))
+        .transpose()?
+        .map(Arc::new);
+    let terms_for_accept = publish_terms.clone();
+    let accept_terms = warp::post()
+        .and(warp::path("api"))
+        .and(warp::path("accept-terms"))
+        .and(warp::path::end())
+        .and(api_enabled(!serve_args.no_api))
+        .and(writable(read_only))
+        .and(warp::header::<String>("Authorization"))
+        .and_then(move |token: String| {
+            let terms = terms_for_accept.clone();
+            async move {
+                let Some(terms) = terms else {
+                    return Err(warp::reject::custom(MissingError(
+                        "the registry has no publishing terms".to_string(),
+                    )));
+                };
+                let publisher = publisher_id(&token);
+                terms
+                    .accept(&publisher)
+                    .map_err(|err| warp::reject::custom(ServerError(err)))?;
+                info!(
+                    "{} accepted the publishing terms at {}",
+                    publisher,
+                    terms.url()
+                );
+                Ok(warp::reply::json(&serde_json::json!({ "ok": true })))
+            }
         })
         .with(warp::trace::request());
+    let publish_args = Arc::new(serve_args.clone());
+    let publishes = Arc::new(Semaphore::new(serve_args.max_concurrent_publishes as usize));
     let publish = warp::put()
         .and(warp::path("api"))
         .and(warp::path("v1"))
         .and(warp::path("crates"))
         .and(warp::path("new"))
         .and(warp::path::end())
-        .and(warp::body::bytes())
-        // We cap total body size to 20 MiB to have some upper bound. At the
-        // time of last check, crates.io employed a limit of 10 MiB.
-        .and(warp::body::content_length_limit(20 * 1024 * 1024))
-        .and_then(move |body| {
-            let index = git_index.clone();
-            let crates_folder = crates_folder.clone();
+        .and(api_enabled(!serve_args.no_api))
+        .and(writable(read_only))
+        // Cap the body size before reading it to have some upper bound.
+        .and(warp::body::content_length_limit(MAX_PUBLISH_SIZE as u64))
+        // Turn the publishes away rather than reading their bodies when too
+        // many are being handled. The permit is held until the response.
+        .and(warp::any().and_then(move || {
+            let publishes = publishes.clone();
             async move {
-                response(
-                    publish_crate(body, index, crates_folder.as_path())
-                        .await
-                        .map(|()| String::new()),
-                )
+                publishes
+                    .try_acquire_owned()
+                    .map_err(|_| warp::reject::custom(PublishesSaturatedError))
             }
-        })
+        }))
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<String>("Authorization"))
+        .and(warp::header::optional::<bool>(DRY_RUN_HEADER))
+        .and_then(
+            move |permit: OwnedSemaphorePermit,
+                  body,
+                  token: Option<String>,
+                  dry_run: Option<bool>| {
+                let index = git_index.clone();
+                let storage = storage.clone();
+                let serve_args = publish_args.clone();
+                let quota = quota.clone();
+                let staging = staging.clone();
+                let terms = publish_terms.clone();
+                async move {
+                    let _permit = permit;
+                    if let Some(terms) = terms {
+                        let publisher = token.as_deref().map(publisher_id);
+                        terms
+                            .check(publisher.as_deref())
+                            .map_err(warp::reject::custom)?;
+                    }
+                    // Report the problems of the publish, without writing anything.
+                    if dry_run == Some(true) {
+                        return check_publish(
+                            body,
+                            &index,
+                            storage.as_ref(),
+                            &quota,
+                            &serve_args,
+                            token.as_deref(),
+                        )
+                        .await
+                        .map(|()| {
+                            info!("request status: checked");
+                            json_response(&serde_json::json!({}), StatusCode::OK)
+                        })
+                        .map_err(warp::reject::custom);
+                    }
+                    if let Some(staging) = staging {
+                        return staging
+                            .stage_publish(body, token.as_deref())
+                            .map(|_| {
+                                info!("request status: staged");
+                                json_response(&serde_json::json!({}), StatusCode::OK)
+                            })
+                            .map_err(warp::reject::custom);
+                    }
+                    publish_crate(
+                        body,
+                        index,
+                        storage.as_ref(),
+                        &quota,
+                        &serve_args,
+                        token.as_deref(),
+                    )
+                    .await
+                    .map(|()| {
+                        info!("request status: success");
+                        json_response(&serde_json::json!({}), StatusCode::OK)
+                    })
+                    .map_err(warp::reject::custom)
+                }
+            },
+        )
         .with(warp::trace::request());
 
     // For Rust installation
-    let dist_dir = warp::path::path("dist").and(warp::fs::dir(root.join("dist")));
-    let rustup_dir = warp::path::path("rustup").and(warp::fs::dir(root.join("rustup")));
-
-    let routes = frontend
-        .or(crates)
-        .or(download)
-        .or(publish)
-        .or(dist_dir)
-        .or(rustup_dir)
-        // .or(sparse_index)
-        .or(index);
-    // Despite the claim that this function "Returns [...] a Future that
-    // can be executed on any runtime." not even the call itself can
-    // happen outside of a tokio runtime. Boy.
+    let dist_dir = warp::path::path("dist").and(static_dir(root.join("dist")));
+    let rustup_dir = warp::path::path("rustup").and(static_dir(root.join("rustup")));
 
-    warp::serve(routes)
-        .run_incoming(TcpListenerStream::new(binding.into().to_listener().await?))
-        .await;
+    // The documentation of a toolchain, browsable offline.
+    let docs_dir = match &serve_args.serve_docs {
+        Some(channel) => {
+            Some(extract_docs(root, channel, &serve_args.docs_platform, read_only).await?)
+        }
+        None => None,
+    };
+    let docs = warp::path("docs").and(match docs_dir {
+        Some(docs_dir) => warp::fs::dir(docs_dir).boxed(),
+        None => warp::any()
+            .and_then(|| async {
+                Err(warp::reject::custom(MissingError(
+                    "the documentation isn't served, see --serve-docs".to_string(),
+                )))
+            })
+            .boxed(),
+    });
 
-    Ok(())
+    let banner = Arc::new(Banner::load(root)?);
+    let banner_for_get = banner.clone();
+    let get_banner = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("banner"))
+        .and(warp::path::end())
+        .map(move || {
+            warp::reply::json(&BannerMessage {
+                message: banner_for_get.message(),
+            })
+        });
+    let banner_for_set = banner.clone();
+    let set_banner = warp::post()
+        .and(warp::path("api"))
+        .and(warp::path("admin"))
+        .and(warp::path("banner"))
+        .and(warp::path::end())
+        .and(writable(read_only))
+        .and(admin(serve_args.admin_token.as_deref()))
+        .and(warp::body::content_length_limit(MAX_BANNER_SIZE))
+        .and(warp::body::json())
+        .and_then(move |body: BannerMessage| {
+            let banner = banner_for_set.clone();
+            async move {
+                banner
+                    .set(&body.message)
+                    .map_err(|err| warp::reject::custom(ServerError(err)))?;
+                info!("Banner set to {:?}", banner.message());
+                Ok::<_, Rejection>(warp::reply::json(&BannerMessage {
+                    message: banner.message(),
+                }))
+            }
+        });
+
+    // The mirror is meant for internal networks, keep crawlers out of it.
+    let robots = warp::get()
+        .and(warp::path("robots.txt"))
+        .and(warp::path::end())
+        .map(|| "User-agent: *\nDisallow: /\n");
+
+    let routes = base_path_filter(&base_path)
+        .and(
+            robots
+                .or(discovery)
+                .or(sparse_index)
+                .or(index)
+                .or(registry_enabled(serve_args.index_only.is_none()).and(
+                    get_banner
+                        .or(set_banner)
+                        .or(crate_downloads)
+                        .or(frontend)
+                        .or(crates)
+                        .or(download)
+                        .or(readme)
+                        .or(publish)
+                        .or(accept_terms)
+                        .or(yank)
+                        .or(delete_version)
+                        .or(dist_dir)
+                        .or(rustup_dir)
+                        .or(docs),
+                )),
+        )
+        .recover(handle_rejection)
+        // Announce the banner, if any, to all the clients of the registry.
+        .map(move |reply| {
+            let mut response = warp::Reply::into_response(reply);
+            if let Some(value) = banner.header_value() {
+                response.headers_mut().insert(BANNER_HEADER, value);
+            }
+            response
+        })
+        .with(warp::reply::with::headers(server_headers));
+    Ok(access_log(routes, serve_args.access_log))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// The target of the access log lines, to filter them apart from the rest.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
 
-    use serde_json::to_string;
+/// The status warp answers a rejection left unhandled with.
+fn rejection_status(rejection: &Rejection) -> StatusCode {
+    if rejection.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else if rejection.find::<MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else if rejection.find::<LengthRequired>().is_some() {
+        StatusCode::LENGTH_REQUIRED
+    } else if rejection.find::<PayloadTooLarge>().is_some() {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else if rejection.find::<UnsupportedMediaType>().is_some() {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    } else if rejection.find::<MissingHeader>().is_some()
+        || rejection.find::<InvalidHeader>().is_some()
+        || rejection.find::<InvalidQuery>().is_some()
+    {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Wrap `routes` to log a line per request to [`ACCESS_LOG_TARGET`] when
+/// `enabled`.
+fn access_log<F, R>(
+    routes: F,
+    enabled: bool,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(
+            routes
+                .map(|reply: R| Ok(reply.into_response()))
+                .or_else(|rejection| async move { Ok::<_, Rejection>((Err(rejection),)) }),
+        )
+        .and_then(
+            move |started: Instant,
+                  method: Method,
+                  path: FullPath,
+                  result: Result<warp::reply::Response, Rejection>| async move {
+                if enabled {
+                    let (status, bytes) = match &result {
+                        Ok(response) => (response.status(), response_size(response)),
+                        Err(rejection) => (rejection_status(rejection), None),
+                    };
+                    info!(
+                        target: ACCESS_LOG_TARGET,
+                        "{} {} {} {} {:?}",
+                        method,
+                        path.as_str(),
+                        status.as_u16(),
+                        bytes.map_or_else(|| "-".to_string(), |bytes| bytes.to_string()),
+                        started.elapsed()
+                    );
+                }
+                result
+            },
+        )
+}
+
+/// The size of the body of `response`, if known upfront.
+fn response_size(response: &warp::reply::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .or_else(|| HttpBody::size_hint(response.body()).exact())
+}
+
+/// The connections accepted by `listener`, configured as per `serve_args`.
+fn incoming(
+    listener: TcpListener,
+    serve_args: ServeArgs,
+) -> impl Stream<Item = std::io::Result<TcpStream>> {
+    TcpListenerStream::new(listener).map(move |stream| {
+        let stream = stream?;
+        // The options only tune the connection, a failure must not end the
+        // server along with the stream of connections.
+        if let Err(err) = configure_stream(&stream, &serve_args) {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+            warn!("Failed to configure the connection of {}: {}", peer, err);
+        }
+        Ok(stream)
+    })
+}
+
+/// A server answering the requests received on `listener` with `routes`
+/// until `shutdown` completes, configured as per `serve_args`.
+async fn http_server<F>(
+    routes: F,
+    listener: TcpListener,
+    serve_args: ServeArgs,
+    shutdown: impl Future<Output = ()>,
+) where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let service = warp::service(routes);
+    let mut builder = Server::builder(accept::from_stream(incoming(listener, serve_args.clone())))
+        .http1_header_read_timeout(Duration::from_secs(serve_args.header_timeout));
+    if let Some(max_header_size) = serve_args.max_header_size {
+        builder = builder.http1_max_buf_size(max_header_size as usize);
+    }
+    let server = builder
+        .serve(make_service_fn(move |_| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service) }
+        }))
+        .with_graceful_shutdown(shutdown);
+    if let Err(err) = server.await {
+        error!("server error: {}", err);
+    }
+}
+
+/// Describe the likely misconfiguration of a registry listening on all the
+/// interfaces while telling clients, through the index config, to reach it
+/// on a loopback address only the local host can connect to.
+fn server_addr_warning(serve_args: &ServeArgs) -> Option<String> {
+    let (server_addr, binding_addr) = (serve_args.server_addr, serve_args.binding_addr);
+    if !(server_addr.ip().is_loopback() && binding_addr.ip().is_unspecified()) {
+        return None;
+    }
+    Some(format!(
+        "the registry listens on {binding_addr} but tells clients to reach it at {server_addr}, \
+         which remote clients can't connect to; pass the address they use, e.g. \
+         --server-addr <host address>:{}",
+        binding_addr.port()
+    ))
+}
+
+/// Serve a registry as described by `serve_args` on the given binding.
+pub async fn serve(serve_args: ServeArgs, binding: impl Into<ServerBinding>) -> Result<()> {
+    if let Some(warning) = server_addr_warning(&serve_args) {
+        warn!("{}", warning);
+    }
+    let routes = routes(&serve_args).await?;
+    // Despite the claim that this function "Returns [...] a Future that
+    // can be executed on any runtime." not even the call itself can
+    // happen outside of a tokio runtime. Boy.
+
+    let listener = binding.into().into_listener(serve_args.listen_backlog)?;
+    http_server(routes, listener, serve_args, future::pending()).await;
+
+    Ok(())
+}
+
+/// A registry server running in the background, as started by
+/// [`spawn_serve`].
+pub struct ServeHandle {
+    addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ServeHandle {
+    /// The address the server is listening on, with the actual port when
+    /// bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting connections and wait for the pending requests to
+    /// complete.
+    pub async fn shutdown(self) -> Result<()> {
+        // The server may be gone already, in which case there is nothing to stop.
+        let _ = self.shutdown.send(());
+        self.task.await.context("server task failed")
+    }
+}
+
+/// Bind the given binding and serve a registry as described by
+/// `serve_args` on it in the background, which allows embedding the
+/// registry, e.g. in tests binding port 0.
+pub async fn spawn_serve(
+    serve_args: ServeArgs,
+    binding: impl Into<ServerBinding>,
+) -> Result<ServeHandle> {
+    if let Some(warning) = server_addr_warning(&serve_args) {
+        warn!("{}", warning);
+    }
+    let routes = routes(&serve_args).await?;
+    let listener = binding.into().into_listener(serve_args.listen_backlog)?;
+    let addr = listener
+        .local_addr()
+        .context("failed to get the listening address")?;
+    let (shutdown, signal) = oneshot::channel();
+    let server = http_server(routes, listener, serve_args, async {
+        let _ = signal.await;
+    });
+    Ok(ServeHandle {
+        addr,
+        shutdown,
+        task: tokio::spawn(server),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use clap::Parser as _;
+    use serde_json::to_string;
+    use tempfile::tempdir;
+
+    use crate::index::Entries;
+    use crate::publish::tests::crate_data;
+    use crate::publish::tests::metadata;
+    use crate::publish::tests::publish_body;
+    use crate::Cli;
+    use crate::Commands;
+
+    /// Create the serve arguments for a registry at `root` as if they
+    /// were passed on the command line, together with `extra_args`.
+    pub(crate) fn serve_args(root: &Path, extra_args: &[&str]) -> ServeArgs {
+        let args = ["crates-registry", "serve", "--root-registry"]
+            .into_iter()
+            .chain([root.to_str().unwrap()])
+            .chain(extra_args.iter().copied());
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Serve(serve_args) => serve_args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn base_path_normalization() {
+        let root = Path::new("/registry");
+        assert_eq!(serve_args(root, &[]).base_path(), "");
+        assert_eq!(serve_args(root, &["--base-path", "/"]).base_path(), "");
+        assert_eq!(
+            serve_args(root, &["--base-path", "registry/"]).base_path(),
+            "/registry"
+        );
+        assert_eq!(
+            serve_args(root, &["--base-path", "/a/b"]).server_url(),
+            "http://127.0.0.1:5000/a/b"
+        );
+    }
+
+    /// Check that the registry can be served under a path prefix.
+    #[tokio::test]
+    async fn serve_under_base_path() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &["--base-path", "/registry"]);
+        let routes = routes(&args).await.unwrap();
+
+        let config =
+            std::fs::read_to_string(root.path().join("index").join("config.json")).unwrap();
+        let config = serde_json::from_str::<serde_json::Value>(&config).unwrap();
+        assert_eq!(config["api"], "http://127.0.0.1:5000/registry");
+        assert_eq!(
+            config["dl"],
+            "http://127.0.0.1:5000/registry/api/v1/crates/{crate}/{version}/download"
+        );
+
+        let response = warp::test::request()
+            .path("/registry/api/v1/crates/my-lib/0.1.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()["location"],
+            "/registry/crates/my/-l/my-lib-0.1.0.crate"
+        );
+
+        let response = warp::test::request()
+            .path("/registry/git/index/info/refs")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.1.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn robots_txt() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+
+        let response = warp::test::request()
+            .path("/robots.txt")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "User-agent: *\nDisallow: /\n");
+    }
+
+    #[tokio::test]
+    async fn discovery_document() {
+        let root = tempdir().unwrap();
+        let args = serve_args(
+            root.path(),
+            &["--server-addr", "10.0.0.1:8080", "--base-path", "registry"],
+        );
+        let routes = routes(&args).await.unwrap();
+
+        let response = warp::test::request()
+            .path("/registry/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let discovery: Discovery = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            discovery.index.git,
+            "http://10.0.0.1:8080/registry/git/index"
+        );
+        assert_eq!(
+            discovery.index.sparse.as_deref(),
+            Some("sparse+http://10.0.0.1:8080/registry/index/")
+        );
+        assert_eq!(
+            discovery.dl,
+            "http://10.0.0.1:8080/registry/api/v1/crates/{crate}/{version}/download"
+        );
+        assert_eq!(
+            discovery.api.as_deref(),
+            Some("http://10.0.0.1:8080/registry")
+        );
+        assert!(!discovery.auth_required);
+
+        // With an access control list, publishing needs a token.
+        let acl = root.path().join("acl.toml");
+        std::fs::write(&acl, "[crates]\n").unwrap();
+        let routes = super::routes(&serve_args(root.path(), &["--acl", acl.to_str().unwrap()]))
+            .await
+            .unwrap();
+        let response = warp::test::request()
+            .path("/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let discovery: Discovery = serde_json::from_slice(response.body()).unwrap();
+        assert!(discovery.auth_required);
+    }
+
+    /// Check that with an `--index-url` the discovery document points to the
+    /// index there, while the config of the index keeps the APIs on the
+    /// registry.
+    #[tokio::test]
+    async fn index_url_override() {
+        let root = tempdir().unwrap();
+        let args = serve_args(
+            root.path(),
+            &[
+                "--server-addr",
+                "10.0.0.1:8080",
+                "--index-url",
+                "https://cdn.example.com/registry/",
+            ],
+        );
+        let routes = routes(&args).await.unwrap();
+
+        let response = warp::test::request()
+            .path("/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        let discovery: Discovery = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            discovery.index.git,
+            "https://cdn.example.com/registry/git/index"
+        );
+        assert_eq!(
+            discovery.index.sparse.as_deref(),
+            Some("sparse+https://cdn.example.com/registry/index/")
+        );
+        assert_eq!(discovery.api.as_deref(), Some("http://10.0.0.1:8080"));
+
+        let response = warp::test::request()
+            .path("/index/config.json")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let config: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            config["dl"],
+            "http://10.0.0.1:8080/api/v1/crates/{crate}/{version}/download"
+        );
+        assert_eq!(config["api"], "http://10.0.0.1:8080");
+    }
+
+    /// Check that the documentation of a mirrored toolchain is served
+    /// under /docs/ with --serve-docs only.
+    #[tokio::test]
+    async fn serve_docs() {
+        let root = tempdir().unwrap();
+        let dist = root.path().join("dist");
+        std::fs::create_dir_all(dist.join("2023-03-09")).unwrap();
+        let url = "https://static.rust-lang.org/dist/2023-03-09/\
+                   rust-docs-1.68.0-x86_64-unknown-linux-gnu.tar.xz";
+        std::fs::write(
+            dist.join("channel-rust-stable.toml"),
+            format!(
+                "manifest-version = \"2\"\n\
+                 date = \"2023-03-09\"\n\
+                 [pkg.rust-docs]\n\
+                 version = \"1.68.0 (2c8cc3432 2023-03-06)\"\n\
+                 [pkg.rust-docs.target.x86_64-unknown-linux-gnu]\n\
+                 available = true\n\
+                 url = \"{url}\"\n\
+                 hash = \"00\"\n\
+                 xz_url = \"{url}\"\n\
+                 xz_hash = \"00\"\n"
+            ),
+        )
+        .unwrap();
+
+        // A package with a fixture docs tree.
+        let staging = tempdir().unwrap();
+        let name = "rust-docs-1.68.0-x86_64-unknown-linux-gnu";
+        let html = staging.path().join(name).join(crate::docs::DOCS_HTML_DIR);
+        std::fs::create_dir_all(html.join("std")).unwrap();
+        std::fs::write(html.join("index.html"), "the rust docs").unwrap();
+        std::fs::write(html.join("std/index.html"), "the std docs").unwrap();
+        let status = std::process::Command::new("tar")
+            .arg("--create")
+            .arg("--xz")
+            .arg("--file")
+            .arg(dist.join("2023-03-09").join(format!("{name}.tar.xz")))
+            .arg("--directory")
+            .arg(staging.path())
+            .arg(name)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let routes = super::routes(&serve_args(root.path(), &["--serve-docs", "stable"]))
+            .await
+            .unwrap();
+        for (path, body) in [
+            ("/docs/", "the rust docs"),
+            ("/docs/std/index.html", "the std docs"),
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            assert_eq!(response.body(), body, "{path}");
+        }
+        assert!(root.path().join("docs").join(name).is_dir());
+
+        let routes = super::routes(&serve_args(root.path(), &[])).await.unwrap();
+        let response = warp::test::request().path("/docs/").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let err = super::routes(&serve_args(root.path(), &["--serve-docs", "beta"]))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("isn't mirrored"), "{err}");
+
+        // A read-only registry serves the documentation extracted beforehand,
+        // and doesn't extract it.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+
+            let set_mode = |mode| {
+                std::fs::set_permissions(root.path(), std::fs::Permissions::from_mode(mode))
+                    .unwrap();
+            };
+            let docs_args = serve_args(root.path(), &["--serve-docs", "stable"]);
+            set_mode(0o555);
+            let routes = super::routes(&docs_args).await;
+            set_mode(0o755);
+            let response = warp::test::request()
+                .path("/docs/")
+                .reply(&routes.unwrap())
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            std::fs::remove_dir_all(root.path().join("docs").join(name)).unwrap();
+            set_mode(0o555);
+            let routes = super::routes(&docs_args).await;
+            set_mode(0o755);
+            let err = routes.err().unwrap();
+            assert!(err.to_string().contains("read-only"), "{err}");
+            assert!(!root.path().join("docs").join(name).exists());
+        }
+    }
+
+    /// Check that a gzip compressed fetch request, as sent by git for
+    /// large negotiations, is answered with the pack of the index.
+    #[tokio::test]
+    async fn compressed_upload_pack_request() {
+        use std::io::Write as _;
+
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let head = git2::Repository::open(root.path().join("index"))
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap();
+
+        let request = format!("0032want {head}\n00000009done\n");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(request.as_bytes()).unwrap();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/git/index/git-upload-pack")
+            .header("Content-Type", "application/x-git-upload-pack-request")
+            .header("Content-Encoding", "gzip")
+            .body(encoder.finish().unwrap())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/x-git-upload-pack-result"
+        );
+        assert!(response.body().starts_with(b"0008NAK\n"));
+        assert!(response.body().windows(4).any(|window| window == b"PACK"));
+    }
+
+    /// Check that the protocol version asked by git clients is forwarded
+    /// to git, for v2 clones.
+    #[tokio::test]
+    async fn git_protocol_v2() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+
+        let response = warp::test::request()
+            .path("/git/index/info/refs?service=git-upload-pack")
+            .header("Git-Protocol", "version=2")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("version 2\n"), "{body}");
+        assert!(body.contains("ls-refs"), "{body}");
+
+        // Without the header, the v0 advertisement lists the refs.
+        let response = warp::test::request()
+            .path("/git/index/info/refs?service=git-upload-pack")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(!body.contains("version 2\n"), "{body}");
+        assert!(body.contains("refs/heads/"), "{body}");
+    }
+
+    /// Check that directories of the mirror are never listed.
+    #[tokio::test]
+    async fn no_directory_listing() {
+        let root = tempdir().unwrap();
+        let platform_dir = root.path().join("rustup/dist/x86_64-unknown-linux-gnu");
+        std::fs::create_dir_all(&platform_dir).unwrap();
+        std::fs::write(platform_dir.join("rustup-init"), "rustup-init").unwrap();
+        std::fs::write(platform_dir.join("index.html"), "listing").unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+
+        for path in [
+            "/rustup/dist/",
+            "/rustup/dist/x86_64-unknown-linux-gnu/",
+            "/crates/",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            // The exact status depends on the other routes that rejected
+            // the request, but nothing may be served.
+            assert!(response.status().is_client_error(), "{path}");
+            assert!(!String::from_utf8_lossy(response.body()).contains("listing"));
+        }
+
+        let response = warp::test::request()
+            .path("/rustup/dist/x86_64-unknown-linux-gnu/rustup-init")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "rustup-init");
+    }
+
+    /// Check that downloads of yanked versions are annotated.
+    #[tokio::test]
+    async fn download_yanked_version() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let crate_meta_dir = root.path().join("index").join(crate_path("my-lib"));
+        std::fs::create_dir_all(&crate_meta_dir).unwrap();
+        let entry = |vers: &str, yanked: bool| {
+            format!(
+                r#"{{"name":"my-lib","vers":"{vers}","deps":[],"cksum":"00","features":{{}},"yanked":{yanked},"links":null}}"#
+            )
+        };
+        std::fs::write(
+            crate_meta_dir.join("my-lib"),
+            [entry("0.1.0", true), entry("0.2.0", false)].join("\n"),
+        )
+        .unwrap();
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.1.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["X-Crate-Yanked"], "true");
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.2.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert!(!response.headers().contains_key("X-Crate-Yanked"));
+    }
+
+    /// Check that crate files are served without a redirect with
+    /// `--direct-download`.
+    #[tokio::test]
+    async fn direct_download() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &["--direct-download"]))
+            .await
+            .unwrap();
+        let data = crate_data("my-lib", "0.1.0", &[("src/lib.rs", "")]);
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), &data))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.1.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/gzip");
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            data.len().to_string().as_str()
+        );
+        assert_eq!(response.body().as_ref(), data.as_slice());
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.2.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        assert!(accepts_encoding("gzip, deflate, br", "gzip"));
+        assert!(accepts_encoding("br;q=1.0, GZIP;q=0.5", "gzip"));
+        assert!(accepts_encoding("*", "deflate"));
+        assert!(!accepts_encoding("gzip;q=0", "gzip"));
+        assert!(!accepts_encoding("br, identity", "gzip"));
+        assert!(!accepts_encoding("", "gzip"));
+    }
+
+    /// Check that the JSON API responses are compressed as accepted by the
+    /// clients, and the crate files never.
+    #[tokio::test]
+    async fn json_api_compressed() {
+        use std::io::Read as _;
+
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let data = crate_data("my-lib", "0.1.0", &[("src/lib.rs", "")]);
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), &data))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let get = |path: &str, accept_encoding: &str| {
+            warp::test::request()
+                .path(path)
+                .header("Accept-Encoding", accept_encoding)
+                .reply(&routes)
+        };
+
+        let response = get("/api/crates", "br;q=1.0, gzip;q=0.8").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&response.body()[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        let crates: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(crates[0]["name"], "my-lib");
+
+        let response = get("/api/versions", "deflate").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Encoding"], "deflate");
+
+        for accept_encoding in ["identity", "gzip;q=0"] {
+            let response = get("/api/crates", accept_encoding).await;
+            assert!(!response.headers().contains_key("Content-Encoding"));
+            let crates: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(crates[0]["name"], "my-lib");
+        }
+
+        let response = get("/api/v1/crates/my-lib/0.1.0/download", "gzip").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        let location = response.headers()["Location"].to_str().unwrap().to_string();
+        let response = get(&location, "gzip").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("Content-Encoding"));
+        assert_eq!(response.body().as_ref(), data.as_slice());
+    }
+
+    /// Check that a compressed route runs once per request, whether it
+    /// rejects it or not.
+    #[tokio::test]
+    async fn compressed_route_runs_once() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let route_runs = runs.clone();
+        let route = warp::path("fail")
+            .map(move || route_runs.fetch_add(1, Ordering::SeqCst))
+            .and_then(|_| async { Err::<String, _>(warp::reject::not_found()) })
+            .or(warp::path("ok").map(|| "{}".to_string()))
+            .unify();
+        let route = compressed(route);
+        for accept_encoding in ["gzip, deflate", "deflate", "identity"] {
+            let response = warp::test::request()
+                .path("/fail")
+                .header("Accept-Encoding", accept_encoding)
+                .filter(&route)
+                .await;
+            assert!(response.is_err());
+            let response = warp::test::request()
+                .path("/ok")
+                .header("Accept-Encoding", accept_encoding)
+                .filter(&route)
+                .await
+                .unwrap();
+            assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    /// Check that the downloads of the versions of the index are counted.
+    #[tokio::test]
+    async fn download_counts() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        for version in ["0.1.0", "0.2.0"] {
+            let data = crate_data("my-lib", version, &[("src/lib.rs", "")]);
+            let response = warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(publish_body(&metadata("my-lib", version), &data))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        for version in ["0.1.0", "0.1.0", "0.1.0", "0.2.0", "0.3.0"] {
+            warp::test::request()
+                .path(&format!("/api/v1/crates/my-lib/{}/download", version))
+                .reply(&routes)
+                .await;
+        }
+
+        let response = warp::test::request()
+            .path("/api/crates")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let crates: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            crates,
+            serde_json::json!([{
+                "name": "my-lib",
+                "downloads": 4,
+                "versions": [
+                    {"version": "0.1.0", "yanked": false, "downloads": 3},
+                    {"version": "0.2.0", "yanked": false, "downloads": 1},
+                ],
+            }])
+        );
+    }
+
+    /// Check that a registry served with `--no-api` tells cargo it can't
+    /// publish, and refuses publishes and yanks.
+    #[tokio::test]
+    async fn read_only_registry() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &["--no-api"]))
+            .await
+            .unwrap();
+        let config: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(root.path().join("index/config.json")).unwrap())
+                .unwrap();
+        assert_eq!(config["api"], serde_json::Value::Null);
+        assert!(config["dl"].as_str().unwrap().ends_with("/download"));
+
+        let data = crate_data("my-lib", "0.1.0", &[("src/lib.rs", "")]);
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), &data))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!root.path().join("index/my/-l/my-lib").exists());
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/api/v1/crates/my-lib/0.1.0/yank")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Serving with the API again restores it in the config.
+        super::routes(&serve_args(root.path(), &[])).await.unwrap();
+        let config: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(root.path().join("index/config.json")).unwrap())
+                .unwrap();
+        assert!(config["api"].is_string());
+    }
+
+    /// Check that a registry on a read-only filesystem is served, and
+    /// refuses the requests changing it.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn read_only_root() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let data = crate_data("my-lib", "0.1.0", &[("src/lib.rs", "")]);
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), &data))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(routes);
+
+        let set_mode = |mode| {
+            for dir in [root.path().to_path_buf(), root.path().join("index")] {
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode)).unwrap();
+            }
+        };
+        set_mode(0o555);
+        let routes = super::routes(&serve_args(root.path(), &[])).await;
+        set_mode(0o755);
+        let routes = routes.unwrap();
+
+        let response = warp::test::request()
+            .path("/index/my/-l/my-lib")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for (method, path) in [
+            ("PUT", "/api/v1/crates/new"),
+            ("DELETE", "/api/v1/crates/my-lib/0.1.0/yank"),
+            ("PUT", "/api/load-pack-file"),
+            ("POST", "/api/admin/banner"),
+        ] {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body(publish_body(&metadata("my-lib", "0.2.0"), &data))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{method} {path}");
+            let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+            assert!(errors.errors[0].detail.contains("read-only"));
+        }
+        let index = Index::open(root.path().join("index")).unwrap();
+        assert!(!index.entry("my-lib", "0.1.0").unwrap().unwrap().yanked);
+        assert!(index.entry("my-lib", "0.2.0").unwrap().is_none());
+
+        // Nothing is cached from upstream.
+        set_mode(0o555);
+        let routes = super::routes(&serve_args(
+            root.path(),
+            &["--upstream", "http://127.0.0.1:1"],
+        ))
+        .await;
+        set_mode(0o755);
+        let err = routes.err().unwrap();
+        assert!(err.to_string().contains("read-only"), "{err}");
+    }
+
+    /// Check that versions with build metadata are downloaded from the
+    /// crate file cargo published, whether the `+` is percent-encoded or not.
+    #[tokio::test]
+    async fn download_version_with_build_metadata() {
+        let root = tempdir().unwrap();
+        for extra_args in [&[][..], &["--direct-download"]] {
+            let routes = routes(&serve_args(root.path(), extra_args)).await.unwrap();
+            let data = crate_data("my-lib", "1.0.0+build.1", &[("src/lib.rs", "")]);
+            let response = warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(publish_body(&metadata("my-lib", "1.0.0+build.1"), &data))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(root
+                .path()
+                .join("crates/my/-l/my-lib-1.0.0+build.1.crate")
+                .exists());
+
+            for path in [
+                "/api/v1/crates/my-lib/1.0.0+build.1/download",
+                "/api/v1/crates/my-lib/1.0.0%2Bbuild.1/download",
+                "/api/v1/crates/my-lib/1.0.0%2bbuild.1/download",
+            ] {
+                let response = warp::test::request().path(path).reply(&routes).await;
+                if extra_args.is_empty() {
+                    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "{path}");
+                    let location = response.headers()["location"].to_str().unwrap();
+                    let response = warp::test::request().path(location).reply(&routes).await;
+                    assert_eq!(response.status(), StatusCode::OK, "{location}");
+                    assert_eq!(response.body().as_ref(), data.as_slice());
+                } else {
+                    assert_eq!(response.status(), StatusCode::OK, "{path}");
+                    assert_eq!(response.body().as_ref(), data.as_slice());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crate_file_uris() {
+        for (base_path, key, uri) in [
+            (
+                "",
+                "my/-l/my-lib-0.1.0.crate",
+                "/crates/my/-l/my-lib-0.1.0.crate",
+            ),
+            (
+                "/registry",
+                "1/a-0.1.0+b.1.crate",
+                "/registry/crates/1/a-0.1.0+b.1.crate",
+            ),
+            ("", "3/a/a b-1 %.crate", "/crates/3/a/a%20b-1%20%25.crate"),
+            (
+                "",
+                "2/é?#-0.1.0.crate",
+                "/crates/2/%C3%A9%3F%23-0.1.0.crate",
+            ),
+        ] {
+            assert_eq!(crate_file_uri(base_path, key).unwrap().to_string(), uri);
+        }
+    }
+
+    /// Check that the downloads of any valid crate name and version redirect
+    /// to the path of their crate file, and that the others are answered
+    /// with a Cargo error.
+    #[tokio::test]
+    async fn download_redirect_paths() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+
+        let alphabet = ['a', 'Z', '0', '9', '-', '_'];
+        let mut names = vec![String::new()];
+        for _ in 0..3 {
+            names = names
+                .iter()
+                .flat_map(|name| alphabet.iter().map(move |c| format!("{name}{c}")))
+                .collect();
+        }
+        let names = names
+            .into_iter()
+            .flat_map(|name| [name[..1].to_string(), name[..2].to_string(), name])
+            .chain(["a-b_", "my-lib", "Z9_-a"].map(String::from))
+            .chain([alphabet.iter().cycle().take(64).collect::<String>()]);
+        for name in names {
+            for version in ["0.1.0", "1.0.0-alpha.1+build.5"] {
+                let path = format!("/api/v1/crates/{name}/{version}/download");
+                let response = warp::test::request().path(&path).reply(&routes).await;
+                assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "{path}");
+                let location = response.headers()["location"].to_str().unwrap();
+                assert_eq!(
+                    location.parse::<Uri>().unwrap().path(),
+                    format!("/crates/{}", crate_key(&name, version)),
+                    "{path}"
+                );
+            }
+        }
+
+        for (name, version) in [
+            ("%C3%A9t%C3%A9", "0.1.0"),
+            ("a%20b", "0.1.0"),
+            ("..", "0.1.0"),
+            ("a%2Fb", "0.1.0"),
+            ("a%3Fb%23", "0.1.0"),
+            ("%25", "0.1.0"),
+            ("my-lib", "0.1.0%2F..%2F.."),
+            ("my-lib", "..%2F..%2F.."),
+            ("my-lib", ".."),
+            ("my-lib", "0.1.0%20"),
+            ("my-lib", "%C3%A9"),
+        ] {
+            let path = format!("/api/v1/crates/{name}/{version}/download");
+            let response = warp::test::request().path(&path).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+            let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+            assert!(errors.errors[0].detail.contains("has no version"), "{path}");
+        }
+    }
+
+    /// Check that a taken down version is announced until its sunset and
+    /// then answered with a 451.
+    #[tokio::test]
+    async fn download_taken_down_version() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &[]);
+        let routes = routes(&args).await.unwrap();
+        for vers in ["0.1.0", "0.2.0", "0.2.1"] {
+            let response = warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(publish_body(&metadata("my-lib", vers), b"data"))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let takedown = |vers: &str, sunset: Option<&str>| {
+            let mut takedown_args = vec![
+                "crates-registry",
+                "takedown",
+                "--root-registry",
+                root.path().to_str().unwrap(),
+                "--crate",
+                "my-lib",
+                "--crate-version",
+                vers,
+                "--reason",
+                "DMCA notice 42",
+            ];
+            takedown_args.extend(
+                sunset
+                    .map(|sunset| ["--sunset", sunset])
+                    .into_iter()
+                    .flatten(),
+            );
+            match Cli::try_parse_from(takedown_args).unwrap().command {
+                Commands::Takedown(takedown_args) => takedown_args,
+                _ => unreachable!(),
+            }
+        };
+        crate::takedown(&takedown("0.1.0", None)).await.unwrap();
+        crate::takedown(&takedown("0.2.0", Some("Fri, 01 Jan 2100 00:00:00 GMT")))
+            .await
+            .unwrap();
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.1.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            errors.errors[0].detail,
+            "crate my-lib in version 0.1.0 is unavailable for legal reasons: DMCA notice 42"
+        );
+        let crate_dir = root.path().join("crates").join(crate_path("my-lib"));
+        assert!(!crate_dir.join("my-lib-0.1.0.crate").exists());
+
+        // Until its sunset, the version remains downloadable.
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.2.0/download")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["Deprecation"], "true");
+        assert_eq!(
+            response.headers()["Sunset"],
+            "Fri, 01 Jan 2100 00:00:00 GMT"
+        );
+        assert!(crate_dir.join("my-lib-0.2.0.crate").exists());
+        let response = warp::test::request()
+            .path("/crates/my/-l/my-lib-0.2.0.crate")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Past its sunset, the crate file is still stored but not served.
+        crate::takedown(&takedown("0.2.1", Some("Thu, 01 Jan 2015 00:00:00 GMT")))
+            .await
+            .unwrap();
+        assert!(crate_dir.join("my-lib-0.2.1.crate").exists());
+        for path in [
+            "/api/v1/crates/my-lib/0.2.1/download",
+            "/crates/my/-l/my-lib-0.2.1.crate",
+            "/crates/my/-l/my%2Dlib-0.2.1.crate",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                "{path}"
+            );
+        }
+
+        // The versions are yanked in the index.
+        let index = Index::open(root.path().join("index")).unwrap();
+        for vers in ["0.1.0", "0.2.0", "0.2.1"] {
+            assert!(index.entry("my-lib", vers).unwrap().unwrap().yanked);
+        }
+        assert!(crate::takedown(&takedown("0.3.0", None)).await.is_err());
+    }
+
+    /// Check that the banner set by the operators is served, announced in
+    /// the responses and kept across restarts.
+    #[tokio::test]
+    async fn maintenance_banner() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &["--admin-token", "admin-secret"]);
+        let routes = routes(&args).await.unwrap();
+        let get_banner = || async {
+            let response = warp::test::request()
+                .path("/api/banner")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()["message"].clone()
+        };
+        let set_banner = |message: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/api/admin/banner")
+                .header("Authorization", "admin-secret")
+                .json(&serde_json::json!({ "message": message }))
+                .reply(&routes)
+        };
+        assert_eq!(get_banner().await, "");
+        // Only the admins set the banner.
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/admin/banner")
+            .json(&serde_json::json!({ "message": "Hacked" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(get_banner().await, "");
+        let response = warp::test::request()
+            .path("/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        assert!(!response.headers().contains_key(BANNER_HEADER));
+
+        let response = set_banner("Maintenance on Saturday\n10:00-12:00 UTC").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            get_banner().await,
+            "Maintenance on Saturday\n10:00-12:00 UTC"
+        );
+        let response = warp::test::request()
+            .path("/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            response.headers()[BANNER_HEADER],
+            "Maintenance on Saturday 10:00-12:00 UTC"
+        );
+
+        // The banner outlives the server.
+        let routes = super::routes(&args).await.unwrap();
+        let response = warp::test::request()
+            .path("/api/banner")
+            .reply(&routes)
+            .await;
+        assert!(response.headers().contains_key(BANNER_HEADER));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/admin/banner")
+            .header("Authorization", "admin-secret")
+            .json(&serde_json::json!({ "message": "" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(BANNER_HEADER));
+        assert!(!root.path().join("banner.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn server_header() {
+        let root = tempdir().unwrap();
+        let routes = super::routes(&serve_args(root.path(), &[])).await.unwrap();
+        // Also on the errors, here a crate missing from the registry.
+        for path in [
+            "/.well-known/cargo",
+            "/git/index/config.json",
+            "/api/v1/crates/my-lib/0.1.0/download",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(
+                response.headers()[SERVER],
+                concat!("crates-registry/", env!("CARGO_PKG_VERSION")),
+                "{path}"
+            );
+        }
+
+        let args = serve_args(root.path(), &["--server-header", "registry-eu-1"]);
+        let routes = super::routes(&args).await.unwrap();
+        let response = warp::test::request()
+            .path("/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.headers()[SERVER], "registry-eu-1");
+
+        let args = serve_args(root.path(), &["--server-header", ""]);
+        let routes = super::routes(&args).await.unwrap();
+        let response = warp::test::request()
+            .path("/.well-known/cargo")
+            .reply(&routes)
+            .await;
+        assert!(!response.headers().contains_key(SERVER));
+
+        let args = serve_args(root.path(), &["--server-header", "bad\nvalue"]);
+        assert!(super::routes(&args).await.is_err());
+    }
+
+    /// Check that each class of publish failure is reported with its own
+    /// status and a registry JSON error.
+    #[tokio::test]
+    async fn publish_error_status_codes() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let publish = |body: Vec<u8>| {
+            warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(body)
+                .reply(&routes)
+        };
+        let detail = |body: &[u8]| -> String {
+            let errors: RegistryErrors = serde_json::from_slice(body).unwrap();
+            errors.errors[0].detail.clone()
+        };
+
+        let response = publish(publish_body(&metadata("my-lib", "0.1.0"), b"first").to_vec()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        assert_eq!(response.headers()["Content-Length"], "2");
+        assert_eq!(response.body(), "{}");
+
+        let response = publish(vec![1, 0]).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        assert_eq!(
+            response.headers()["Content-Length"],
+            response.body().len().to_string()
+        );
+        assert!(detail(response.body()).starts_with("invalid publish request"));
+
+        // A crate file sent as is.
+        let response = publish(crate_data("my-lib", "0.2.0", &[("src/lib.rs", "")])).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error = detail(response.body());
+        assert!(
+            error.starts_with("invalid publish request: the body is a gzip archive"),
+            "{error}"
+        );
+        assert!(error.contains("the format of cargo publish"), "{error}");
+
+        let response = publish(to_string(&metadata("my-lib", "0.2.0")).unwrap().into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(detail(response.body()).starts_with("invalid publish request: the body is JSON"));
+
+        let response = publish(publish_body(&metadata("", "0.1.0"), b"data").to_vec()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            detail(response.body()),
+            "invalid crate metadata: crate name cannot be empty"
+        );
+
+        // Names the download routes would refuse are never published.
+        for name in ["my/lib", "..", "my-lib.rs"] {
+            let response = publish(publish_body(&metadata(name, "0.1.0"), b"data").to_vec()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                detail(response.body()),
+                format!(
+                    "invalid crate metadata: invalid crate name {name}: \
+                     only ASCII letters, digits, `-` and `_` are allowed"
+                )
+            );
+        }
+
+        let response =
+            publish(publish_body(&metadata("my-lib", "0.1.0"), b"second").to_vec()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            detail(response.body()),
+            "crate my-lib in version 0.1.0 already exists in the registry"
+        );
+
+        let response = publish(vec![0; MAX_PUBLISH_SIZE + 1]).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(detail(response.body()).contains("exceeds the limit"));
+
+        // A file in place of the crate directory makes storing the crate fail.
+        std::fs::write(root.path().join("crates/ot"), "").unwrap();
+        let response = publish(publish_body(&metadata("other", "0.1.0"), b"data").to_vec()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(detail(response.body()).starts_with("failed to create directory"));
+    }
+
+    /// Check that the publishes beyond `--max-concurrent-publishes` are
+    /// turned away with a 503, and that the limit is lifted once they end.
+    #[tokio::test]
+    async fn concurrent_publishes_limited() {
+        use tokio::io::AsyncWriteExt as _;
+
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &["--max-concurrent-publishes", "2"]);
+        let handle = spawn_serve(args, SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        let body = |vers: &str| {
+            let data = crate_data("my-lib", vers, &[("src/lib.rs", "")]);
+            publish_body(&metadata("my-lib", vers), &data).to_vec()
+        };
+        let publish = |vers: &str| {
+            reqwest::Client::new()
+                .put(format!("http://{addr}/api/v1/crates/new"))
+                .body(body(vers))
+                .send()
+        };
+
+        // Publishes sending their body slowly, holding the two permits.
+        let mut slow_publishes = Vec::new();
+        for vers in ["0.1.0", "0.2.0"] {
+            let body = body(vers);
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let head = format!(
+                "PUT /api/v1/crates/new HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body[..1]).await.unwrap();
+            slow_publishes.push((stream, body));
+        }
+        let started = Instant::now();
+        let saturated = loop {
+            // Dry runs, not to publish anything before the permits are taken.
+            let response = reqwest::Client::new()
+                .put(format!("http://{addr}/api/v1/crates/new"))
+                .header(DRY_RUN_HEADER, "true")
+                .body(body("0.3.0"))
+                .send()
+                .await
+                .unwrap();
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                break response;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(saturated.headers()[RETRY_AFTER], "5");
+        let errors: RegistryErrors =
+            serde_json::from_slice(&saturated.bytes().await.unwrap()).unwrap();
+        assert!(errors.errors[0].detail.contains("too many publishes"));
+        let responses = futures::future::join_all(["0.3.0", "0.4.0", "0.5.0"].map(publish)).await;
+        for response in responses {
+            assert_eq!(response.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        for (mut stream, body) in slow_publishes {
+            stream.write_all(&body[1..]).await.unwrap();
+            let mut response = [0; 12];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"HTTP/1.1 200");
+        }
+        let response = publish("0.3.0").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        handle.shutdown().await.unwrap();
+    }
+
+    /// Check that a dry run publish reports the errors of a real one, and
+    /// writes nothing either way.
+    #[tokio::test]
+    async fn dry_run_publish() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let publish = |body: Vec<u8>, dry_run: &str| {
+            warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .header(DRY_RUN_HEADER, dry_run)
+                .body(body)
+                .reply(&routes)
+        };
+        let head = || {
+            git2::Repository::open(root.path().join("index"))
+                .unwrap()
+                .head()
+                .unwrap()
+                .target()
+                .unwrap()
+        };
+        let initial_head = head();
+        let index_file = root.path().join("index").join(crate_path("my-lib"));
+        let crate_dir = root.path().join("crates").join(crate_path("my-lib"));
+
+        let data = crate_data("my-lib", "0.1.0", &[("src/lib.rs", "")]);
+        let body = publish_body(&metadata("my-lib", "0.1.0"), &data).to_vec();
+        let response = publish(body.clone(), "true").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "{}");
+        assert!(!index_file.exists());
+        assert!(!crate_dir.exists());
+        assert_eq!(head(), initial_head);
+
+        let response = publish(
+            publish_body(&metadata("", "0.1.0"), b"data").to_vec(),
+            "true",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Without the dry run, it is published.
+        let response = publish(body, "false").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(index_file.join("my-lib").exists());
+        let published_head = head();
+        assert_ne!(published_head, initial_head);
+
+        let other = publish_body(&metadata("my-lib", "0.1.0"), b"other").to_vec();
+        let response = publish(other, "true").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let data = crate_data("my-lib", "0.2.0", &[("src/lib.rs", "")]);
+        let response = publish(
+            publish_body(&metadata("my-lib", "0.2.0"), &data).to_vec(),
+            "true",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(head(), published_head);
+        assert!(!crate_dir.join("my-lib-0.2.0.crate").exists());
+    }
+
+    /// Check that a publish is visible through the sparse index as soon as
+    /// it is acknowledged, with the content git clients fetch.
+    #[tokio::test]
+    async fn sparse_index_after_publish() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), b"data"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .path("/index/my/-l/my-lib")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let entry: crate::index::Entry = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            (entry.name.as_str(), entry.vers.as_str()),
+            ("my-lib", "0.1.0")
+        );
+        assert_eq!(
+            response.body(),
+            &std::fs::read(root.path().join("index/my/-l/my-lib")).unwrap()
+        );
+
+        let response = warp::test::request()
+            .path("/index/config.json")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+
+        // Only committed files are visible.
+        std::fs::write(root.path().join("index/my/-l/my-app"), "{}").unwrap();
+        for path in [
+            "/index/my/-l/my-app",
+            "/index/.git/config",
+            "/index/my/../config.json",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert!(response.status().is_client_error(), "{path}");
+        }
+    }
+
+    /// Check that with `--publish-terms` the publishes of a token are refused
+    /// until it accepted the terms.
+    #[tokio::test]
+    async fn publish_terms_accepted() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(
+            root.path(),
+            &["--publish-terms", "https://example.com/terms"],
+        ))
+        .await
+        .unwrap();
+        let publish = |version: &str, token: Option<&str>| {
+            let mut request = warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(publish_body(&metadata("my-lib", version), b"data"));
+            if let Some(token) = token {
+                request = request.header("Authorization", token);
+            }
+            request.reply(&routes)
+        };
+        let accept = |token: Option<&str>| {
+            let mut request = warp::test::request()
+                .method("POST")
+                .path("/api/accept-terms");
+            if let Some(token) = token {
+                request = request.header("Authorization", token);
+            }
+            request.reply(&routes)
+        };
+
+        for token in [Some("token-a"), None] {
+            let response = publish("0.1.0", token).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+            assert!(errors.errors[0]
+                .detail
+                .contains("terms at https://example.com/terms must be accepted"));
+        }
+        assert!(accept(None).await.status().is_client_error());
+
+        let response = accept(Some("token-a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), r#"{"ok":true}"#);
+        assert_eq!(
+            publish("0.1.0", Some("token-a")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            publish("0.2.0", Some("token-b")).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        // The acceptances outlive the registry.
+        let routes = super::routes(&serve_args(
+            root.path(),
+            &["--publish-terms", "https://example.com/terms"],
+        ))
+        .await
+        .unwrap();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", "token-a")
+            .body(publish_body(&metadata("my-lib", "0.2.0"), b"data"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Without terms there's nothing to accept.
+        let routes = super::routes(&serve_args(root.path(), &[])).await.unwrap();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/accept-terms")
+            .header("Authorization", "token-b")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Check the responses Cargo expects from `cargo yank` and
+    /// `cargo yank --undo`.
+    #[tokio::test]
+    async fn yank_and_unyank() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), b"data"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let index = Index::open(root.path().join("index")).unwrap();
+        let yank = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Authorization", "token")
+                .reply(&routes)
+        };
+
+        // Each request succeeds even when the version is already in the requested state.
+        for (method, action, yanked) in [
+            ("DELETE", "yank", true),
+            ("DELETE", "yank", true),
+            ("PUT", "unyank", false),
+            ("PUT", "unyank", false),
+        ] {
+            let response = yank(method, &format!("/api/v1/crates/my-lib/0.1.0/{action}")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.body(), r#"{"ok":true}"#);
+            let entry = index.entry("my-lib", "0.1.0").unwrap().unwrap();
+            assert_eq!(entry.yanked, yanked);
+        }
+        assert_eq!(
+            index.status().await.unwrap().message,
+            "Unyank my-lib in version 0.1.0"
+        );
+
+        for path in [
+            "/api/v1/crates/my-lib/0.2.0/yank",
+            "/api/v1/crates/other/0.1.0/yank",
+        ] {
+            let response = yank("DELETE", path).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+            assert!(errors.errors[0].detail.contains("has no version"));
+        }
+        // Unyanking is only done with PUT.
+        let response = yank("DELETE", "/api/v1/crates/my-lib/0.1.0/unyank").await;
+        assert!(response.status().is_client_error());
+
+        // Anonymous yanks are refused.
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/api/v1/crates/my-lib/0.1.0/yank")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+        assert!(errors.errors[0].detail.contains("anonymous yanks"));
+        assert!(!index.entry("my-lib", "0.1.0").unwrap().unwrap().yanked);
+    }
+
+    /// Check that the yanks sent while a crate is published keep both
+    /// changes.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn yank_during_publishes() {
+        let root = tempdir().unwrap();
+        let handle = spawn_serve(
+            serve_args(root.path(), &["--max-concurrent-publishes", "32"]),
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+        )
+        .await
+        .unwrap();
+        let addr = handle.local_addr();
+        let client = reqwest::Client::new();
+        let publish = |vers: String| {
+            client
+                .put(format!("http://{addr}/api/v1/crates/new"))
+                .body(publish_body(&metadata("my-lib", &vers), b"data").to_vec())
+                .send()
+        };
+        let yank = |yanked: bool| {
+            let url = format!("http://{addr}/api/v1/crates/my-lib/0.1.0");
+            match yanked {
+                true => client.delete(format!("{url}/yank")),
+                false => client.put(format!("{url}/unyank")),
+            }
+            .header("Authorization", "token")
+            .send()
+        };
+        let response = publish("0.1.0".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let publishes =
+            futures::future::join_all((2..20).map(|minor| publish(format!("0.{minor}.0"))));
+        let yanks = futures::future::join_all((2..20).map(|minor| yank(minor % 2 == 1)));
+        let (published, yanked) = tokio::join!(publishes, yanks);
+        for response in published.into_iter().chain(yanked) {
+            assert_eq!(response.unwrap().status(), StatusCode::OK);
+        }
+        let response = yank(true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        handle.shutdown().await.unwrap();
+
+        let index = Index::open(root.path().join("index")).unwrap();
+        let content = std::fs::read_to_string(root.path().join("index/my/-l/my-lib")).unwrap();
+        let committed = index
+            .committed_file(Path::new("my/-l/my-lib"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(committed, content.as_bytes());
+        let entries: Entries = content.try_into().unwrap();
+        assert_eq!(entries.len(), 19);
+        assert!(entries
+            .iter()
+            .all(|entry| entry.yanked == (entry.vers == "0.1.0")));
+    }
+
+    /// Check that the access control list applies to yanks.
+    #[tokio::test]
+    async fn yank_with_acl() {
+        let root = tempdir().unwrap();
+        let acl = root.path().join("acl.toml");
+        std::fs::write(
+            &acl,
+            format!(
+                "[crates]\n\"my-*\" = [\"{}\"]\n",
+                publisher_id("team-a-token")
+            ),
+        )
+        .unwrap();
+        let routes = routes(&serve_args(root.path(), &["--acl", acl.to_str().unwrap()]))
+            .await
+            .unwrap();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", "team-a-token")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), b"data"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let index = Index::open(root.path().join("index")).unwrap();
+        let yank = |token: &str| {
+            warp::test::request()
+                .method("DELETE")
+                .path("/api/v1/crates/my-lib/0.1.0/yank")
+                .header("Authorization", token)
+                .reply(&routes)
+        };
+
+        let response = yank("team-b-token").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            errors.errors[0].detail,
+            format!(
+                "{} is not allowed to publish crate my-lib",
+                publisher_id("team-b-token")
+            )
+        );
+        assert!(!index.entry("my-lib", "0.1.0").unwrap().unwrap().yanked);
+
+        let response = yank("team-a-token").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(index.entry("my-lib", "0.1.0").unwrap().unwrap().yanked);
+    }
+
+    /// Check that the README of a published crate is served.
+    #[tokio::test]
+    async fn crate_readme() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(root.path(), &[])).await.unwrap();
+
+        let mut with_readme = metadata("my-lib", "0.1.0");
+        with_readme["readme_file"] = "README.md".into();
+        let data = crate_data(
+            "my-lib",
+            "0.1.0",
+            &[("Cargo.toml", "[package]"), ("README.md", "# my-lib\n")],
+        );
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&with_readme, &data))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .body(publish_body(&metadata("my-lib", "0.2.0"), b"no readme"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.1.0/readme")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "# my-lib\n");
+
+        for path in [
+            "/api/v1/crates/my-lib/0.2.0/readme",
+            "/api/v1/crates/other/0.1.0/readme",
+            "/api/v1/crates/..../0.1.0/readme",
+            "/api/v1/crates/my-lib/..%2F..%2F..%2Findex%2Fconfig.json/readme",
+            "/api/v1/crates/my-lib/0.1.0%2F..%2F..%2F..%2F..%2Findex%2Fconfig.json/readme",
+            "/api/v1/crates/my-lib/../readme",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert!(response.status().is_client_error(), "{path}");
+        }
+
+        // The README path is made of the version, which must not escape the
+        // crates folder.
+        std::fs::create_dir_all(root.path().join("crates/my/-l/my-lib-1.0.0")).unwrap();
+        let readme_outside = root.path().join("x.readme");
+        std::fs::write(&readme_outside, "kept").unwrap();
+        for vers in ["1.0.0/../../x", "1.0.0/../../../../x"] {
+            let response = warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(publish_body(&metadata("my-lib", vers), b"data"))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let errors: RegistryErrors = serde_json::from_slice(response.body()).unwrap();
+            let expected = format!("invalid crate metadata: invalid crate version {vers}");
+            assert!(errors.errors[0].detail.starts_with(&expected));
+        }
+        assert_eq!(std::fs::read_to_string(&readme_outside).unwrap(), "kept");
+
+        // Only a missing README is a 404, the other errors are reported.
+        std::fs::create_dir(root.path().join("crates/my/-l/my-lib-0.3.0.readme")).unwrap();
+        let response = warp::test::request()
+            .path("/api/v1/crates/my-lib/0.3.0/readme")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn serve_with_tcp_options() {
+        let root = tempdir().unwrap();
+        let args = serve_args(
+            root.path(),
+            &[
+                "--listen-backlog",
+                "16",
+                "--tcp-nodelay",
+                "false",
+                "--tcp-keepalive",
+                "30",
+            ],
+        );
+        assert_eq!(args.listen_backlog, 16);
+        let listener = bind(([127, 0, 0, 1], 0).into(), args.listen_backlog).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(args, listener));
+
+        let response = reqwest::get(format!("http://{addr}/robots.txt"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Check that a client not finishing to send the headers of its request
+    /// within `--header-timeout` is disconnected.
+    #[tokio::test]
+    async fn slow_headers_disconnected() {
+        use tokio::io::AsyncWriteExt as _;
+
+        let root = tempdir().unwrap();
+        let args = serve_args(
+            root.path(),
+            &["--header-timeout", "1", "--max-header-size", "8192"],
+        );
+        let handle = spawn_serve(args, SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        stream
+            .write_all(b"GET /robots.txt HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+            .await
+            .expect("the connection should be closed")
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert!(!String::from_utf8_lossy(&response).contains("200 OK"));
+
+        // Clients sending their headers in time are served.
+        let response = reqwest::get(format!("http://{}/robots.txt", handle.local_addr()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Unless their headers are too large.
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/robots.txt", handle.local_addr()))
+            .header("X-Padding", "a".repeat(10_000))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn spawn_serve_on_port_0() {
+        let root = tempdir().unwrap();
+        let args = serve_args(root.path(), &[]);
+        let handle = spawn_serve(args, SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        assert_ne!(addr.port(), 0);
+
+        let response = reqwest::get(format!("http://{addr}/robots.txt"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        handle.shutdown().await.unwrap();
+        assert!(reqwest::get(format!("http://{addr}/robots.txt"))
+            .await
+            .is_err());
+    }
+
+    /// Check that a zero `--git-buffer-size` is refused with the arguments.
+    #[test]
+    fn git_buffer_size_not_zero() {
+        let parse = |size: &str| {
+            Cli::try_parse_from([
+                "crates-registry",
+                "serve",
+                "--root-registry",
+                "/registry",
+                "--git-buffer-size",
+                size,
+            ])
+        };
+        assert!(parse("0").is_err());
+        assert!(parse("512").is_ok());
+    }
+
+    #[test]
+    fn server_addr_warning_condition() {
+        let root = Path::new("/registry");
+        // The defaults: listening everywhere, advertised on the loopback.
+        let warning = server_addr_warning(&serve_args(root, &[])).unwrap();
+        assert!(warning.contains("--server-addr <host address>:5000"));
+
+        for args in [
+            &["--server-addr", "10.0.0.1:5000"][..],
+            &["--binding-addr", "127.0.0.1:5000"],
+            &["--binding-addr", "10.0.0.1:5000"],
+        ] {
+            assert_eq!(server_addr_warning(&serve_args(root, args)), None);
+        }
+        let args = serve_args(
+            root,
+            &["--binding-addr", "[::]:8080", "--server-addr", "[::1]:8080"],
+        );
+        assert!(server_addr_warning(&args).is_some());
+    }
+
+    /// A log writer keeping the lines in memory.
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Check that the access log lines are emitted with the verbosity at
+    /// its lowest, while the other info lines are not.
+    #[tokio::test]
+    async fn access_log_at_warn_level() {
+        use tracing_subscriber::layer::SubscriberExt as _;
+        use tracing_subscriber::Layer as _;
+
+        let root = tempdir().unwrap();
+        let cli = Cli::try_parse_from([
+            "crates-registry",
+            "--verbosity",
+            "0",
+            "serve",
+            "--root-registry",
+            root.path().to_str().unwrap(),
+            "--access-log",
+        ])
+        .unwrap();
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .with_filter(cli.log_filter()),
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let Commands::Serve(args) = cli.command else {
+            unreachable!()
+        };
+        let routes = routes(&args).await.unwrap();
+
+        let response = warp::test::request()
+            .path("/robots.txt")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = warp::test::request()
+            .path("/api/v1/crates/missing/0.1.0/yank")
+            .method("DELETE")
+            .header("Authorization", "token")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines = log
+            .lines()
+            .filter(|line| line.contains("access_log:"))
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(lines[0].contains(" INFO access_log: GET /robots.txt 200 26 "));
+        assert!(lines[1].contains("access_log: DELETE /api/v1/crates/missing/0.1.0/yank 404 "));
+        assert!(!log
+            .lines()
+            .any(|line| line.contains(" INFO ") && !line.contains("access_log:")));
+    }
 
     #[test]
     fn registry_error_encoding() {
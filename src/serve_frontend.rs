@@ -1,30 +1,54 @@
-use anyhow::{anyhow, Result};
-use bytes::Bytes;
+use anyhow::{anyhow, bail, Context as _, Result};
+use bytes::Buf;
+use futures::stream::{self, Stream, StreamExt as _};
 use glob::glob;
 use include_dir::{include_dir, Dir};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::convert::Infallible;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt as _;
+use tokio::sync::{watch, RwLock};
 use toml::Table;
 use tracing::error;
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
 use warp::hyper::Body;
 use warp::path::Tail;
 use warp::reply::Response;
 use warp::Filter;
 
+use crate::cli::ExtractOptions;
+use crate::pack::is_out_of_space;
+use crate::pack::unpack_with_progress;
+use crate::publish::PublishError;
+use crate::serve::admin;
+use crate::serve::api_enabled;
+use crate::serve::compressed;
+use crate::serve::writable;
 use crate::serve::ServerError;
-use crate::unpack;
+use crate::staging::Staging;
 
 static FRONTEND: Dir<'_> = include_dir!("$OUT_DIR/frontend_dist_folder/");
 
+/// The platforms of the rustup-init files of the registry. A registry
+/// without any rustup installation yet has no platforms.
 fn available_platforms(root: &Path) -> Result<Vec<String>> {
-    Ok(std::fs::read_dir(root.join("rustup").join("dist"))?
+    let dist = root.join("rustup").join("dist");
+    let entries = match std::fs::read_dir(&dist) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", dist.display())),
+    };
+    entries
         .map(|entry| {
             let platform_folder = entry?;
             Ok(platform_folder.file_name().to_str().unwrap().to_owned())
         })
-        .collect::<Result<Vec<_>>>()?)
+        .collect::<Result<Vec<_>>>()
 }
 
 #[derive(Serialize, Deserialize)]
@@ -49,7 +73,7 @@ fn extract_available_platforms_for_channel(
             .get("versions")?
             .as_table()?
             .values()
-            .flat_map(|v| v.as_array().map(Clone::clone).unwrap_or_default())
+            .flat_map(|v| v.as_array().cloned().unwrap_or_default())
             .filter_map(|p| {
                 let p = p.as_str()?;
                 let prefix = format!("cargo-{}-", version_name);
@@ -60,8 +84,26 @@ fn extract_available_platforms_for_channel(
     )
 }
 
-fn available_versions(root: &Path) -> Result<Versions> {
-    let versions = glob(root.join("*.toml").to_str().unwrap())?
+/// The directories holding the channel history files: the registry root,
+/// where they used to be written, and then `channel_history_dir` if any.
+fn history_dirs(root: &Path, channel_history_dir: Option<&Path>) -> Vec<PathBuf> {
+    std::iter::once(root.to_path_buf())
+        .chain(channel_history_dir.map(|dir| root.join(dir)))
+        .collect()
+}
+
+/// Collect the versions of the channel history files of `history_dirs`.
+/// The files of the later directories take precedence.
+fn available_versions(history_dirs: &[PathBuf]) -> Result<Versions> {
+    let mut versions = HashMap::new();
+    for dir in history_dirs {
+        versions.extend(dir_versions(dir)?);
+    }
+    Ok(Versions { versions })
+}
+
+fn dir_versions(dir: &Path) -> Result<HashMap<String, Vec<String>>> {
+    glob(dir.join("*.toml").to_str().unwrap())?
         .map(|conf_path| -> Result<_> {
             let conf_path: PathBuf = conf_path?;
             let conf_file = load_config(&conf_path)?;
@@ -77,7 +119,7 @@ fn available_versions(root: &Path) -> Result<Versions> {
                     .ok_or(anyhow!("strip_suffix NoneError"))?
             };
             let platforms: Vec<String> =
-                extract_available_platforms_for_channel(&conf_file, &version_name)
+                extract_available_platforms_for_channel(&conf_file, version_name)
                     .ok_or(anyhow!("None Error channel config"))?;
             let version_name = if is_nightly {
                 let date = file_name
@@ -92,12 +134,187 @@ fn available_versions(root: &Path) -> Result<Versions> {
 
             Ok((version_name, platforms))
         })
-        .collect::<Result<HashMap<String, Vec<String>>>>()?;
-    Ok(Versions { versions })
+        .collect::<Result<HashMap<String, Vec<String>>>>()
+}
+
+/// How to install a mirrored toolchain, for the users of the registry who
+/// set `RUSTUP_DIST_SERVER` to it.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Toolchain {
+    channel: String,
+    /// The mirrored platforms rustup itself can be installed on.
+    hosts: Vec<String>,
+    /// The mirrored platforms of the channel.
+    targets: Vec<String>,
+    /// The rustup commands installing the toolchain and all its targets.
+    commands: Vec<String>,
+}
+
+/// The installable toolchains of `versions`, by channel name, given the
+/// `platforms` of the mirrored rustup-init files.
+fn toolchains(versions: &Versions, platforms: &[String]) -> Vec<Toolchain> {
+    let mut toolchains = versions
+        .versions
+        .iter()
+        .map(|(channel, targets)| {
+            // The history file names of the archives end with a dot.
+            let mut targets = targets
+                .iter()
+                .map(|target| target.trim_end_matches('.').to_owned())
+                .collect::<Vec<_>>();
+            targets.sort();
+            targets.dedup();
+            let hosts = targets
+                .iter()
+                .filter(|target| platforms.contains(target))
+                .cloned()
+                .collect();
+            let mut commands = vec![format!("rustup toolchain install {}", channel)];
+            if !targets.is_empty() {
+                commands.push(format!(
+                    "rustup target add --toolchain {} {}",
+                    channel,
+                    targets.join(" ")
+                ));
+            }
+            Toolchain {
+                channel: channel.clone(),
+                hosts,
+                targets,
+                commands,
+            }
+        })
+        .collect::<Vec<_>>();
+    toolchains.sort_by(|a, b| a.channel.cmp(&b.channel));
+    toolchains
+}
+
+/// The versions available in the registry, computed on first use and
+/// kept until the registry content changes.
+#[derive(Clone, Default)]
+struct VersionsCache(Arc<RwLock<Option<Arc<Versions>>>>);
+
+impl VersionsCache {
+    /// Get the cached versions, computing them if needed.
+    async fn get(&self, history_dirs: &[PathBuf]) -> Result<Arc<Versions>> {
+        if let Some(versions) = self.0.read().await.as_ref() {
+            return Ok(versions.clone());
+        }
+        self.refresh(history_dirs).await
+    }
+
+    /// Recompute the versions from the registry content.
+    async fn refresh(&self, history_dirs: &[PathBuf]) -> Result<Arc<Versions>> {
+        let mut cache = self.0.write().await;
+        let versions = Arc::new(available_versions(history_dirs)?);
+        *cache = Some(versions.clone());
+        Ok(versions)
+    }
+
+    /// Forget the cached versions, they will be computed on next use.
+    async fn invalidate(&self) {
+        *self.0.write().await = None;
+    }
+}
+
+/// The stage of the load of a pack file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LoadState {
+    #[default]
+    Idle,
+    Receiving,
+    Unpacking,
+    Done,
+    Failed,
+}
+
+/// The progress of the last load of a pack file, reported to the frontend.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoadProgress {
+    state: LoadState,
+    bytes_received: u64,
+    /// The size of the pack file, when the upload has a `Content-Length`.
+    total_bytes: Option<u64>,
+    files_extracted: u64,
+    error: Option<String>,
+}
+
+/// Write the uploaded pack file to a temporary file, and then unpack it in
+/// the registry at `root`, or stage it when there is a `staging` area,
+/// reporting the progress of both.
+async fn load_pack(
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    root: &Path,
+    staging: Option<&Staging>,
+    versions_cache: &VersionsCache,
+    progress: &watch::Sender<LoadProgress>,
+) -> Result<()> {
+    let mut body = Box::pin(body);
+    let tmp = NamedTempFile::new()?;
+    let mut file = tokio::fs::File::create(tmp.path()).await?;
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("failed to receive the pack file")?;
+        let chunk = chunk.chunk();
+        if let Err(e) = file.write_all(chunk).await {
+            error!("error writing file: {}", e);
+            if is_out_of_space(&e) {
+                bail!(
+                    "not enough disk space to store the uploaded pack file ({} bytes)",
+                    size + chunk.len() as u64
+                );
+            }
+            return Err(e.into());
+        }
+        size += chunk.len() as u64;
+        progress.send_modify(|progress| progress.bytes_received = size);
+    }
+    file.flush().await?;
+    drop(file);
+
+    // Held for review, the pack is only loaded once accepted.
+    if let Some(staging) = staging {
+        staging.stage_pack(tmp)?;
+        return Ok(());
+    }
+
+    progress.send_modify(|progress| progress.state = LoadState::Unpacking);
+    let unpacked = unpack_with_progress(tmp.path(), root, &ExtractOptions::default(), &|| {
+        progress.send_modify(|progress| progress.files_extracted += 1)
+    })
+    .await;
+    // Even a failed unpack may have changed the content.
+    versions_cache.invalidate().await;
+    unpacked
+}
+
+/// The progress of the loads as newline delimited JSON: the current
+/// progress, and then every change until a load ends.
+fn progress_events(
+    receiver: watch::Receiver<LoadProgress>,
+) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+    stream::unfold(Some((receiver, true)), |state| async move {
+        let (mut receiver, first) = state?;
+        if !first && receiver.changed().await.is_err() {
+            return None;
+        }
+        let progress = receiver.borrow_and_update().clone();
+        let ended = !first && matches!(progress.state, LoadState::Done | LoadState::Failed);
+        let mut line = serde_json::to_vec(&progress).unwrap();
+        line.push(b'\n');
+        Some((Ok(line), (!ended).then_some((receiver, false))))
+    })
 }
 
 fn frontend_api(
     root: &Path,
+    channel_history_dir: Option<&Path>,
+    staging: Option<Arc<Staging>>,
+    read_only: bool,
+    publish_api: bool,
+    admin_token: Option<&str>,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let path_for_platforms = root.to_path_buf();
     let available_platforms = warp::get()
@@ -106,79 +323,578 @@ fn frontend_api(
         .and_then(move || {
             let path_for_api = path_for_platforms.clone();
             async move {
-                let res = available_platforms(&path_for_api)
+                available_platforms(&path_for_api)
                     .map_err(|e| warp::reject::custom(ServerError(e)))
-                    .map(|platforms| warp::reply::json(&platforms));
-                res
+                    .map(|platforms| warp::reply::json(&platforms))
             }
         });
 
-    let path_for_versions = root.to_path_buf();
+    let versions_cache = VersionsCache::default();
+    let history_dirs = history_dirs(root, channel_history_dir);
+    let path_for_versions = history_dirs.clone();
+    let cache_for_versions = versions_cache.clone();
     let versions_for_channel = warp::get()
         .and(warp::path("api"))
         .and(warp::path("versions"))
         .and_then(move || {
             let path_for_version = path_for_versions.clone();
+            let versions_cache = cache_for_versions.clone();
+            async move {
+                versions_cache
+                    .get(&path_for_version)
+                    .await
+                    .map_err(|e| warp::reject::custom(ServerError(e)))
+                    .map(|versions| warp::reply::json(&*versions))
+            }
+        });
+    let path_for_toolchains = history_dirs.clone();
+    let root_for_toolchains = root.to_path_buf();
+    let cache_for_toolchains = versions_cache.clone();
+    let installable_toolchains = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("toolchains"))
+        .and_then(move || {
+            let history_dirs = path_for_toolchains.clone();
+            let root = root_for_toolchains.clone();
+            let versions_cache = cache_for_toolchains.clone();
+            async move {
+                let versions = versions_cache
+                    .get(&history_dirs)
+                    .await
+                    .map_err(|e| warp::reject::custom(ServerError(e)))?;
+                let platforms = self::available_platforms(&root)
+                    .map_err(|e| warp::reject::custom(ServerError(e)))?;
+                Ok::<_, warp::Rejection>(warp::reply::json(&toolchains(&versions, &platforms)))
+            }
+        });
+    let path_for_refresh = history_dirs;
+    let cache_for_refresh = versions_cache.clone();
+    let cache_for_review = versions_cache.clone();
+    let refresh = warp::post()
+        .and(warp::path("api"))
+        .and(warp::path("refresh"))
+        .and_then(move || {
+            let path_for_refresh = path_for_refresh.clone();
+            let versions_cache = cache_for_refresh.clone();
             async move {
-                available_versions(&path_for_version)
+                versions_cache
+                    .refresh(&path_for_refresh)
+                    .await
                     .map_err(|e| warp::reject::custom(ServerError(e)))
-                    .map(|versions| warp::reply::json(&versions))
+                    .map(|versions| warp::reply::json(&*versions))
             }
         });
     let path_for_loading = root.to_path_buf();
+    let staging_for_loading = staging.clone();
+    let (progress, _) = watch::channel(LoadProgress::default());
+    let progress = Arc::new(progress);
+    let progress_for_loading = progress.clone();
     let load_pack_file = warp::put()
         .and(warp::path("api"))
         .and(warp::path("load-pack-file"))
-        .and(warp::body::bytes())
+        .and(writable(read_only))
         .and(warp::header::optional::<String>("Content-Type"))
-        .and_then(move |data: Bytes, content_type: Option<String>| {
-            // FIXME() - Stream the body to file without load the whole file in the memory.
-            let path_for_loading = path_for_loading.clone();
-            async move {
-                if !matches!(content_type, Some(file_type) if file_type == "application/x-tar") {
-                    error!("Invalid content type. support only tar files (application/x-tar)");
-                    return Err(warp::reject::custom(ServerError(anyhow!(
-                        "Invalid content type. support only tar files (application/x-tar)"
-                    ))));
+        .and(warp::header::optional::<u64>("Content-Length"))
+        .and(warp::body::stream())
+        .and_then(
+            move |content_type: Option<String>,
+                  total_bytes: Option<u64>,
+                  body| {
+                let path_for_loading = path_for_loading.clone();
+                let versions_cache = versions_cache.clone();
+                let staging = staging_for_loading.clone();
+                let progress = progress_for_loading.clone();
+                async move {
+                    if !matches!(content_type.as_deref(), Some("application/x-tar" | "application/zstd")) {
+                        error!("Invalid content type. support only tar files (application/x-tar) and zstd-compressed tar files (application/zstd)");
+                        return Err(warp::reject::custom(ServerError(anyhow!(
+                            "Invalid content type. support only tar files (application/x-tar) and zstd-compressed tar files (application/zstd)"
+                        ))));
+                    }
+
+                    progress.send_replace(LoadProgress {
+                        state: LoadState::Receiving,
+                        total_bytes,
+                        ..LoadProgress::default()
+                    });
+                    let loaded = load_pack(
+                        body,
+                        &path_for_loading,
+                        staging.as_deref(),
+                        &versions_cache,
+                        &progress,
+                    )
+                    .await;
+                    progress.send_modify(|progress| match &loaded {
+                        Ok(()) => progress.state = LoadState::Done,
+                        Err(e) => {
+                            progress.state = LoadState::Failed;
+                            progress.error = Some(format!("{:#}", e));
+                        }
+                    });
+                    loaded.map_err(|e| warp::reject::custom(ServerError(e)))?;
+                    Ok(warp::reply())
                 }
+            },
+        );
+    let load_progress = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("load-pack-file"))
+        .and(warp::path("progress"))
+        .and(warp::path::end())
+        .map(move || {
+            let mut response =
+                Response::new(Body::wrap_stream(progress_events(progress.subscribe())));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/x-ndjson"),
+            );
+            response
+        });
 
-                let tmp = NamedTempFile::new()
-                    .map_err(|e| warp::reject::custom(ServerError(anyhow!(e))))?;
-                tokio::fs::write(tmp.path(), data).await.map_err(|e| {
-                    error!("error writing file: {}", e);
-                    warp::reject::reject()
-                })?;
-                unpack(tmp.path(), &path_for_loading)
-                    .await
-                    .map_err(|e| warp::reject::custom(ServerError(anyhow!(e))))?;
-                Ok(warp::reply())
+    let staging_for_list = staging.clone();
+    let pending = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("pending"))
+        .and(warp::path::end())
+        .and(admin(admin_token))
+        .and_then(move || {
+            let staging = staging_for_list.clone();
+            async move {
+                staging
+                    .map_or_else(|| Ok(Vec::new()), |staging| staging.list())
+                    .map_err(|e| warp::reject::custom(ServerError(e)))
+                    .map(|pending| warp::reply::json(&pending))
             }
         });
+    let review = warp::post()
+        .and(warp::path("api"))
+        .and(warp::path("pending"))
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(api_enabled(publish_api))
+        .and(admin(admin_token))
+        .and(writable(read_only))
+        .and_then(move |id: String, action: String| {
+            let staging = staging.clone();
+            let versions_cache = cache_for_review.clone();
+            async move {
+                let staging = staging.ok_or_else(warp::reject::not_found)?;
+                let reviewed = match action.as_str() {
+                    "accept" => {
+                        let accepted = staging.accept(&id).await;
+                        // Even a failed unpack may have changed the content.
+                        versions_cache.invalidate().await;
+                        accepted
+                    }
+                    "reject" => staging.reject(&id),
+                    _ => return Err(warp::reject::not_found()),
+                };
+                match reviewed {
+                    Ok(Some(pending)) => Ok(warp::reply::json(&pending)),
+                    Ok(None) => Err(warp::reject::not_found()),
+                    // Keep the status of publishes rejected by the registry.
+                    Err(e) => Err(match e.downcast::<PublishError>() {
+                        Ok(e) => warp::reject::custom(e),
+                        Err(e) => warp::reject::custom(ServerError(e)),
+                    }),
+                }
+            }
+        });
+
+    // The progress events are streamed, they aren't compressed not to be
+    // held back by the compressor.
+    compressed(
+        available_platforms
+            .or(versions_for_channel)
+            .or(installable_toolchains)
+            .or(refresh),
+    )
+    .or(load_pack_file)
+    .or(load_progress)
+    .or(compressed(pending))
+    .or(review)
+}
 
-    available_platforms
-        .or(versions_for_channel)
-        .or(load_pack_file)
+/// Get a file of the frontend, from `frontend_dir` when it has it and from
+/// the embedded frontend otherwise.
+async fn frontend_file(frontend_dir: Option<&Path>, path: &str) -> Option<Cow<'static, [u8]>> {
+    if let Some(frontend_dir) = frontend_dir {
+        let relative_path = Path::new(path);
+        // Never serve anything outside of the directory.
+        if relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            if let Ok(content) = tokio::fs::read(frontend_dir.join(relative_path)).await {
+                return Some(Cow::Owned(content));
+            }
+        }
+    }
+    FRONTEND.get_file(path).map(|f| Cow::Borrowed(f.contents()))
 }
 
+/// Serve the frontend and its API. The files of `frontend_dir`, if any,
+/// take precedence over the embedded frontend. The channel history files
+/// are looked up in `channel_history_dir` besides the registry root. With a
+/// `staging` area the loaded pack files are held there until accepted, and
+/// reviewed by the admins sending the `admin_token`. A `read_only` registry
+/// refuses the loads and the reviews, and the reviews are not found without
+/// the `publish_api`.
 pub fn serve_frontend(
     root: &Path,
+    frontend_dir: Option<&Path>,
+    channel_history_dir: Option<&Path>,
+    staging: Option<Arc<Staging>>,
+    read_only: bool,
+    publish_api: bool,
+    admin_token: Option<&str>,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let home_page = warp::get().and(warp::path::end()).and_then(|| async {
-        FRONTEND
-            .get_file("index.html")
-            .ok_or_else(warp::reject::not_found)
-            .map(|f| warp::reply::html(f.contents()))
+    let frontend_dir = frontend_dir.map(Path::to_path_buf);
+    let home_page_dir = frontend_dir.clone();
+    let home_page = warp::get().and(warp::path::end()).and_then(move || {
+        let frontend_dir = home_page_dir.clone();
+        async move {
+            frontend_file(frontend_dir.as_deref(), "index.html")
+                .await
+                .ok_or_else(warp::reject::not_found)
+                .map(warp::reply::html)
+        }
     });
 
     let static_files = warp::get()
         .and(warp::path::tail())
-        .and_then(|path: Tail| async move {
-            FRONTEND
-                .get_file(path.as_str())
-                .ok_or_else(warp::reject::not_found)
-                .map(|f| Response::new(Body::from(f.contents())))
+        .and_then(move |path: Tail| {
+            let frontend_dir = frontend_dir.clone();
+            async move {
+                frontend_file(frontend_dir.as_deref(), path.as_str())
+                    .await
+                    .ok_or_else(warp::reject::not_found)
+                    .map(|content| Response::new(Body::from(content)))
+            }
         });
 
-    let api = frontend_api(&root);
+    let api = frontend_api(
+        root,
+        channel_history_dir,
+        staging,
+        read_only,
+        publish_api,
+        admin_token,
+    );
     home_page.or(api).or(static_files)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+    use warp::http::StatusCode;
+
+    /// Create a pack file containing a single file.
+    fn pack_with_file(path: &str, content: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn history(version: &str) -> String {
+        format!(
+            r#"[versions]
+"2023-03-09" = ["dist/2023-03-09/cargo-{version}-x86_64-unknown-linux-gnu.tar.xz"]
+"#
+        )
+    }
+
+    /// Check that a fresh registry has no platforms rather than failing.
+    #[tokio::test]
+    async fn available_platforms_of_empty_registry() {
+        let root = tempdir().unwrap();
+        let frontend = serve_frontend(root.path(), None, None, None, false, true, None);
+        let platforms = || async {
+            let response = warp::test::request()
+                .path("/api/available-platforms")
+                .reply(&frontend)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            serde_json::from_slice::<Vec<String>>(response.body()).unwrap()
+        };
+        assert!(platforms().await.is_empty());
+
+        std::fs::create_dir_all(root.path().join("rustup/dist/x86_64-unknown-linux-gnu")).unwrap();
+        assert_eq!(platforms().await, ["x86_64-unknown-linux-gnu"]);
+
+        // Other errors are still reported.
+        std::fs::remove_dir_all(root.path().join("rustup/dist")).unwrap();
+        std::fs::write(root.path().join("rustup/dist"), "").unwrap();
+        assert!(available_platforms(root.path()).is_err());
+    }
+
+    /// Check that the files of the frontend directory override the
+    /// embedded ones.
+    #[tokio::test]
+    async fn frontend_dir_overrides_embedded_files() {
+        let root = tempdir().unwrap();
+        let frontend_dir = tempdir().unwrap();
+        std::fs::write(frontend_dir.path().join("index.html"), "<h1>Custom</h1>").unwrap();
+        std::fs::write(frontend_dir.path().join("logo.svg"), "<svg/>").unwrap();
+        let frontend = serve_frontend(
+            root.path(),
+            Some(frontend_dir.path()),
+            None,
+            None,
+            false,
+            true,
+            None,
+        );
+
+        let response = warp::test::request().path("/").reply(&frontend).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "<h1>Custom</h1>");
+        let response = warp::test::request()
+            .path("/logo.svg")
+            .reply(&frontend)
+            .await;
+        assert_eq!(response.body(), "<svg/>");
+
+        // Without the directory the embedded frontend is served.
+        let frontend = serve_frontend(root.path(), None, None, None, false, true, None);
+        let response = warp::test::request().path("/").reply(&frontend).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.body(),
+            FRONTEND.get_file("index.html").unwrap().contents()
+        );
+        let response = warp::test::request()
+            .path("/logo.svg")
+            .reply(&frontend)
+            .await;
+        assert!(response.status().is_client_error());
+    }
+
+    async fn get_versions(
+        api: &(impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + 'static),
+    ) -> serde_json::Value {
+        let response = warp::test::request().path("/api/versions").reply(api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    /// Check that the channel history files are read both from the
+    /// registry root and from the channel history directory.
+    #[tokio::test]
+    async fn versions_from_both_history_locations() {
+        let root = tempdir().unwrap();
+        std::fs::write(
+            root.path().join("mirror-1.67.1-history.toml"),
+            history("1.67.1"),
+        )
+        .unwrap();
+        std::fs::create_dir(root.path().join("history")).unwrap();
+        std::fs::write(
+            root.path().join("history/mirror-1.68.0-history.toml"),
+            history("1.68.0"),
+        )
+        .unwrap();
+
+        let versions =
+            get_versions(&frontend_api(root.path(), None, None, false, true, None)).await;
+        let versions = versions["versions"].as_object().unwrap();
+        assert_eq!(versions.keys().collect::<Vec<_>>(), ["1.67.1"]);
+
+        let api = frontend_api(
+            root.path(),
+            Some(Path::new("history")),
+            None,
+            false,
+            true,
+            None,
+        );
+        let versions = get_versions(&api).await;
+        let mut names = versions["versions"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["1.67.1", "1.68.0"]);
+    }
+
+    /// Check the install commands of the mirrored toolchains.
+    #[tokio::test]
+    async fn toolchain_install_commands() {
+        let root = tempdir().unwrap();
+        std::fs::write(
+            root.path().join("mirror-1.67.1-history.toml"),
+            r#"[versions]
+"2023-02-09" = [
+    "dist/2023-02-09/cargo-1.67.1-x86_64-unknown-linux-gnu.tar.xz",
+    "dist/2023-02-09/cargo-1.67.1-x86_64-pc-windows-msvc.tar.xz",
+]
+"#,
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.path().join("rustup/dist/x86_64-unknown-linux-gnu")).unwrap();
+        let api = frontend_api(root.path(), None, None, false, true, None);
+
+        let response = warp::test::request()
+            .path("/api/toolchains")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let toolchains: Vec<Toolchain> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            toolchains,
+            [Toolchain {
+                channel: "1.67.1".to_string(),
+                hosts: vec!["x86_64-unknown-linux-gnu".to_string()],
+                targets: vec![
+                    "x86_64-pc-windows-msvc".to_string(),
+                    "x86_64-unknown-linux-gnu".to_string()
+                ],
+                commands: vec![
+                    "rustup toolchain install 1.67.1".to_string(),
+                    "rustup target add --toolchain 1.67.1 x86_64-pc-windows-msvc x86_64-unknown-linux-gnu"
+                        .to_string()
+                ],
+            }]
+        );
+    }
+
+    /// Check that the cached versions are updated when a pack is loaded.
+    #[tokio::test]
+    async fn versions_cache_updated_after_load() {
+        let root = tempdir().unwrap();
+        std::fs::write(
+            root.path().join("mirror-1.67.1-history.toml"),
+            history("1.67.1"),
+        )
+        .unwrap();
+        let api = frontend_api(root.path(), None, None, false, true, None);
+
+        let versions = get_versions(&api).await;
+        assert_eq!(versions["versions"].as_object().unwrap().len(), 1);
+
+        // Files added behind our back are only seen after a refresh.
+        std::fs::write(
+            root.path().join("mirror-1.68.0-history.toml"),
+            history("1.68.0"),
+        )
+        .unwrap();
+        assert_eq!(
+            get_versions(&api).await["versions"]
+                .as_object()
+                .unwrap()
+                .len(),
+            1
+        );
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/refresh")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            get_versions(&api).await["versions"]
+                .as_object()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/load-pack-file")
+            .header("Content-Type", "application/x-tar")
+            .body(pack_with_file(
+                "mirror-1.69.0-history.toml",
+                &history("1.69.0"),
+            ))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let versions = get_versions(&api).await;
+        assert_eq!(versions["versions"].as_object().unwrap().len(), 3);
+        assert!(versions["versions"]["1.69.0"].is_array());
+    }
+
+    /// Check that the progress of a load is streamed until it is done.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn load_progress_events() {
+        let root = tempdir().unwrap();
+        let (addr, server) = warp::serve(frontend_api(root.path(), None, None, false, true, None))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut events = reqwest::get(format!("http://{addr}/api/load-pack-file/progress"))
+            .await
+            .unwrap();
+        assert_eq!(
+            events.headers()[CONTENT_TYPE.as_str()],
+            "application/x-ndjson"
+        );
+        // The current progress comes first, once subscribed to the changes.
+        let first = events.chunk().await.unwrap().unwrap();
+        let idle: LoadProgress = serde_json::from_slice(&first).unwrap();
+        assert_eq!(idle, LoadProgress::default());
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in [("a.txt", "a"), ("dir/b.txt", "bb")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let pack = builder.into_inner().unwrap();
+        let size = pack.len() as u64;
+        let response = reqwest::Client::new()
+            .put(format!("http://{addr}/api/load-pack-file"))
+            .header("Content-Type", "application/x-tar")
+            .body(pack)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The stream ends with the end of the load.
+        let mut body = Vec::new();
+        while let Some(chunk) = events.chunk().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+        let progress = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<LoadProgress>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(progress
+            .windows(2)
+            .all(|pair| pair[0].state <= pair[1].state));
+        assert_eq!(
+            progress.last().unwrap(),
+            &LoadProgress {
+                state: LoadState::Done,
+                bytes_received: size,
+                total_bytes: Some(size),
+                files_extracted: 2,
+                error: None,
+            }
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("dir/b.txt")).unwrap(),
+            "bb"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use tracing::info;
+use warp::hyper::body::Bytes;
+
+use crate::cli::ExtractOptions;
+use crate::cli::ServeArgs;
+use crate::index::Index;
+use crate::publish::check_acl;
+use crate::publish::publish_crate_as;
+use crate::publish::published_crate;
+use crate::publish::publisher_id;
+use crate::publish::PublishError;
+use crate::publish::Quota;
+use crate::storage::Storage;
+use crate::unpack;
+
+/// The file of a pending item describing it.
+const PENDING_FILE: &str = "pending.json";
+/// The file of a pending item holding the publish request body or the pack file.
+const DATA_FILE: &str = "data";
+
+/// What a pending item brings into the registry once accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PendingKind {
+    /// A crate publish.
+    Publish,
+    /// A loaded pack file.
+    Pack,
+}
+
+/// A publish or a pack file waiting to be accepted into the registry.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pending {
+    pub id: String,
+    pub kind: PendingKind,
+    /// What is pending, e.g. the crate name and version.
+    pub description: String,
+    /// The size in bytes of the publish request or the pack file.
+    pub size: u64,
+    /// The identifier of the publishing token, if any.
+    pub publisher: Option<String>,
+    /// When the item was staged, in seconds since the Unix epoch.
+    pub staged_at: u64,
+}
+
+/// The staging area holding the publishes and pack files until they are
+/// reviewed. Each pending item lives in its own directory of
+/// `<root>/staging`.
+pub struct Staging {
+    root: PathBuf,
+    folder: PathBuf,
+    index: Arc<Index>,
+    storage: Arc<dyn Storage>,
+    quota: Arc<Quota>,
+    serve_args: Arc<ServeArgs>,
+}
+
+impl Staging {
+    pub(crate) fn new(
+        root: &Path,
+        index: Arc<Index>,
+        storage: Arc<dyn Storage>,
+        quota: Arc<Quota>,
+        serve_args: Arc<ServeArgs>,
+    ) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            folder: root.join("staging"),
+            index,
+            storage,
+            quota,
+            serve_args,
+        }
+    }
+
+    /// Stage a publish request body, after checking its metadata.
+    pub(crate) fn stage_publish(
+        &self,
+        body: Bytes,
+        token: Option<&str>,
+    ) -> Result<Pending, PublishError> {
+        let (name, version) = published_crate(&body)?;
+        let publisher = token.map(publisher_id);
+        check_acl(&self.serve_args, &name, publisher.as_deref())?;
+        let pending = self.stage(
+            PendingKind::Publish,
+            format!("{} {}", name, version),
+            body.len() as u64,
+            publisher,
+            |data_path| Ok(std::fs::write(data_path, &body)?),
+        )?;
+        Ok(pending)
+    }
+
+    /// Stage an uploaded pack file, moving it into the staging area rather
+    /// than reading it in memory.
+    pub(crate) fn stage_pack(&self, file: NamedTempFile) -> Result<Pending> {
+        let size = file.as_file().metadata()?.len();
+        self.stage(
+            PendingKind::Pack,
+            format!("pack file of {} bytes", size),
+            size,
+            None,
+            |data_path| match file.persist(data_path) {
+                Ok(_) => Ok(()),
+                // The upload may be on another file system than the registry.
+                Err(err) => {
+                    std::fs::copy(err.file.path(), data_path)?;
+                    Ok(())
+                }
+            },
+        )
+    }
+
+    /// Create a pending item, with `write_data` writing its data file.
+    fn stage(
+        &self,
+        kind: PendingKind,
+        description: String,
+        size: u64,
+        publisher: Option<String>,
+        write_data: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<Pending> {
+        std::fs::create_dir_all(&self.folder)
+            .with_context(|| format!("failed to create directory {}", self.folder.display()))?;
+        let dir = tempfile::Builder::new()
+            .prefix(match kind {
+                PendingKind::Publish => "publish-",
+                PendingKind::Pack => "pack-",
+            })
+            .tempdir_in(&self.folder)
+            .context("failed to create pending directory")?
+            .into_path();
+        let pending = Pending {
+            id: dir.file_name().unwrap().to_string_lossy().into_owned(),
+            kind,
+            description,
+            size,
+            publisher,
+            staged_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        };
+        write_data(&dir.join(DATA_FILE))
+            .with_context(|| format!("failed to write pending data in {}", dir.display()))?;
+        // Written last, the item is only listed once complete.
+        std::fs::write(dir.join(PENDING_FILE), serde_json::to_vec(&pending)?)
+            .with_context(|| format!("failed to write pending file in {}", dir.display()))?;
+        info!("Staged {} as {}", pending.description, pending.id);
+        Ok(pending)
+    }
+
+    /// List the pending items, oldest first.
+    pub(crate) fn list(&self) -> Result<Vec<Pending>> {
+        if !self.folder.exists() {
+            return Ok(Vec::new());
+        }
+        let mut pending = Vec::new();
+        for entry in std::fs::read_dir(&self.folder)? {
+            let pending_path = entry?.path().join(PENDING_FILE);
+            if !pending_path.exists() {
+                continue;
+            }
+            let content = std::fs::read(&pending_path)
+                .with_context(|| format!("failed to read {}", pending_path.display()))?;
+            pending.push(
+                serde_json::from_slice::<Pending>(&content)
+                    .with_context(|| format!("invalid pending file {}", pending_path.display()))?,
+            );
+        }
+        pending.sort_by(|a, b| (a.staged_at, &a.id).cmp(&(b.staged_at, &b.id)));
+        Ok(pending)
+    }
+
+    /// Get the pending item `id` and its directory, if any.
+    fn get(&self, id: &str) -> Result<Option<(Pending, PathBuf)>> {
+        // Never look outside of the staging folder.
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Ok(None);
+        }
+        let dir = self.folder.join(id);
+        let pending_path = dir.join(PENDING_FILE);
+        if !pending_path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read(&pending_path)
+            .with_context(|| format!("failed to read {}", pending_path.display()))?;
+        let pending = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid pending file {}", pending_path.display()))?;
+        Ok(Some((pending, dir)))
+    }
+
+    /// Move a pending item into the live registry and remove it from the
+    /// staging area.
+    /// Returns `None` when there is no such item.
+    pub(crate) async fn accept(&self, id: &str) -> Result<Option<Pending>> {
+        let Some((pending, dir)) = self.get(id)? else {
+            return Ok(None);
+        };
+        let data_path = dir.join(DATA_FILE);
+        match pending.kind {
+            PendingKind::Publish => {
+                let body = std::fs::read(&data_path)
+                    .with_context(|| format!("failed to read {}", data_path.display()))?;
+                publish_crate_as(
+                    body.into(),
+                    self.index.clone(),
+                    self.storage.as_ref(),
+                    &self.quota,
+                    &self.serve_args,
+                    pending.publisher.as_deref(),
+                )
+                .await?;
+            }
+            PendingKind::Pack => {
+                unpack(&data_path, &self.root, &ExtractOptions::default()).await?;
+            }
+        }
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("failed to remove {}", dir.display()))?;
+        info!("Accepted {} ({})", pending.description, pending.id);
+        Ok(Some(pending))
+    }
+
+    /// Drop a pending item from the staging area.
+    /// Returns `None` when there is no such item.
+    pub(crate) fn reject(&self, id: &str) -> Result<Option<Pending>> {
+        let Some((pending, dir)) = self.get(id)? else {
+            return Ok(None);
+        };
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("failed to remove {}", dir.display()))?;
+        info!("Rejected {} ({})", pending.description, pending.id);
+        Ok(Some(pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    use crate::publish::tests::metadata;
+    use crate::publish::tests::publish_body;
+    use crate::serve::routes;
+    use crate::serve::tests::serve_args;
+
+    const ADMIN_TOKEN: &str = "admin-secret";
+
+    async fn list_pending(
+        routes: &(impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + 'static),
+    ) -> Vec<Pending> {
+        let response = warp::test::request()
+            .path("/api/pending")
+            .header("Authorization", ADMIN_TOKEN)
+            .reply(routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    async fn review(
+        routes: &(impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + 'static),
+        id: &str,
+        action: &str,
+    ) -> StatusCode {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/pending/{id}/{action}"))
+            .header("Authorization", ADMIN_TOKEN)
+            .reply(routes)
+            .await
+            .status()
+    }
+
+    /// Check that a staged publish only reaches the registry once accepted.
+    #[tokio::test]
+    async fn accept_staged_publish() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(
+            root.path(),
+            &["--staging", "--admin-token", ADMIN_TOKEN],
+        ))
+        .await
+        .unwrap();
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", "secret")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), b"data"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let index = Index::open(root.path().join("index")).unwrap();
+        assert!(index.entry("my-lib", "0.1.0").unwrap().is_none());
+
+        let pending = list_pending(&routes).await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].kind, PendingKind::Publish);
+        assert_eq!(pending[0].description, "my-lib 0.1.0");
+        assert_eq!(pending[0].publisher, Some(publisher_id("secret")));
+
+        assert_eq!(
+            review(&routes, &pending[0].id, "accept").await,
+            StatusCode::OK
+        );
+        assert!(index.entry("my-lib", "0.1.0").unwrap().is_some());
+        assert!(root.path().join("crates/my/-l/my-lib-0.1.0.crate").exists());
+        assert_eq!(
+            index.status().await.unwrap().message,
+            "Add my-lib in version 0.1.0"
+        );
+        assert!(list_pending(&routes).await.is_empty());
+        assert!(review(&routes, &pending[0].id, "accept")
+            .await
+            .is_client_error());
+    }
+
+    /// Check that a rejected pack file is dropped without being loaded.
+    #[tokio::test]
+    async fn reject_staged_pack() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(
+            root.path(),
+            &["--staging", "--admin-token", ADMIN_TOKEN],
+        ))
+        .await
+        .unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "dist/file", &b"data"[..])
+            .unwrap();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/load-pack-file")
+            .header("Content-Type", "application/x-tar")
+            .body(builder.into_inner().unwrap())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let pending = list_pending(&routes).await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].kind, PendingKind::Pack);
+        assert!(!root.path().join("dist/file").exists());
+
+        assert!(review(&routes, "../index", "reject")
+            .await
+            .is_client_error());
+        assert_eq!(
+            review(&routes, &pending[0].id, "reject").await,
+            StatusCode::OK
+        );
+        assert!(list_pending(&routes).await.is_empty());
+        assert!(!root.path().join("dist/file").exists());
+        assert!(!root.path().join("staging").join(&pending[0].id).exists());
+    }
+
+    /// Check that only the admins list and review the staging area: not
+    /// anonymous clients, nor the publisher of the staged crate.
+    #[tokio::test]
+    async fn review_needs_admin_token() {
+        let root = tempdir().unwrap();
+        let routes = routes(&serve_args(
+            root.path(),
+            &["--staging", "--admin-token", ADMIN_TOKEN],
+        ))
+        .await
+        .unwrap();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", "secret")
+            .body(publish_body(&metadata("my-lib", "0.1.0"), b"data"))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let id = list_pending(&routes).await[0].id.clone();
+
+        for authorization in [None, Some("secret")] {
+            let request = |method: &str, path: &str| {
+                let request = warp::test::request().method(method).path(path);
+                match authorization {
+                    Some(authorization) => request.header("Authorization", authorization),
+                    None => request,
+                }
+            };
+            let response = request("GET", "/api/pending").reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let response = request("POST", &format!("/api/pending/{id}/accept"))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let index = Index::open(root.path().join("index")).unwrap();
+        assert!(index.entry("my-lib", "0.1.0").unwrap().is_none());
+        assert_eq!(list_pending(&routes).await.len(), 1);
+
+        // Without an admin token, nobody reviews.
+        let routes = crate::serve::routes(&serve_args(root.path(), &["--staging"]))
+            .await
+            .unwrap();
+        assert_eq!(review(&routes, &id, "accept").await, StatusCode::FORBIDDEN);
+
+        // Nor without the publish API.
+        let routes = crate::serve::routes(&serve_args(
+            root.path(),
+            &["--staging", "--admin-token", ADMIN_TOKEN, "--no-api"],
+        ))
+        .await
+        .unwrap();
+        assert_eq!(review(&routes, &id, "accept").await, StatusCode::NOT_FOUND);
+        let index = Index::open(root.path().join("index")).unwrap();
+        assert!(index.entry("my-lib", "0.1.0").unwrap().is_none());
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::ErrorKind;
+use std::io::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt as _;
+use itertools::Itertools as _;
+use tempfile::NamedTempFile;
+
+use crate::cli::StorageArgs;
+use crate::publish::crate_file_name;
+use crate::publish::crate_path;
+use crate::publish::sync_dir;
+
+/// The key of the file of the crate `name` in version `vers` in a storage:
+/// its path relative to the crates folder, with `/` separators.
+pub(crate) fn crate_key(name: &str, vers: &str) -> String {
+    crate_path(name)
+        .join(crate_file_name(name, vers))
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .join("/")
+}
+
+/// Where the `.crate` files of the registry are stored. The index and the
+/// other files of the registry stay in its root directory.
+///
+/// The methods return boxed futures for the trait to be usable as a trait
+/// object.
+pub trait Storage: Send + Sync {
+    /// The content of the file at `key`, `None` when there is none.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>>>;
+
+    /// Store `data` as the file at `key`, replacing the previous one if any.
+    /// Readers never see a partially written file.
+    fn put<'a>(&'a self, key: &'a str, data: Bytes) -> BoxFuture<'a, Result<()>>;
+
+    /// Whether there is a file at `key`.
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    /// The size in bytes of the file at `key`, `None` when there is none.
+    fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<u64>>>;
+
+    /// Delete the file at `key`. Deleting a missing file is fine.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// The URL clients download the file at `key` from, `None` when the
+    /// registry serves it under `/crates`.
+    fn url(&self, key: &str) -> Option<String>;
+
+    /// The path of the file at `key`, when stored on the local file system.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The storage of the crate files in a local directory, `<root>/crates`.
+pub struct LocalStorage {
+    folder: PathBuf,
+    /// Whether to wait for the written files to reach the disk.
+    fsync: bool,
+}
+
+impl LocalStorage {
+    pub fn new(folder: &Path, fsync: bool) -> Self {
+        Self {
+            folder: folder.to_path_buf(),
+            fsync,
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.folder.join(key)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>>> {
+        async move {
+            let path = self.path(key);
+            match tokio::fs::read(&path).await {
+                Ok(data) => Ok(Some(data.into())),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+            }
+        }
+        .boxed()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: Bytes) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let path = self.path(key);
+            let dir = path.parent().unwrap_or(&self.folder);
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
+            // Write aside first so a concurrent request never serves a partial
+            // file, in a file of its own for concurrent writes not to mix.
+            let mut file = NamedTempFile::new_in(dir)
+                .with_context(|| format!("failed to create a file in {}", dir.display()))?;
+            file.write_all(&data)
+                .and_then(|()| {
+                    if self.fsync {
+                        file.as_file().sync_all()
+                    } else {
+                        Ok(())
+                    }
+                })
+                .with_context(|| format!("failed to write crate file {}", file.path().display()))?;
+            file.persist(&path)
+                .with_context(|| format!("failed to move crate file {}", path.display()))?;
+            if self.fsync {
+                sync_dir(dir)
+                    .with_context(|| format!("failed to sync directory {}", dir.display()))?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool>> {
+        async move { Ok(self.path(key).is_file()) }.boxed()
+    }
+
+    fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<u64>>> {
+        async move {
+            // Like `exists`, anything but a file reads as no file.
+            let metadata = tokio::fs::metadata(self.path(key)).await.ok();
+            Ok(metadata
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len()))
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let path = self.path(key);
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => Ok(()),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+                Err(err) => {
+                    Err(err).with_context(|| format!("failed to delete {}", path.display()))
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn url(&self, _key: &str) -> Option<String> {
+        None
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+/// Open the storage of the crate files of the registry at `root`: an S3
+/// bucket with `--s3-bucket`, `<root>/crates` otherwise.
+pub(crate) fn open_storage(
+    root: &Path,
+    storage_args: &StorageArgs,
+    fsync: bool,
+) -> Result<Arc<dyn Storage>> {
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = &storage_args.s3_bucket {
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID")
+            .context("AWS_ACCESS_KEY_ID is required with --s3-bucket")?;
+        let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+            .context("AWS_SECRET_ACCESS_KEY is required with --s3-bucket")?;
+        return Ok(Arc::new(crate::s3::S3Storage::new(
+            &storage_args.s3_endpoint,
+            bucket,
+            &storage_args.s3_region,
+            &access_key,
+            &secret_key,
+        )));
+    }
+    #[cfg(not(feature = "s3"))]
+    let _ = storage_args;
+    Ok(Arc::new(LocalStorage::new(&root.join("crates"), fsync)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn crate_keys() {
+        assert_eq!(crate_key("my-lib", "0.1.0"), "my/-l/my-lib-0.1.0.crate");
+        assert_eq!(crate_key("a", "1.0.0"), "1/a-1.0.0.crate");
+    }
+
+    #[tokio::test]
+    async fn local_storage() {
+        let root = tempdir().unwrap();
+        let storage = LocalStorage::new(root.path(), true);
+        let key = crate_key("my-lib", "0.1.0");
+        assert!(!storage.exists(&key).await.unwrap());
+        assert_eq!(storage.get(&key).await.unwrap(), None);
+        assert_eq!(storage.size(&key).await.unwrap(), None);
+
+        storage.put(&key, Bytes::from("data")).await.unwrap();
+        assert!(storage.exists(&key).await.unwrap());
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), "data");
+        storage.put(&key, Bytes::from("other")).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), "other");
+        assert_eq!(storage.size(&key).await.unwrap(), Some(5));
+
+        let path = root.path().join("my/-l/my-lib-0.1.0.crate");
+        assert_eq!(storage.local_path(&key), Some(path.clone()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"other");
+        // Nothing is left aside.
+        assert_eq!(
+            std::fs::read_dir(root.path().join("my/-l"))
+                .unwrap()
+                .count(),
+            1
+        );
+        assert_eq!(storage.url(&key), None);
+
+        storage.delete(&key).await.unwrap();
+        assert!(!path.exists());
+        storage.delete(&key).await.unwrap();
+    }
+
+    /// Check that concurrent writes of the same file don't mix.
+    #[tokio::test]
+    async fn concurrent_puts() {
+        let root = tempdir().unwrap();
+        let storage = LocalStorage::new(root.path(), false);
+        let key = crate_key("my-lib", "0.1.0");
+        let datas = (0..8)
+            .map(|i| Bytes::from(vec![i; 1 << 20]))
+            .collect::<Vec<_>>();
+        let puts = datas.iter().map(|data| storage.put(&key, data.clone()));
+        for result in futures::future::join_all(puts).await {
+            result.unwrap();
+        }
+        let data = storage.get(&key).await.unwrap().unwrap();
+        assert!(datas.contains(&data));
+    }
+}
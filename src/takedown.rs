@@ -0,0 +1,136 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+
+use crate::cli::TakedownArgs;
+use crate::index::Index;
+use crate::publish::crate_path;
+use crate::publish::readme_file_name;
+use crate::storage::crate_key;
+use crate::storage::open_storage;
+
+/// Why a crate version was taken down, stored next to where its crate file
+/// was. Unlike a yank, which leaves the crate downloadable for the lock
+/// files pinning it, a taken down version can't be downloaded anymore.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct Takedown {
+    /// The explanation given to the clients.
+    pub(crate) reason: String,
+    /// Until when, in seconds since the Unix epoch, the version remains
+    /// downloadable. Downloads are announced as deprecated until then.
+    pub(crate) sunset: Option<u64>,
+}
+
+/// The path of the takedown notice of the crate `name` in version `vers`.
+fn takedown_path(crates_folder: &Path, name: &str, vers: &str) -> PathBuf {
+    crates_folder
+        .join(crate_path(name))
+        .join(format!("{}-{}.takedown.json", name, vers))
+}
+
+impl Takedown {
+    /// Read the takedown notice of the crate `name` in version `vers`, if
+    /// it was taken down.
+    pub(crate) fn read(crates_folder: &Path, name: &str, vers: &str) -> Result<Option<Self>> {
+        let path = takedown_path(crates_folder, name, vers);
+        match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map(Some)
+                .with_context(|| format!("invalid takedown notice {}", path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    /// Whether the version can't be downloaded anymore at `now`.
+    pub(crate) fn in_effect(&self, now: SystemTime) -> bool {
+        self.sunset.map_or(true, |sunset| {
+            now.duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(true, |since| since.as_secs() >= sunset)
+        })
+    }
+
+    /// The sunset as a time, if any.
+    pub(crate) fn sunset_time(&self) -> Option<SystemTime> {
+        self.sunset
+            .map(|sunset| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(sunset))
+    }
+}
+
+/// Take down a crate version of the registry: yank it in the index, record
+/// the reason and, unless a sunset is given, delete its crate file and README.
+pub async fn takedown(takedown_args: &TakedownArgs) -> Result<()> {
+    let root = &takedown_args.root_registry;
+    let name = &takedown_args.crate_name;
+    let vers = &takedown_args.crate_version;
+    let sunset = takedown_args
+        .sunset
+        .as_deref()
+        .map(|sunset| {
+            let time = httpdate::parse_http_date(sunset)
+                .with_context(|| format!("invalid HTTP date {}", sunset))?;
+            Ok::<_, anyhow::Error>(
+                time.duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+            )
+        })
+        .transpose()?;
+
+    let index = Index::open(root.join("index"))?;
+    index
+        .set_yanked(name, vers, true)
+        .await?
+        .ok_or_else(|| anyhow!("crate {} has no version {}", name, vers))?;
+
+    let crates_folder = root.join("crates");
+    let path = takedown_path(&crates_folder, name, vers);
+    let notice = Takedown {
+        reason: takedown_args.reason.clone(),
+        sunset,
+    };
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, serde_json::to_vec(&notice)?)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    if sunset.is_none() {
+        // The crate file may be in a bucket, the README is in the registry.
+        let storage = open_storage(root, &takedown_args.storage, true)?;
+        storage.delete(&crate_key(name, vers)).await?;
+        let readme = readme_file_name(name, vers);
+        match std::fs::remove_file(crates_folder.join(crate_path(name)).join(&readme)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err).with_context(|| format!("failed to remove {}", readme))
+            }
+            _ => (),
+        }
+    }
+    info!("Took down {} in version {}", name, vers);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn takedown_in_effect() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let takedown = |sunset| Takedown {
+            reason: "legal".to_string(),
+            sunset,
+        };
+        assert!(takedown(None).in_effect(now));
+        assert!(takedown(Some(1000)).in_effect(now));
+        assert!(!takedown(Some(1001)).in_effect(now));
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::download::append_to_path;
+use crate::publish::PublishError;
+
+/// The acknowledgment of the publishing terms by a publisher.
+#[derive(Debug, Deserialize, Serialize)]
+struct Acceptance {
+    /// The URL of the accepted terms.
+    terms: String,
+    /// When they were accepted, in seconds since the Unix epoch.
+    accepted_at: u64,
+}
+
+/// The terms of `--publish-terms` the publishers must accept before their
+/// first publish, and who accepted them. The acceptances are stored by
+/// publisher identity in `<root>/accepted-terms.json`.
+#[derive(Debug)]
+pub(crate) struct PublishTerms {
+    url: String,
+    path: PathBuf,
+    accepted: Mutex<BTreeMap<String, Acceptance>>,
+}
+
+impl PublishTerms {
+    /// Load the acceptances of the terms at `url` of the registry at `root`.
+    pub(crate) fn load(root: &Path, url: &str) -> Result<Self> {
+        let path = root.join("accepted-terms.json");
+        let accepted = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("invalid terms acceptances in {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        Ok(Self {
+            url: url.to_string(),
+            path,
+            accepted: Mutex::new(accepted),
+        })
+    }
+
+    /// The URL of the terms.
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Check that `publisher` accepted the terms. Terms accepted at another
+    /// URL must be accepted again, and anonymous publishers can't accept
+    /// them.
+    pub(crate) fn check(&self, publisher: Option<&str>) -> Result<(), PublishError> {
+        let accepted = self.accepted.lock().unwrap();
+        match publisher.and_then(|publisher| accepted.get(publisher)) {
+            Some(acceptance) if acceptance.terms == self.url => Ok(()),
+            _ => Err(PublishError::TermsNotAccepted {
+                terms: self.url.clone(),
+            }),
+        }
+    }
+
+    /// Record that `publisher` accepted the terms.
+    pub(crate) fn accept(&self, publisher: &str) -> Result<()> {
+        let mut accepted = self.accepted.lock().unwrap();
+        let accepted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let previous = accepted.insert(
+            publisher.to_string(),
+            Acceptance {
+                terms: self.url.clone(),
+                accepted_at,
+            },
+        );
+        // Write aside first for a crash to never leave a truncated file.
+        let part_path = append_to_path(&self.path, ".part");
+        let written = std::fs::write(&part_path, serde_json::to_vec_pretty(&*accepted)?)
+            .with_context(|| format!("failed to write {}", part_path.display()))
+            .and_then(|()| {
+                std::fs::rename(&part_path, &self.path)
+                    .with_context(|| format!("failed to move {}", self.path.display()))
+            });
+        // Only the recorded acceptances count.
+        if written.is_err() {
+            match previous {
+                Some(previous) => accepted.insert(publisher.to_string(), previous),
+                None => accepted.remove(publisher),
+            };
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn acceptances_persisted() {
+        let root = tempdir().unwrap();
+        let terms = PublishTerms::load(root.path(), "https://example.com/terms").unwrap();
+        assert!(terms.check(Some("3f2a9c1b0d4e")).is_err());
+        terms.accept("3f2a9c1b0d4e").unwrap();
+        assert!(terms.check(Some("3f2a9c1b0d4e")).is_ok());
+        assert!(terms.check(Some("8d1e07c2b5a9")).is_err());
+        assert!(terms.check(None).is_err());
+
+        let terms = PublishTerms::load(root.path(), "https://example.com/terms").unwrap();
+        assert!(terms.check(Some("3f2a9c1b0d4e")).is_ok());
+        // New terms must be accepted again.
+        let terms = PublishTerms::load(root.path(), "https://example.com/terms-v2").unwrap();
+        let err = terms.check(Some("3f2a9c1b0d4e")).unwrap_err();
+        assert!(err.to_string().contains("https://example.com/terms-v2"));
+    }
+}
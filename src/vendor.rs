@@ -0,0 +1,887 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::StreamExt;
+use reqwest::header::HeaderValue;
+use reqwest::Client;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+
+use crate::cli::PrefetchDeps;
+use crate::cli::VendorArgs;
+use crate::download::download;
+use crate::proxy::{fetch_sparse_index_file, sparse_index_file_url};
+use crate::publish::{crate_file_name, crate_path, Kind};
+use crate::rustup::registry_progress_bar;
+
+/// The source of the packages coming from crates.io, as reported by `cargo metadata`.
+const CRATES_IO_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// The subset of the `cargo metadata` output needed for vendoring.
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+    resolve: Option<Resolve>,
+    /// The directory of the workspace, where its `Cargo.lock` is.
+    #[serde(default)]
+    workspace_root: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    id: String,
+    /// Where the package comes from, null for path dependencies.
+    source: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Resolve {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+    id: String,
+    deps: Vec<NodeDep>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NodeDep {
+    pkg: String,
+    dep_kinds: Vec<DepKindInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DepKindInfo {
+    /// The dependency kind, null for normal dependencies.
+    kind: Option<Kind>,
+}
+
+impl NodeDep {
+    /// Whether the dependency is still needed when the given kinds are left out.
+    fn is_followed(&self, exclude_kinds: &[Kind]) -> bool {
+        // Old cargo versions don't report the kinds, keep the dependency then.
+        self.dep_kinds.is_empty()
+            || self
+                .dep_kinds
+                .iter()
+                .any(|info| !exclude_kinds.contains(&info.kind.unwrap_or(Kind::Normal)))
+    }
+}
+
+/// Collect the packages reachable from the workspace members without going
+/// through dependencies of the excluded kinds.
+/// The workspace members themselves are not part of the result.
+fn dependency_closure<'m>(
+    metadata: &'m Metadata,
+    exclude_kinds: &[Kind],
+) -> Result<Vec<&'m Package>> {
+    let resolve = metadata
+        .resolve
+        .as_ref()
+        .ok_or_else(|| anyhow!("the metadata contains no dependency resolution"))?;
+    let nodes: HashMap<_, _> = resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+    let packages: HashMap<_, _> = metadata.packages.iter().map(|pkg| (&pkg.id, pkg)).collect();
+
+    let mut visited: HashSet<&String> = metadata.workspace_members.iter().collect();
+    let mut queue: VecDeque<&String> = metadata.workspace_members.iter().collect();
+    let mut closure = Vec::new();
+    while let Some(id) = queue.pop_front() {
+        let node = nodes
+            .get(id)
+            .ok_or_else(|| anyhow!("package {} is missing from the resolution", id))?;
+        for dep in node
+            .deps
+            .iter()
+            .filter(|dep| dep.is_followed(exclude_kinds))
+        {
+            if visited.insert(&dep.pkg) {
+                let package = packages
+                    .get(&dep.pkg)
+                    .ok_or_else(|| anyhow!("package {} is missing from the metadata", dep.pkg))?;
+                closure.push(*package);
+                queue.push_back(&dep.pkg);
+            }
+        }
+    }
+    Ok(closure)
+}
+
+/// Run `cargo metadata` on the project at `manifest_path`, resolved with
+/// the features of `prefetch` enabled on its members. As with Cargo, the
+/// features are only enabled on the workspace members, the dependencies
+/// keeping the features the members request.
+async fn cargo_metadata(manifest_path: &Path, prefetch: Option<&PrefetchDeps>) -> Result<Metadata> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let mut command = tokio::process::Command::new(cargo);
+    command
+        .args(["metadata", "--format-version", "1", "--manifest-path"])
+        .arg(manifest_path);
+    match prefetch {
+        Some(PrefetchDeps::AllFeatures) => {
+            command.arg("--all-features");
+        }
+        Some(PrefetchDeps::Features(features)) => {
+            command.arg("--features").arg(features.join(","));
+        }
+        None => {}
+    }
+    let output = command
+        .output()
+        .await
+        .context("failed to run cargo metadata")?;
+    if !output.status.success() {
+        bail!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    serde_json::from_slice(&output.stdout).context("failed to parse cargo metadata output")
+}
+
+/// The subset of a `Cargo.lock` file needed to check the vendored crates.
+#[derive(Debug, Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    /// The SHA-256 checksum of the `.crate` file, for registry packages.
+    checksum: Option<String>,
+}
+
+/// The checksums of the crates.io packages locked in the `Cargo.lock` of
+/// the workspace at `workspace_root`, by name and version.
+fn lock_checksums(workspace_root: &Path) -> Result<HashMap<(String, String), String>> {
+    let path = workspace_root.join("Cargo.lock");
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let lockfile: Lockfile =
+        toml::from_str(&content).with_context(|| format!("invalid {}", path.display()))?;
+    Ok(lockfile
+        .package
+        .into_iter()
+        .filter(|pkg| pkg.source.as_deref() == Some(CRATES_IO_SOURCE))
+        .filter_map(|pkg| Some(((pkg.name, pkg.version), pkg.checksum?)))
+        .collect())
+}
+
+/// The subset of a sparse index entry needed to resolve dependencies.
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    name: String,
+    vers: String,
+    deps: Vec<IndexDep>,
+    /// The SHA-256 checksum of the `.crate` file.
+    cksum: String,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+    /// The features using the newer syntaxes, e.g. "dep:name".
+    #[serde(default)]
+    features2: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    yanked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexDep {
+    /// The name the dependency is used under, see `package`.
+    name: String,
+    req: String,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    optional: bool,
+    #[serde(default = "default_features")]
+    default_features: bool,
+    /// The dependency kind, null for normal dependencies.
+    kind: Option<Kind>,
+    /// The actual name of a renamed dependency.
+    package: Option<String>,
+}
+
+fn default_features() -> bool {
+    true
+}
+
+impl IndexEntry {
+    /// What the feature `name` enables, if it is a feature.
+    fn feature(&self, name: &str) -> Option<&[String]> {
+        self.features
+            .get(name)
+            .or_else(|| self.features2.get(name))
+            .map(Vec::as_slice)
+    }
+
+    /// All the features, including the implicit features of the optional
+    /// dependencies.
+    fn all_features(&self) -> HashSet<String> {
+        self.features
+            .keys()
+            .chain(self.features2.keys())
+            .cloned()
+            .chain(
+                self.deps
+                    .iter()
+                    .filter(|dep| dep.optional)
+                    .map(|dep| dep.name.clone()),
+            )
+            .collect()
+    }
+
+    /// The dependencies activated by the `enabled` features, by the name
+    /// they are used under, with the features enabled on each of them.
+    fn active_deps(&self, enabled: &HashSet<String>) -> HashMap<&str, HashSet<String>> {
+        let mut active: HashMap<&str, HashSet<String>> = self
+            .deps
+            .iter()
+            .filter(|dep| !dep.optional)
+            .map(|dep| (dep.name.as_str(), HashSet::new()))
+            .collect();
+        // "dep?/feature" only enables the feature if the dependency is active.
+        let mut weak = Vec::new();
+        let mut seen = HashSet::new();
+        let mut features: Vec<&str> = enabled.iter().map(String::as_str).collect();
+        while let Some(feature) = features.pop() {
+            if !seen.insert(feature) {
+                continue;
+            }
+            let Some(items) = self.feature(feature) else {
+                // An optional dependency without an explicit feature.
+                if let Some(dep) = self.deps.iter().find(|dep| dep.name == feature) {
+                    active.entry(&dep.name).or_default();
+                }
+                continue;
+            };
+            for item in items {
+                if let Some(dep) = item.strip_prefix("dep:") {
+                    active.entry(dep).or_default();
+                } else if let Some((dep, dep_feature)) = item.split_once('/') {
+                    match dep.strip_suffix('?') {
+                        Some(dep) => weak.push((dep, dep_feature)),
+                        None => {
+                            active
+                                .entry(dep)
+                                .or_default()
+                                .insert(dep_feature.to_string());
+                        }
+                    }
+                } else {
+                    features.push(item);
+                }
+            }
+        }
+        for (dep, dep_feature) in weak {
+            if let Some(dep_features) = active.get_mut(dep) {
+                dep_features.insert(dep_feature.to_string());
+            }
+        }
+        active
+    }
+}
+
+/// A sparse index, whose crate files are fetched once.
+struct SparseIndex {
+    client: Client,
+    user_agent: HeaderValue,
+    url: String,
+    files: HashMap<String, Arc<Vec<IndexEntry>>>,
+}
+
+impl SparseIndex {
+    fn new(url: &str, client: Client, user_agent: HeaderValue) -> Self {
+        Self {
+            client,
+            user_agent,
+            url: url.to_string(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// All the versions of the crate `name`.
+    async fn versions(&mut self, name: &str) -> Result<Arc<Vec<IndexEntry>>> {
+        let key = name.to_lowercase();
+        if let Some(entries) = self.files.get(&key) {
+            return Ok(entries.clone());
+        }
+        let url = sparse_index_file_url(&self.url, name);
+        let content = fetch_sparse_index_file(&self.client, &self.user_agent, &url)
+            .await?
+            .ok_or_else(|| anyhow!("crate {} not found in the index", name))?;
+        let entries = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<IndexEntry>, _>>()
+            .with_context(|| format!("invalid index file {}", url))?;
+        let entries = Arc::new(entries);
+        self.files.insert(key, entries.clone());
+        Ok(entries)
+    }
+}
+
+/// Parse a "name@version" crate specification.
+fn parse_crate_spec(spec: &str) -> Result<(String, Version)> {
+    let (name, version) = spec
+        .split_once('@')
+        .ok_or_else(|| anyhow!("invalid crate {}, expected name@version", spec))?;
+    let version =
+        Version::parse(version).with_context(|| format!("invalid version in crate {}", spec))?;
+    Ok((name.to_string(), version))
+}
+
+/// Resolve the crate versions of `roots` and all their transitive
+/// dependencies against `index`, without going through dependencies of the
+/// excluded kinds. Each dependency is resolved to the highest version
+/// matching its requirement that isn't yanked, and only the optional
+/// dependencies enabled by the default features or the requested ones are
+/// followed. With `prefetch`, its features are enabled on the roots, or all
+/// the features on every crate. As with Cargo, the dev-dependencies are only
+/// followed for the roots. Returns the name, version and checksum of the
+/// crates.
+async fn resolve_crates(
+    index: &mut SparseIndex,
+    roots: &[(String, Version)],
+    exclude_kinds: &[Kind],
+    prefetch: Option<&PrefetchDeps>,
+) -> Result<Vec<(String, String, Option<String>)>> {
+    let mut features: BTreeMap<(String, String), HashSet<String>> = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    let mut root_keys = HashSet::new();
+    for (name, version) in roots {
+        let versions = index.versions(name).await?;
+        let entry = versions
+            .iter()
+            .find(|entry| Version::parse(&entry.vers).ok().as_ref() == Some(version))
+            .ok_or_else(|| anyhow!("crate {} has no version {} in the index", name, version))?;
+        let key = (entry.name.clone(), entry.vers.clone());
+        let mut enabled = HashSet::from(["default".to_string()]);
+        if let Some(PrefetchDeps::Features(requested)) = prefetch {
+            enabled.extend(requested.iter().cloned());
+        }
+        features.insert(key.clone(), enabled);
+        root_keys.insert(key.clone());
+        queue.push_back(key);
+    }
+
+    while let Some(key) = queue.pop_front() {
+        let (name, vers) = &key;
+        let versions = index.versions(name).await?;
+        let entry = versions
+            .iter()
+            .find(|entry| &entry.vers == vers)
+            .ok_or_else(|| anyhow!("crate {} has no version {} in the index", name, vers))?;
+        let active = if prefetch == Some(&PrefetchDeps::AllFeatures) {
+            entry.active_deps(&entry.all_features())
+        } else {
+            entry.active_deps(&features[&key])
+        };
+        for dep in &entry.deps {
+            let Some(dep_features) = active.get(dep.name.as_str()) else {
+                continue;
+            };
+            let kind = dep.kind.unwrap_or(Kind::Normal);
+            if exclude_kinds.contains(&kind) || (kind == Kind::Dev && !root_keys.contains(&key)) {
+                continue;
+            }
+            let package = dep.package.as_deref().unwrap_or(&dep.name);
+            let req = VersionReq::parse(&dep.req).with_context(|| {
+                format!("invalid requirement {} of {} on {}", dep.req, name, package)
+            })?;
+            let candidates = index.versions(package).await?;
+            let resolved = candidates
+                .iter()
+                .filter(|candidate| !candidate.yanked)
+                .filter_map(|candidate| {
+                    let version = Version::parse(&candidate.vers).ok()?;
+                    req.matches(&version).then_some((version, candidate))
+                })
+                .max_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(_, candidate)| candidate)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "no version of {} matches {}, as required by {} {}",
+                        package,
+                        dep.req,
+                        name,
+                        vers
+                    )
+                })?;
+            debug!(
+                "{} {} requires {} {}",
+                name, vers, resolved.name, resolved.vers
+            );
+
+            let mut requested: HashSet<String> =
+                dep.features.iter().chain(dep_features).cloned().collect();
+            if dep.default_features {
+                requested.insert("default".to_string());
+            }
+            let dep_key = (resolved.name.clone(), resolved.vers.clone());
+            let enabled = features.entry(dep_key.clone()).or_default();
+            let before = enabled.len();
+            enabled.extend(requested);
+            // Process a crate again whenever more of its features are enabled.
+            if enabled.len() > before || before == 0 {
+                queue.push_back(dep_key);
+            }
+        }
+    }
+    let mut crates = Vec::new();
+    for (name, vers) in features.into_keys() {
+        let cksum = index
+            .versions(&name)
+            .await?
+            .iter()
+            .find(|entry| entry.vers == vers)
+            .map(|entry| entry.cksum.clone());
+        crates.push((name, vers, cksum));
+    }
+    Ok(crates)
+}
+
+/// Download the crates a project depends on, or the crates given with
+/// `--crates` and their dependencies, into the `crates` folder of the output
+/// directory, using the layout of the root registry.
+pub async fn vendor(vendor_args: VendorArgs) -> Result<()> {
+    let client = Client::new();
+    let user_agent =
+        HeaderValue::from_str(&format!("Offline Mirror/{}", env!("CARGO_PKG_VERSION")))
+            .expect("Hardcoded user agent string should never fail.");
+
+    let crates = if vendor_args.crates.is_empty() {
+        let metadata = cargo_metadata(
+            &vendor_args.manifest_path,
+            vendor_args.prefetch_deps.as_ref(),
+        )
+        .await?;
+        let checksums = lock_checksums(&metadata.workspace_root)?;
+        let closure = dependency_closure(&metadata, &vendor_args.exclude_kinds)?;
+        let (crates, others): (Vec<_>, Vec<_>) = closure
+            .into_iter()
+            .partition(|pkg| pkg.source.as_deref() == Some(CRATES_IO_SOURCE));
+        for pkg in others {
+            warn!(
+                "Skipping {} {}, only crates.io dependencies can be vendored",
+                pkg.name, pkg.version
+            );
+        }
+        crates
+            .into_iter()
+            .map(|pkg| {
+                let key = (pkg.name.clone(), pkg.version.clone());
+                let cksum = checksums.get(&key).cloned();
+                if cksum.is_none() {
+                    warn!(
+                        "{} {} has no checksum in the Cargo.lock, it is downloaded unchecked",
+                        pkg.name, pkg.version
+                    );
+                }
+                (key.0, key.1, cksum)
+            })
+            .collect()
+    } else {
+        let roots = vendor_args
+            .crates
+            .iter()
+            .map(|spec| parse_crate_spec(spec))
+            .collect::<Result<Vec<_>>>()?;
+        let mut index = SparseIndex::new(&vendor_args.index, client.clone(), user_agent.clone());
+        resolve_crates(
+            &mut index,
+            &roots,
+            &vendor_args.exclude_kinds,
+            vendor_args.prefetch_deps.as_ref(),
+        )
+        .await?
+    };
+
+    info!("Downloading {} crates...", crates.len());
+    let crates_folder = vendor_args.output.join("crates");
+    let pb = registry_progress_bar(crates.len());
+    pb.enable_steady_tick(Duration::from_millis(10));
+
+    let failures = futures::stream::iter(crates)
+        .map(|(name, version, cksum)| {
+            let client = client.clone();
+            let user_agent = user_agent.clone();
+            let pb = pb.clone();
+            let file_name = crate_file_name(&name, &version);
+            let url = format!("{}/{}/{}", vendor_args.source, name, file_name);
+            let path = crates_folder.join(crate_path(&name)).join(file_name);
+            let retries = vendor_args.retry_policy();
+            async move {
+                let out = download(
+                    &client,
+                    &url,
+                    &path,
+                    cksum.as_deref(),
+                    retries,
+                    false,
+                    &user_agent,
+                )
+                .await;
+                pb.inc(1);
+                out.map_err(|e| error!("Failed to download {}: {}", url, e))
+            }
+        })
+        .buffer_unordered(vendor_args.threads)
+        .filter(|result| futures::future::ready(result.is_err()))
+        .count()
+        .await;
+
+    if failures > 0 {
+        bail!("failed {} downloads", failures);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use warp::Filter as _;
+
+    /// A workspace with a single member `app` depending on `serde` (normal),
+    /// `cc` (build) and `criterion` (dev), `criterion` being also a normal
+    /// dependency of `bench-utils` which is only a dev dependency of `app`.
+    const METADATA: &str = r#"{
+        "packages": [
+            {"name": "app", "version": "0.1.0", "id": "app 0.1.0 (path+file:///app)", "source": null},
+            {"name": "serde", "version": "1.0.160", "id": "serde 1.0.160 (registry+https://github.com/rust-lang/crates.io-index)", "source": "registry+https://github.com/rust-lang/crates.io-index"},
+            {"name": "serde_derive", "version": "1.0.160", "id": "serde_derive 1.0.160 (registry+https://github.com/rust-lang/crates.io-index)", "source": "registry+https://github.com/rust-lang/crates.io-index"},
+            {"name": "cc", "version": "1.0.79", "id": "cc 1.0.79 (registry+https://github.com/rust-lang/crates.io-index)", "source": "registry+https://github.com/rust-lang/crates.io-index"},
+            {"name": "criterion", "version": "0.4.0", "id": "criterion 0.4.0 (registry+https://github.com/rust-lang/crates.io-index)", "source": "registry+https://github.com/rust-lang/crates.io-index"},
+            {"name": "bench-utils", "version": "0.1.0", "id": "bench-utils 0.1.0 (path+file:///bench-utils)", "source": null}
+        ],
+        "workspace_members": ["app 0.1.0 (path+file:///app)"],
+        "resolve": {
+            "root": "app 0.1.0 (path+file:///app)",
+            "nodes": [
+                {"id": "app 0.1.0 (path+file:///app)", "deps": [
+                    {"name": "serde", "pkg": "serde 1.0.160 (registry+https://github.com/rust-lang/crates.io-index)", "dep_kinds": [{"kind": null, "target": null}]},
+                    {"name": "cc", "pkg": "cc 1.0.79 (registry+https://github.com/rust-lang/crates.io-index)", "dep_kinds": [{"kind": "build", "target": null}]},
+                    {"name": "criterion", "pkg": "criterion 0.4.0 (registry+https://github.com/rust-lang/crates.io-index)", "dep_kinds": [{"kind": "dev", "target": null}]},
+                    {"name": "bench_utils", "pkg": "bench-utils 0.1.0 (path+file:///bench-utils)", "dep_kinds": [{"kind": "dev", "target": null}]}
+                ]},
+                {"id": "serde 1.0.160 (registry+https://github.com/rust-lang/crates.io-index)", "deps": [
+                    {"name": "serde_derive", "pkg": "serde_derive 1.0.160 (registry+https://github.com/rust-lang/crates.io-index)", "dep_kinds": [{"kind": null, "target": null}]}
+                ]},
+                {"id": "serde_derive 1.0.160 (registry+https://github.com/rust-lang/crates.io-index)", "deps": []},
+                {"id": "cc 1.0.79 (registry+https://github.com/rust-lang/crates.io-index)", "deps": []},
+                {"id": "criterion 0.4.0 (registry+https://github.com/rust-lang/crates.io-index)", "deps": []},
+                {"id": "bench-utils 0.1.0 (path+file:///bench-utils)", "deps": [
+                    {"name": "criterion", "pkg": "criterion 0.4.0 (registry+https://github.com/rust-lang/crates.io-index)", "dep_kinds": [{"kind": null, "target": null}]}
+                ]}
+            ]
+        }
+    }"#;
+
+    fn closure_names(exclude_kinds: &[Kind]) -> Vec<String> {
+        let metadata: Metadata = serde_json::from_str(METADATA).unwrap();
+        let mut names = dependency_closure(&metadata, exclude_kinds)
+            .unwrap()
+            .into_iter()
+            .map(|pkg| pkg.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn closure_with_all_kinds() {
+        assert_eq!(
+            closure_names(&[]),
+            ["bench-utils", "cc", "criterion", "serde", "serde_derive"]
+        );
+    }
+
+    #[test]
+    fn closure_excluding_dev_deps() {
+        assert_eq!(closure_names(&[Kind::Dev]), ["cc", "serde", "serde_derive"]);
+        assert_eq!(
+            closure_names(&[Kind::Dev, Kind::Build]),
+            ["serde", "serde_derive"]
+        );
+    }
+
+    /// Serve a sparse index where `root-lib` 1.0.0 depends on `log-lib`
+    /// (normal, with a yanked and a non-matching version), `fmt-lib`
+    /// (optional, enabled by default), `serde-lib` (optional, not enabled),
+    /// `cc-lib` (build) and `test-lib` (dev), `test-lib` also being a
+    /// dev-dependency of `log-lib`. `serde-lib` optionally depends on
+    /// `derive-lib`, enabled by the `json` feature of `root-lib`, and on
+    /// `extra-lib`.
+    fn fixture_index() -> SocketAddr {
+        let entry = |name: &str, vers: &str, yanked: bool, deps: &str, features: &str| {
+            format!(
+                r#"{{"name":"{name}","vers":"{vers}","deps":[{deps}],"cksum":"00","features":{features},"yanked":{yanked}}}"#
+            )
+        };
+        let dep = |name: &str, req: &str, kind: &str, optional: bool| {
+            format!(
+                r#"{{"name":"{name}","req":"{req}","features":[],"optional":{optional},"default_features":true,"target":null,"kind":"{kind}"}}"#
+            )
+        };
+        let files = HashMap::from([
+            (
+                "ro/ot/root-lib",
+                entry(
+                    "root-lib",
+                    "1.0.0",
+                    false,
+                    &[
+                        dep("log-lib", "^0.4", "normal", false),
+                        dep("fmt-lib", "^2", "normal", true),
+                        dep("serde-lib", "^1", "normal", true),
+                        dep("cc-lib", "^1", "build", false),
+                        dep("test-lib", "^1", "dev", false),
+                    ]
+                    .join(","),
+                    r#"{"default":["fmt"],"fmt":["dep:fmt-lib"],"json":["serde-lib/derive"]}"#,
+                ),
+            ),
+            (
+                "lo/g-/log-lib",
+                [
+                    entry(
+                        "log-lib",
+                        "0.4.1",
+                        false,
+                        &dep("test-lib", "^1", "dev", false),
+                        "{}",
+                    ),
+                    entry("log-lib", "0.4.2", true, "", "{}"),
+                    entry("log-lib", "0.5.0", false, "", "{}"),
+                ]
+                .join("\n"),
+            ),
+            (
+                "fm/t-/fmt-lib",
+                [
+                    entry("fmt-lib", "2.0.0", false, "", "{}"),
+                    entry("fmt-lib", "2.1.0", false, "", "{}"),
+                ]
+                .join("\n"),
+            ),
+            (
+                "se/rd/serde-lib",
+                entry(
+                    "serde-lib",
+                    "1.0.0",
+                    false,
+                    &[
+                        dep("derive-lib", "^1", "normal", true),
+                        dep("extra-lib", "^1", "normal", true),
+                    ]
+                    .join(","),
+                    r#"{"derive":["dep:derive-lib"]}"#,
+                ),
+            ),
+            (
+                "de/ri/derive-lib",
+                entry("derive-lib", "1.0.0", false, "", "{}"),
+            ),
+            (
+                "ex/tr/extra-lib",
+                entry("extra-lib", "1.0.0", false, "", "{}"),
+            ),
+            ("cc/-l/cc-lib", entry("cc-lib", "1.0.0", false, "", "{}")),
+            (
+                "te/st/test-lib",
+                entry("test-lib", "1.0.0", false, "", "{}"),
+            ),
+        ]);
+        let routes = warp::path::tail().and_then(move |tail: warp::path::Tail| {
+            let file = files.get(tail.as_str()).cloned();
+            async move { file.ok_or_else(warp::reject::not_found) }
+        });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn resolve_fixture(
+        exclude_kinds: &[Kind],
+        prefetch: Option<&PrefetchDeps>,
+    ) -> Vec<String> {
+        let mut index = SparseIndex::new(
+            &format!("http://{}/", fixture_index()),
+            Client::new(),
+            HeaderValue::from_static("test"),
+        );
+        let roots = [parse_crate_spec("root-lib@1.0.0").unwrap()];
+        resolve_crates(&mut index, &roots, exclude_kinds, prefetch)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(name, vers, cksum)| {
+                assert_eq!(cksum.as_deref(), Some("00"));
+                format!("{name}@{vers}")
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resolve_crates_from_index() {
+        assert_eq!(
+            resolve_fixture(&[], None).await,
+            [
+                "cc-lib@1.0.0",
+                "fmt-lib@2.1.0",
+                "log-lib@0.4.1",
+                "root-lib@1.0.0",
+                "test-lib@1.0.0"
+            ]
+        );
+        assert_eq!(
+            resolve_fixture(&[Kind::Dev, Kind::Build], None).await,
+            ["fmt-lib@2.1.0", "log-lib@0.4.1", "root-lib@1.0.0"]
+        );
+    }
+
+    /// Check that `--prefetch-deps` follows the optional dependencies of the
+    /// requested features, or of all of them.
+    #[tokio::test]
+    async fn resolve_prefetched_deps() {
+        let features = PrefetchDeps::Features(vec!["json".to_string()]);
+        assert_eq!(
+            resolve_fixture(&[Kind::Dev, Kind::Build], Some(&features)).await,
+            [
+                "derive-lib@1.0.0",
+                "fmt-lib@2.1.0",
+                "log-lib@0.4.1",
+                "root-lib@1.0.0",
+                "serde-lib@1.0.0"
+            ]
+        );
+        assert_eq!(
+            resolve_fixture(&[Kind::Dev, Kind::Build], Some(&PrefetchDeps::AllFeatures)).await,
+            [
+                "derive-lib@1.0.0",
+                "extra-lib@1.0.0",
+                "fmt-lib@2.1.0",
+                "log-lib@0.4.1",
+                "root-lib@1.0.0",
+                "serde-lib@1.0.0"
+            ]
+        );
+    }
+
+    /// Write a workspace where the member `app` optionally depends on the
+    /// path crate `extra` behind its `extra` feature, `extra` optionally
+    /// depending on `deeper` behind a feature of its own.
+    fn manifest_fixture(root: &Path) -> PathBuf {
+        let write = |name: &str, manifest: &str| {
+            std::fs::create_dir_all(root.join(name).join("src")).unwrap();
+            std::fs::write(root.join(name).join("Cargo.toml"), manifest).unwrap();
+            std::fs::write(root.join(name).join("src/lib.rs"), "").unwrap();
+        };
+        write(
+            "app",
+            r#"[package]
+name = "app"
+version = "0.1.0"
+
+[dependencies]
+extra = { path = "../extra", optional = true }
+"#,
+        );
+        write(
+            "extra",
+            r#"[package]
+name = "extra"
+version = "0.1.0"
+
+[dependencies]
+deeper = { path = "../deeper", optional = true }
+"#,
+        );
+        write(
+            "deeper",
+            "[package]\nname = \"deeper\"\nversion = \"0.1.0\"\n",
+        );
+        root.join("app").join("Cargo.toml")
+    }
+
+    #[tokio::test]
+    async fn manifest_prefetched_deps() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = manifest_fixture(dir.path());
+        let closure = |prefetch: Option<PrefetchDeps>| {
+            let manifest_path = manifest_path.clone();
+            async move {
+                let metadata = cargo_metadata(&manifest_path, prefetch.as_ref())
+                    .await
+                    .unwrap();
+                dependency_closure(&metadata, &[])
+                    .unwrap()
+                    .into_iter()
+                    .map(|pkg| pkg.name.clone())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert!(closure(None).await.is_empty());
+        let features = PrefetchDeps::Features(vec!["extra".to_string()]);
+        assert_eq!(closure(Some(features)).await, ["extra"]);
+        // The features of the dependencies aren't enabled.
+        assert_eq!(closure(Some(PrefetchDeps::AllFeatures)).await, ["extra"]);
+    }
+
+    #[test]
+    fn lock_checksums_parsing() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("Cargo.lock"),
+            r#"version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["serde"]
+
+[[package]]
+name = "serde"
+version = "1.0.160"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "bb2f3770c8bce3bcda7e149193a069a0f4365bda1fa5cd88e03bca26afc1216c"
+
+[[package]]
+name = "forked"
+version = "1.0.0"
+source = "git+https://example.com/forked#0123456789abcdef"
+"#,
+        )
+        .unwrap();
+        let checksums = lock_checksums(dir.path()).unwrap();
+        assert_eq!(checksums.len(), 1);
+        assert_eq!(
+            checksums[&("serde".to_string(), "1.0.160".to_string())],
+            "bb2f3770c8bce3bcda7e149193a069a0f4365bda1fa5cd88e03bca26afc1216c"
+        );
+    }
+
+    #[test]
+    fn crate_spec_parsing() {
+        assert_eq!(
+            parse_crate_spec("serde@1.0.160").unwrap(),
+            ("serde".to_string(), Version::new(1, 0, 160))
+        );
+        assert!(parse_crate_spec("serde").is_err());
+        assert!(parse_crate_spec("serde@1.0").is_err());
+    }
+}
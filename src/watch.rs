@@ -0,0 +1,194 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use notify::event::EventKind;
+use notify::Event;
+use notify::RecursiveMode;
+use notify::Watcher as _;
+use tokio::sync::mpsc;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::index::Index;
+use crate::repair::collect_crate_files;
+use crate::repair::crate_file_entry;
+use crate::repair::crate_file_version;
+
+/// How long the crate files must stay unchanged before being indexed, for
+/// the files still being written and the other files of a batch to be
+/// indexed together.
+pub(crate) const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Add the `.crate` files of the created or changed files of `event` to
+/// `paths`.
+fn changed_crate_files(event: notify::Result<Event>, paths: &mut BTreeSet<PathBuf>) {
+    let event = match event {
+        Ok(event) => event,
+        Err(err) => {
+            warn!("Failed to watch the crate files: {}", err);
+            return;
+        }
+    };
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+        return;
+    }
+    for path in event.paths {
+        if path.is_dir() {
+            // The files written to a new directory before the watcher
+            // watched it come without event.
+            let mut files = Vec::new();
+            match collect_crate_files(&path, &mut files) {
+                Ok(()) => paths.extend(files),
+                Err(err) => warn!("{:#}", err),
+            }
+        } else if path
+            .extension()
+            .map_or(false, |extension| extension == "crate")
+        {
+            paths.insert(path);
+        }
+    }
+}
+
+/// Add the crate files at `paths` missing from `index` to it, from their
+/// manifest, in a single commit.
+async fn index_crate_files(index: &Index, crates_folder: &Path, paths: BTreeSet<PathBuf>) {
+    let mut entries = Vec::new();
+    for path in paths {
+        // The file may have been moved or removed since.
+        if !path.is_file() {
+            continue;
+        }
+        // Published crates, in particular, are already in the index.
+        match crate_file_version(crates_folder, &path).map(|(name, vers)| index.entry(&name, &vers))
+        {
+            Some(Ok(Some(_))) => continue,
+            Some(Err(err)) => {
+                warn!(
+                    "Failed to look up {} in the index: {:#}",
+                    path.display(),
+                    err
+                );
+                continue;
+            }
+            _ => {}
+        }
+        match crate_file_entry(crates_folder, &path) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => warn!("Failed to index {}: {:#}", path.display(), err),
+        }
+    }
+    if entries.is_empty() {
+        return;
+    }
+    let versions = entries
+        .iter()
+        .map(|entry| format!("{} {}", entry.name, entry.vers))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let message = format!("Index the crate files of {}", versions);
+    match index.add_entries(entries, &message).await {
+        Ok(added) if added > 0 => info!("Indexed the crate files of {}", versions),
+        Ok(_) => {}
+        Err(err) => error!("Failed to index the crate files of {}: {:#}", versions, err),
+    }
+}
+
+/// Watch `crates_folder` for the crate files added out of band and add them
+/// to `index`, once no crate file changed for `debounce`. The returned
+/// future watches until dropped.
+pub(crate) fn watch_crates(
+    index: Arc<Index>,
+    crates_folder: PathBuf,
+    debounce: Duration,
+) -> Result<impl Future<Output = ()>> {
+    std::fs::create_dir_all(&crates_folder)
+        .with_context(|| format!("failed to create directory {}", crates_folder.display()))?;
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // The receiver is gone when the registry stops.
+        let _ = sender.send(event);
+    })
+    .context("failed to create the crate files watcher")?;
+    watcher
+        .watch(&crates_folder, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch {}", crates_folder.display()))?;
+    Ok(async move {
+        // Events are only sent as long as the watcher lives.
+        let _watcher = watcher;
+        while let Some(event) = receiver.recv().await {
+            let mut paths = BTreeSet::new();
+            changed_crate_files(event, &mut paths);
+            while let Ok(Some(event)) = tokio::time::timeout(debounce, receiver.recv()).await {
+                changed_crate_files(event, &mut paths);
+            }
+            if !paths.is_empty() {
+                index_crate_files(&index, &crates_folder, paths).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use tempfile::tempdir;
+
+    use crate::publish::tests::crate_data;
+
+    /// Check that crate files dropped together in the crates folder are
+    /// added to the index in a single commit.
+    #[tokio::test]
+    async fn dropped_crate_files_indexed() {
+        let root = tempdir().unwrap();
+        let index = Arc::new(
+            Index::new(root.path().join("index"), "http://127.0.0.1:0")
+                .await
+                .unwrap(),
+        );
+        let crates_folder = root.path().join("crates");
+        let watch = watch_crates(
+            index.clone(),
+            crates_folder.clone(),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+        tokio::spawn(watch);
+
+        let repository = git2::Repository::open(root.path().join("index")).unwrap();
+        let head = repository.head().unwrap().target().unwrap();
+        for (name, dir) in [("my-lib", "my/-l"), ("other", "ot/he")] {
+            let manifest = format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n");
+            let data = crate_data(name, "0.1.0", &[("Cargo.toml", &manifest)]);
+            let dir = crates_folder.join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(format!("{name}-0.1.0.crate")), data).unwrap();
+        }
+        // Not a crate file.
+        std::fs::write(crates_folder.join("notes.txt"), "").unwrap();
+
+        let started = Instant::now();
+        while repository.head().unwrap().target().unwrap() == head {
+            assert!(started.elapsed() < Duration::from_secs(10), "not indexed");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(index.entry("my-lib", "0.1.0").unwrap().is_some());
+        assert!(index.entry("other", "0.1.0").unwrap().is_some());
+        let commit = repository.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), head);
+        assert_eq!(
+            commit.message(),
+            Some("Index the crate files of my-lib 0.1.0, other 0.1.0")
+        );
+    }
+}
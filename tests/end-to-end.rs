@@ -18,7 +18,13 @@ use tokio::net::TcpListener;
 use tokio::spawn;
 use tokio::task::JoinHandle;
 
+use clap::Parser as _;
+
+use crates_registry::bootstrap_index;
 use crates_registry::serve;
+use crates_registry::Cli;
+use crates_registry::Commands;
+use crates_registry::ServeArgs;
 
 const REGISTRY: &str = "e2e-test-registry";
 
@@ -47,7 +53,6 @@ where
 {
     let mut file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(file)
         .context("failed to open file for writing")?;
@@ -70,9 +75,8 @@ index = "{path}"
 token = "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"
 "#,
                 registry = REGISTRY,
-                path = Url::from_file_path(path)
-                    .map_err(|_| anyhow!("Can't convert path to url"))?
-                    .to_string(),
+                path =
+                    Url::from_file_path(path).map_err(|_| anyhow!("Can't convert path to url"))?,
             )
         }
         Locator::Socket(addr) => {
@@ -118,7 +122,7 @@ where
 {
     let args = vec!["init", "--vcs", "none", "--registry", REGISTRY]
         .into_iter()
-        .chain(args.into_iter());
+        .chain(args);
 
     cargo(home, args).await
 }
@@ -138,7 +142,7 @@ where
         REGISTRY,
     ]
     .into_iter()
-    .chain(args.into_iter());
+    .chain(args);
 
     cargo(home, args).await
 }
@@ -147,17 +151,34 @@ where
 async fn serve_registry() -> (JoinHandle<()>, PathBuf, SocketAddr) {
     let root = tempdir().unwrap();
     let path = root.path();
-        let listener = get_listener_in_available_port().await;
-        let addr = listener.local_addr().unwrap();
+    let listener = get_listener_in_available_port().await;
+    let addr = listener.local_addr().unwrap();
+
+    let serve_args = serve_args(path, addr, &[]);
+    let handle = spawn(async move { serve(serve_args, listener).await.unwrap() });
 
-        let server = move || {
-            let path = path.to_owned();
-            let addr = addr.clone();
-            async move { serve(&path, listener, addr).await.unwrap() }
-        };
-        let handle = spawn(server());
+    (handle, path.to_owned(), addr)
+}
 
-        (handle, path.to_owned(), addr)
+/// Create the arguments for serving the registry at `root` as if they
+/// were passed on the command line, together with `extra_args`.
+fn serve_args(root: &Path, addr: SocketAddr, extra_args: &[&str]) -> ServeArgs {
+    let addr = addr.to_string();
+    let args = [
+        "crates-registry",
+        "serve",
+        "--root-registry",
+        root.to_str().unwrap(),
+        "--server-addr",
+        &addr,
+    ]
+    .into_iter()
+    .chain(extra_args.iter().copied());
+    let cli = Cli::try_parse_from(args).unwrap();
+    match cli.command {
+        Commands::Serve(serve_args) => serve_args,
+        _ => unreachable!(),
+    }
 }
 
 /// Check that we can publish a crate.
@@ -243,3 +264,164 @@ async fn get_filesystem() {
     let (_handle, root, _) = serve_registry().await;
     test_publish_and_consume(Locator::Path(root.join("index"))).await
 }
+
+/// Check that a larger index can be cloned when it is streamed through a
+/// small buffer.
+#[tokio::test]
+async fn clone_index_with_small_buffer() {
+    let source = tempdir().unwrap();
+    for i in 0..500 {
+        let name = format!("crate-{i:03}");
+        let dir = source.path().join("cr").join("at");
+        std::fs::create_dir_all(&dir).unwrap();
+        let entries = (0..20)
+            .map(|minor| {
+                format!(
+                    r#"{{"name":"{name}","vers":"0.{minor}.0","deps":[],"cksum":"{:064x}","features":{{}},"yanked":false,"links":null}}"#,
+                    i * 100 + minor
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        std::fs::write(dir.join(&name), entries).unwrap();
+    }
+
+    let root = tempdir().unwrap();
+    let imported = bootstrap_index(root.path(), source.path().to_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(imported, 500);
+
+    let listener = get_listener_in_available_port().await;
+    let addr = listener.local_addr().unwrap();
+    let serve_args = serve_args(root.path(), addr, &["--git-buffer-size", "512"]);
+    let _handle = spawn(async move { serve(serve_args, listener).await.unwrap() });
+
+    let clone = tempdir().unwrap();
+    let clone_path = clone.path().join("index");
+    let url = format!("http://{addr}/git/index");
+    let status = tokio::process::Command::new("git")
+        .args(["clone", "--quiet", &url, clone_path.to_str().unwrap()])
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+    let cloned = std::fs::read_dir(clone_path.join("cr").join("at"))
+        .unwrap()
+        .count();
+    assert_eq!(cloned, 500);
+}
+
+/// Fetch the index over the smart HTTP protocol and check that the clone
+/// matches the registry index, commit and content.
+#[tokio::test]
+async fn clone_index_matches() {
+    let source = tempdir().unwrap();
+    let dir = source.path().join("se").join("rd");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("serde"),
+        r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"0000000000000000000000000000000000000000000000000000000000000001","features":{},"yanked":false,"links":null}"#,
+    )
+    .unwrap();
+
+    let root = tempdir().unwrap();
+    bootstrap_index(root.path(), source.path().to_str().unwrap())
+        .await
+        .unwrap();
+
+    let listener = get_listener_in_available_port().await;
+    let addr = listener.local_addr().unwrap();
+    let serve_args = serve_args(root.path(), addr, &[]);
+    let _handle = spawn(async move { serve(serve_args, listener).await.unwrap() });
+
+    let clone = tempdir().unwrap();
+    let clone_path = clone.path().join("index");
+    let url = format!("http://{addr}/git/index");
+    let status = tokio::process::Command::new("git")
+        .args(["clone", "--quiet", &url])
+        .arg(&clone_path)
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+
+    let head = |repo: &Path| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let index_path = root.path().join("index");
+    assert_eq!(head(&clone_path), head(&index_path));
+    for file in ["config.json", "se/rd/serde"] {
+        assert_eq!(
+            std::fs::read(clone_path.join(file)).unwrap(),
+            std::fs::read(index_path.join(file)).unwrap(),
+        );
+    }
+}
+
+/// Serve only the index with `--index-only`: it can be cloned and points the
+/// downloads to the other registry, which serves them instead of this one.
+#[tokio::test]
+async fn index_only() {
+    let root = tempdir().unwrap();
+    let listener = get_listener_in_available_port().await;
+    let addr = listener.local_addr().unwrap();
+    let serve_args = serve_args(
+        root.path(),
+        addr,
+        &["--index-only", "http://crates.example:8080/"],
+    );
+    let _handle = spawn(async move { serve(serve_args, listener).await.unwrap() });
+
+    let clone = tempdir().unwrap();
+    let clone_path = clone.path().join("index");
+    let url = format!("http://{addr}/git/index");
+    let status = tokio::process::Command::new("git")
+        .args(["clone", "--quiet", &url])
+        .arg(&clone_path)
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+    let config: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(clone_path.join("config.json")).unwrap()).unwrap();
+    assert_eq!(
+        config["dl"],
+        "http://crates.example:8080/api/v1/crates/{crate}/{version}/download"
+    );
+    assert_eq!(config["api"], "http://crates.example:8080");
+
+    let response = reqwest::get(format!("http://{addr}/index/config.json"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let response = reqwest::get(format!("http://{addr}/api/v1/crates/foo/0.1.0/download"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+/// Check that `--json-errors` prints a failing command's error as a JSON object.
+#[test]
+fn json_errors() {
+    let root = tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_crates-registry"))
+        .args(["index-status", "--json-errors", "--root-registry"])
+        .arg(root.path().join("missing"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr.lines().last().unwrap();
+    let error: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert!(error["error"].is_string());
+    assert!(error["context"].is_array());
+}